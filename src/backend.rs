@@ -0,0 +1,738 @@
+//! Sources of filesystem events: the native notify backend of the platform,
+//! or polling where native events are unavailable or unreliable.
+
+use crate::budget::FdBudget;
+use crate::config::Settings;
+use crate::error::{MonitorError, Result};
+use crate::hardlinks::Hardlinks;
+use crate::io::EventSender;
+use crate::monitor::{validate_dir, Event, Tag, Watch};
+use log::{debug, warn};
+use notify::event::{EventKind, Flag, MetadataKind, ModifyKind};
+use notify::{Config, PollWatcher, RecommendedWatcher, RecursiveMode, WatcherKind};
+use notify_debouncer_full::{new_debouncer_opt, DebounceEventResult, Debouncer, FileIdMap};
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long events for one path are collected before they are delivered, by
+/// default.
+pub const DEBOUNCE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Shortest debounce timeout accepted, the debouncer ticks at a quarter of it.
+pub const MIN_DEBOUNCE_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Longest debounce timeout accepted, unison would look stuck beyond it.
+pub const MAX_DEBOUNCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the polling backend scans watched trees, by default.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Shortest poll interval accepted.
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest poll interval accepted.
+pub const MAX_POLL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long FSEvents collects changes before passing them on, by default.
+pub const FSEVENTS_LATENCY: Duration = Duration::ZERO;
+
+/// Longest FSEvents latency accepted, the debounce timeout adds to it.
+pub const MAX_FSEVENTS_LATENCY: Duration = Duration::from_secs(10);
+
+/// Events a second from one replica that make a storm, by default.
+pub const STORM_RATE: usize = 1000;
+
+/// Longest events are held back in a storm.
+pub const MAX_STORM_WINDOW: Duration = Duration::from_secs(10);
+
+/// Name of the native backend of this platform.
+pub const NATIVE: &str = if cfg!(any(target_os = "linux", target_os = "android")) {
+    "inotify"
+} else if cfg!(target_os = "macos") {
+    "fsevents"
+} else if cfg!(target_os = "windows") {
+    "windows"
+} else if cfg!(any(target_os = "illumos", target_os = "solaris")) {
+    "fen"
+} else if cfg!(any(
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    target_os = "ios"
+)) {
+    "kqueue"
+} else {
+    "poll"
+};
+
+/// Every backend name, each native one only available on its platform,
+/// fanotify only on Linux and watchman only on unix.
+pub const BACKENDS: &[&str] = &[
+    "inotify", "fsevents", "kqueue", "windows", "fen", "fanotify", "watchman", "poll",
+];
+
+/// Creates the watchers of replicas.
+pub trait FsBackend {
+    /// Watcher of one replica, sending its events tagged with the replica id.
+    fn watcher(&self, replica_id: &str, tx: EventSender) -> Result<Box<dyn Watch + Send>>;
+}
+
+/// A notify watcher behind `notify-debouncer-full`.
+pub struct NotifyBackend<W> {
+    config: Config,
+    debounce: Duration,
+    storm_rate: usize,
+    /// Poll interval for trees with more entries than kqueue has
+    /// descriptors for, if budgeted.
+    fd_budget: Option<Duration>,
+    watcher: PhantomData<W>,
+}
+
+impl<W> NotifyBackend<W> {
+    pub fn new(config: Config, debounce: Duration) -> Self {
+        Self {
+            config,
+            debounce,
+            storm_rate: 0,
+            fd_budget: None,
+            watcher: PhantomData,
+        }
+    }
+
+    /// Debounce adaptively once more than `rate` events a second arrive, 0
+    /// for never. See `Storm`.
+    pub fn with_storm_rate(mut self, rate: usize) -> Self {
+        self.storm_rate = rate;
+        self
+    }
+
+    /// Poll every `poll_interval` what a kqueue watcher has not the file
+    /// descriptors for. See `FdBudget`.
+    pub fn with_fd_budget(mut self, poll_interval: Duration) -> Self {
+        self.fd_budget = Some(poll_interval);
+        self
+    }
+}
+
+pub type NativeBackend = NotifyBackend<RecommendedWatcher>;
+pub type PollBackend = NotifyBackend<PollWatcher>;
+pub type CoarsePollBackend = NotifyBackend<crate::coarse::CoarsePollWatcher>;
+#[cfg(target_os = "macos")]
+pub type FsEventsBackend = NotifyBackend<crate::fsevents::FsEventsWatcher>;
+#[cfg(windows)]
+pub type WindowsBackend = NotifyBackend<crate::windows::WindowsWatcher>;
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+pub type FenBackend = NotifyBackend<crate::fen::FenWatcher>;
+#[cfg(target_os = "linux")]
+pub type FanotifyBackend = NotifyBackend<crate::fanotify::FanotifyWatcher>;
+#[cfg(unix)]
+pub type WatchmanBackend = NotifyBackend<crate::watchman::WatchmanWatcher>;
+
+impl<W: notify::Watcher + Send + 'static> FsBackend for NotifyBackend<W> {
+    /// Backend errors become rescan requests, events may have been lost with
+    /// them.
+    fn watcher(&self, replica_id: &str, tx: EventSender) -> Result<Box<dyn Watch + Send>> {
+        let poll = match self.fd_budget {
+            Some(interval) if W::kind() == WatcherKind::Kqueue => Some((
+                PollBackend::new(
+                    Config::default().with_poll_interval(interval),
+                    self.debounce,
+                )
+                .with_storm_rate(self.storm_rate)
+                .watcher(replica_id, tx.clone())?,
+                interval,
+            )),
+            _ => None,
+        };
+        let replica_id: Tag = replica_id.into();
+        let polling = W::kind() == WatcherKind::PollWatcher;
+        let deliver = move |events: Vec<notify::Event>| {
+            for event in events {
+                let _ = tx.send(Event::ReplicaEvent(replica_id.clone(), event));
+            }
+        };
+        let mut forward: Box<dyn FnMut(Vec<notify::Event>) + Send> = if self.storm_rate > 0 {
+            let storm = spawn_storm(self.storm_rate, self.debounce, deliver);
+            Box::new(move |events| {
+                let _ = storm.send(events);
+            })
+        } else {
+            Box::new(deliver)
+        };
+        let handler = move |result: DebounceEventResult| {
+            let events: Vec<notify::Event> = match result {
+                Ok(events) => events
+                    .into_iter()
+                    .map(|event| event.event)
+                    .filter(|event| !(polling && is_directory_mtime(event)))
+                    .collect(),
+                Err(errors) => errors
+                    .into_iter()
+                    .map(|err| {
+                        warn!("Watch error: {}", err);
+                        let mut event = notify::Event::new(EventKind::Other).set_flag(Flag::Rescan);
+                        event.paths = err.paths;
+                        event
+                    })
+                    .collect(),
+            };
+            forward(events);
+        };
+        let debouncer = new_debouncer_opt::<_, W, FileIdMap>(
+            self.debounce,
+            None,
+            handler,
+            FileIdMap::new(),
+            self.config,
+        )?;
+        match poll {
+            Some((poll, interval)) => Ok(Box::new(FdBudget::new(debouncer, poll, interval))),
+            None => Ok(Box::new(debouncer)),
+        }
+    }
+}
+
+/// Debouncing on top of the fixed one, for builds and large copies: once
+/// more than `rate` events arrive in a second, they are held back until the
+/// tree was quiet for a window, or for `MAX_STORM_WINDOW` at most. The window
+/// starts at the debounce timeout and doubles for every stretch that stays
+/// stormy, then halves for every one that is quiet until events pass
+/// straight through again. Held events are delivered once each.
+struct Storm {
+    rate: usize,
+    base: Duration,
+    /// Zero while calm.
+    window: Duration,
+    held: Vec<notify::Event>,
+    seen: HashSet<notify::Event>,
+    /// When the first held event arrived.
+    since: Option<Instant>,
+    /// When the last event arrived, or events were last delivered.
+    last: Instant,
+    /// Start of the current second and the events in it so far.
+    second: Instant,
+    count: usize,
+    /// Whether the rate was exceeded since events were last delivered.
+    stormy: bool,
+}
+
+impl Storm {
+    fn new(rate: usize, base: Duration, now: Instant) -> Self {
+        Self {
+            rate,
+            base,
+            window: Duration::ZERO,
+            held: vec![],
+            seen: HashSet::new(),
+            since: None,
+            last: now,
+            second: now,
+            count: 0,
+            stormy: false,
+        }
+    }
+
+    /// Take in `events`, returning the ones to deliver right away.
+    fn push(&mut self, events: Vec<notify::Event>, now: Instant) -> Vec<notify::Event> {
+        if now.duration_since(self.second) >= Duration::from_secs(1) {
+            self.second = now;
+            self.count = 0;
+        }
+        self.count += events.len();
+        if self.count > self.rate {
+            if self.window.is_zero() {
+                debug!("Event storm, holding events back");
+                self.window = self.base;
+            }
+            self.stormy = true;
+        }
+        self.last = now;
+        if self.window.is_zero() {
+            return events;
+        }
+        self.since.get_or_insert(now);
+        for event in events {
+            if self.seen.insert(event.clone()) {
+                self.held.push(event);
+            }
+        }
+        vec![]
+    }
+
+    /// When to `poll` next, if at all.
+    fn deadline(&self) -> Option<Instant> {
+        if self.window.is_zero() {
+            return None;
+        }
+        let quiet = self.last + self.window;
+        Some(match self.since {
+            Some(since) => quiet.min(since + MAX_STORM_WINDOW),
+            None => quiet,
+        })
+    }
+
+    /// The held events once due, adapting the window to whether the storm
+    /// went on meanwhile.
+    fn poll(&mut self, now: Instant) -> Vec<notify::Event> {
+        match self.deadline() {
+            Some(deadline) if now >= deadline => {}
+            _ => return vec![],
+        }
+        self.window = if self.stormy {
+            (self.window * 2).min(MAX_STORM_WINDOW)
+        } else if self.window / 2 >= self.base {
+            self.window / 2
+        } else {
+            debug!("Event storm over");
+            Duration::ZERO
+        };
+        self.stormy = false;
+        self.since = None;
+        self.last = now;
+        self.seen.clear();
+        std::mem::take(&mut self.held)
+    }
+}
+
+/// Run events through a `Storm` on a thread of its own, handing them to
+/// `deliver`. Held events are delivered once the sender is dropped.
+fn spawn_storm(
+    rate: usize,
+    base: Duration,
+    mut deliver: impl FnMut(Vec<notify::Event>) + Send + 'static,
+) -> mpsc::Sender<Vec<notify::Event>> {
+    let (events_tx, events_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut storm = Storm::new(rate, base, Instant::now());
+        loop {
+            let received = match storm.deadline() {
+                Some(deadline) => {
+                    events_rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => events_rx
+                    .recv()
+                    .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+            let now = Instant::now();
+            let events = match received {
+                Ok(events) => storm.push(events, now),
+                Err(mpsc::RecvTimeoutError::Timeout) => storm.poll(now),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    deliver(std::mem::take(&mut storm.held));
+                    return;
+                }
+            };
+            if !events.is_empty() {
+                deliver(events);
+            }
+        }
+    });
+    events_tx
+}
+
+/// Polling sees an entry come or go once on its own and once more as a newer
+/// mtime of its directory, which would rescan all of the directory.
+fn is_directory_mtime(event: &notify::Event) -> bool {
+    event.kind == EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime))
+        && event.paths.iter().all(|path| path.is_dir())
+}
+
+/// The backend named in `settings`: `auto` or the native name for the
+/// native backend, `fanotify` for whole filesystems on Linux, `watchman` for
+/// subscriptions to a Watchman server on unix, `poll` for polling, by size
+/// and coarse mtimes on FAT and exFAT. On macOS the native one is FSEvents
+/// with the latency of `settings`, on Windows one that rescans what
+/// overflowed, on illumos and Solaris FEN. Trees kqueue has too few file
+/// descriptors for are polled, as are those FEN cannot watch.
+pub fn select(settings: &Settings) -> Result<Box<dyn FsBackend>> {
+    let debounce = settings.debounce;
+    #[cfg(windows)]
+    let native =
+        || WindowsBackend::new(Config::default(), debounce).with_storm_rate(settings.storm_rate);
+    #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+    let native = || {
+        FenBackend::new(
+            Config::default().with_poll_interval(settings.poll_interval),
+            debounce,
+        )
+        .with_storm_rate(settings.storm_rate)
+    };
+    #[cfg(not(any(
+        target_os = "macos",
+        windows,
+        target_os = "illumos",
+        target_os = "solaris"
+    )))]
+    let native = || {
+        NativeBackend::new(Config::default(), debounce)
+            .with_storm_rate(settings.storm_rate)
+            .with_fd_budget(settings.poll_interval)
+    };
+    #[cfg(target_os = "macos")]
+    let native = || {
+        let config = Config::default().with_poll_interval(settings.fsevents_latency);
+        FsEventsBackend::new(config, debounce).with_storm_rate(settings.storm_rate)
+    };
+    match settings.backend.as_str() {
+        "auto" | "native" => Ok(Box::new(native())),
+        "poll" if settings.coarse_timestamps => Ok(Box::new(
+            CoarsePollBackend::new(
+                Config::default()
+                    .with_poll_interval(settings.poll_interval)
+                    .with_compare_contents(settings.sample_contents),
+                debounce,
+            )
+            .with_storm_rate(settings.storm_rate),
+        )),
+        "poll" => Ok(Box::new(
+            PollBackend::new(
+                Config::default().with_poll_interval(settings.poll_interval),
+                debounce,
+            )
+            .with_storm_rate(settings.storm_rate),
+        )),
+        #[cfg(target_os = "linux")]
+        "fanotify" => Ok(Box::new(
+            FanotifyBackend::new(Config::default(), debounce).with_storm_rate(settings.storm_rate),
+        )),
+        #[cfg(unix)]
+        "watchman" => Ok(Box::new(
+            WatchmanBackend::new(Config::default(), debounce).with_storm_rate(settings.storm_rate),
+        )),
+        name if name == NATIVE => Ok(Box::new(native())),
+        name if BACKENDS.contains(&name) => Err(MonitorError::UnsupportedFeature(format!(
+            "Backend {} is not available on {}, use auto, {} or poll",
+            name,
+            std::env::consts::OS,
+            NATIVE
+        ))),
+        name => Err(MonitorError::UnsupportedFeature(format!(
+            "Unknown backend {}, use auto, {} or poll",
+            name, NATIVE
+        ))),
+    }
+}
+
+enum WatchRequest {
+    Watch(PathBuf, RecursiveMode),
+    Unwatch(PathBuf),
+    Hardlinks(PathBuf),
+}
+
+/// Sets up the watches of a replica on a thread of its own, as a recursive
+/// watch of a huge tree can take minutes and unison would time out waiting.
+/// Each outcome comes back as `Event::Watched`, requests are served in order.
+/// Hard links are mapped there too, coming back as `Event::Hardlinks`.
+pub struct Background {
+    requests: mpsc::Sender<WatchRequest>,
+}
+
+impl Background {
+    pub fn new(mut watcher: Box<dyn Watch + Send>, replica_id: &str, tx: EventSender) -> Self {
+        let (requests, rx) = mpsc::channel();
+        let replica_id: Tag = replica_id.into();
+        // Ends with the last request once the sender is dropped, taking the
+        // watcher along.
+        thread::spawn(move || {
+            for request in rx {
+                match request {
+                    WatchRequest::Watch(path, recursive_mode) => {
+                        let result = watcher.watch(&path, recursive_mode);
+                        if tx
+                            .send(Event::Watched(replica_id.clone(), path, result))
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    WatchRequest::Unwatch(path) => {
+                        if let Err(err) = watcher.unwatch(&path) {
+                            warn!("Cannot unwatch {}: {}", path.display(), err);
+                        }
+                    }
+                    WatchRequest::Hardlinks(root) => {
+                        let hardlinks = Hardlinks::scan(&root);
+                        if tx
+                            .send(Event::Hardlinks(replica_id.clone(), root, hardlinks))
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        Self { requests }
+    }
+
+    fn request(&self, request: WatchRequest) -> Result<()> {
+        self.requests
+            .send(request)
+            .map_err(|_| MonitorError::WatchError("Watcher thread stopped".into()))
+    }
+}
+
+impl Watch for Background {
+    fn validate(&self, path: &Path) -> Result<()> {
+        validate_dir(path)
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        self.request(WatchRequest::Watch(path.to_owned(), recursive_mode))
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        self.request(WatchRequest::Unwatch(path.to_owned()))
+    }
+
+    fn hardlinks(&mut self, root: &Path) -> Option<Hardlinks> {
+        if let Err(err) = self.request(WatchRequest::Hardlinks(root.to_owned())) {
+            warn!(
+                "Cannot map the hard links below {}: {}",
+                root.display(),
+                err
+            );
+        }
+        None
+    }
+}
+
+impl Watch for RecommendedWatcher {
+    fn validate(&self, path: &Path) -> Result<()> {
+        validate_dir(path)
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        Ok(notify::Watcher::watch(self, path, recursive_mode)?)
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        Ok(notify::Watcher::unwatch(self, path)?)
+    }
+}
+
+impl<W: notify::Watcher> Watch for Debouncer<W, FileIdMap> {
+    fn validate(&self, path: &Path) -> Result<()> {
+        validate_dir(path)
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        // Also keeps the file ids that pair both ends of a rename.
+        Ok(Debouncer::watch(self, path, recursive_mode)?)
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        Ok(Debouncer::unwatch(self, path)?)
+    }
+}
+
+#[test]
+fn test_select() {
+    let select = |name: &str| {
+        select(&Settings {
+            backend: name.into(),
+            ..Settings::default()
+        })
+    };
+    assert!(select("auto").is_ok());
+    assert!(select(NATIVE).is_ok());
+    assert!(select("poll").is_ok());
+    let fanotify = cfg!(target_os = "linux").then_some("fanotify");
+    assert_eq!(select("fanotify").is_ok(), fanotify.is_some());
+    let watchman = cfg!(unix).then_some("watchman");
+    assert_eq!(select("watchman").is_ok(), watchman.is_some());
+    let available = [Some(NATIVE), Some("poll"), fanotify, watchman];
+    for name in BACKENDS
+        .iter()
+        .filter(|name| !available.contains(&Some(**name)))
+    {
+        let err = select(name).err().unwrap();
+        assert!(err.to_string().contains("not available on"), "{}", err);
+    }
+    let err = select("carrier-pigeon").err().unwrap();
+    assert!(matches!(err, MonitorError::UnsupportedFeature(_)));
+    assert!(err.to_string().starts_with("Unknown backend"));
+}
+
+#[test]
+fn test_background() {
+    /// Fails to watch anything but the one path, slowly.
+    struct SlowWatcher {
+        path: std::path::PathBuf,
+    }
+
+    impl Watch for SlowWatcher {
+        fn watch(&mut self, path: &Path, _recursive_mode: RecursiveMode) -> Result<()> {
+            thread::sleep(Duration::from_millis(50));
+            if path != self.path {
+                return Err(MonitorError::WatchError("No such file or directory".into()));
+            }
+            Ok(())
+        }
+    }
+
+    let (tx, mut rx) = crate::io::event_channel(crate::io::EVENT_CAPACITY);
+    let watcher = Box::new(SlowWatcher {
+        path: "/tmp/good".into(),
+    });
+    let mut background = Background::new(watcher, "123", tx);
+    background
+        .watch(Path::new("/tmp/good"), RecursiveMode::Recursive)
+        .unwrap();
+    background
+        .watch(Path::new("/tmp/bad"), RecursiveMode::Recursive)
+        .unwrap();
+    // Mapped behind the watches rather than right away.
+    assert!(background.hardlinks(Path::new("/tmp/good")).is_none());
+    drop(background);
+
+    let mut outcomes = vec![];
+    let mut mapped = vec![];
+    while let Some(event) = rx.blocking_recv() {
+        match event {
+            Event::Watched(id, path, result) => {
+                assert_eq!(&*id, "123");
+                outcomes.push((path, result.is_ok()));
+            }
+            Event::Hardlinks(id, root, _) => {
+                assert_eq!(&*id, "123");
+                assert_eq!(outcomes.len(), 2);
+                mapped.push(root);
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+    assert_eq!(
+        outcomes,
+        [("/tmp/good".into(), true), ("/tmp/bad".into(), false)]
+    );
+    assert_eq!(mapped, [PathBuf::from("/tmp/good")]);
+}
+
+#[test]
+fn test_storm() {
+    let event = |name: &str| {
+        notify::Event::new(EventKind::Create(notify::event::CreateKind::File))
+            .add_path(Path::new("/tmp/sample").join(name))
+    };
+    let base = Duration::from_millis(100);
+    let start = Instant::now();
+    let at = |ms: u64| start + Duration::from_millis(ms);
+    let mut storm = Storm::new(3, base, start);
+
+    // Calm, events pass straight through.
+    assert_eq!(storm.push(vec![event("a"), event("b")], at(0)).len(), 2);
+    assert_eq!(storm.deadline(), None);
+
+    // Over the rate, held back and delivered once each after a quiet window.
+    assert!(storm.push(vec![event("c"), event("d")], at(10)).is_empty());
+    assert!(storm.push(vec![event("c")], at(50)).is_empty());
+    assert_eq!(storm.deadline(), Some(at(150)));
+    assert!(storm.poll(at(100)).is_empty());
+    assert_eq!(storm.poll(at(150)), [event("c"), event("d")]);
+
+    // Still stormy, the window doubles.
+    assert!(storm
+        .push((0..5).map(|i| event(&i.to_string())).collect(), at(1200))
+        .is_empty());
+    assert_eq!(storm.deadline(), Some(at(1400)));
+    assert_eq!(storm.poll(at(1400)).len(), 5);
+    assert_eq!(storm.deadline(), Some(at(1800)));
+
+    // Quiet again, the window halves back to nothing.
+    assert!(storm.poll(at(1800)).is_empty());
+    assert_eq!(storm.deadline(), Some(at(2000)));
+    assert!(storm.poll(at(2000)).is_empty());
+    assert_eq!(storm.deadline(), Some(at(2100)));
+    assert!(storm.poll(at(2100)).is_empty());
+    assert_eq!(storm.deadline(), None);
+    assert_eq!(storm.push(vec![event("e")], at(3000)).len(), 1);
+}
+
+#[test]
+fn test_storm_max_window() {
+    let base = Duration::from_millis(100);
+    let start = Instant::now();
+    let mut storm = Storm::new(0, base, start);
+    let event = notify::Event::new(EventKind::Any).add_path("/tmp/sample/a".into());
+
+    // Events keep coming, they are delivered after the longest window.
+    let mut now = start;
+    while now < start + MAX_STORM_WINDOW {
+        assert!(storm.push(vec![event.clone()], now).is_empty());
+        now += Duration::from_millis(50);
+    }
+    assert_eq!(storm.deadline(), Some(start + MAX_STORM_WINDOW));
+    assert_eq!(storm.poll(now), [event]);
+}
+
+/// Events received within `timeout`, or up to the first one `stop` accepts.
+#[cfg(all(test, unix))]
+fn collect_events(
+    rx: &mut crate::io::EventReceiver,
+    timeout: Duration,
+    mut stop: impl FnMut(&notify::Event) -> bool,
+) -> Vec<notify::Event> {
+    let deadline = std::time::Instant::now() + timeout;
+    let mut events = vec![];
+    while std::time::Instant::now() < deadline {
+        match rx.try_recv() {
+            Some(Event::ReplicaEvent(id, event)) => {
+                assert_eq!(&*id, "123");
+                let done = stop(&event);
+                events.push(event);
+                if done {
+                    break;
+                }
+            }
+            Some(_) => {}
+            None => std::thread::sleep(Duration::from_millis(20)),
+        }
+    }
+    events
+}
+
+#[cfg(unix)]
+#[test]
+fn test_poll_backend() {
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    std::fs::create_dir_all(base.join("subdir")).unwrap();
+    let base = base.canonicalize().unwrap();
+    // Polling compares mtimes by the second, make the change stand out.
+    let an_hour_ago = std::time::SystemTime::now() - Duration::from_secs(60 * 60);
+    for dir in [base.join("subdir"), base.clone()] {
+        std::fs::File::open(dir)
+            .unwrap()
+            .set_modified(an_hour_ago)
+            .unwrap();
+    }
+    let backend = PollBackend::new(
+        Config::default().with_poll_interval(Duration::from_millis(50)),
+        DEBOUNCE_TIMEOUT,
+    );
+    let (tx, mut rx) = crate::io::event_channel(crate::io::EVENT_CAPACITY);
+
+    let mut watcher = backend.watcher("123", tx).unwrap();
+    watcher.validate(&base).unwrap();
+    watcher.watch(&base, RecursiveMode::Recursive).unwrap();
+    std::fs::write(base.join("subdir/filename"), b"").unwrap();
+
+    let mut events = collect_events(&mut rx, Duration::from_secs(5), |event| {
+        event.paths.contains(&base.join("subdir/filename"))
+    });
+    events.extend(collect_events(&mut rx, Duration::from_millis(500), |_| {
+        false
+    }));
+
+    let paths: Vec<_> = events.iter().flat_map(|event| &event.paths).collect();
+    assert!(paths.contains(&&base.join("subdir/filename")));
+    // Newer directory mtimes are not passed on.
+    assert!(!paths.contains(&&base.join("subdir")));
+    assert!(!paths.contains(&&base));
+}
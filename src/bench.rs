@@ -0,0 +1,249 @@
+//! `bench`: time what serving a tree costs, to tell whether slowness comes
+//! from setting up its watch, from handling its events or from unison.
+
+use crate::error::{MonitorError, Result};
+use crate::io::EventReceiver;
+use crate::monitor::{Event, Monitor, Watch};
+use crate::protocol::encode;
+use crate::snapshot::Errors;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+
+/// Longest wait for the events of a step before the bench gives up.
+pub const STEP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Files written one at a time to time how soon each is reported.
+pub const PROBES: usize = 5;
+
+/// What serving a tree took.
+#[derive(Debug)]
+pub struct Report {
+    /// From `START` until the recursive watch was in place.
+    pub setup: Duration,
+    /// Events the burst of files caused.
+    pub events: usize,
+    /// Time spent handling those events, not waiting for them.
+    pub handling: Duration,
+    /// From writing each probe until its change was pending, sorted.
+    pub latencies: Vec<Duration>,
+    /// The debounce window, which latencies include.
+    pub debounce: Duration,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "watch setup  {:?}", self.setup)?;
+        let rate = self.events as f64 / self.handling.as_secs_f64().max(f64::EPSILON);
+        writeln!(
+            f,
+            "throughput   {} events handled in {:?}, {:.0} events/s",
+            self.events, self.handling, rate
+        )?;
+        if let (Some(median), Some(max)) = (
+            self.latencies.get(self.latencies.len() / 2),
+            self.latencies.last(),
+        ) {
+            writeln!(
+                f,
+                "attribution  median {:?}, max {:?}, debounce {:?}",
+                median, max, self.debounce
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Watch `path` as a replica, write `files` files at once into a scratch
+/// directory below it and then `PROBES` one after the other, and print how
+/// long each step took to `out`. The scratch directory is removed again.
+pub async fn run<WATCH: Watch>(
+    monitor: &mut Monitor<WATCH, Errors>,
+    mut rx: EventReceiver,
+    path: &Path,
+    files: usize,
+    out: &mut impl Write,
+) -> Result<()> {
+    let root = path.canonicalize()?;
+    let scratch = root.join(format!(".unison-fsmonitor-bench-{}", std::process::id()));
+    fs::create_dir(&scratch)?;
+    let result = bench(monitor, &mut rx, &root, &scratch, files).await;
+    let _ = fs::remove_dir_all(&scratch);
+    monitor.shutdown()?;
+    writeln!(out, "{}", result?)?;
+    out.flush()?;
+    Ok(())
+}
+
+async fn bench<WATCH: Watch>(
+    monitor: &mut Monitor<WATCH, Errors>,
+    rx: &mut EventReceiver,
+    root: &Path,
+    scratch: &Path,
+    files: usize,
+) -> Result<Report> {
+    // The root doubles as the replica id.
+    let id = root.to_string_lossy().into_owned();
+    let start = Instant::now();
+    monitor.handle_event(Event::Input(format!(
+        "START {} {}\n",
+        encode(OsStr::new(&id)),
+        encode(root.as_os_str())
+    )))?;
+    let setup = start.elapsed();
+    let debounce = match monitor.replicas.get(id.as_str()) {
+        Some(replica) => replica.settings.debounce,
+        None => {
+            let errors = std::mem::take(&mut monitor.writer.0);
+            return Err(MonitorError::WatchError(errors.join(", ")));
+        }
+    };
+    let relative = |path: &Path| path.strip_prefix(root).unwrap_or(path).to_owned();
+
+    let burst: Vec<PathBuf> = (0..files)
+        .map(|i| scratch.join(format!("file-{}", i)))
+        .collect();
+    for path in &burst {
+        fs::write(path, b"")?;
+    }
+    let pending: Vec<PathBuf> = burst.iter().map(|path| relative(path)).collect();
+    let (events, handling) = settle(monitor, rx, &id, &pending).await?;
+
+    let mut latencies = vec![];
+    for i in 0..PROBES {
+        let path = scratch.join(format!("probe-{}", i));
+        let written = Instant::now();
+        fs::write(&path, b"")?;
+        settle(monitor, rx, &id, &[relative(&path)]).await?;
+        latencies.push(written.elapsed());
+    }
+    latencies.sort();
+
+    Ok(Report {
+        setup,
+        events,
+        handling,
+        latencies,
+        debounce,
+    })
+}
+
+/// Handle events until a change covering each of `paths` is pending for the
+/// replica, then take its changes. Returns how many filesystem events were
+/// handled and how long that took.
+async fn settle<WATCH: Watch>(
+    monitor: &mut Monitor<WATCH, Errors>,
+    rx: &mut EventReceiver,
+    id: &str,
+    paths: &[PathBuf],
+) -> Result<(usize, Duration)> {
+    let deadline = Instant::now() + STEP_TIMEOUT;
+    let mut events = 0;
+    let mut handling = Duration::ZERO;
+    loop {
+        let replica = match monitor.replicas.get_mut(id) {
+            Some(replica) => replica,
+            None => return Err(MonitorError::WatchError(format!("Lost {}", id))),
+        };
+        let changes = &replica.pending_changes;
+        if paths
+            .iter()
+            .all(|path| changes.iter().any(|change| path.starts_with(change)))
+        {
+            replica.take_changes();
+            return Ok((events, handling));
+        }
+        let event = match timeout_at(deadline, rx.recv()).await {
+            Ok(Some(event)) => event,
+            _ => {
+                return Err(MonitorError::WatchError(format!(
+                    "No events for {} within {:?}",
+                    id, STEP_TIMEOUT
+                )))
+            }
+        };
+        if event.is_filesystem() {
+            events += 1;
+        }
+        let start = Instant::now();
+        if let Err(err) = monitor.handle_event(event) {
+            monitor.handle_error(err)?;
+        }
+        handling += start.elapsed();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::{event_channel, EventSender, EVENT_CAPACITY};
+    use notify::event::{CreateKind, EventKind};
+    use notify::RecursiveMode;
+
+    /// Reports what is written below a watched path right away.
+    struct Watcher {
+        tx: EventSender,
+    }
+
+    impl Watch for Watcher {
+        fn watch(&mut self, path: &Path, _recursive_mode: RecursiveMode) -> Result<()> {
+            let tx = self.tx.clone();
+            let path = path.to_owned();
+            std::thread::spawn(move || {
+                let mut seen = std::collections::HashSet::new();
+                loop {
+                    for entry in walk_files(&path) {
+                        if seen.insert(entry.clone()) {
+                            let event = notify::Event::new(EventKind::Create(CreateKind::File))
+                                .add_path(entry);
+                            if tx.send(Event::FSEvent(event)).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            });
+            Ok(())
+        }
+    }
+
+    fn walk_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files = vec![];
+        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                files.extend(walk_files(&path));
+            } else {
+                files.push(path);
+            }
+        }
+        files
+    }
+
+    #[tokio::test]
+    async fn test_bench() {
+        let tmp = crate::test_dir();
+        let base = tmp.path();
+        let (tx, rx) = event_channel(EVENT_CAPACITY);
+        let mut monitor = Monitor::new(
+            move |_, _| Ok(Watcher { tx: tx.clone() }),
+            Errors::default(),
+        );
+        let mut out = vec![];
+
+        run(&mut monitor, rx, base, 20, &mut out).await.unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("watch setup  "), "{}", out);
+        assert!(out.contains("throughput   20 events handled"), "{}", out);
+        assert!(out.contains("attribution  median "), "{}", out);
+        assert!(monitor.replicas.is_empty());
+        // Nothing is left behind.
+        assert_eq!(fs::read_dir(base).unwrap().count(), 0);
+    }
+}
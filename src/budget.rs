@@ -0,0 +1,272 @@
+//! kqueue takes a file descriptor for every file and directory it watches,
+//! and a tree with more entries than the process may open fails halfway or
+//! starves the monitor of descriptors for anything else. Trees that do not
+//! fit are watched an entry at a time from the top down, as many levels as
+//! fit, and the directories below those levels are polled.
+
+use crate::error::Result;
+use crate::monitor::Watch;
+use log::{info, warn};
+use notify::RecursiveMode;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Descriptors left alone for the monitor itself: stdio, logs, journals,
+/// the poll watcher.
+pub const FD_RESERVE: usize = 128;
+
+/// How many descriptors the process may open, after raising the soft limit
+/// up to the hard one.
+#[cfg(unix)]
+fn fd_limit() -> usize {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return usize::MAX;
+    }
+    if limit.rlim_cur < limit.rlim_max {
+        let raised = libc::rlimit {
+            rlim_cur: limit.rlim_max,
+            rlim_max: limit.rlim_max,
+        };
+        // macOS refuses anything beyond kern.maxfilesperproc, keep the soft
+        // limit then.
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            limit = raised;
+        }
+    }
+    usize::try_from(limit.rlim_cur).unwrap_or(usize::MAX)
+}
+
+#[cfg(not(unix))]
+fn fd_limit() -> usize {
+    usize::MAX
+}
+
+/// Descriptors the kqueue watches of every `FdBudget` took when they were
+/// placed. `/dev/fd` lists only stdio on the BSDs without fdescfs, so they
+/// are counted here, and what else the process opens is left to
+/// `FD_RESERVE`.
+static WATCHES: AtomicUsize = AtomicUsize::new(0);
+
+/// How to watch a tree with a number of descriptors.
+#[derive(Debug, PartialEq)]
+enum Plan {
+    /// Every entry can have a watch of its own.
+    Whole { entries: usize },
+    /// These entries, every one of the levels from the root down that fit,
+    /// and polling for the directories below them.
+    Levels {
+        entries: usize,
+        watched: Vec<PathBuf>,
+        polled: Vec<PathBuf>,
+    },
+}
+
+/// Count the entries below `root`, without following links, to see whether
+/// they fit into `available` descriptors, or which levels do.
+fn plan(root: &Path, available: usize) -> Plan {
+    let mut entries = 1;
+    let mut levels = vec![vec![(root.to_owned(), true)]];
+    loop {
+        let mut next = vec![];
+        for (dir, _) in levels.last().unwrap().iter().filter(|(_, dir)| *dir) {
+            for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+                let dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+                next.push((entry.path(), dir));
+            }
+        }
+        if next.is_empty() {
+            break;
+        }
+        entries += next.len();
+        levels.push(next);
+    }
+    if entries <= available {
+        return Plan::Whole { entries };
+    }
+    let mut watched = vec![];
+    let mut polled = vec![root.to_owned()];
+    for level in levels {
+        if watched.len() + level.len() > available {
+            break;
+        }
+        polled = level
+            .iter()
+            .filter(|(_, dir)| *dir)
+            .map(|(path, _)| path.clone())
+            .collect();
+        watched.extend(level.into_iter().map(|(path, _)| path));
+    }
+    Plan::Levels {
+        entries,
+        watched,
+        polled,
+    }
+}
+
+/// What an `FdBudget` placed for a root watched recursively.
+enum Placed {
+    /// A kqueue watch of the whole tree, with that many entries.
+    Whole(usize),
+    /// Single kqueue watches, and the directories polled below them.
+    Levels {
+        watched: Vec<PathBuf>,
+        polled: Vec<PathBuf>,
+    },
+}
+
+/// Wraps a kqueue watcher to keep its recursive watches within the
+/// descriptors the process may open, polling what does not fit.
+pub struct FdBudget<W> {
+    inner: W,
+    poll: Box<dyn Watch + Send>,
+    poll_interval: Duration,
+    limit: usize,
+    placed: HashMap<PathBuf, Placed>,
+}
+
+impl<W: Watch> FdBudget<W> {
+    pub fn new(inner: W, poll: Box<dyn Watch + Send>, poll_interval: Duration) -> Self {
+        Self {
+            inner,
+            poll,
+            poll_interval,
+            limit: fd_limit(),
+            placed: HashMap::new(),
+        }
+    }
+
+    fn release(&mut self, root: &Path, placed: Placed) -> Result<()> {
+        match placed {
+            Placed::Whole(entries) => {
+                WATCHES.fetch_sub(entries, Ordering::Relaxed);
+                self.inner.unwatch(root)
+            }
+            Placed::Levels { watched, polled } => {
+                WATCHES.fetch_sub(watched.len(), Ordering::Relaxed);
+                for entry in watched {
+                    let _ = self.inner.unwatch(&entry);
+                }
+                for dir in polled {
+                    let _ = self.poll.unwatch(&dir);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<W: Watch> Watch for FdBudget<W> {
+    fn validate(&self, path: &Path) -> Result<()> {
+        self.inner.validate(path)
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        if recursive_mode == RecursiveMode::NonRecursive {
+            return self.inner.watch(path, recursive_mode);
+        }
+        let available = self
+            .limit
+            .saturating_sub(WATCHES.load(Ordering::Relaxed) + FD_RESERVE);
+        let (entries, watched, polled) = match plan(path, available) {
+            Plan::Whole { entries } => {
+                self.inner.watch(path, recursive_mode)?;
+                WATCHES.fetch_add(entries, Ordering::Relaxed);
+                self.placed.insert(path.to_owned(), Placed::Whole(entries));
+                return Ok(());
+            }
+            Plan::Levels {
+                entries,
+                watched,
+                polled,
+            } => (entries, watched, polled),
+        };
+        warn!(
+            "{} holds {} entries, more than the {} file descriptors left for kqueue. \
+             Watching {} of them and polling the {} directories below every {}s, raise \
+             the limit with `ulimit -n` to watch all of it",
+            path.display(),
+            entries,
+            available,
+            watched.len(),
+            polled.len(),
+            self.poll_interval.as_secs()
+        );
+        let mut placed = vec![];
+        for entry in watched {
+            if let Err(err) = self.inner.watch(&entry, RecursiveMode::NonRecursive) {
+                info!("Cannot watch {}: {}", entry.display(), err);
+                continue;
+            }
+            placed.push(entry);
+        }
+        WATCHES.fetch_add(placed.len(), Ordering::Relaxed);
+        let mut placed = Placed::Levels {
+            watched: placed,
+            polled: vec![],
+        };
+        for dir in polled {
+            if let Err(err) = self.poll.watch(&dir, RecursiveMode::Recursive) {
+                let _ = self.release(path, placed);
+                return Err(err);
+            }
+            if let Placed::Levels { polled, .. } = &mut placed {
+                polled.push(dir);
+            }
+        }
+        self.placed.insert(path.to_owned(), placed);
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        match self.placed.remove(path) {
+            Some(placed) => self.release(path, placed),
+            None => self.inner.unwatch(path),
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_plan() {
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    fs::create_dir_all(base.join("a/b")).unwrap();
+    for file in ["a/x", "a/y", "a/b/z", "c"] {
+        fs::write(base.join(file), b"").unwrap();
+    }
+
+    // The root, a, c, x, y, b and z.
+    assert_eq!(plan(base, 7), Plan::Whole { entries: 7 });
+    let levels = |available| match plan(base, available) {
+        Plan::Levels {
+            entries,
+            mut watched,
+            mut polled,
+        } => {
+            assert_eq!(entries, 7);
+            // In the order read_dir has them.
+            watched.sort();
+            polled.sort();
+            (watched, polled)
+        }
+        plan => panic!("{:?}", plan),
+    };
+    let paths = |paths: &[&str]| -> Vec<PathBuf> {
+        let mut paths: Vec<_> = paths.iter().map(|path| base.join(path)).collect();
+        paths.sort();
+        paths
+    };
+    assert_eq!(
+        levels(6),
+        (paths(&["", "a", "c", "a/b", "a/x", "a/y"]), paths(&["a/b"]))
+    );
+    assert_eq!(levels(5), (paths(&["", "a", "c"]), paths(&["a"])));
+    assert_eq!(levels(0), (vec![], vec![base.to_owned()]));
+}
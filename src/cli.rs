@@ -0,0 +1,787 @@
+//! Command line options. Unison runs the monitor without any, so each has a
+//! default and can be set from an environment variable too. Options given
+//! take precedence over the config file, except in its replica sections.
+
+use crate::backend::{
+    MAX_DEBOUNCE_TIMEOUT, MAX_FSEVENTS_LATENCY, MAX_POLL_INTERVAL, MIN_DEBOUNCE_TIMEOUT,
+    MIN_POLL_INTERVAL,
+};
+use crate::config::{self, Config, MAX_HOLD_DOWN, MAX_SAFETY_SCAN, MIN_HOLD_DOWN, MIN_SAFETY_SCAN};
+use crate::error::{MonitorError, Result};
+use crate::filter::{Glob, PathRegex, Preset};
+use crate::instance::Duplicates;
+use crate::logging::{LogFormat, Rotation};
+use crate::pipeline::Xattrs;
+use crate::profile::{self, Ignores};
+use crate::snapshot::Format;
+use clap::builder::FalseyValueParser;
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+#[cfg(test)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Separates paths in environment variables, like in `PATH`.
+const ENV_PATH_DELIMITER: char = if cfg!(windows) { ';' } else { ':' };
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Options {
+    /// How long events for one path are collected before they are reported,
+    /// in milliseconds. Longer suits bursts of writes like build output,
+    /// shorter reports sooner. [default: 100]
+    #[arg(
+        long,
+        value_name = "MS",
+        env = "UNISON_FSMONITOR_DEBOUNCE",
+        value_parser = clap::value_parser!(u64).range(
+            MIN_DEBOUNCE_TIMEOUT.as_millis() as u64..=MAX_DEBOUNCE_TIMEOUT.as_millis() as u64
+        )
+    )]
+    pub debounce_ms: Option<u64>,
+
+    /// Log at LEVEL instead of what RUST_LOG says: off, error, warn, info,
+    /// debug or trace. Send SIGUSR2 to step up to debug, trace and back at
+    /// runtime.
+    #[arg(long, value_name = "LEVEL", env = "UNISON_FSMONITOR_LOGLEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Append the log to FILE instead of writing it to stderr.
+    #[arg(long, value_name = "FILE", env = "UNISON_FSMONITOR_LOGFILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Rotate the log and trace files once they would grow beyond a size
+    /// like 10M, daily or never.
+    #[arg(
+        long,
+        value_name = "WHEN",
+        env = "UNISON_FSMONITOR_LOGROTATE",
+        default_value = "10M"
+    )]
+    pub log_rotate: Rotation,
+
+    /// How many rotated log and trace files to keep.
+    #[arg(
+        long,
+        value_name = "N",
+        env = "UNISON_FSMONITOR_LOGKEEP",
+        default_value_t = 5
+    )]
+    pub log_keep: usize,
+
+    /// Write the log as text or as json, one object per record with the
+    /// replica, path and event kind it is about as fields.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        env = "UNISON_FSMONITOR_LOGFORMAT",
+        default_value = "text"
+    )]
+    pub log_format: LogFormat,
+
+    /// Append every line unison sends and gets back to FILE, with a
+    /// timestamp, to attach to bug reports.
+    #[arg(long, value_name = "FILE", env = "UNISON_FSMONITOR_TRACE")]
+    pub trace: Option<PathBuf>,
+
+    /// Source of filesystem events: native for the native backend of the
+    /// platform, auto for the same but polling replicas on network
+    /// filesystems, its name (inotify, fsevents, kqueue, windows or fen),
+    /// fanotify on Linux, watchman on unix, or poll. [default: auto]
+    #[arg(long, value_name = "NAME", env = "UNISON_FSMONITOR_BACKEND")]
+    pub backend: Option<String>,
+
+    /// Poll all replicas, for filesystems like NFS or SMB that deliver no
+    /// native events. Same as --backend poll.
+    #[arg(
+        long,
+        env = "UNISON_FSMONITOR_POLL",
+        value_parser = FalseyValueParser::new(),
+        conflicts_with = "backend"
+    )]
+    pub poll: bool,
+
+    /// Seconds between scans when polling. [default: 2]
+    #[arg(
+        long,
+        value_name = "SECS",
+        env = "UNISON_FSMONITOR_POLL_INTERVAL",
+        value_parser = clap::value_parser!(u64).range(
+            MIN_POLL_INTERVAL.as_secs()..=MAX_POLL_INTERVAL.as_secs()
+        )
+    )]
+    pub poll_interval: Option<u64>,
+
+    /// Milliseconds FSEvents collects changes for on macOS before passing
+    /// them on, fewer wakeups for busy trees at the cost of reporting later.
+    /// [default: 0]
+    #[arg(
+        long,
+        value_name = "MS",
+        env = "UNISON_FSMONITOR_FSEVENTS_LATENCY",
+        value_parser = clap::value_parser!(u64).range(
+            0..=MAX_FSEVENTS_LATENCY.as_millis() as u64
+        )
+    )]
+    pub fsevents_latency_ms: Option<u64>,
+
+    /// Poll a replica once the native backend runs out of watches for it,
+    /// instead of answering unison with an error.
+    #[arg(
+        long,
+        env = "UNISON_FSMONITOR_POLL_FALLBACK",
+        value_parser = FalseyValueParser::new()
+    )]
+    pub poll_fallback: bool,
+
+    /// When polling a replica on FAT or exFAT, which keep mtimes to two
+    /// seconds, compare samples of the contents of files written within
+    /// that window rather than report them once more.
+    #[arg(
+        long,
+        env = "UNISON_FSMONITOR_SAMPLE_CONTENTS",
+        value_parser = FalseyValueParser::new()
+    )]
+    pub sample_contents: bool,
+
+    /// Watch directories at most N levels below a replica root, one by one,
+    /// and report changes further down on their ancestor N levels down.
+    #[arg(long, value_name = "N", env = "UNISON_FSMONITOR_MAX_DEPTH")]
+    pub max_depth: Option<usize>,
+
+    /// Report a path at most once every SECS seconds, holding back further
+    /// changes to it until then, for files written to all the time like logs
+    /// and downloads.
+    #[arg(
+        long,
+        value_name = "SECS",
+        env = "UNISON_FSMONITOR_HOLD_DOWN",
+        value_parser = clap::value_parser!(u64).range(
+            MIN_HOLD_DOWN.as_secs()..=MAX_HOLD_DOWN.as_secs()
+        )
+    )]
+    pub hold_down: Option<u64>,
+
+    /// Report a directory as a whole once more than N changes directly in it
+    /// are pending, 0 for never. [default: 100]
+    #[arg(long, value_name = "N", env = "UNISON_FSMONITOR_COLLAPSE_AFTER")]
+    pub collapse_after: Option<usize>,
+
+    /// Rescan a replica as a whole once more than N changes are pending for
+    /// it, 0 for no limit. [default: 100000]
+    #[arg(long, value_name = "N", env = "UNISON_FSMONITOR_MAX_PENDING")]
+    pub max_pending: Option<usize>,
+
+    /// Once more than N events a second arrive for a replica, hold them back
+    /// until it was quiet for a while, longer the longer the storm lasts. 0
+    /// for never. [default: 1000]
+    #[arg(long, value_name = "N", env = "UNISON_FSMONITOR_STORM_RATE")]
+    pub storm_rate: Option<usize>,
+
+    /// Scan each replica every SECS seconds for changes its watcher missed,
+    /// e.g. when the kernel dropped events or a filesystem was remounted.
+    #[arg(
+        long,
+        value_name = "SECS",
+        env = "UNISON_FSMONITOR_SAFETY_SCAN",
+        value_parser = clap::value_parser!(u64).range(
+            MIN_SAFETY_SCAN.as_secs()..=MAX_SAFETY_SCAN.as_secs()
+        )
+    )]
+    pub safety_scan: Option<u64>,
+
+    /// Read settings from FILE. [default: unison-fsmonitor/config.toml in
+    /// the user's config directory, if it exists]
+    #[arg(long, value_name = "FILE", env = "UNISON_FSMONITOR_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Watch only the directories unison announces, for huge replicas.
+    #[arg(
+        long,
+        env = "UNISON_FSMONITOR_SELECTIVE",
+        value_parser = FalseyValueParser::new()
+    )]
+    pub selective: bool,
+
+    /// Write the process id to FILE while running. Fails to start if another
+    /// running monitor holds it.
+    #[arg(long, value_name = "FILE", env = "UNISON_FSMONITOR_PIDFILE")]
+    pub pid_file: Option<PathBuf>,
+
+    /// Journal the changes unison has not taken yet in DIR, to report them
+    /// after the monitor restarts. [default: unison-fsmonitor in the user's
+    /// state directory]
+    #[arg(long, value_name = "DIR", env = "UNISON_FSMONITOR_STATE_DIR")]
+    pub state_dir: Option<PathBuf>,
+
+    /// What to do with a replica another running monitor watches already:
+    /// share it, logging a warning, or refuse it with an error to unison.
+    #[arg(
+        long,
+        value_name = "WHAT",
+        env = "UNISON_FSMONITOR_DUPLICATES",
+        default_value = "share"
+    )]
+    pub duplicates: Duplicates,
+
+    /// Watch the ROOTs for a single debounce window, print what changed in
+    /// it and exit, instead of serving unison.
+    #[arg(long, value_name = "ROOT", num_args = 1..)]
+    pub once: Vec<PathBuf>,
+
+    /// Serve unison over TCP at ADDR, like 127.0.0.1:7789, instead of on
+    /// stdin and stdout: a session for each connection, until stopped. The
+    /// config is read again for every session. Unison reaches it through a
+    /// monitor run with --connect.
+    #[arg(long, value_name = "ADDR", env = "UNISON_FSMONITOR_LISTEN")]
+    pub listen: Option<String>,
+
+    /// Pass the session with unison on to the monitor listening at ADDR,
+    /// rather than serve it: a TCP address, or on Windows a named pipe like
+    /// \\.\pipe\unison-fsmonitor, where the service listens. Set
+    /// UNISON_FSMONITOR_CONNECT where unison runs to have it do so.
+    #[arg(long, value_name = "ADDR", env = "UNISON_FSMONITOR_CONNECT")]
+    pub connect: Option<String>,
+
+    /// Serve counters and gauges of all sessions at http://ADDR/metrics in
+    /// the Prometheus text format, like events handled and dropped, changes
+    /// reported, pending changes and watch errors.
+    #[arg(long, value_name = "ADDR", env = "UNISON_FSMONITOR_METRICS_LISTEN")]
+    pub metrics_listen: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// How --once prints changes: protocol, as unison would hear them, or
+    /// plain absolute paths.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        env = "UNISON_FSMONITOR_FORMAT",
+        default_value = "protocol"
+    )]
+    pub format: Format,
+
+    /// Don't report changes at or below paths matching REGEX, a regular
+    /// expression for the whole path relative to the replica root. May be
+    /// given more than once.
+    #[arg(long, value_name = "REGEX", env = "UNISON_FSMONITOR_IGNORE_REGEX")]
+    pub ignore_regex: Vec<PathRegex>,
+
+    /// Don't report what built-in ignores cover: editors for swap, backup
+    /// and lock files of editors, os for .DS_Store, Thumbs.db and the like.
+    /// Takes a list separated by commas.
+    #[arg(
+        long,
+        value_name = "PRESET",
+        env = "UNISON_FSMONITOR_IGNORE_PRESET",
+        value_delimiter = ','
+    )]
+    pub ignore_preset: Vec<Preset>,
+
+    /// Don't report what .gitignore files within replicas ignore, for source
+    /// trees.
+    #[arg(
+        long,
+        env = "UNISON_FSMONITOR_GITIGNORE",
+        value_parser = FalseyValueParser::new()
+    )]
+    pub gitignore: bool,
+
+    /// Report a change to a file linked more than once within a replica on
+    /// each of its paths. Takes a walk of the replica when it starts.
+    #[arg(
+        long,
+        env = "UNISON_FSMONITOR_HARDLINKS",
+        value_parser = FalseyValueParser::new()
+    )]
+    pub hardlinks: bool,
+
+    /// Don't report changes to nothing but permissions, ownership or other
+    /// attributes, for syncs with perms = 0.
+    #[arg(
+        long,
+        env = "UNISON_FSMONITOR_IGNORE_METADATA",
+        value_parser = FalseyValueParser::new()
+    )]
+    pub ignore_metadata: bool,
+
+    /// What to make of changes to nothing but extended attributes: auto
+    /// treats them like other attributes, report keeps them even with
+    /// --ignore-metadata, ignore drops them where the backend tells them
+    /// apart.
+    #[arg(long, value_name = "WHAT", env = "UNISON_FSMONITOR_XATTRS")]
+    pub xattrs: Option<Xattrs>,
+
+    /// Report the temporary files and archives unison writes itself too,
+    /// which are left out by default.
+    #[arg(
+        long,
+        env = "UNISON_FSMONITOR_REPORT_UNISON_FILES",
+        value_parser = FalseyValueParser::new()
+    )]
+    pub report_unison_files: bool,
+
+    /// Watch and report snapshot directories like .zfs, .snapshot and
+    /// .snapshots too, which are left out by default.
+    #[arg(
+        long,
+        env = "UNISON_FSMONITOR_INCLUDE_SNAPSHOTS",
+        value_parser = FalseyValueParser::new()
+    )]
+    pub include_snapshots: bool,
+
+    /// Answer a START for a path that does not exist yet, like the mount
+    /// point of an unplugged disk, and watch the path once it appears
+    /// rather than fail.
+    #[arg(
+        long,
+        env = "UNISON_FSMONITOR_WAIT_FOR_PATH",
+        value_parser = FalseyValueParser::new()
+    )]
+    pub wait_for_path: bool,
+
+    /// Don't report what the ignore and ignorenot preferences of the unison
+    /// PROFILE skip, given by name or as a path to its .prf file.
+    #[arg(long, value_name = "PROFILE", env = "UNISON_FSMONITOR_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Don't report changes at or below paths matching PATTERN, a glob
+    /// relative to the replica root like target or **/*.tmp. A leading !
+    /// makes an exception to earlier patterns, the last match wins. May be
+    /// given more than once, the environment variable takes a list separated
+    /// like PATH.
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        env = "UNISON_FSMONITOR_IGNORE",
+        value_delimiter = ENV_PATH_DELIMITER
+    )]
+    pub ignore: Vec<Glob>,
+}
+
+impl Options {
+    /// The config file with these options applied on top.
+    pub fn config(&self) -> Result<Config> {
+        let path = self
+            .config
+            .clone()
+            .or_else(|| config::default_path().filter(|path| path.is_file()));
+        let mut config = match path {
+            Some(path) => Config::load(&path)?,
+            None => Config::default(),
+        };
+        if self.debounce_ms.is_some() {
+            config.debounce_ms = self.debounce_ms;
+        }
+        if self.poll {
+            config.backend = Some("poll".into());
+        } else if self.backend.is_some() {
+            config.backend = self.backend.clone();
+        }
+        if self.poll_interval.is_some() {
+            config.poll_interval = self.poll_interval;
+        }
+        if self.fsevents_latency_ms.is_some() {
+            config.fsevents_latency_ms = self.fsevents_latency_ms;
+        }
+        if self.poll_fallback {
+            config.poll_fallback = Some(true);
+        }
+        if self.sample_contents {
+            config.sample_contents = Some(true);
+        }
+        if self.max_depth.is_some() {
+            config.max_depth = self.max_depth;
+        }
+        if self.hold_down.is_some() {
+            config.hold_down = self.hold_down;
+        }
+        if self.collapse_after.is_some() {
+            config.collapse_after = self.collapse_after;
+        }
+        if self.max_pending.is_some() {
+            config.max_pending = self.max_pending;
+        }
+        if self.storm_rate.is_some() {
+            config.storm_rate = self.storm_rate;
+        }
+        if self.safety_scan.is_some() {
+            config.safety_scan = self.safety_scan;
+        }
+        config.ignore.extend(self.ignore.iter().cloned());
+        config
+            .ignore_regex
+            .extend(self.ignore_regex.iter().cloned());
+        for preset in &self.ignore_preset {
+            if !config.ignore_preset.contains(preset) {
+                config.ignore_preset.push(*preset);
+            }
+        }
+        if self.gitignore {
+            config.gitignore = Some(true);
+        }
+        if self.hardlinks {
+            config.hardlinks = Some(true);
+        }
+        if self.wait_for_path {
+            config.wait_for_path = Some(true);
+        }
+        if self.ignore_metadata {
+            config.ignore_metadata = Some(true);
+        }
+        if self.xattrs.is_some() {
+            config.xattrs = self.xattrs;
+        }
+        if self.report_unison_files {
+            config.ignore_unison_files = Some(false);
+        }
+        if self.include_snapshots {
+            config.ignore_snapshots = Some(false);
+        }
+        if let Some(name) = &self.profile {
+            let path = profile::locate(name).ok_or_else(|| {
+                MonitorError::ConfigError(format!("Cannot locate profile {}", name))
+            })?;
+            config.profile = Some(Arc::new(Ignores::load(&path)?));
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// What to do instead of serving unison.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Time watching PATH, handling a burst of events below it and how soon
+    /// single changes are reported, then exit. Writes to a scratch directory
+    /// in PATH, with the other options applying as to a replica.
+    Bench {
+        path: PathBuf,
+
+        /// Files to write at once for timing event handling.
+        #[arg(long, value_name = "N", default_value = "1000")]
+        files: usize,
+    },
+
+    /// Run as a Windows service, listening at --listen or else at the named
+    /// pipe \\.\pipe\unison-fsmonitor for unison runs to connect to,
+    /// scheduled ones included.
+    #[cfg(windows)]
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+}
+
+#[cfg(windows)]
+#[derive(Debug, Subcommand)]
+pub enum ServiceAction {
+    /// Register the service and start it, to start with Windows from then
+    /// on with the options given before `service`. Takes an administrator.
+    Install,
+    /// Stop the service and remove it.
+    Uninstall,
+    /// Serve as the service, which is how Windows starts it.
+    Run {
+        /// The account that may connect to the pipe, by SID: the one that
+        /// installed the service.
+        #[arg(long, value_name = "SID")]
+        user: String,
+    },
+}
+
+#[cfg(test)]
+fn globs(patterns: &[&str]) -> Vec<Glob> {
+    patterns
+        .iter()
+        .map(|pattern| pattern.parse().unwrap())
+        .collect()
+}
+
+#[test]
+fn test_no_arguments() {
+    let options = Options::try_parse_from(["unison-fsmonitor"]).unwrap();
+    assert_eq!(options.debounce_ms, None);
+    assert_eq!(options.log_level, None);
+    assert_eq!(options.log_file, None);
+    assert_eq!(options.log_rotate, Rotation::Size(10 << 20));
+    assert_eq!(options.log_keep, 5);
+    assert_eq!(options.log_format, LogFormat::Text);
+    assert_eq!(options.trace, None);
+    assert!(!options.selective);
+    assert_eq!(options.pid_file, None);
+    assert_eq!(options.state_dir, None);
+    assert_eq!(options.duplicates, Duplicates::Share);
+    assert!(options.once.is_empty());
+    assert_eq!(options.listen, None);
+    assert_eq!(options.connect, None);
+    assert_eq!(options.metrics_listen, None);
+    assert!(options.command.is_none());
+    assert_eq!(options.fsevents_latency_ms, None);
+    assert!(!options.poll_fallback);
+    assert!(!options.sample_contents);
+    assert!(!options.gitignore);
+    assert!(!options.hardlinks);
+    assert!(!options.ignore_metadata);
+    assert_eq!(options.xattrs, None);
+    assert!(!options.report_unison_files);
+    assert!(!options.include_snapshots);
+    assert!(!options.wait_for_path);
+    assert_eq!(options.max_depth, None);
+    assert_eq!(options.hold_down, None);
+    assert_eq!(options.collapse_after, None);
+    assert_eq!(options.max_pending, None);
+    assert_eq!(options.storm_rate, None);
+    assert_eq!(options.safety_scan, None);
+    assert!(options.ignore_preset.is_empty());
+    assert!(options.ignore_regex.is_empty());
+    assert!(options.ignore.is_empty());
+}
+
+#[test]
+fn test_arguments() {
+    let options = Options::try_parse_from([
+        "unison-fsmonitor",
+        "--debounce-ms",
+        "500",
+        "--log-level",
+        "info",
+        "--log-file",
+        "/tmp/fsmonitor.log",
+        "--log-rotate",
+        "daily",
+        "--log-keep",
+        "7",
+        "--log-format",
+        "json",
+        "--trace",
+        "/tmp/fsmonitor.trace",
+        "--backend",
+        "poll",
+        "--fsevents-latency-ms",
+        "250",
+        "--poll-fallback",
+        "--sample-contents",
+        "--listen",
+        "127.0.0.1:7789",
+        "--metrics-listen",
+        "127.0.0.1:9789",
+        "--selective",
+        "--pid-file",
+        "/tmp/fsmonitor.pid",
+        "--state-dir",
+        "/tmp/state",
+        "--duplicates",
+        "refuse",
+        "--ignore",
+        "target",
+        "--ignore",
+        "**/*.tmp",
+        "--ignore-preset",
+        "editors,os",
+        "--ignore-regex",
+        r"logs/\d+",
+        "--ignore-metadata",
+        "--xattrs",
+        "ignore",
+        "--report-unison-files",
+        "--include-snapshots",
+        "--wait-for-path",
+        "--max-depth",
+        "2",
+        "--hold-down",
+        "30",
+        "--collapse-after",
+        "500",
+        "--max-pending",
+        "1000",
+        "--storm-rate",
+        "5000",
+        "--safety-scan",
+        "600",
+    ])
+    .unwrap();
+    assert_eq!(options.debounce_ms, Some(500));
+    assert_eq!(options.log_level, Some(LevelFilter::Info));
+    assert_eq!(options.log_file, Some("/tmp/fsmonitor.log".into()));
+    assert_eq!(options.log_rotate, Rotation::Daily);
+    assert_eq!(options.log_keep, 7);
+    assert_eq!(options.log_format, LogFormat::Json);
+    assert_eq!(options.trace, Some("/tmp/fsmonitor.trace".into()));
+    assert_eq!(options.backend.as_deref(), Some("poll"));
+    assert_eq!(options.fsevents_latency_ms, Some(250));
+    assert!(options.poll_fallback);
+    assert!(options.sample_contents);
+    assert_eq!(options.listen.as_deref(), Some("127.0.0.1:7789"));
+    assert_eq!(options.metrics_listen.as_deref(), Some("127.0.0.1:9789"));
+    assert!(options.selective);
+    assert_eq!(options.pid_file, Some("/tmp/fsmonitor.pid".into()));
+    assert_eq!(options.state_dir, Some("/tmp/state".into()));
+    assert_eq!(options.duplicates, Duplicates::Refuse);
+    assert_eq!(options.ignore, globs(&["target", "**/*.tmp"]));
+    assert_eq!(options.ignore_preset, [Preset::Editors, Preset::Os]);
+    assert_eq!(options.ignore_regex, [r"logs/\d+".parse().unwrap()]);
+    assert!(options.ignore_metadata);
+    assert_eq!(options.xattrs, Some(Xattrs::Ignore));
+    assert!(options.report_unison_files);
+    assert!(options.include_snapshots);
+    assert!(options.wait_for_path);
+    assert_eq!(options.max_depth, Some(2));
+    assert_eq!(options.hold_down, Some(30));
+    assert_eq!(options.collapse_after, Some(500));
+    assert_eq!(options.max_pending, Some(1000));
+    assert_eq!(options.storm_rate, Some(5000));
+    assert_eq!(options.safety_scan, Some(600));
+
+    assert!(Options::try_parse_from(["unison-fsmonitor", "--debounce-ms", "soon"]).is_err());
+    assert!(Options::try_parse_from(["unison-fsmonitor", "--debounce-ms", "-1"]).is_err());
+    assert!(Options::try_parse_from(["unison-fsmonitor", "--frobnicate"]).is_err());
+    assert!(Options::try_parse_from(["unison-fsmonitor", "--ignore", "[abc"]).is_err());
+    assert!(Options::try_parse_from(["unison-fsmonitor", "--hold-down", "0"]).is_err());
+    assert!(
+        Options::try_parse_from(["unison-fsmonitor", "--fsevents-latency-ms", "60000"]).is_err()
+    );
+}
+
+#[test]
+fn test_once() {
+    let options = Options::try_parse_from([
+        "unison-fsmonitor",
+        "--once",
+        "/tmp/a",
+        "/tmp/b",
+        "--format",
+        "plain",
+    ])
+    .unwrap();
+    assert_eq!(options.once, [PathBuf::from("/tmp/a"), "/tmp/b".into()]);
+    assert_eq!(options.format, Format::Plain);
+
+    assert!(Options::try_parse_from(["unison-fsmonitor", "--once"]).is_err());
+}
+
+#[test]
+fn test_bench() {
+    let options =
+        Options::try_parse_from(["unison-fsmonitor", "--poll", "bench", "/tmp/a"]).unwrap();
+    assert!(options.poll);
+    assert!(matches!(
+        options.command,
+        Some(Command::Bench { path, files: 1000 }) if path == Path::new("/tmp/a")
+    ));
+
+    let options =
+        Options::try_parse_from(["unison-fsmonitor", "bench", "--files", "10", "/tmp/a"]).unwrap();
+    assert!(matches!(
+        options.command,
+        Some(Command::Bench { files: 10, .. })
+    ));
+
+    assert!(Options::try_parse_from(["unison-fsmonitor", "bench"]).is_err());
+}
+
+#[test]
+fn test_debounce_bounds() {
+    let parse = |ms: &str| Options::try_parse_from(["unison-fsmonitor", "--debounce-ms", ms]);
+    assert_eq!(parse("10").unwrap().debounce_ms, Some(10));
+    assert_eq!(parse("60000").unwrap().debounce_ms, Some(60000));
+    assert!(parse("0").is_err());
+    assert!(parse("9").is_err());
+    assert!(parse("60001").is_err());
+}
+
+#[test]
+fn test_config() {
+    let tmp = crate::test_dir();
+    let path = tmp.path().join("config.toml");
+    std::fs::write(
+        &path,
+        "debounce-ms = 200\nbackend = \"poll\"\nignore = [\".git\"]\n",
+    )
+    .unwrap();
+    let mut options = Options::try_parse_from(["unison-fsmonitor", "--ignore", "target"]).unwrap();
+    options.config = Some(path.clone());
+    options.backend = Some("auto".into());
+    let config = options.config().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(config.debounce_ms, Some(200));
+    assert_eq!(config.backend.as_deref(), Some("auto"));
+    assert_eq!(config.ignore, globs(&[".git", "target"]));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_poll() {
+    let options =
+        Options::try_parse_from(["unison-fsmonitor", "--poll", "--poll-interval", "10"]).unwrap();
+    let config = Options {
+        config: Some("/dev/null".into()),
+        ..options
+    }
+    .config()
+    .unwrap();
+    assert_eq!(config.backend.as_deref(), Some("poll"));
+    assert_eq!(config.poll_interval, Some(10));
+
+    assert!(Options::try_parse_from(["unison-fsmonitor", "--poll", "--backend", "auto"]).is_err());
+    assert!(Options::try_parse_from(["unison-fsmonitor", "--poll-interval", "0"]).is_err());
+
+    let options = Options::try_parse_from([
+        "unison-fsmonitor",
+        "--config",
+        "/dev/null",
+        "--backend",
+        "carrier-pigeon",
+    ])
+    .unwrap();
+    assert!(options.config().is_err());
+}
+
+#[test]
+fn test_environment() {
+    use clap::CommandFactory;
+
+    let command = Options::command();
+    for arg in command.get_arguments() {
+        let name = arg.get_id().as_str();
+        // Only scripts ask for --once, never unison starting the monitor.
+        if !["help", "version", "once"].contains(&name) {
+            let env = arg
+                .get_env()
+                .unwrap_or_else(|| panic!("--{} has no variable", name));
+            assert!(env.to_string_lossy().starts_with("UNISON_FSMONITOR_"));
+        }
+    }
+}
+
+#[cfg(windows)]
+#[test]
+fn test_service() {
+    let options = Options::try_parse_from([
+        "unison-fsmonitor",
+        "--listen",
+        "127.0.0.1:8000",
+        "service",
+        "install",
+    ])
+    .unwrap();
+    assert!(matches!(
+        options.command,
+        Some(Command::Service {
+            action: ServiceAction::Install
+        })
+    ));
+    assert!(Options::try_parse_from(["unison-fsmonitor", "service"]).is_err());
+    let options =
+        Options::try_parse_from(["unison-fsmonitor", "service", "run", "--user", "S-1-5-18"])
+            .unwrap();
+    assert!(matches!(
+        options.command,
+        Some(Command::Service {
+            action: ServiceAction::Run { user }
+        }) if user == "S-1-5-18"
+    ));
+}
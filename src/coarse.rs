@@ -0,0 +1,356 @@
+//! FAT and exFAT keep mtimes to two seconds, and polling by mtime alone
+//! misses a file written twice within them, or reports it again when the
+//! precise mtime the kernel cached is rounded on its way to the disk.
+//! Replicas on them are polled by size and by mtime in two second steps,
+//! and files written within a step of a scan are looked at again on the
+//! next: reported once more, or compared by samples of their contents.
+
+use notify::event::{CreateKind, DataChange, EventKind, ModifyKind, RemoveKind};
+use notify::{Config, EventHandler, RecursiveMode, WatcherKind};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::hash::Hasher;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Filesystem types with mtimes in two second steps, as each platform names
+/// them.
+const COARSE_TYPES: &[&str] = &["vfat", "msdos", "msdosfs", "fat", "fat32", "exfat"];
+
+/// Seconds FAT rounds mtimes to.
+const STEP: i64 = 2;
+
+/// Bytes read from the start, the middle and the end of a file to sample it.
+const SAMPLE_LEN: u64 = 4096;
+
+#[cfg(not(windows))]
+fn fs_type(path: &Path) -> Option<String> {
+    crate::netfs::fs_type(path)
+}
+
+#[cfg(windows)]
+fn fs_type(path: &Path) -> Option<String> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{GetVolumeInformationW, GetVolumePathNameW};
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut volume = vec![0u16; wide.len().max(4)];
+    if unsafe { GetVolumePathNameW(wide.as_ptr(), volume.as_mut_ptr(), volume.len() as u32) } == 0 {
+        return None;
+    }
+    let mut name = [0u16; 32];
+    let ok = unsafe {
+        GetVolumeInformationW(
+            volume.as_ptr(),
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            name.as_mut_ptr(),
+            name.len() as u32,
+        )
+    };
+    if ok == 0 {
+        return None;
+    }
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Some(String::from_utf16_lossy(&name[..len]))
+}
+
+/// The type of the filesystem `path` is on, if it keeps coarse mtimes.
+pub fn coarse_fs(path: &Path) -> Option<String> {
+    let fstype = fs_type(path)?;
+    COARSE_TYPES
+        .iter()
+        .any(|coarse| fstype.eq_ignore_ascii_case(coarse))
+        .then_some(fstype)
+}
+
+/// Seconds since the epoch, negative before it.
+fn seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs() as i64),
+    }
+}
+
+/// A hash of the size and of samples of the contents of a file.
+fn sample(path: &Path, size: u64) -> Option<u64> {
+    let mut file = File::open(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u64(size);
+    let mut buf = vec![0; SAMPLE_LEN as usize];
+    for at in [0, size / 2, size.saturating_sub(SAMPLE_LEN)] {
+        file.seek(SeekFrom::Start(at)).ok()?;
+        let read = file.read(&mut buf).ok()?;
+        hasher.write(&buf[..read]);
+    }
+    Some(hasher.finish())
+}
+
+/// What a scan keeps of an entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stat {
+    dir: bool,
+    size: u64,
+    /// The mtime in steps of `STEP` seconds.
+    step: i64,
+    /// Whether the mtime was within a step of the scan, when another write
+    /// could still leave it as it is.
+    recent: bool,
+    /// See `sample`, taken of recent files when sampling.
+    sample: Option<u64>,
+}
+
+impl Stat {
+    fn new(path: &Path, meta: &Metadata, now: i64, old: Option<&Stat>, sampling: bool) -> Self {
+        let mtime = meta.modified().map_or(0, seconds);
+        let recent = now - mtime < 2 * STEP;
+        let dir = meta.is_dir();
+        let size = if dir { 0 } else { meta.len() };
+        let sampled = sampling && !dir && (recent || old.is_some_and(|old| old.recent));
+        Self {
+            dir,
+            size,
+            step: mtime.div_euclid(STEP),
+            recent,
+            sample: sampled.then(|| sample(path, size)).flatten(),
+        }
+    }
+
+    /// Whether a file changed since `self` was taken. Directories only come
+    /// and go, what changes in them is reported on its own.
+    fn changed(&self, new: &Stat) -> bool {
+        if self.dir || new.dir {
+            return self.dir != new.dir;
+        }
+        self.size != new.size
+            || self.step != new.step
+            || (self.recent && (self.sample.is_none() || self.sample != new.sample))
+    }
+}
+
+/// Stat `root` and, down to `depth` levels if any, what is below it,
+/// without following links.
+fn scan(
+    root: &Path,
+    depth: Option<usize>,
+    old: &HashMap<PathBuf, Stat>,
+    sampling: bool,
+) -> HashMap<PathBuf, Stat> {
+    let now = seconds(SystemTime::now());
+    let mut entries = HashMap::new();
+    let mut pending = vec![(root.to_owned(), 0)];
+    while let Some((path, level)) = pending.pop() {
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        if meta.is_dir() && depth.is_none_or(|depth| level < depth) {
+            for entry in fs::read_dir(&path).into_iter().flatten().flatten() {
+                pending.push((entry.path(), level + 1));
+            }
+        }
+        let stat = Stat::new(&path, &meta, now, old.get(&path), sampling);
+        entries.insert(path, stat);
+    }
+    entries
+}
+
+/// A watched path and what its last scan found.
+struct Tree {
+    depth: Option<usize>,
+    entries: HashMap<PathBuf, Stat>,
+}
+
+struct Shared {
+    trees: Mutex<HashMap<PathBuf, Tree>>,
+    handler: Mutex<Box<dyn EventHandler>>,
+    sampling: bool,
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    /// Rescan every tree and report what changed.
+    fn poll(&self) {
+        let mut trees = self.trees.lock().unwrap();
+        for (root, tree) in trees.iter_mut() {
+            let entries = scan(root, tree.depth, &tree.entries, self.sampling);
+            let mut handler = self.handler.lock().unwrap();
+            for (path, new) in &entries {
+                let kind = match tree.entries.get(path) {
+                    None => EventKind::Create(CreateKind::Any),
+                    Some(old) if old.changed(new) => {
+                        EventKind::Modify(ModifyKind::Data(DataChange::Any))
+                    }
+                    Some(_) => continue,
+                };
+                handler.handle_event(Ok(notify::Event::new(kind).add_path(path.clone())));
+            }
+            for path in tree.entries.keys() {
+                if !entries.contains_key(path) {
+                    let event = notify::Event::new(EventKind::Remove(RemoveKind::Any));
+                    handler.handle_event(Ok(event.add_path(path.clone())));
+                }
+            }
+            tree.entries = entries;
+        }
+    }
+}
+
+/// Polls watched trees every poll interval of its `Config`, sampling the
+/// contents of recent files if the `Config` compares contents.
+pub struct CoarsePollWatcher {
+    shared: Arc<Shared>,
+}
+
+impl notify::Watcher for CoarsePollWatcher {
+    fn new<F: EventHandler>(event_handler: F, config: Config) -> notify::Result<Self> {
+        let shared = Arc::new(Shared {
+            trees: Mutex::new(HashMap::new()),
+            handler: Mutex::new(Box::new(event_handler)),
+            sampling: config.compare_contents(),
+            stopped: AtomicBool::new(false),
+        });
+        let interval = config
+            .poll_interval()
+            .unwrap_or(crate::backend::POLL_INTERVAL);
+        let poller = shared.clone();
+        thread::Builder::new()
+            .name("coarse-poll".into())
+            .spawn(move || {
+                // Woken often enough to stop soon after the watcher is gone.
+                let tick = interval.min(Duration::from_millis(250));
+                let mut waited = Duration::ZERO;
+                while !poller.stopped.load(Ordering::Relaxed) {
+                    thread::sleep(tick);
+                    waited += tick;
+                    if waited >= interval {
+                        waited = Duration::ZERO;
+                        poller.poll();
+                    }
+                }
+            })
+            .map_err(notify::Error::io)?;
+        Ok(Self { shared })
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        if fs::symlink_metadata(path).is_err() {
+            return Err(notify::Error::path_not_found().add_path(path.to_owned()));
+        }
+        let depth = match recursive_mode {
+            RecursiveMode::Recursive => None,
+            RecursiveMode::NonRecursive => Some(1),
+        };
+        let entries = scan(path, depth, &HashMap::new(), self.shared.sampling);
+        let tree = Tree { depth, entries };
+        self.shared
+            .trees
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), tree);
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        match self.shared.trees.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(notify::Error::watch_not_found().add_path(path.to_owned())),
+        }
+    }
+
+    fn kind() -> WatcherKind {
+        WatcherKind::PollWatcher
+    }
+}
+
+impl Drop for CoarsePollWatcher {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn test_changed() {
+    let file = Stat {
+        dir: false,
+        size: 10,
+        step: 100,
+        recent: false,
+        sample: None,
+    };
+    // Rounding within a step is no change, a new size or step is.
+    assert!(!file.changed(&file));
+    assert!(file.changed(&Stat { size: 11, ..file }));
+    assert!(file.changed(&Stat { step: 101, ..file }));
+    // Another write within the step of a recent file leaves both as they
+    // are: reported unless samples tell otherwise.
+    let recent = Stat {
+        recent: true,
+        ..file
+    };
+    assert!(recent.changed(&file));
+    let sampled = Stat {
+        sample: Some(1),
+        ..recent
+    };
+    assert!(!sampled.changed(&Stat {
+        sample: Some(1),
+        ..file
+    }));
+    assert!(sampled.changed(&Stat {
+        sample: Some(2),
+        ..file
+    }));
+    let dir = Stat {
+        dir: true,
+        recent: true,
+        ..file
+    };
+    assert!(!dir.changed(&Stat { step: 101, ..dir }));
+    assert!(dir.changed(&file));
+}
+
+#[test]
+fn test_coarse_poll() {
+    use notify::Watcher;
+    use std::sync::mpsc;
+
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    fs::create_dir_all(base.join("dir")).unwrap();
+    fs::write(base.join("dir/file"), b"one").unwrap();
+    let (tx, rx) = mpsc::channel();
+    let config = Config::default()
+        .with_poll_interval(Duration::from_millis(50))
+        .with_compare_contents(true);
+    let mut watcher = CoarsePollWatcher::new(tx, config).unwrap();
+    watcher.watch(base, RecursiveMode::Recursive).unwrap();
+
+    let next = || rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    // Same size and most likely the same step, told apart by samples.
+    fs::write(base.join("dir/file"), b"two").unwrap();
+    let event = next();
+    assert_eq!(
+        event.kind,
+        EventKind::Modify(ModifyKind::Data(DataChange::Any))
+    );
+    assert_eq!(event.paths, [base.join("dir/file")]);
+    fs::write(base.join("dir/new"), b"").unwrap();
+    let event = next();
+    assert_eq!(event.kind, EventKind::Create(CreateKind::Any));
+    assert_eq!(event.paths, [base.join("dir/new")]);
+    fs::remove_file(base.join("dir/new")).unwrap();
+    let event = next();
+    assert_eq!(event.kind, EventKind::Remove(RemoveKind::Any));
+
+    watcher.unwatch(base).unwrap();
+    assert!(watcher.unwatch(base).is_err());
+}
@@ -0,0 +1,565 @@
+//! Settings from the config file, with `[[replica]]` sections overriding them
+//! for the replicas under a path:
+//!
+//! ```toml
+//! debounce-ms = 200
+//! ignore = [".git"]
+//! log-level = "info"
+//!
+//! [[replica]]
+//! path = "/home/me/src/monorepo"
+//! debounce-ms = 2000
+//! ignore = ["target", "**/node_modules"]
+//!
+//! [[replica]]
+//! path = "/mnt/nfs/share"
+//! backend = "poll"
+//! poll-interval = 10
+//! ```
+
+use crate::backend::{
+    self, DEBOUNCE_TIMEOUT, FSEVENTS_LATENCY, MAX_DEBOUNCE_TIMEOUT, MAX_FSEVENTS_LATENCY,
+    MAX_POLL_INTERVAL, MIN_DEBOUNCE_TIMEOUT, MIN_POLL_INTERVAL, POLL_INTERVAL, STORM_RATE,
+};
+use crate::error::{MonitorError, Result};
+use crate::filter::{Glob, PathRegex, Preset};
+use crate::monitor::MAX_PENDING;
+use crate::pipeline::{Xattrs, COLLAPSE_AFTER};
+use crate::profile::Ignores;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Bounds of `hold-down`, the window within which a path is reported once.
+pub const MIN_HOLD_DOWN: Duration = Duration::from_secs(1);
+pub const MAX_HOLD_DOWN: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Bounds of `safety-scan`, the time between scans for missed changes.
+pub const MIN_SAFETY_SCAN: Duration = Duration::from_secs(10);
+pub const MAX_SAFETY_SCAN: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub debounce_ms: Option<u64>,
+    pub backend: Option<String>,
+    /// Seconds between scans of the polling backend.
+    pub poll_interval: Option<u64>,
+    /// Milliseconds FSEvents collects changes for before passing them on.
+    pub fsevents_latency_ms: Option<u64>,
+    /// Poll replicas the native backend runs out of watches for.
+    pub poll_fallback: Option<bool>,
+    pub ignore: Vec<Glob>,
+    pub ignore_regex: Vec<PathRegex>,
+    /// Built-in ignores, added to `ignore`.
+    pub ignore_preset: Vec<Preset>,
+    /// Also skip what `.gitignore` files within replicas ignore.
+    pub gitignore: Option<bool>,
+    /// Report changes to files linked more than once on each of their paths.
+    pub hardlinks: Option<bool>,
+    /// Drop changes to nothing but attributes, like chmod.
+    pub ignore_metadata: Option<bool>,
+    /// What to make of changes to extended attributes alone.
+    pub xattrs: Option<Xattrs>,
+    /// Drop the temporary files and archives of unison, on by default.
+    pub ignore_unison_files: Option<bool>,
+    /// Drop changes within snapshot directories, on by default.
+    pub ignore_snapshots: Option<bool>,
+    /// Watch directories this many levels below a replica root at most.
+    pub max_depth: Option<usize>,
+    /// Seconds a reported path is held back for should it change again.
+    pub hold_down: Option<u64>,
+    /// Pending changes in a directory it takes to report the directory
+    /// instead, 0 for never.
+    pub collapse_after: Option<usize>,
+    /// Pending changes it takes to rescan a whole replica instead, 0 for no
+    /// limit.
+    pub max_pending: Option<usize>,
+    /// Events a second it takes to debounce adaptively, 0 for never.
+    pub storm_rate: Option<usize>,
+    /// Seconds between scans for changes the watcher missed.
+    pub safety_scan: Option<u64>,
+    /// Wait for paths unison starts before they exist, rather than fail.
+    pub wait_for_path: Option<bool>,
+    /// Compare samples of the contents of files polled on FAT and exFAT
+    /// whose mtime is too coarse to tell writes apart.
+    pub sample_contents: Option<bool>,
+    /// Like `--log-level`, which takes precedence.
+    pub log_level: Option<LevelFilter>,
+    pub replica: Vec<ReplicaConfig>,
+    /// Ignores of the unison profile given with `--profile`.
+    #[serde(skip)]
+    pub profile: Option<Arc<Ignores>>,
+}
+
+/// Overrides for the replicas at or below `path`, the most specific section
+/// wins. Ignores add to the global ones.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ReplicaConfig {
+    pub path: PathBuf,
+    pub debounce_ms: Option<u64>,
+    pub backend: Option<String>,
+    pub poll_interval: Option<u64>,
+    pub fsevents_latency_ms: Option<u64>,
+    pub poll_fallback: Option<bool>,
+    pub ignore: Vec<Glob>,
+    pub ignore_regex: Vec<PathRegex>,
+    pub ignore_preset: Vec<Preset>,
+    pub gitignore: Option<bool>,
+    pub hardlinks: Option<bool>,
+    pub ignore_metadata: Option<bool>,
+    pub xattrs: Option<Xattrs>,
+    pub ignore_unison_files: Option<bool>,
+    pub ignore_snapshots: Option<bool>,
+    pub max_depth: Option<usize>,
+    pub hold_down: Option<u64>,
+    pub collapse_after: Option<usize>,
+    pub max_pending: Option<usize>,
+    pub storm_rate: Option<usize>,
+    pub safety_scan: Option<u64>,
+    pub wait_for_path: Option<bool>,
+    pub sample_contents: Option<bool>,
+}
+
+/// What applies to one replica.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub debounce: Duration,
+    pub backend: String,
+    pub poll_interval: Duration,
+    pub fsevents_latency: Duration,
+    pub poll_fallback: bool,
+    /// Matched against paths relative to the replica root.
+    pub ignore: Vec<Glob>,
+    pub ignore_regex: Vec<PathRegex>,
+    pub gitignore: bool,
+    pub hardlinks: bool,
+    pub ignore_metadata: bool,
+    pub xattrs: Xattrs,
+    pub ignore_unison_files: bool,
+    pub ignore_snapshots: bool,
+    /// Changes deeper down are reported on their ancestor at this depth.
+    pub max_depth: Option<usize>,
+    pub hold_down: Option<Duration>,
+    pub collapse_after: usize,
+    pub max_pending: usize,
+    pub storm_rate: usize,
+    pub safety_scan: Option<Duration>,
+    pub wait_for_path: bool,
+    pub sample_contents: bool,
+    /// Whether the replica is on FAT or exFAT, found when it starts rather
+    /// than configured.
+    pub coarse_timestamps: bool,
+    pub profile: Option<Arc<Ignores>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Config::default().settings(Path::new(""))
+    }
+}
+
+/// `unison-fsmonitor/config.toml` in the user's config directory.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(dir.join("unison-fsmonitor").join("config.toml"))
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path)?;
+        text.parse()
+            .map_err(|err| MonitorError::ConfigError(format!("{}: {}", path.display(), err)))
+    }
+
+    /// The settings of the replica at `root`, the fspath unison starts it
+    /// with.
+    pub fn settings(&self, root: &Path) -> Settings {
+        let mut settings = Settings {
+            debounce: self
+                .debounce_ms
+                .map_or(DEBOUNCE_TIMEOUT, Duration::from_millis),
+            backend: self.backend.clone().unwrap_or_else(|| "auto".into()),
+            poll_interval: self
+                .poll_interval
+                .map_or(POLL_INTERVAL, Duration::from_secs),
+            fsevents_latency: self
+                .fsevents_latency_ms
+                .map_or(FSEVENTS_LATENCY, Duration::from_millis),
+            poll_fallback: self.poll_fallback.unwrap_or(false),
+            ignore: vec![],
+            ignore_regex: self.ignore_regex.clone(),
+            gitignore: self.gitignore.unwrap_or(false),
+            hardlinks: self.hardlinks.unwrap_or(false),
+            ignore_metadata: self.ignore_metadata.unwrap_or(false),
+            xattrs: self.xattrs.unwrap_or_default(),
+            ignore_unison_files: self.ignore_unison_files.unwrap_or(true),
+            ignore_snapshots: self.ignore_snapshots.unwrap_or(true),
+            max_depth: self.max_depth,
+            hold_down: self.hold_down.map(Duration::from_secs),
+            collapse_after: self.collapse_after.unwrap_or(COLLAPSE_AFTER),
+            max_pending: self.max_pending.unwrap_or(MAX_PENDING),
+            storm_rate: self.storm_rate.unwrap_or(STORM_RATE),
+            safety_scan: self.safety_scan.map(Duration::from_secs),
+            wait_for_path: self.wait_for_path.unwrap_or(false),
+            sample_contents: self.sample_contents.unwrap_or(false),
+            coarse_timestamps: false,
+            profile: self.profile.clone(),
+        };
+        let section = self
+            .replica
+            .iter()
+            .filter(|section| root.starts_with(&section.path))
+            .max_by_key(|section| section.path.components().count());
+        if let Some(section) = section {
+            if let Some(debounce_ms) = section.debounce_ms {
+                settings.debounce = Duration::from_millis(debounce_ms);
+            }
+            if let Some(backend) = &section.backend {
+                settings.backend = backend.clone();
+            }
+            if let Some(poll_interval) = section.poll_interval {
+                settings.poll_interval = Duration::from_secs(poll_interval);
+            }
+            if let Some(fsevents_latency_ms) = section.fsevents_latency_ms {
+                settings.fsevents_latency = Duration::from_millis(fsevents_latency_ms);
+            }
+            if let Some(poll_fallback) = section.poll_fallback {
+                settings.poll_fallback = poll_fallback;
+            }
+            settings
+                .ignore_regex
+                .extend(section.ignore_regex.iter().cloned());
+            if let Some(gitignore) = section.gitignore {
+                settings.gitignore = gitignore;
+            }
+            if let Some(hardlinks) = section.hardlinks {
+                settings.hardlinks = hardlinks;
+            }
+            if let Some(ignore_metadata) = section.ignore_metadata {
+                settings.ignore_metadata = ignore_metadata;
+            }
+            if let Some(xattrs) = section.xattrs {
+                settings.xattrs = xattrs;
+            }
+            if let Some(ignore_unison_files) = section.ignore_unison_files {
+                settings.ignore_unison_files = ignore_unison_files;
+            }
+            if let Some(ignore_snapshots) = section.ignore_snapshots {
+                settings.ignore_snapshots = ignore_snapshots;
+            }
+            if section.max_depth.is_some() {
+                settings.max_depth = section.max_depth;
+            }
+            if let Some(hold_down) = section.hold_down {
+                settings.hold_down = Some(Duration::from_secs(hold_down));
+            }
+            if let Some(collapse_after) = section.collapse_after {
+                settings.collapse_after = collapse_after;
+            }
+            if let Some(max_pending) = section.max_pending {
+                settings.max_pending = max_pending;
+            }
+            if let Some(storm_rate) = section.storm_rate {
+                settings.storm_rate = storm_rate;
+            }
+            if let Some(safety_scan) = section.safety_scan {
+                settings.safety_scan = Some(Duration::from_secs(safety_scan));
+            }
+            if let Some(wait_for_path) = section.wait_for_path {
+                settings.wait_for_path = wait_for_path;
+            }
+            if let Some(sample_contents) = section.sample_contents {
+                settings.sample_contents = sample_contents;
+            }
+        }
+        // Presets go first, for the user's own ignores to take back.
+        let mut presets = self.ignore_preset.clone();
+        let mut ignore = self.ignore.clone();
+        if let Some(section) = section {
+            let added = section.ignore_preset.iter();
+            presets.extend(added.filter(|preset| !self.ignore_preset.contains(preset)));
+            ignore.extend(section.ignore.iter().cloned());
+        }
+        settings.ignore = presets
+            .iter()
+            .flat_map(|preset| preset.globs())
+            .chain(ignore)
+            .collect();
+        settings
+    }
+
+    /// Reject settings no replica could be watched with.
+    pub fn validate(&self) -> Result<()> {
+        let roots = self.replica.iter().map(|section| section.path.as_path());
+        for root in [Path::new("")].into_iter().chain(roots) {
+            let settings = self.settings(root);
+            check_range(
+                "debounce-ms",
+                settings.debounce,
+                MIN_DEBOUNCE_TIMEOUT..=MAX_DEBOUNCE_TIMEOUT,
+                |duration| duration.as_millis(),
+            )?;
+            check_range(
+                "poll-interval",
+                settings.poll_interval,
+                MIN_POLL_INTERVAL..=MAX_POLL_INTERVAL,
+                |duration| duration.as_secs().into(),
+            )?;
+            check_range(
+                "fsevents-latency-ms",
+                settings.fsevents_latency,
+                Duration::ZERO..=MAX_FSEVENTS_LATENCY,
+                |duration| duration.as_millis(),
+            )?;
+            if let Some(hold_down) = settings.hold_down {
+                check_range(
+                    "hold-down",
+                    hold_down,
+                    MIN_HOLD_DOWN..=MAX_HOLD_DOWN,
+                    |duration| duration.as_secs().into(),
+                )?;
+            }
+            if let Some(safety_scan) = settings.safety_scan {
+                check_range(
+                    "safety-scan",
+                    safety_scan,
+                    MIN_SAFETY_SCAN..=MAX_SAFETY_SCAN,
+                    |duration| duration.as_secs().into(),
+                )?;
+            }
+            backend::select(&settings).map_err(|err| MonitorError::ConfigError(err.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+fn check_range(
+    name: &str,
+    value: Duration,
+    range: RangeInclusive<Duration>,
+    unit: impl Fn(Duration) -> u128,
+) -> Result<()> {
+    if range.contains(&value) {
+        Ok(())
+    } else {
+        Err(MonitorError::ConfigError(format!(
+            "{} {} is not in {}..={}",
+            name,
+            unit(value),
+            unit(*range.start()),
+            unit(*range.end())
+        )))
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = MonitorError;
+
+    fn from_str(text: &str) -> Result<Config> {
+        let config: Config =
+            toml::from_str(text).map_err(|err| MonitorError::ConfigError(err.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+fn globs(patterns: &[&str]) -> Vec<Glob> {
+    patterns
+        .iter()
+        .map(|pattern| pattern.parse().unwrap())
+        .collect()
+}
+
+#[test]
+fn test_parse() {
+    let config: Config = r#"
+        ignore = [".git"]
+        log-level = "debug"
+
+        [[replica]]
+        path = "/home/me/src"
+        debounce-ms = 2000
+        ignore = ["target", "**/*.tmp"]
+        ignore-regex = ['logs/\d{4}-\d{2}']
+        poll-fallback = true
+        gitignore = true
+        hardlinks = true
+        ignore-metadata = true
+        xattrs = "report"
+        ignore-snapshots = false
+        hold-down = 60
+        max-pending = 5000
+        storm-rate = 0
+        safety-scan = 600
+        wait-for-path = true
+
+        [[replica]]
+        path = "/home/me/src/notes"
+        debounce-ms = 10
+        backend = "poll"
+        poll-interval = 30
+        fsevents-latency-ms = 500
+        ignore-unison-files = false
+        max-depth = 2
+        collapse-after = 0
+        sample-contents = true
+    "#
+    .parse()
+    .unwrap();
+
+    assert_eq!(config.log_level, Some(LevelFilter::Debug));
+    assert_eq!(
+        config.settings(Path::new("/tmp")),
+        Settings {
+            ignore: globs(&[".git"]),
+            ..Settings::default()
+        }
+    );
+    assert_eq!(
+        config.settings(Path::new("/home/me/src/project")),
+        Settings {
+            debounce: Duration::from_secs(2),
+            backend: "auto".into(),
+            poll_interval: POLL_INTERVAL,
+            fsevents_latency: FSEVENTS_LATENCY,
+            poll_fallback: true,
+            ignore: globs(&[".git", "target", "**/*.tmp"]),
+            ignore_regex: vec![r"logs/\d{4}-\d{2}".parse().unwrap()],
+            gitignore: true,
+            hardlinks: true,
+            ignore_metadata: true,
+            xattrs: Xattrs::Report,
+            ignore_unison_files: true,
+            ignore_snapshots: false,
+            max_depth: None,
+            hold_down: Some(Duration::from_secs(60)),
+            collapse_after: COLLAPSE_AFTER,
+            max_pending: 5000,
+            storm_rate: 0,
+            safety_scan: Some(Duration::from_secs(600)),
+            wait_for_path: true,
+            sample_contents: false,
+            coarse_timestamps: false,
+            profile: None,
+        }
+    );
+    assert_eq!(
+        config.settings(Path::new("/home/me/src/notes")),
+        Settings {
+            debounce: Duration::from_millis(10),
+            backend: "poll".into(),
+            poll_interval: Duration::from_secs(30),
+            fsevents_latency: Duration::from_millis(500),
+            poll_fallback: false,
+            ignore: globs(&[".git"]),
+            ignore_regex: vec![],
+            gitignore: false,
+            hardlinks: false,
+            ignore_metadata: false,
+            xattrs: Xattrs::Auto,
+            ignore_unison_files: false,
+            ignore_snapshots: true,
+            max_depth: Some(2),
+            hold_down: None,
+            collapse_after: 0,
+            max_pending: MAX_PENDING,
+            storm_rate: STORM_RATE,
+            safety_scan: None,
+            wait_for_path: false,
+            sample_contents: true,
+            coarse_timestamps: false,
+            profile: None,
+        }
+    );
+    // Whole components only.
+    assert_eq!(
+        config.settings(Path::new("/home/me/src/notes2")).debounce,
+        Duration::from_secs(2)
+    );
+}
+
+#[test]
+fn test_ignore_preset() {
+    let config: Config = r#"
+        ignore-preset = ["os"]
+
+        [[replica]]
+        path = "/home/me/src"
+        ignore-preset = ["editors", "os"]
+    "#
+    .parse()
+    .unwrap();
+    let os = Preset::Os.patterns().len();
+    let editors = Preset::Editors.patterns().len();
+
+    assert_eq!(config.settings(Path::new("/tmp")).ignore.len(), os);
+    assert_eq!(
+        config.settings(Path::new("/home/me/src")).ignore.len(),
+        os + editors
+    );
+    // Ignores can take back what presets ignore.
+    let config: Config = "ignore-preset = [\"os\"]\nignore = [\"!keep/.DS_Store\"]"
+        .parse()
+        .unwrap();
+    let ignored = |path: &str| {
+        crate::filter::is_ignored(&config.settings(Path::new("/tmp")).ignore, Path::new(path))
+    };
+    assert!(ignored(".DS_Store"));
+    assert!(!ignored("keep/.DS_Store"));
+    assert!(matches!(
+        "ignore-preset = [\"ide\"]".parse::<Config>(),
+        Err(MonitorError::ConfigError(_))
+    ));
+}
+
+#[test]
+fn test_parse_invalid() {
+    let parse = |text: &str| text.parse::<Config>().unwrap_err();
+    assert!(matches!(
+        parse("debounce = 1"),
+        MonitorError::ConfigError(_)
+    ));
+    assert!(matches!(
+        parse("ignore = [\"[abc\"]"),
+        MonitorError::ConfigError(_)
+    ));
+    assert!(matches!(
+        parse("ignore-regex = [\"(\"]"),
+        MonitorError::ConfigError(_)
+    ));
+    assert!(matches!(
+        parse("log-level = \"loud\""),
+        MonitorError::ConfigError(_)
+    ));
+    assert!(matches!(
+        parse("debounce-ms = 0"),
+        MonitorError::ConfigError(_)
+    ));
+    assert!(matches!(
+        parse("[[replica]]\npath = \"/tmp\"\npoll-interval = 0"),
+        MonitorError::ConfigError(_)
+    ));
+    assert!(matches!(
+        parse("fsevents-latency-ms = 60000"),
+        MonitorError::ConfigError(_)
+    ));
+    assert!(matches!(
+        parse("hold-down = 0"),
+        MonitorError::ConfigError(_)
+    ));
+    assert!(matches!(
+        parse("[[replica]]\npath = \"/tmp\"\nbackend = \"carrier-pigeon\""),
+        MonitorError::ConfigError(_)
+    ));
+}
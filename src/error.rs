@@ -0,0 +1,80 @@
+//! Errors of the monitor, split by whether the session can go on after
+//! reporting them to unison.
+
+use std::io;
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, MonitorError>;
+
+#[derive(Debug, Error)]
+pub enum MonitorError {
+    /// Malformed input from unison, e.g. a command missing its arguments.
+    #[error("{0}")]
+    ProtocolError(String),
+    /// A path could not be watched.
+    #[error("{0}")]
+    WatchError(String),
+    /// The system ran out of watches, which no retry will fix.
+    #[error(
+        "{0}, raise the limit with `sysctl fs.inotify.max_user_watches=524288` \
+         or pass --poll-fallback to poll such replicas"
+    )]
+    WatchLimit(String),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+    /// Unison asked for something this monitor does not implement.
+    #[error("{0}")]
+    UnsupportedFeature(String),
+    /// The config file could not be parsed or holds invalid settings.
+    #[error("{0}")]
+    ConfigError(String),
+}
+
+impl MonitorError {
+    /// Whether the session has to end. Other errors are reported with `ERROR`
+    /// and the monitor keeps serving.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            MonitorError::IoError(_)
+                | MonitorError::UnsupportedFeature(_)
+                | MonitorError::ConfigError(_)
+        )
+    }
+}
+
+impl From<notify::Error> for MonitorError {
+    fn from(err: notify::Error) -> Self {
+        let limit = match &err.kind {
+            notify::ErrorKind::MaxFilesWatch => true,
+            #[cfg(target_os = "linux")]
+            notify::ErrorKind::Io(err) => err.raw_os_error() == Some(libc::ENOSPC),
+            _ => false,
+        };
+        if limit {
+            MonitorError::WatchLimit("Out of file watches".into())
+        } else {
+            MonitorError::WatchError(err.to_string())
+        }
+    }
+}
+
+#[test]
+fn test_is_fatal() {
+    assert!(!MonitorError::ProtocolError("Missing argument".into()).is_fatal());
+    assert!(!MonitorError::WatchError("No such file or directory".into()).is_fatal());
+    assert!(!MonitorError::WatchLimit("Out of file watches".into()).is_fatal());
+    assert!(MonitorError::IoError(io::ErrorKind::BrokenPipe.into()).is_fatal());
+    assert!(MonitorError::UnsupportedFeature("VERSION 2".into()).is_fatal());
+    assert!(MonitorError::ConfigError("Unknown field".into()).is_fatal());
+}
+
+#[test]
+fn test_watch_limit() {
+    let err = MonitorError::from(notify::Error::new(notify::ErrorKind::MaxFilesWatch));
+    assert!(matches!(err, MonitorError::WatchLimit(_)));
+    assert!(err.to_string().contains("fs.inotify.max_user_watches"));
+
+    let err = MonitorError::from(notify::Error::path_not_found());
+    assert!(matches!(err, MonitorError::WatchError(_)));
+}
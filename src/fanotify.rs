@@ -0,0 +1,498 @@
+//! fanotify on Linux: one mark for a whole filesystem rather than an inotify
+//! watch per directory, for replicas too large to watch one directory at a
+//! time. Events of the whole filesystem arrive, only those below watched
+//! paths are passed on. Marking a filesystem takes CAP_SYS_ADMIN, telling
+//! where an event happened CAP_DAC_READ_SEARCH, and the names of entries
+//! Linux 5.9 or later.
+
+use notify::event::{
+    CreateKind, DataChange, EventKind, Flag, MetadataKind, ModifyKind, RemoveKind, RenameMode,
+};
+use notify::{Config, EventHandler, RecursiveMode, WatcherKind};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// What each filesystem is marked for.
+const MASK: u64 = libc::FAN_CREATE
+    | libc::FAN_DELETE
+    | libc::FAN_MOVED_FROM
+    | libc::FAN_MOVED_TO
+    | libc::FAN_MODIFY
+    | libc::FAN_ATTRIB
+    | libc::FAN_DELETE_SELF
+    | libc::FAN_MOVE_SELF
+    | libc::FAN_ONDIR;
+
+/// Size of `fanotify_event_metadata`.
+const METADATA_LEN: usize = 24;
+
+/// How often the reading thread looks whether its watcher is gone, in
+/// milliseconds.
+const STOP_CHECK_MS: libc::c_int = 200;
+
+/// How many directories `Dirs` keeps the path of.
+const DIR_CACHE: usize = 4096;
+
+/// Id of a filesystem, as `statfs` and the events tell it.
+type Fsid = [libc::c_int; 2];
+
+/// An event as the kernel reports it: the directory by handle, and the name
+/// of the entry within it or `.` for the directory itself.
+#[derive(Debug, PartialEq)]
+struct Record {
+    mask: u64,
+    fsid: Fsid,
+    /// A `struct file_handle`, header included.
+    handle: Vec<u8>,
+    name: PathBuf,
+}
+
+/// The events in what one read returned. Events without a directory, as an
+/// overflow of the queue, come with an empty handle.
+fn parse(mut buf: &[u8]) -> Vec<Record> {
+    let u16_at = |buf: &[u8], at: usize| u16::from_ne_bytes([buf[at], buf[at + 1]]) as usize;
+    let u32_at = |buf: &[u8], at: usize| u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap());
+    let mut records = vec![];
+    while buf.len() >= METADATA_LEN {
+        let event_len = u32_at(buf, 0) as usize;
+        let metadata_len = u16_at(buf, 6);
+        if event_len < metadata_len || event_len > buf.len() || metadata_len < METADATA_LEN {
+            break;
+        }
+        let mut record = Record {
+            mask: u64::from_ne_bytes(buf[8..16].try_into().unwrap()),
+            fsid: [0, 0],
+            handle: vec![],
+            name: PathBuf::new(),
+        };
+        let info = &buf[metadata_len..event_len];
+        // Header, fsid and the head of the handle.
+        if buf[4] == libc::FANOTIFY_METADATA_VERSION && info.len() >= 20 {
+            let info_type = info[0];
+            let len = u16_at(info, 2).min(info.len());
+            let handle_end = 20 + u32_at(info, 12) as usize;
+            if handle_end <= len {
+                record.fsid = [u32_at(info, 4) as i32, u32_at(info, 8) as i32];
+                record.handle = info[12..handle_end].to_vec();
+                if info_type == libc::FAN_EVENT_INFO_TYPE_DFID_NAME {
+                    let name = info[handle_end..len].split(|b| *b == 0).next();
+                    record.name = OsStr::from_bytes(name.unwrap_or_default()).into();
+                }
+            }
+        }
+        records.push(record);
+        buf = &buf[event_len..];
+    }
+    records
+}
+
+fn kind(mask: u64) -> EventKind {
+    let dir = mask & libc::FAN_ONDIR != 0;
+    if mask & libc::FAN_CREATE != 0 {
+        EventKind::Create(if dir {
+            CreateKind::Folder
+        } else {
+            CreateKind::File
+        })
+    } else if mask & libc::FAN_DELETE != 0 {
+        EventKind::Remove(if dir {
+            RemoveKind::Folder
+        } else {
+            RemoveKind::File
+        })
+    } else if mask & libc::FAN_MOVED_FROM != 0 {
+        EventKind::Modify(ModifyKind::Name(RenameMode::From))
+    } else if mask & libc::FAN_MOVED_TO != 0 {
+        EventKind::Modify(ModifyKind::Name(RenameMode::To))
+    } else if mask & libc::FAN_DELETE_SELF != 0 {
+        EventKind::Remove(RemoveKind::Any)
+    } else if mask & libc::FAN_MOVE_SELF != 0 {
+        EventKind::Modify(ModifyKind::Name(RenameMode::Any))
+    } else if mask & libc::FAN_MODIFY != 0 {
+        EventKind::Modify(ModifyKind::Data(DataChange::Any))
+    } else if mask & libc::FAN_ATTRIB != 0 {
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any))
+    } else {
+        EventKind::Other
+    }
+}
+
+fn fsid(file: &File) -> io::Result<Fsid> {
+    // SAFETY: statfs is plain data, filled in by the call.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: two ints either way, fsid_t only keeps them private.
+    Ok(unsafe { std::mem::transmute::<libc::fsid_t, Fsid>(stat.f_fsid) })
+}
+
+/// Open what `handle` names on the filesystem of `mount`, as a path only.
+fn open_by_handle(mount: &File, handle: &[u8]) -> io::Result<OwnedFd> {
+    // The kernel reads the header of struct file_handle as ints.
+    let mut aligned = vec![0u32; handle.len().div_ceil(4)];
+    // SAFETY: `aligned` holds at least `handle.len()` bytes.
+    unsafe {
+        std::ptr::copy_nonoverlapping(handle.as_ptr(), aligned.as_mut_ptr().cast(), handle.len());
+    }
+    let fd = unsafe {
+        libc::open_by_handle_at(mount.as_raw_fd(), aligned.as_mut_ptr().cast(), libc::O_PATH)
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: a new descriptor nothing else owns.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// A marked filesystem.
+struct Filesystem {
+    /// A watched directory on it, which handles are opened against and the
+    /// mark is removed through.
+    dir: Arc<File>,
+    /// Watched paths on it.
+    roots: usize,
+}
+
+#[derive(Default)]
+struct State {
+    roots: HashMap<PathBuf, (RecursiveMode, Fsid)>,
+    filesystems: HashMap<Fsid, Filesystem>,
+}
+
+impl State {
+    fn is_watched(&self, path: &Path) -> bool {
+        // A lookup per ancestor rather than a look at every root.
+        path.ancestors()
+            .enumerate()
+            .any(|(depth, ancestor)| match self.roots.get(ancestor) {
+                Some((RecursiveMode::Recursive, _)) => true,
+                Some((RecursiveMode::NonRecursive, _)) => depth <= 1,
+                None => false,
+            })
+    }
+}
+
+/// The paths of directories events named by handle, so that further events
+/// in them take no syscalls. Forgotten all at once when full, or when a
+/// directory moved and took the paths below it along.
+#[derive(Default)]
+struct Dirs {
+    paths: HashMap<Fsid, HashMap<Vec<u8>, PathBuf>>,
+    len: usize,
+}
+
+impl Dirs {
+    fn clear(&mut self) {
+        self.paths.clear();
+        self.len = 0;
+    }
+
+    /// Where the entry of an event is, unless its directory is gone too.
+    fn resolve(&mut self, mount: &File, record: &Record) -> Option<PathBuf> {
+        let moved = libc::FAN_MOVED_FROM | libc::FAN_MOVED_TO;
+        if record.mask & libc::FAN_MOVE_SELF != 0
+            || record.mask & libc::FAN_ONDIR != 0 && record.mask & moved != 0
+        {
+            self.clear();
+        }
+        let cached = self
+            .paths
+            .get(&record.fsid)
+            .and_then(|paths| paths.get(&record.handle));
+        let dir = match cached {
+            Some(dir) => dir.clone(),
+            None => {
+                let fd = open_by_handle(mount, &record.handle).ok()?;
+                let dir = fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).ok()?;
+                if self.len >= DIR_CACHE {
+                    self.clear();
+                }
+                let paths = self.paths.entry(record.fsid).or_default();
+                paths.insert(record.handle.clone(), dir.clone());
+                self.len += 1;
+                dir
+            }
+        };
+        if record.name == Path::new(".") {
+            return Some(dir);
+        }
+        Some(dir.join(&record.name))
+    }
+}
+
+struct Shared {
+    fd: OwnedFd,
+    state: Mutex<State>,
+    stopped: AtomicBool,
+}
+
+fn mark(fd: &OwnedFd, flags: libc::c_uint, dir: &File) -> notify::Result<()> {
+    let flags = flags | libc::FAN_MARK_FILESYSTEM;
+    let marked = unsafe {
+        libc::fanotify_mark(
+            fd.as_raw_fd(),
+            flags,
+            MASK,
+            dir.as_raw_fd(),
+            std::ptr::null(),
+        )
+    };
+    if marked < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EPERM) {
+            return Err(notify::Error::generic(
+                "fanotify needs CAP_SYS_ADMIN to watch a filesystem",
+            ));
+        }
+        return Err(notify::Error::io(err));
+    }
+    Ok(())
+}
+
+/// Watches whole filesystems with one fanotify descriptor, passing on the
+/// events below the paths it was asked to watch.
+pub struct FanotifyWatcher {
+    shared: Arc<Shared>,
+}
+
+impl notify::Watcher for FanotifyWatcher {
+    fn new<F: EventHandler>(event_handler: F, _config: Config) -> notify::Result<Self> {
+        let flags = libc::FAN_CLASS_NOTIF
+            | libc::FAN_CLOEXEC
+            | libc::FAN_NONBLOCK
+            | libc::FAN_REPORT_DFID_NAME;
+        let event_flags = (libc::O_RDONLY | libc::O_LARGEFILE) as libc::c_uint;
+        let fd = unsafe { libc::fanotify_init(flags, event_flags) };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(libc::EINVAL) => {
+                    notify::Error::generic("fanotify needs Linux 5.9 or later to report names")
+                }
+                Some(libc::EPERM) => notify::Error::generic("fanotify needs CAP_SYS_ADMIN"),
+                _ => notify::Error::io(err),
+            });
+        }
+        let shared = Arc::new(Shared {
+            // SAFETY: a new descriptor nothing else owns.
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            state: Mutex::new(State::default()),
+            stopped: AtomicBool::new(false),
+        });
+        let reader = shared.clone();
+        thread::spawn(move || read_events(&reader, event_handler));
+        Ok(Self { shared })
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        let io_error = |err| notify::Error::io(err).add_path(path.to_owned());
+        let dir = File::open(path).map_err(io_error)?;
+        let fsid = fsid(&dir).map_err(io_error)?;
+        let mut state = self.shared.state.lock().unwrap();
+        if let Entry::Vacant(entry) = state.filesystems.entry(fsid) {
+            mark(&self.shared.fd, libc::FAN_MARK_ADD, &dir)?;
+            entry.insert(Filesystem {
+                dir: Arc::new(dir),
+                roots: 0,
+            });
+        }
+        if state
+            .roots
+            .insert(path.to_owned(), (recursive_mode, fsid))
+            .is_none()
+        {
+            state.filesystems.get_mut(&fsid).unwrap().roots += 1;
+        }
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        let fsid = match state.roots.remove(path) {
+            Some((_, fsid)) => fsid,
+            None => return Err(notify::Error::watch_not_found().add_path(path.to_owned())),
+        };
+        let filesystem = state.filesystems.get_mut(&fsid).unwrap();
+        filesystem.roots -= 1;
+        if filesystem.roots == 0 {
+            let filesystem = state.filesystems.remove(&fsid).unwrap();
+            mark(&self.shared.fd, libc::FAN_MARK_REMOVE, &filesystem.dir)?;
+        }
+        Ok(())
+    }
+
+    /// None of notify's kinds, which only tell per-directory and polling
+    /// backends from the rest here.
+    fn kind() -> WatcherKind {
+        WatcherKind::NullWatcher
+    }
+}
+
+impl Drop for FanotifyWatcher {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Pass on the events below watched paths until the watcher is dropped.
+fn read_events(shared: &Shared, mut handler: impl EventHandler) {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut dirs = Dirs::default();
+    while !shared.stopped.load(Ordering::Relaxed) {
+        let mut pollfd = libc::pollfd {
+            fd: shared.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pollfd, 1, STOP_CHECK_MS) } <= 0 {
+            continue;
+        }
+        let read = unsafe { libc::read(shared.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if read < 0 {
+            let err = io::Error::last_os_error();
+            if matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ) {
+                continue;
+            }
+            handler.handle_event(Err(notify::Error::io(err)));
+            return;
+        }
+        for record in parse(&buf[..read as usize]) {
+            if record.mask & libc::FAN_Q_OVERFLOW != 0 {
+                let event = notify::Event::new(EventKind::Other).set_flag(Flag::Rescan);
+                handler.handle_event(Ok(event));
+                continue;
+            }
+            // Events of a filesystem unmarked meanwhile may still be queued.
+            let mount = match shared.state.lock().unwrap().filesystems.get(&record.fsid) {
+                Some(filesystem) => filesystem.dir.clone(),
+                None => continue,
+            };
+            // Resolved without the state locked, watching waits for none
+            // of it.
+            let path = match dirs.resolve(&mount, &record) {
+                Some(path) => path,
+                None => continue,
+            };
+            if shared.state.lock().unwrap().is_watched(&path) {
+                handler.handle_event(Ok(notify::Event::new(kind(record.mask)).add_path(path)));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    let event = |mask: u64, info: &[u8]| {
+        let mut event = vec![];
+        event.extend((METADATA_LEN as u32 + info.len() as u32).to_ne_bytes());
+        event.push(libc::FANOTIFY_METADATA_VERSION);
+        event.push(0);
+        event.extend((METADATA_LEN as u16).to_ne_bytes());
+        event.extend(mask.to_ne_bytes());
+        event.extend(libc::FAN_NOFD.to_ne_bytes());
+        event.extend(1234i32.to_ne_bytes());
+        event.extend(info);
+        event
+    };
+    let mut info = vec![libc::FAN_EVENT_INFO_TYPE_DFID_NAME, 0];
+    info.extend(32u16.to_ne_bytes());
+    info.extend(7i32.to_ne_bytes());
+    info.extend(8i32.to_ne_bytes());
+    // Handle of 4 bytes, type 1.
+    info.extend(4u32.to_ne_bytes());
+    info.extend(1i32.to_ne_bytes());
+    info.extend([9, 9, 9, 9]);
+    // Name, padded.
+    info.extend(b"file\0\0\0\0");
+
+    let mut buf = event(libc::FAN_CREATE, &info);
+    buf.extend(event(libc::FAN_Q_OVERFLOW, &[]));
+    let records = parse(&buf);
+    assert_eq!(
+        records,
+        [
+            Record {
+                mask: libc::FAN_CREATE,
+                fsid: [7, 8],
+                handle: [&4u32.to_ne_bytes()[..], &1i32.to_ne_bytes(), &[9, 9, 9, 9]].concat(),
+                name: "file".into(),
+            },
+            Record {
+                mask: libc::FAN_Q_OVERFLOW,
+                fsid: [0, 0],
+                handle: vec![],
+                name: PathBuf::new(),
+            }
+        ]
+    );
+    assert_eq!(
+        kind(records[0].mask | libc::FAN_ONDIR),
+        EventKind::Create(CreateKind::Folder)
+    );
+    // Cut short, the rest is left for the next read.
+    assert_eq!(parse(&buf[..buf.len() - 1]).len(), 1);
+}
+
+#[test]
+fn test_is_watched() {
+    let mut state = State::default();
+    state
+        .roots
+        .insert("/a".into(), (RecursiveMode::Recursive, [0, 0]));
+    state
+        .roots
+        .insert("/b".into(), (RecursiveMode::NonRecursive, [0, 0]));
+    for path in ["/a", "/a/x/y", "/b", "/b/x"] {
+        assert!(state.is_watched(Path::new(path)), "{}", path);
+    }
+    for path in ["/", "/ab", "/b/x/y", "/c"] {
+        assert!(!state.is_watched(Path::new(path)), "{}", path);
+    }
+}
+
+#[test]
+fn test_fanotify() {
+    use notify::Watcher;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    fs::create_dir_all(base.join("watched")).unwrap();
+    fs::create_dir_all(base.join("other")).unwrap();
+    let (tx, rx) = mpsc::channel();
+    let watched = FanotifyWatcher::new(tx, Config::default()).and_then(|mut watcher| {
+        watcher.watch(&base.join("watched"), RecursiveMode::Recursive)?;
+        Ok(watcher)
+    });
+    let mut watcher = match watched {
+        Ok(watcher) => watcher,
+        // Not privileged, or a filesystem fanotify cannot mark.
+        Err(err) => {
+            eprintln!("Skipping, cannot use fanotify: {}", err);
+            return;
+        }
+    };
+
+    fs::write(base.join("other/file"), b"").unwrap();
+    fs::write(base.join("watched/file"), b"").unwrap();
+    let event = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!(event.kind, EventKind::Create(CreateKind::File));
+    assert_eq!(event.paths, [base.join("watched/file")]);
+
+    watcher.unwatch(&base.join("watched")).unwrap();
+    assert!(watcher.unwatch(&base.join("watched")).is_err());
+}
@@ -0,0 +1,522 @@
+//! File Event Notification on illumos and Solaris, which notify has no
+//! watcher for. Every file and directory is associated with an event port,
+//! and associated again once an event fired for it. A directory only tells
+//! that it changed, the entries that came or went are found by listing it
+//! again. Filesystems FEN does not support, NFS among them, and trees with
+//! more entries than a port may hold are polled instead.
+
+use log::{info, warn};
+use notify::event::{
+    CreateKind, DataChange, EventKind, Flag, MetadataKind, ModifyKind, RemoveKind, RenameMode,
+};
+use notify::{Config, EventHandler, PollWatcher, RecursiveMode, WatcherKind};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// From <sys/port.h>, which libc leaves out.
+const FILE_MODIFIED: c_int = 0x2;
+const FILE_ATTRIB: c_int = 0x4;
+const FILE_DELETE: c_int = 0x10;
+const FILE_RENAME_TO: c_int = 0x20;
+const FILE_RENAME_FROM: c_int = 0x40;
+const FILE_TRUNC: c_int = 0x10_0000;
+const FILE_NOFOLLOW: c_int = 0x1000_0000;
+const UNMOUNTED: c_int = 0x2000_0000;
+const MOUNTEDOVER: c_int = 0x4000_0000;
+
+/// What each entry is associated for.
+const EVENTS: c_int = FILE_MODIFIED | FILE_ATTRIB | FILE_TRUNC | FILE_NOFOLLOW;
+
+/// `file_obj_t`: an entry and its times when it was last seen, an event
+/// fires right away if they differ by the time it is associated.
+#[repr(C)]
+struct FileObj {
+    atime: libc::timespec,
+    mtime: libc::timespec,
+    ctime: libc::timespec,
+    pad: [libc::uintptr_t; 3],
+    name: *const c_char,
+}
+
+/// An associated file or directory.
+struct Entry {
+    /// At a fixed address, which is what events name.
+    obj: Box<FileObj>,
+    /// What `obj` names it by.
+    name: CString,
+    /// The names in a directory whose entries are watched too, to tell
+    /// what came and went.
+    listing: Option<HashSet<OsString>>,
+    /// How deep below it each watched root that reaches it watches, `None`
+    /// for all the way down. Roots may nest, it is watched as long as one
+    /// of them is.
+    depths: HashMap<PathBuf, Option<usize>>,
+}
+
+impl Entry {
+    /// Whether a root watches the entries of the directory too.
+    fn lists(&self) -> bool {
+        self.depths.values().any(|depth| *depth != Some(0))
+    }
+}
+
+fn timespec(sec: i64, nsec: i64) -> libc::timespec {
+    libc::timespec {
+        tv_sec: sec as libc::time_t,
+        tv_nsec: nsec as libc::c_long,
+    }
+}
+
+fn list(dir: &Path) -> HashSet<OsString> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.file_name())
+        .collect()
+}
+
+/// Whether an association failed for good, rather than for the entry only.
+fn is_unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::ENOTSUP | libc::EOPNOTSUPP | libc::EAGAIN)
+    )
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<PathBuf, Entry>,
+    /// Paths by the address of their `FileObj`.
+    objects: HashMap<usize, PathBuf>,
+}
+
+impl State {
+    /// Associate `path` (again) with its current times.
+    fn associate(&mut self, port: RawFd, path: &Path) -> io::Result<()> {
+        let meta = fs::symlink_metadata(path)?;
+        let entry = self.entries.get_mut(path).unwrap();
+        entry.obj.atime = timespec(meta.atime(), meta.atime_nsec());
+        entry.obj.mtime = timespec(meta.mtime(), meta.mtime_nsec());
+        entry.obj.ctime = timespec(meta.ctime(), meta.ctime_nsec());
+        entry.obj.name = entry.name.as_ptr();
+        let object = &*entry.obj as *const FileObj as libc::uintptr_t;
+        let associated = unsafe {
+            libc::port_associate(
+                port,
+                libc::PORT_SOURCE_FILE,
+                object,
+                EVENTS,
+                std::ptr::null_mut(),
+            )
+        };
+        if associated != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Watch `path` for `root`, and below it as deep as `depth` says:
+    /// `None` for all the way down, `Some(0)` for the entry only. Entries
+    /// watched for another root already are watched deeper if need be.
+    fn add(
+        &mut self,
+        port: RawFd,
+        root: &Path,
+        path: &Path,
+        depth: Option<usize>,
+    ) -> io::Result<()> {
+        if !self.entries.contains_key(path) {
+            let name = CString::new(path.as_os_str().as_bytes())?;
+            let obj = Box::new(FileObj {
+                atime: timespec(0, 0),
+                mtime: timespec(0, 0),
+                ctime: timespec(0, 0),
+                pad: [0; 3],
+                name: name.as_ptr(),
+            });
+            self.objects
+                .insert(&*obj as *const FileObj as usize, path.to_owned());
+            self.entries.insert(
+                path.to_owned(),
+                Entry {
+                    obj,
+                    name,
+                    listing: None,
+                    depths: HashMap::new(),
+                },
+            );
+            if let Err(err) = self.associate(port, path) {
+                self.forget(port, path);
+                return Err(err);
+            }
+        }
+        let entry = self.entries.get_mut(path).unwrap();
+        entry.depths.insert(root.to_owned(), depth);
+        if depth == Some(0) || !path.is_dir() || path.is_symlink() {
+            return Ok(());
+        }
+        // Listed after the association, a change in between fires it.
+        let listing = entry.listing.take().unwrap_or_else(|| list(path));
+        for name in &listing {
+            let child = path.join(name);
+            match self.add(port, root, &child, depth.map(|depth| depth - 1)) {
+                Err(err) if is_unsupported(&err) => {
+                    self.entries.get_mut(path).unwrap().listing = Some(listing);
+                    return Err(err);
+                }
+                // Gone already, or not ours to read.
+                _ => {}
+            }
+        }
+        self.entries.get_mut(path).unwrap().listing = Some(listing);
+        Ok(())
+    }
+
+    /// Stop watching `path` and below it for `root`, and whatever no other
+    /// root watches any more with it.
+    fn release(&mut self, port: RawFd, root: &Path, path: &Path) {
+        let entry = match self.entries.get_mut(path) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if entry.depths.remove(root).is_none() {
+            return;
+        }
+        let listing: Vec<OsString> = entry.listing.iter().flatten().cloned().collect();
+        for name in listing {
+            self.release(port, root, &path.join(name));
+        }
+        let entry = self.entries.get_mut(path).unwrap();
+        if entry.depths.is_empty() {
+            // What is below and still watched is so for other roots.
+            self.dissociate(port, path);
+        } else if !entry.lists() {
+            entry.listing = None;
+        }
+    }
+
+    /// Stop watching `path` itself.
+    fn dissociate(&mut self, port: RawFd, path: &Path) -> Option<Entry> {
+        let entry = self.entries.remove(path)?;
+        let object = &*entry.obj as *const FileObj as usize;
+        self.objects.remove(&object);
+        // Not associated any more once its event fired, that fails then.
+        unsafe { libc::port_dissociate(port, libc::PORT_SOURCE_FILE, object) };
+        Some(entry)
+    }
+
+    /// Stop watching `path` and all watched below it, for every root.
+    fn forget(&mut self, port: RawFd, path: &Path) {
+        let entry = match self.dissociate(port, path) {
+            Some(entry) => entry,
+            None => return,
+        };
+        for name in entry.listing.into_iter().flatten() {
+            self.forget(port, &path.join(name));
+        }
+    }
+
+    /// The events for what fired for `object`, watching it on.
+    fn fired(&mut self, port: RawFd, object: usize, flags: c_int) -> Vec<notify::Event> {
+        let path = match self.objects.get(&object) {
+            Some(path) => path.clone(),
+            // Forgotten meanwhile.
+            None => return vec![],
+        };
+        let event = |kind| notify::Event::new(kind).add_path(path.clone());
+        if flags & (UNMOUNTED | MOUNTEDOVER) != 0 {
+            self.forget(port, &path);
+            return vec![event(EventKind::Other).set_flag(Flag::Rescan)];
+        }
+        if flags & (FILE_DELETE | FILE_RENAME_FROM) != 0 {
+            self.forget(port, &path);
+            return vec![event(EventKind::Remove(RemoveKind::Any))];
+        }
+        if self.associate(port, &path).is_err() {
+            self.forget(port, &path);
+            return vec![event(EventKind::Remove(RemoveKind::Any))];
+        }
+        let entry = &self.entries[&path];
+        let depths: Vec<(PathBuf, Option<usize>)> = entry
+            .depths
+            .iter()
+            .filter(|(_, depth)| **depth != Some(0))
+            .map(|(root, depth)| (root.clone(), depth.map(|depth| depth - 1)))
+            .collect();
+        // A listed directory that changed tells through its entries.
+        let old = match &entry.listing {
+            Some(old) if flags & FILE_MODIFIED != 0 => old.clone(),
+            _ if flags & FILE_RENAME_TO != 0 => {
+                return vec![event(EventKind::Modify(ModifyKind::Name(RenameMode::To)))]
+            }
+            _ if flags & (FILE_MODIFIED | FILE_TRUNC) != 0 => {
+                return vec![event(EventKind::Modify(ModifyKind::Data(DataChange::Any)))]
+            }
+            _ => {
+                return vec![event(EventKind::Modify(ModifyKind::Metadata(
+                    MetadataKind::Any,
+                )))]
+            }
+        };
+        let new = list(&path);
+        let mut events = vec![];
+        for name in new.difference(&old) {
+            let child = path.join(name);
+            for (root, depth) in &depths {
+                let _ = self.add(port, root, &child, *depth);
+            }
+            events.push(notify::Event::new(EventKind::Create(CreateKind::Any)).add_path(child));
+        }
+        for name in old.difference(&new) {
+            let child = path.join(name);
+            self.forget(port, &child);
+            events.push(notify::Event::new(EventKind::Remove(RemoveKind::Any)).add_path(child));
+        }
+        self.entries.get_mut(&path).unwrap().listing = Some(new);
+        events
+    }
+}
+
+// SAFETY: the pointers in `FileObj` point into the `CString` next to it,
+// which moves along.
+unsafe impl Send for State {}
+
+struct Shared {
+    port: OwnedFd,
+    state: Mutex<State>,
+    handler: Mutex<Box<dyn EventHandler>>,
+    stopped: AtomicBool,
+}
+
+/// Pass on the events of the port until the watcher is dropped.
+fn read_events(shared: &Shared) {
+    let port = shared.port.as_raw_fd();
+    while !shared.stopped.load(Ordering::Relaxed) {
+        let mut event: libc::port_event = unsafe { std::mem::zeroed() };
+        // Woken now and then to see whether to stop.
+        let mut timeout = timespec(0, 250_000_000);
+        if unsafe { libc::port_get(port, &mut event, &mut timeout) } != 0 {
+            let err = io::Error::last_os_error();
+            if matches!(err.raw_os_error(), Some(libc::ETIME | libc::EINTR)) {
+                continue;
+            }
+            shared
+                .handler
+                .lock()
+                .unwrap()
+                .handle_event(Err(notify::Error::io(err)));
+            return;
+        }
+        if c_int::from(event.portev_source) != libc::PORT_SOURCE_FILE {
+            continue;
+        }
+        let events =
+            shared
+                .state
+                .lock()
+                .unwrap()
+                .fired(port, event.portev_object, event.portev_events);
+        let mut handler = shared.handler.lock().unwrap();
+        for event in events {
+            handler.handle_event(Ok(event));
+        }
+    }
+}
+
+/// Watches files and directories through an event port, polling what it
+/// cannot watch.
+pub struct FenWatcher {
+    shared: Arc<Shared>,
+    roots: HashMap<PathBuf, RecursiveMode>,
+    /// For filesystems FEN does not support, with the poll interval of
+    /// `config`.
+    config: Config,
+    poll: Option<PollWatcher>,
+    polled: HashSet<PathBuf>,
+}
+
+impl FenWatcher {
+    fn poll(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        if self.poll.is_none() {
+            let shared = self.shared.clone();
+            let forward = move |event: notify::Result<notify::Event>| {
+                shared.handler.lock().unwrap().handle_event(event);
+            };
+            self.poll = Some(notify::Watcher::new(forward, self.config)?);
+        }
+        notify::Watcher::watch(self.poll.as_mut().unwrap(), path, recursive_mode)?;
+        self.polled.insert(path.to_owned());
+        Ok(())
+    }
+}
+
+impl notify::Watcher for FenWatcher {
+    fn new<F: EventHandler>(event_handler: F, config: Config) -> notify::Result<Self> {
+        let port = unsafe { libc::port_create() };
+        if port < 0 {
+            return Err(notify::Error::io(io::Error::last_os_error()));
+        }
+        let shared = Arc::new(Shared {
+            // SAFETY: a new descriptor nothing else owns.
+            port: unsafe { OwnedFd::from_raw_fd(port) },
+            state: Mutex::new(State::default()),
+            handler: Mutex::new(Box::new(event_handler)),
+            stopped: AtomicBool::new(false),
+        });
+        let reader = shared.clone();
+        thread::Builder::new()
+            .name("fen".into())
+            .spawn(move || read_events(&reader))
+            .map_err(notify::Error::io)?;
+        Ok(Self {
+            shared,
+            roots: HashMap::new(),
+            config,
+            poll: None,
+            polled: HashSet::new(),
+        })
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        let _ = self.unwatch(path);
+        let port = self.shared.port.as_raw_fd();
+        let depth = match recursive_mode {
+            RecursiveMode::Recursive => None,
+            RecursiveMode::NonRecursive => Some(1),
+        };
+        let added = self
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .add(port, path, path, depth);
+        match added {
+            Ok(()) => {}
+            Err(err) if is_unsupported(&err) => {
+                self.shared.state.lock().unwrap().release(port, path, path);
+                if err.raw_os_error() == Some(libc::EAGAIN) {
+                    warn!(
+                        "{} holds more entries than an event port may, polling it. \
+                         Raise process.max-port-events to watch all of it",
+                        path.display()
+                    );
+                } else {
+                    info!("FEN cannot watch {}, polling it", path.display());
+                }
+                return self.poll(path, recursive_mode);
+            }
+            Err(err) => return Err(notify::Error::io(err).add_path(path.to_owned())),
+        }
+        self.roots.insert(path.to_owned(), recursive_mode);
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        if self.polled.remove(path) {
+            if let Some(poll) = &mut self.poll {
+                notify::Watcher::unwatch(poll, path)?;
+            }
+            return Ok(());
+        }
+        if self.roots.remove(path).is_none() {
+            return Err(notify::Error::watch_not_found().add_path(path.to_owned()));
+        }
+        let port = self.shared.port.as_raw_fd();
+        self.shared.state.lock().unwrap().release(port, path, path);
+        Ok(())
+    }
+
+    /// None of notify's kinds, which only tell per-directory and polling
+    /// backends from the rest here.
+    fn kind() -> WatcherKind {
+        WatcherKind::NullWatcher
+    }
+}
+
+impl Drop for FenWatcher {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn test_fen() {
+    use notify::Watcher;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    fs::create_dir_all(base.join("dir")).unwrap();
+    fs::write(base.join("dir/file"), b"").unwrap();
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = FenWatcher::new(tx, Config::default()).unwrap();
+    watcher.watch(base, RecursiveMode::Recursive).unwrap();
+
+    let next = || rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    fs::write(base.join("dir/new"), b"").unwrap();
+    let event = next();
+    assert_eq!(event.kind, EventKind::Create(CreateKind::Any));
+    assert_eq!(event.paths, [base.join("dir/new")]);
+    fs::write(base.join("dir/file"), b"changed").unwrap();
+    let event = next();
+    assert_eq!(event.paths, [base.join("dir/file")]);
+
+    watcher.unwatch(base).unwrap();
+    assert!(watcher.unwatch(base).is_err());
+}
+
+#[test]
+fn test_nested_roots() {
+    use notify::Watcher;
+    use std::sync::mpsc;
+
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    fs::create_dir_all(base.join("b/c/d")).unwrap();
+    let (tx, _rx) = mpsc::channel();
+    let mut watcher = FenWatcher::new(tx, Config::default()).unwrap();
+    let watched = |watcher: &FenWatcher, path: &str| {
+        let state = watcher.shared.state.lock().unwrap();
+        state
+            .entries
+            .get(&base.join(path))
+            .map(|entry| entry.listing.is_some())
+    };
+
+    watcher.watch(base, RecursiveMode::NonRecursive).unwrap();
+    watcher
+        .watch(&base.join("b"), RecursiveMode::NonRecursive)
+        .unwrap();
+    assert_eq!(watched(&watcher, "b"), Some(true));
+    assert_eq!(watched(&watcher, "b/c"), Some(false));
+    assert_eq!(watched(&watcher, "b/c/d"), None);
+
+    // The first watch keeps the directory as one of its entries.
+    watcher.unwatch(&base.join("b")).unwrap();
+    assert_eq!(watched(&watcher, "b"), Some(false));
+    assert_eq!(watched(&watcher, "b/c"), None);
+
+    // Watched deeper than the first watch does.
+    watcher
+        .watch(&base.join("b"), RecursiveMode::Recursive)
+        .unwrap();
+    assert_eq!(watched(&watcher, "b/c/d"), Some(true));
+    watcher.unwatch(base).unwrap();
+    assert_eq!(watched(&watcher, ""), None);
+    assert_eq!(watched(&watcher, "b"), Some(true));
+
+    watcher.unwatch(&base.join("b")).unwrap();
+    assert!(watcher.shared.state.lock().unwrap().entries.is_empty());
+}
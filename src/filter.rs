@@ -0,0 +1,425 @@
+//! Patterns selecting the changes not to report, matched against paths
+//! relative to the replica root.
+
+use regex::Regex;
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// A glob anchored at the replica root, matching a path if it matches the
+/// path or one of its parents, so everything below a match is ignored too.
+/// `*` and `?` stay within a component, `**` spans any number of them and
+/// `[...]` is a character class, `[!...]` a negated one. A pattern without
+/// any of these is a plain path. A leading `!` negates the glob, it then
+/// takes back what an earlier one ignored.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Glob {
+    pattern: String,
+    regex: Regex,
+    negated: bool,
+}
+
+/// Whether `globs` ignore `path`: the last one matching it decides, so
+/// `["build", "!build/config.json"]` ignores the build directory but for
+/// one file in it.
+pub fn is_ignored(globs: &[Glob], path: &Path) -> bool {
+    globs
+        .iter()
+        .rev()
+        .find(|glob| glob.is_match(path))
+        .is_some_and(|glob| !glob.negated)
+}
+
+/// The path with `/` between its components, as patterns are written.
+fn slashed(path: &Path) -> String {
+    let components: Vec<_> = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    components.join("/")
+}
+
+/// Translate a glob into the body of a regex.
+pub(crate) fn translate(pattern: &str) -> Result<String, String> {
+    let mut regex = String::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let component_start = regex.is_empty() || regex.ends_with('/');
+                if component_start && chars.peek() == Some(&'/') {
+                    // Zero or more leading components.
+                    chars.next();
+                    regex.push_str("(?:[^/]*/)*");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let mut class = String::from("[");
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    class.push('^');
+                }
+                let mut closed = false;
+                let mut first = true;
+                for c in chars.by_ref() {
+                    match c {
+                        ']' if !first => {
+                            closed = true;
+                            break;
+                        }
+                        '-' if !first => class.push('-'),
+                        c => class.push_str(&regex::escape(&c.to_string())),
+                    }
+                    first = false;
+                }
+                if !closed {
+                    return Err(format!("{}: unclosed [", pattern));
+                }
+                class.push(']');
+                regex.push_str(&class);
+            }
+            '\\' => match chars.next() {
+                Some(c) => regex.push_str(&regex::escape(&c.to_string())),
+                None => return Err(format!("{}: trailing \\", pattern)),
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    Ok(regex)
+}
+
+impl Glob {
+    /// Whether `path`, relative to the replica root, is at or below a match.
+    pub fn is_match(&self, path: &Path) -> bool {
+        self.regex.is_match(&slashed(path))
+    }
+
+    pub fn is_negated(&self) -> bool {
+        self.negated
+    }
+
+    /// The leading components free of wildcards, under which everything the
+    /// glob matches lies.
+    pub fn base(&self) -> PathBuf {
+        match self.pattern.find(['*', '?', '[', '\\']) {
+            None => self.pattern.clone().into(),
+            Some(end) => match self.pattern[..end].rfind('/') {
+                Some(idx) => self.pattern[..idx].into(),
+                None => PathBuf::new(),
+            },
+        }
+    }
+}
+
+impl FromStr for Glob {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negated, pattern) = match s.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, s),
+        };
+        let pattern = pattern.trim_matches('/');
+        if pattern.is_empty() {
+            return Err(format!("{} would ignore the whole replica", s));
+        }
+        let regex = format!("^{}(?:/|$)", translate(pattern)?);
+        Ok(Glob {
+            pattern: pattern.to_owned(),
+            regex: Regex::new(&regex).map_err(|err| format!("{}: {}", s, err))?,
+            negated,
+        })
+    }
+}
+
+impl TryFrom<String> for Glob {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl PartialEq for Glob {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern && self.negated == other.negated
+    }
+}
+
+impl Eq for Glob {}
+
+impl fmt::Display for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.negated {
+            f.write_str("!")?;
+        }
+        f.write_str(&self.pattern)
+    }
+}
+
+impl fmt::Debug for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+/// A regular expression that has to match all of a path relative to the
+/// replica root, with `/` between components, or one of its parents.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PathRegex {
+    pattern: String,
+    regex: Regex,
+}
+
+impl PathRegex {
+    /// Whether `path`, relative to the replica root, is at or below a match.
+    pub fn is_match(&self, path: &Path) -> bool {
+        self.regex.is_match(&slashed(path))
+    }
+}
+
+impl FromStr for PathRegex {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let regex = format!("^(?:{})(?:/|$)", s);
+        Ok(PathRegex {
+            pattern: s.to_owned(),
+            regex: Regex::new(&regex).map_err(|err| format!("{}: {}", s, err))?,
+        })
+    }
+}
+
+impl TryFrom<String> for PathRegex {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl PartialEq for PathRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl Eq for PathRegex {}
+
+impl fmt::Display for PathRegex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl fmt::Debug for PathRegex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.pattern)
+    }
+}
+
+/// Read-only snapshots ZFS, Btrfs tools like snapper and NetApp filers expose
+/// within the trees they snapshot, each holding a full copy of the tree.
+pub const SNAPSHOT_DIRS: [&str; 3] = [".zfs", ".snapshot", ".snapshots"];
+
+/// Whether `path`, relative to the replica root, lies in a snapshot
+/// directory.
+pub fn in_snapshot(path: &Path) -> bool {
+    path.components().any(|component| match component {
+        Component::Normal(name) => SNAPSHOT_DIRS.iter().any(|dir| name == *dir),
+        _ => false,
+    })
+}
+
+/// Curated ignores for noise nobody wants synced on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    /// Swap, backup and lock files of Vim, Emacs and JetBrains IDEs.
+    Editors,
+    /// Metadata macOS and Windows drop into directories.
+    Os,
+}
+
+impl Preset {
+    pub fn patterns(self) -> &'static [&'static str] {
+        match self {
+            Preset::Editors => &[
+                "**/.*.sw[a-p]",
+                "**/*~",
+                // Vim checks whether it may write a directory with this.
+                "**/4913",
+                "**/#*#",
+                "**/.#*",
+                "**/*___jb_tmp___",
+                "**/*___jb_old___",
+            ],
+            Preset::Os => &[
+                "**/.DS_Store",
+                "**/._*",
+                "**/.Spotlight-V100",
+                "**/.Trashes",
+                "**/.fseventsd",
+                "**/Thumbs.db",
+                "**/ehthumbs.db",
+                "**/desktop.ini",
+            ],
+        }
+    }
+
+    pub fn globs(self) -> impl Iterator<Item = Glob> {
+        self.patterns()
+            .iter()
+            .map(|pattern| pattern.parse().expect("valid preset pattern"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        pattern.parse::<Glob>().unwrap().is_match(Path::new(path))
+    }
+
+    #[test]
+    fn test_plain_path() {
+        assert!(matches("target", "target"));
+        assert!(matches("target", "target/debug/build"));
+        assert!(matches("a/.git/", "a/.git/HEAD"));
+        assert!(!matches("target", "targets"));
+        assert!(!matches("target", "a/target"));
+        assert!(!matches("a.b", "axb"));
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*.tmp", "a.tmp"));
+        assert!(matches("*.tmp", "a.tmp/b"));
+        assert!(!matches("*.tmp", "a/b.tmp"));
+        assert!(matches("**/*.tmp", "a/b/c.tmp"));
+        assert!(matches("**/*.tmp", "c.tmp"));
+        assert!(matches("**/node_modules", "web/node_modules/x/y.js"));
+        assert!(matches("build/**/*.o", "build/a/b/c.o"));
+        assert!(matches("build/**/*.o", "build/c.o"));
+        assert!(matches("log-??", "log-12/today"));
+        assert!(!matches("log-??", "log-123"));
+        assert!(matches("[#.]*[#~]", "#notes#"));
+        assert!(matches("[!.]*", "src"));
+        assert!(!matches("[!.]*", ".git"));
+        assert!(matches("a[0-9]", "a7"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!("[abc".parse::<Glob>().is_err());
+        assert!("abc\\".parse::<Glob>().is_err());
+        assert!("/".parse::<Glob>().is_err());
+    }
+
+    #[test]
+    fn test_negated() {
+        let ignored = |patterns: &[&str], path: &str| {
+            let globs: Vec<Glob> = patterns.iter().map(|p| p.parse().unwrap()).collect();
+            is_ignored(&globs, Path::new(path))
+        };
+        let patterns = ["build", "!build/config.json"];
+        assert!(ignored(&patterns, "build"));
+        assert!(ignored(&patterns, "build/out.o"));
+        assert!(!ignored(&patterns, "build/config.json"));
+        assert!(!ignored(&patterns, "src"));
+        // The last match wins, whatever came before.
+        let patterns = ["!**/*.log", "**/*.log", "!keep.log"];
+        assert!(ignored(&patterns, "a/b.log"));
+        assert!(!ignored(&patterns, "keep.log"));
+        assert!(!ignored(&["!keep.log"], "keep.log"));
+        // Escaped, it is a file name starting with `!`.
+        assert!(ignored(&["\\!important"], "!important"));
+        assert!("!".parse::<Glob>().is_err());
+        let glob: Glob = "!/build/config.json".parse().unwrap();
+        assert!(glob.is_negated());
+        assert_eq!(glob.to_string(), "!build/config.json");
+        assert_eq!(glob.base(), Path::new("build/config.json"));
+    }
+
+    #[test]
+    fn test_regex() {
+        let matches = |pattern: &str, path: &str| {
+            pattern
+                .parse::<PathRegex>()
+                .unwrap()
+                .is_match(Path::new(path))
+        };
+        assert!(matches(r"logs/\d{4}-\d{2}-\d{2}", "logs/2024-01-31"));
+        assert!(matches(
+            r"logs/\d{4}-\d{2}-\d{2}",
+            "logs/2024-01-31/app.log"
+        ));
+        assert!(!matches(r"logs/\d{4}-\d{2}-\d{2}", "logs/2024-01-311"));
+        assert!(!matches(r"logs/\d{4}-\d{2}-\d{2}", "old/logs/2024-01-31"));
+        assert!(matches(r".*\.(bak|orig)", "src/main.rs.orig"));
+        assert!(matches("a|b", "b/c"));
+        assert!(!matches("a|b", "ab"));
+        assert!("(".parse::<PathRegex>().is_err());
+    }
+
+    #[test]
+    fn test_in_snapshot() {
+        assert!(in_snapshot(Path::new(".zfs/snapshot/daily/a")));
+        assert!(in_snapshot(Path::new("home/.snapshot")));
+        assert!(in_snapshot(Path::new("data/.snapshots/1/snapshot/b")));
+        assert!(!in_snapshot(Path::new("data/snapshots/b")));
+        assert!(!in_snapshot(Path::new("a/.zfsrc")));
+        assert!(!in_snapshot(Path::new("")));
+    }
+
+    #[test]
+    fn test_presets() {
+        let ignored =
+            |preset: Preset, path: &str| preset.globs().any(|glob| glob.is_match(Path::new(path)));
+        for path in [
+            "src/.main.rs.swp",
+            "src/main.rs~",
+            "src/4913",
+            "notes/#todo.org#",
+            "notes/.#todo.org",
+            "src/Main.java___jb_tmp___",
+        ] {
+            assert!(ignored(Preset::Editors, path), "{}", path);
+        }
+        for path in [".DS_Store", "photos/Thumbs.db", "docs/._report.pdf"] {
+            assert!(ignored(Preset::Os, path), "{}", path);
+        }
+        for path in [
+            "src/main.rs",
+            "src/swap.rs",
+            "notes/todo.org",
+            "photos/a.jpg",
+        ] {
+            assert!(!ignored(Preset::Editors, path), "{}", path);
+            assert!(!ignored(Preset::Os, path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_base() {
+        let base = |pattern: &str| pattern.parse::<Glob>().unwrap().base();
+        assert_eq!(base("target"), Path::new("target"));
+        assert_eq!(base("build/**/*.o"), Path::new("build"));
+        assert_eq!(base("a/b*"), Path::new("a"));
+        assert_eq!(base("*.tmp"), Path::new(""));
+    }
+}
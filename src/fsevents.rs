@@ -0,0 +1,429 @@
+//! FSEvents on macOS with a latency of our choosing. notify's watcher asks
+//! for events on single files too, but always with no latency, so every
+//! change wakes the monitor on its own. With a latency, FSEvents collects
+//! the changes of that long into one callback, the first one after a quiet
+//! spell still arrives right away.
+
+use fsevent_sys as fs;
+use fsevent_sys::core_foundation as cf;
+use notify::event::{
+    CreateKind, DataChange, EventKind, Flag, MetadataKind, ModifyKind, RemoveKind, RenameMode,
+};
+use notify::{Config, EventHandler, RecursiveMode, WatcherKind};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::os::raw::{c_char, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// `kCFRunLoopEntry`, a run loop starting to run.
+const RUN_LOOP_ENTRY: usize = 1;
+
+#[repr(C)]
+struct CFRunLoopObserverContext {
+    version: cf::CFIndex,
+    info: *mut c_void,
+    retain: Option<extern "C" fn(*const c_void) -> *const c_void>,
+    release: Option<extern "C" fn(*const c_void)>,
+    copy_description: Option<extern "C" fn(*const c_void) -> cf::CFStringRef>,
+}
+
+extern "C" {
+    fn CFRetain(cf: cf::CFRef) -> cf::CFRef;
+    fn CFRunLoopObserverCreate(
+        allocator: cf::CFAllocatorRef,
+        activities: usize,
+        repeats: cf::Boolean,
+        order: cf::CFIndex,
+        callout: extern "C" fn(cf::CFRef, usize, *mut c_void),
+        context: *mut CFRunLoopObserverContext,
+    ) -> cf::CFRef;
+    fn CFRunLoopAddObserver(runloop: cf::CFRunLoopRef, observer: cf::CFRef, mode: cf::CFStringRef);
+}
+
+/// Events on single files, and the first change after a quiet spell passed
+/// on without waiting for the latency.
+const FLAGS: fs::FSEventStreamCreateFlags =
+    fs::kFSEventStreamCreateFlagFileEvents | fs::kFSEventStreamCreateFlagNoDefer;
+
+fn has(flags: fs::FSEventStreamEventFlags, flag: fs::FSEventStreamEventFlags) -> bool {
+    flags & flag != 0
+}
+
+/// One kind for all that happened to an entry within the latency, FSEvents
+/// merges the flags. The strongest one wins, so that a file created and
+/// chmodded is not taken for a change of attributes alone.
+fn kind(flags: fs::FSEventStreamEventFlags) -> EventKind {
+    let dir = has(flags, fs::kFSEventStreamEventFlagItemIsDir);
+    if has(flags, fs::kFSEventStreamEventFlagItemRemoved) {
+        EventKind::Remove(if dir {
+            RemoveKind::Folder
+        } else {
+            RemoveKind::File
+        })
+    } else if has(flags, fs::kFSEventStreamEventFlagItemCreated) {
+        EventKind::Create(if dir {
+            CreateKind::Folder
+        } else {
+            CreateKind::File
+        })
+    } else if has(flags, fs::kFSEventStreamEventFlagItemRenamed) {
+        // Either end, FSEvents does not tell.
+        EventKind::Modify(ModifyKind::Name(RenameMode::Any))
+    } else if has(flags, fs::kFSEventStreamEventFlagItemModified) {
+        EventKind::Modify(ModifyKind::Data(DataChange::Content))
+    } else if has(flags, fs::kFSEventStreamEventFlagItemChangeOwner) {
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::Ownership))
+    } else if has(
+        flags,
+        fs::kFSEventStreamEventFlagItemInodeMetaMod | fs::kFSEventStreamEventFlagItemFinderInfoMod,
+    ) {
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any))
+    } else if has(flags, fs::kFSEventStreamEventFlagItemXattrMod) {
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::Extended))
+    } else {
+        EventKind::Any
+    }
+}
+
+/// The event to pass on for one entry, if any.
+fn event(path: PathBuf, flags: fs::FSEventStreamEventFlags) -> Option<notify::Event> {
+    if has(flags, fs::kFSEventStreamEventFlagHistoryDone) {
+        return None;
+    }
+    // Events were dropped or coalesced, all below `path` may have changed.
+    if has(
+        flags,
+        fs::kFSEventStreamEventFlagMustScanSubDirs | fs::kFSEventStreamEventFlagRootChanged,
+    ) {
+        return Some(
+            notify::Event::new(EventKind::Other)
+                .set_flag(Flag::Rescan)
+                .add_path(path),
+        );
+    }
+    Some(notify::Event::new(kind(flags)).add_path(path))
+}
+
+/// What a stream passes its callback.
+struct Context {
+    handler: Arc<Mutex<dyn EventHandler>>,
+    /// Canonical, as FSEvents reports paths.
+    roots: Vec<(PathBuf, RecursiveMode)>,
+}
+
+impl Context {
+    fn is_watched(&self, path: &Path) -> bool {
+        self.roots.iter().any(|(root, mode)| match mode {
+            RecursiveMode::Recursive => path.starts_with(root),
+            RecursiveMode::NonRecursive => path == root || path.parent() == Some(root),
+        })
+    }
+}
+
+/// Freed with the stream.
+extern "C" fn release_context(info: *const c_void) {
+    // SAFETY: the box `restart` handed the stream, released only once.
+    drop(unsafe { Box::from_raw(info as *mut Context) });
+}
+
+extern "C" fn callback(
+    _stream: fs::FSEventStreamRef,
+    info: *mut c_void,
+    count: usize,
+    paths: *mut c_void,
+    flags: *const fs::FSEventStreamEventFlags,
+    _ids: *const fs::FSEventStreamEventId,
+) {
+    // SAFETY: FSEvents passes `count` C strings and flags, and the context
+    // `restart` created, which lives as long as the stream.
+    let context = unsafe { &*(info as *const Context) };
+    let paths = paths as *const *const c_char;
+    let mut handler = context.handler.lock().unwrap();
+    for i in 0..count {
+        let (path, flags) = unsafe { (CStr::from_ptr(*paths.add(i)), *flags.add(i)) };
+        let path = PathBuf::from(OsStr::from_bytes(path.to_bytes()));
+        if !context.is_watched(&path) {
+            continue;
+        }
+        if let Some(event) = event(path, flags) {
+            handler.handle_event(Ok(event));
+        }
+    }
+}
+
+/// Pointers moved to and from the thread running a stream.
+struct SendRef(cf::CFRef);
+
+// SAFETY: CoreFoundation objects may be used from any thread.
+unsafe impl Send for SendRef {}
+
+/// Where the thread of a stream reports its run loop once it runs, or why
+/// the stream did not start.
+type Ready = mpsc::Sender<notify::Result<SendRef>>;
+
+/// Observes the run loop entering, a run loop stopped before it runs would
+/// run on regardless.
+extern "C" fn entered(_observer: cf::CFRef, _activity: usize, info: *mut c_void) {
+    // SAFETY: the sender `run` keeps while its run loop runs.
+    let ready = unsafe { &*(info as *const Ready) };
+    let runloop = unsafe { CFRetain(cf::CFRunLoopGetCurrent()) };
+    let _ = ready.send(Ok(SendRef(runloop)));
+}
+
+/// Run `stream` on the run loop of this thread until it is stopped, and
+/// return the id of the last event it passed on.
+fn run(stream: SendRef, since: fs::FSEventStreamEventId, ready: Ready) -> fs::FSEventStreamEventId {
+    let fail = |message| {
+        // SAFETY: the stream is not running, and released only here.
+        unsafe {
+            fs::FSEventStreamInvalidate(stream.0);
+            fs::FSEventStreamRelease(stream.0);
+        }
+        let _ = ready.send(Err(notify::Error::generic(message)));
+        since
+    };
+    // SAFETY: the stream `restart` created, owned by this thread from now
+    // on, and the sender, which outlives the run loop.
+    unsafe {
+        let runloop = cf::CFRunLoopGetCurrent();
+        fs::FSEventStreamScheduleWithRunLoop(stream.0, runloop, cf::kCFRunLoopDefaultMode);
+        if fs::FSEventStreamStart(stream.0) == 0 {
+            return fail("FSEvents cannot start a stream");
+        }
+        let mut context = CFRunLoopObserverContext {
+            version: 0,
+            info: &ready as *const Ready as *mut c_void,
+            retain: None,
+            release: None,
+            copy_description: None,
+        };
+        let observer = CFRunLoopObserverCreate(
+            cf::kCFAllocatorDefault,
+            RUN_LOOP_ENTRY,
+            0,
+            0,
+            entered,
+            &mut context,
+        );
+        if observer.is_null() {
+            fs::FSEventStreamStop(stream.0);
+            return fail("FSEvents cannot observe its run loop");
+        }
+        CFRunLoopAddObserver(runloop, observer, cf::kCFRunLoopDefaultMode);
+        cf::CFRelease(observer);
+        // Until `stop`.
+        cf::CFRunLoopRun();
+        let latest = fs::FSEventStreamGetLatestEventId(stream.0);
+        fs::FSEventStreamStop(stream.0);
+        fs::FSEventStreamInvalidate(stream.0);
+        fs::FSEventStreamRelease(stream.0);
+        latest
+    }
+}
+
+/// A stream scheduled on the run loop of a thread of its own.
+struct Stream {
+    /// Retained.
+    runloop: SendRef,
+    thread: thread::JoinHandle<fs::FSEventStreamEventId>,
+}
+
+/// Watches paths with one FSEvents stream, recreated whenever they change
+/// as streams cannot be changed once created.
+pub struct FsEventsWatcher {
+    handler: Arc<Mutex<dyn EventHandler>>,
+    latency: Duration,
+    roots: HashMap<PathBuf, RecursiveMode>,
+    stream: Option<Stream>,
+    /// The last event the previous stream passed on, for the next one to
+    /// pick up from there.
+    since: Option<fs::FSEventStreamEventId>,
+}
+
+impl FsEventsWatcher {
+    fn stop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            // SAFETY: retained until here, and running since `entered`.
+            unsafe { cf::CFRunLoopStop(stream.runloop.0) };
+            if let Ok(latest) = stream.thread.join() {
+                self.since = Some(latest);
+            }
+            unsafe { cf::CFRelease(stream.runloop.0) };
+        }
+    }
+
+    fn restart(&mut self) -> notify::Result<()> {
+        self.stop();
+        if self.roots.is_empty() {
+            // Nothing to catch up on once something is watched again.
+            self.since = None;
+            return Ok(());
+        }
+        let paths = unsafe {
+            cf::CFArrayCreateMutable(cf::kCFAllocatorDefault, 0, &cf::kCFTypeArrayCallBacks)
+        };
+        for root in self.roots.keys() {
+            // FSEvents takes paths as UTF-8 strings only.
+            let path = CString::new(root.as_os_str().as_bytes())
+                .ok()
+                .map(|path| unsafe {
+                    cf::CFStringCreateWithCString(
+                        cf::kCFAllocatorDefault,
+                        path.as_ptr(),
+                        cf::kCFStringEncodingUTF8,
+                    )
+                })
+                .filter(|path| !path.is_null());
+            let path = match path {
+                Some(path) => path,
+                None => {
+                    unsafe { cf::CFRelease(paths) };
+                    return Err(notify::Error::generic(
+                        "FSEvents cannot watch a path that is not UTF-8",
+                    )
+                    .add_path(root.clone()));
+                }
+            };
+            unsafe {
+                cf::CFArrayAppendValue(paths, path);
+                cf::CFRelease(path);
+            }
+        }
+        let roots = self
+            .roots
+            .iter()
+            .map(|(path, mode)| (path.canonicalize().unwrap_or(path.clone()), *mode))
+            .collect();
+        let context = fs::FSEventStreamContext {
+            version: 0,
+            info: Box::into_raw(Box::new(Context {
+                handler: self.handler.clone(),
+                roots,
+            })) as *mut c_void,
+            retain: None,
+            release: Some(release_context),
+            copy_description: None,
+        };
+        // An id rather than `kFSEventStreamEventIdSinceNow`, which the
+        // stream would report back as its latest event until it has one.
+        let since = self
+            .since
+            .unwrap_or_else(|| unsafe { fs::FSEventsGetCurrentEventId() });
+        let stream = unsafe {
+            let stream = fs::FSEventStreamCreate(
+                cf::kCFAllocatorDefault,
+                callback,
+                &context,
+                paths,
+                since,
+                self.latency.as_secs_f64(),
+                FLAGS,
+            );
+            cf::CFRelease(paths);
+            stream
+        };
+        if stream.is_null() {
+            // No stream took the context over.
+            drop(unsafe { Box::from_raw(context.info as *mut Context) });
+            return Err(notify::Error::generic("FSEvents cannot create a stream"));
+        }
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let spawned = thread::Builder::new().name("fsevents".into()).spawn({
+            let stream = SendRef(stream);
+            move || run(stream, since, ready_tx)
+        });
+        let thread = match spawned {
+            Ok(thread) => thread,
+            Err(err) => {
+                unsafe { fs::FSEventStreamRelease(stream) };
+                return Err(notify::Error::io(err));
+            }
+        };
+        match ready_rx.recv() {
+            Ok(Ok(runloop)) => {
+                self.stream = Some(Stream { runloop, thread });
+                Ok(())
+            }
+            Ok(Err(err)) => {
+                let _ = thread.join();
+                Err(err)
+            }
+            Err(_) => Err(notify::Error::generic("FSEvents thread stopped")),
+        }
+    }
+}
+
+impl notify::Watcher for FsEventsWatcher {
+    /// FSEvents has nothing to poll, the poll interval of `config` is how
+    /// long it collects changes before passing them on.
+    fn new<F: EventHandler>(event_handler: F, config: Config) -> notify::Result<Self> {
+        Ok(Self {
+            handler: Arc::new(Mutex::new(event_handler)),
+            latency: config.poll_interval().unwrap_or(Duration::ZERO),
+            roots: HashMap::new(),
+            stream: None,
+            since: None,
+        })
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        if !path.exists() {
+            return Err(notify::Error::path_not_found().add_path(path.to_owned()));
+        }
+        self.roots.insert(path.to_owned(), recursive_mode);
+        let result = self.restart();
+        if result.is_err() {
+            // Back to watching what was watched before.
+            self.roots.remove(path);
+            let _ = self.restart();
+        }
+        result
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        if self.roots.remove(path).is_none() {
+            return Err(notify::Error::watch_not_found().add_path(path.to_owned()));
+        }
+        self.restart()
+    }
+
+    fn kind() -> WatcherKind {
+        WatcherKind::Fsevent
+    }
+}
+
+impl Drop for FsEventsWatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[test]
+fn test_event() {
+    let path = PathBuf::from("/tmp/sample");
+    let kind_of = |flags| event(path.clone(), flags).unwrap().kind;
+    assert_eq!(
+        kind_of(
+            fs::kFSEventStreamEventFlagItemCreated
+                | fs::kFSEventStreamEventFlagItemModified
+                | fs::kFSEventStreamEventFlagItemXattrMod
+        ),
+        EventKind::Create(CreateKind::File)
+    );
+    assert_eq!(
+        kind_of(fs::kFSEventStreamEventFlagItemRemoved | fs::kFSEventStreamEventFlagItemIsDir),
+        EventKind::Remove(RemoveKind::Folder)
+    );
+    assert_eq!(
+        kind_of(fs::kFSEventStreamEventFlagItemXattrMod),
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::Extended))
+    );
+    let rescan = event(path.clone(), fs::kFSEventStreamEventFlagMustScanSubDirs).unwrap();
+    assert_eq!(rescan.flag(), Some(Flag::Rescan));
+    assert_eq!(rescan.paths, std::slice::from_ref(&path));
+    assert!(event(path, fs::kFSEventStreamEventFlagHistoryDone).is_none());
+}
@@ -0,0 +1,188 @@
+//! `.gitignore` files within a replica, for source trees whose build output
+//! should not wake unison. Follows git: patterns are relative to the
+//! directory of their file, deeper files and later lines take precedence,
+//! `!` re-includes and nothing below an ignored directory can be.
+
+use crate::filter::translate;
+use log::{debug, warn};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const FILE_NAME: &str = ".gitignore";
+
+/// One line of a `.gitignore`.
+#[derive(Debug, Clone)]
+struct Rule {
+    line: String,
+    regex: Regex,
+    negated: bool,
+    dir_only: bool,
+}
+
+impl PartialEq for Rule {
+    fn eq(&self, other: &Self) -> bool {
+        self.line == other.line
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Rule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        // A slash anywhere but at the end anchors the pattern to the
+        // directory of its file, otherwise it matches names at any depth.
+        let regex = match pattern.strip_prefix('/') {
+            Some(pattern) => format!("^{}$", translate(pattern).ok()?),
+            None if pattern.contains('/') => format!("^{}$", translate(pattern).ok()?),
+            None => format!("^(?:.*/)?{}$", translate(pattern).ok()?),
+        };
+        Some(Rule {
+            line: line.to_owned(),
+            regex: Regex::new(&regex).ok()?,
+            negated,
+            dir_only,
+        })
+    }
+}
+
+/// The `.gitignore` files of one replica, by the directory holding them
+/// relative to the root.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GitIgnores {
+    files: BTreeMap<PathBuf, Vec<Rule>>,
+}
+
+fn slashed(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+impl GitIgnores {
+    /// Read every `.gitignore` below `root`, skipping `.git` and ignored
+    /// directories as git does.
+    pub fn load(root: &Path) -> GitIgnores {
+        let mut gitignores = GitIgnores::default();
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            gitignores.reload(root, &dir);
+            let entries = match fs::read_dir(root.join(&dir)) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    debug!("Cannot list {}: {}", root.join(&dir).display(), err);
+                    continue;
+                }
+            };
+            for entry in entries.flatten() {
+                let path = dir.join(entry.file_name());
+                let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+                if is_dir && entry.file_name() != ".git" && !gitignores.matches(&path, true) {
+                    dirs.push(path);
+                }
+            }
+        }
+        gitignores
+    }
+
+    /// Read the `.gitignore` in `dir` again, returning whether its rules
+    /// changed.
+    pub fn reload(&mut self, root: &Path, dir: &Path) -> bool {
+        let path = root.join(dir).join(FILE_NAME);
+        let rules: Vec<Rule> = match fs::read_to_string(&path) {
+            Ok(text) => text.lines().filter_map(Rule::parse).collect(),
+            Err(err) => {
+                if path.exists() {
+                    warn!("Cannot read {}: {}", path.display(), err);
+                }
+                vec![]
+            }
+        };
+        let old = if rules.is_empty() {
+            self.files.remove(dir)
+        } else {
+            self.files.insert(dir.to_owned(), rules)
+        };
+        old.as_ref() != self.files.get(dir)
+    }
+
+    /// Whether `path` itself is ignored by the rules of the directories
+    /// above it, not looking at its parents.
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        // The deepest file with a matching rule decides, by its last one.
+        for (dir, rules) in self.files.iter().rev() {
+            let relative = match path.strip_prefix(dir) {
+                Ok(relative) if relative != Path::new("") => slashed(relative),
+                _ => continue,
+            };
+            let rule = rules
+                .iter()
+                .rev()
+                .find(|rule| (is_dir || !rule.dir_only) && rule.regex.is_match(&relative));
+            if let Some(rule) = rule {
+                return !rule.negated;
+            }
+        }
+        false
+    }
+
+    /// Whether git ignores `path`, relative to the replica at `root`, or one
+    /// of its parents.
+    pub fn is_ignored(&self, root: &Path, path: &Path) -> bool {
+        let mut ancestors: Vec<&Path> = path.ancestors().collect();
+        ancestors.pop();
+        ancestors.into_iter().rev().any(|ancestor| {
+            // Only the changed path itself may be gone.
+            let is_dir = ancestor != path || root.join(path).is_dir();
+            self.matches(ancestor, is_dir)
+        })
+    }
+}
+
+#[test]
+fn test_gitignore() {
+    let tmp = crate::test_dir();
+    let root = tmp.path();
+    for dir in ["src/gen", "web/node_modules/pkg", "target/debug", ".git"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    fs::write(root.join(".gitignore"), "/target\n*.log\n!keep.log\n").unwrap();
+    fs::write(root.join("src/.gitignore"), "gen/\n!*.log\n").unwrap();
+    fs::write(root.join("web/.gitignore"), "node_modules\n").unwrap();
+    // Never read, git does not look into ignored directories.
+    fs::write(root.join("target/.gitignore"), "!debug\n").unwrap();
+
+    let mut gitignores = GitIgnores::load(root);
+    let ignored =
+        |gitignores: &GitIgnores, path: &str| gitignores.is_ignored(root, Path::new(path));
+    assert_eq!(gitignores.files.len(), 3);
+    assert!(ignored(&gitignores, "target"));
+    assert!(ignored(&gitignores, "target/debug/app"));
+    assert!(!ignored(&gitignores, "src/target"));
+    assert!(ignored(&gitignores, "a.log"));
+    assert!(ignored(&gitignores, "web/a.log"));
+    assert!(!ignored(&gitignores, "keep.log"));
+    assert!(!ignored(&gitignores, "src/a.log"));
+    assert!(ignored(&gitignores, "src/gen"));
+    assert!(ignored(&gitignores, "src/gen/a.rs"));
+    // A file named like an ignored directory is not.
+    assert!(!ignored(&gitignores, "src/lib/gen"));
+    assert!(ignored(&gitignores, "web/node_modules/pkg/index.js"));
+    assert!(!ignored(&gitignores, "web/index.js"));
+    assert!(!ignored(&gitignores, ""));
+
+    fs::write(root.join("web/.gitignore"), "dist\n").unwrap();
+    assert!(gitignores.reload(root, Path::new("web")));
+    assert!(!gitignores.reload(root, Path::new("web")));
+    assert!(!ignored(&gitignores, "web/node_modules/pkg/index.js"));
+    assert!(ignored(&gitignores, "web/dist"));
+}
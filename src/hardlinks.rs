@@ -0,0 +1,129 @@
+//! Files with more than one link within a replica, so a write through one
+//! path is reported on the others too. Unison compares each path on its
+//! own and would miss the change on the aliases until a full scan.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+
+/// Device and inode, or `None` for files with a single link and where
+/// links cannot be told apart.
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.is_file() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The paths of each file linked more than once below a root.
+#[derive(Clone, Debug, Default)]
+pub struct Hardlinks {
+    paths: HashMap<(u64, u64), BTreeSet<PathBuf>>,
+    ids: HashMap<PathBuf, (u64, u64)>,
+}
+
+impl Hardlinks {
+    /// Walk the tree below `root`, without following links to directories.
+    pub fn scan(root: &Path) -> Self {
+        let mut hardlinks = Self::default();
+        let mut dirs = vec![root.to_owned()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let metadata = match fs::symlink_metadata(entry.path()) {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                } else if let Some(id) = file_id(&metadata) {
+                    hardlinks.insert(entry.path(), id);
+                }
+            }
+        }
+        hardlinks
+    }
+
+    fn insert(&mut self, path: PathBuf, id: (u64, u64)) {
+        self.paths.entry(id).or_default().insert(path.clone());
+        self.ids.insert(path, id);
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(id) = self.ids.remove(path) {
+            if let Some(paths) = self.paths.get_mut(&id) {
+                paths.remove(path);
+                if paths.is_empty() {
+                    self.paths.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// Look at `path` again after an event for it: it may have been linked,
+    /// unlinked or replaced by another file.
+    pub fn update(&mut self, path: &Path) {
+        let id = fs::symlink_metadata(path)
+            .ok()
+            .and_then(|metadata| file_id(&metadata));
+        if id == self.ids.get(path).copied() {
+            return;
+        }
+        self.remove(path);
+        if let Some(id) = id {
+            self.insert(path.to_owned(), id);
+        }
+    }
+
+    /// The other known paths of the file at `path`.
+    pub fn aliases<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a PathBuf> + 'a {
+        self.ids
+            .get(path)
+            .and_then(|id| self.paths.get(id))
+            .into_iter()
+            .flatten()
+            .filter(move |alias| alias.as_path() != path)
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hardlinks() {
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    fs::create_dir_all(base.join("a")).unwrap();
+    fs::write(base.join("a/file"), b"").unwrap();
+    fs::hard_link(base.join("a/file"), base.join("link")).unwrap();
+    fs::write(base.join("single"), b"").unwrap();
+    let aliases = |hardlinks: &Hardlinks, path: &str| {
+        let aliases: Vec<PathBuf> = hardlinks.aliases(&base.join(path)).cloned().collect();
+        aliases
+    };
+
+    let mut hardlinks = Hardlinks::scan(base);
+    assert_eq!(aliases(&hardlinks, "a/file"), [base.join("link")]);
+    assert_eq!(aliases(&hardlinks, "link"), [base.join("a/file")]);
+    assert!(aliases(&hardlinks, "single").is_empty());
+
+    // Linked once more after the scan.
+    fs::hard_link(base.join("a/file"), base.join("a/another")).unwrap();
+    hardlinks.update(&base.join("a/another"));
+    assert_eq!(
+        aliases(&hardlinks, "link"),
+        [base.join("a/another"), base.join("a/file")]
+    );
+
+    // Replaced by a file of its own, as editors save.
+    fs::remove_file(base.join("link")).unwrap();
+    fs::write(base.join("link"), b"").unwrap();
+    hardlinks.update(&base.join("link"));
+    assert!(aliases(&hardlinks, "link").is_empty());
+    assert_eq!(aliases(&hardlinks, "a/file"), [base.join("a/another")]);
+}
@@ -0,0 +1,153 @@
+//! Which replicas an event path may belong to, found by walking the
+//! components of the path down a trie of replica roots instead of matching
+//! every replica against it.
+
+use crate::protocol::Id;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path};
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<OsString, Node>,
+    /// Replicas with a prefix ending here.
+    ids: BTreeSet<Id>,
+}
+
+impl Node {
+    /// Drop `id` from this node and below, and the nodes left empty.
+    fn prune(&mut self, id: &str) {
+        self.ids.remove(id);
+        self.children.retain(|_, child| {
+            child.prune(id);
+            !child.ids.is_empty() || !child.children.is_empty()
+        });
+    }
+
+    /// The nodes along `path` from this one down, as far as the trie goes.
+    fn trail<'a>(&'a self, path: &'a Path, fold: bool) -> impl Iterator<Item = &'a Node> {
+        let mut components = path.components();
+        std::iter::successors(Some(self), move |node| {
+            if node.children.is_empty() {
+                return None;
+            }
+            node.children.get(&*key(components.next()?, fold))
+        })
+    }
+}
+
+/// The paths events of each replica arrive under, by replica. Replicas on
+/// filesystems that ignore case are kept apart with their components
+/// lowercased, so a lookup may turn up those the path does not belong to.
+#[derive(Debug, Default)]
+pub struct ReplicaIndex {
+    exact: Node,
+    folded: Node,
+}
+
+fn key(component: Component, fold: bool) -> Cow<OsStr> {
+    let name = component.as_os_str();
+    match name.to_str() {
+        Some(name) if fold => Cow::Owned(name.to_lowercase().into()),
+        _ => Cow::Borrowed(name),
+    }
+}
+
+impl ReplicaIndex {
+    pub fn insert(&mut self, id: &Id, prefix: &Path, case_insensitive: bool) {
+        let root = match case_insensitive {
+            true => &mut self.folded,
+            false => &mut self.exact,
+        };
+        let node = prefix.components().fold(root, |node, component| {
+            let key = key(component, case_insensitive).into_owned();
+            node.children.entry(key).or_default()
+        });
+        node.ids.insert(id.clone());
+    }
+
+    /// Index a replica by `prefixes` alone.
+    pub fn update<'a>(
+        &mut self,
+        id: &Id,
+        prefixes: impl IntoIterator<Item = &'a Path>,
+        case_insensitive: bool,
+    ) {
+        self.remove(id);
+        for prefix in prefixes {
+            self.insert(id, prefix, case_insensitive);
+        }
+    }
+
+    /// Forget every prefix of a replica.
+    pub fn remove(&mut self, id: &str) {
+        self.exact.prune(id);
+        self.folded.prune(id);
+    }
+
+    pub fn clear(&mut self) {
+        *self = ReplicaIndex::default();
+    }
+
+    /// The replicas with a prefix at or above `path`, each once.
+    pub fn lookup<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a Id> {
+        [(&self.exact, false), (&self.folded, true)]
+            .into_iter()
+            .flat_map(move |(root, fold)| {
+                root.trail(path, fold)
+                    .enumerate()
+                    .flat_map(move |(depth, node)| {
+                        // Skip those with a prefix further up too.
+                        node.ids.iter().filter(move |id| {
+                            !root
+                                .trail(path, fold)
+                                .take(depth)
+                                .any(|above| above.ids.contains(*id))
+                        })
+                    })
+            })
+    }
+}
+
+#[test]
+fn test_lookup() {
+    let mut index = ReplicaIndex::default();
+    let (one, two, three): (Id, Id, Id) = ("1".into(), "2".into(), "3".into());
+    index.insert(&one, Path::new("/home/me/src"), false);
+    index.insert(&two, Path::new("/home/me/src/project"), false);
+    index.insert(&two, Path::new("/mnt/data"), false);
+    index.insert(&two, Path::new("/mnt"), false);
+    index.insert(&three, Path::new("/Users/Me/Docs"), true);
+    let lookup = |index: &ReplicaIndex, path: &str| {
+        let mut ids: Vec<String> = index
+            .lookup(Path::new(path))
+            .map(|id| id.to_string())
+            .collect();
+        ids.sort();
+        ids
+    };
+
+    assert_eq!(lookup(&index, "/home/me/src/a"), ["1"]);
+    assert_eq!(lookup(&index, "/home/me/src/project/a"), ["1", "2"]);
+    assert_eq!(lookup(&index, "/home/me/src/project"), ["1", "2"]);
+    assert_eq!(lookup(&index, "/mnt/data/b"), ["2"]);
+    assert!(lookup(&index, "/home/me/srcs").is_empty());
+    assert!(lookup(&index, "/home/me").is_empty());
+    assert!(lookup(&index, "/Home/me/src/a").is_empty());
+    assert_eq!(lookup(&index, "/users/me/DOCS/a.txt"), ["3"]);
+    assert!(lookup(&index, "/Users/Me/Documents").is_empty());
+
+    index.remove("2");
+    assert_eq!(lookup(&index, "/home/me/src/project/a"), ["1"]);
+    assert!(lookup(&index, "/mnt/data/b").is_empty());
+    // Nothing is left of /mnt or below /home/me/src, /users/me/docs stays.
+    fn size(node: &Node) -> usize {
+        1 + node.children.values().map(size).sum::<usize>()
+    }
+    assert_eq!(size(&index.exact), 5);
+    assert_eq!(size(&index.folded), 5);
+
+    index.clear();
+    assert!(lookup(&index, "/home/me/src/a").is_empty());
+}
@@ -0,0 +1,270 @@
+//! Other monitors running on this host: a PID file for this one, and a lock
+//! file per replica root so a second monitor watching the same tree notices.
+
+use crate::protocol::Id;
+use log::warn;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// What to do with a replica another live monitor watches already.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Duplicates {
+    /// Watch it anyway, logging a warning.
+    #[default]
+    Share,
+    /// Answer `START` with an error.
+    Refuse,
+}
+
+/// A monitor holding a lock, by the pid it wrote to the lock file. Where
+/// the lock keeps others from reading it, as on Windows, the pid is unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Holder(pub Option<u32>);
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(pid) => write!(f, "pid {}", pid),
+            None => write!(f, "pid unknown"),
+        }
+    }
+}
+
+/// The outcome of locking a file.
+enum Locked {
+    /// Locked by this monitor until the file is closed.
+    Ours(File),
+    /// Locked by another monitor.
+    Theirs(Holder),
+}
+
+/// Whether `file` is still the one at `path`, rather than one its previous
+/// holder removed after this monitor opened it.
+#[cfg(unix)]
+fn is_current(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(held), Ok(current)) => held.dev() == current.dev() && held.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_current(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+/// Take the OS lock on `path`, creating it, and write `contents` to it. The
+/// lock goes away with the process that holds it, so one left behind by a
+/// monitor that died is taken over. Where the platform has no such locks
+/// the file is written unlocked.
+fn lock(path: &Path, contents: &str) -> io::Result<Locked> {
+    let mut file = loop {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) if is_current(&file, path) => break file,
+            // Removed by the monitor that held it meanwhile, try again.
+            Ok(()) => continue,
+            Err(TryLockError::WouldBlock) => {
+                let mut text = String::new();
+                let pid = file
+                    .read_to_string(&mut text)
+                    .ok()
+                    .and_then(|_| text.lines().next()?.trim().parse().ok());
+                return Ok(Locked::Theirs(Holder(pid)));
+            }
+            Err(TryLockError::Error(err)) if err.kind() == io::ErrorKind::Unsupported => {
+                break file
+            }
+            Err(TryLockError::Error(err)) => return Err(err),
+        }
+    };
+    file.set_len(0)?;
+    file.write_all(contents.as_bytes())?;
+    Ok(Locked::Ours(file))
+}
+
+/// Holds this monitor's pid and a lock on it while it runs, removed again
+/// when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    _file: File,
+}
+
+impl PidFile {
+    /// Write the pid to `path`, failing if another monitor still runs with
+    /// it. A file left behind by one that died is taken over.
+    pub fn create(path: &Path) -> io::Result<PidFile> {
+        match lock(path, &format!("{}\n", process::id()))? {
+            Locked::Ours(file) => Ok(PidFile {
+                path: path.to_owned(),
+                _file: file,
+            }),
+            Locked::Theirs(holder) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is held by another monitor, {}", path.display(), holder),
+            )),
+        }
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!("Cannot remove {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// `unison-fsmonitor` in the user's runtime directory, or in the temporary
+/// one.
+pub fn default_dir() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join("unison-fsmonitor")
+}
+
+/// FNV-1a, stable across builds unlike `DefaultHasher`, so every monitor
+/// names the files of a root alike.
+pub(crate) fn hash(path: &Path) -> u64 {
+    path.as_os_str()
+        .to_string_lossy()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Lock files of the replica roots this monitor watches, holding its pid and
+/// the root.
+#[derive(Debug)]
+pub struct ReplicaLocks {
+    dir: PathBuf,
+    pub duplicates: Duplicates,
+    /// Lock file per replica. Replicas of this monitor may share one.
+    held: HashMap<Id, PathBuf>,
+    /// The open lock files, which keep them locked.
+    files: HashMap<PathBuf, File>,
+}
+
+impl ReplicaLocks {
+    pub fn new(dir: PathBuf, duplicates: Duplicates) -> Self {
+        Self {
+            dir,
+            duplicates,
+            held: HashMap::new(),
+            files: HashMap::new(),
+        }
+    }
+
+    fn lock_path(&self, root: &Path) -> PathBuf {
+        self.dir.join(format!("{:016x}.lock", hash(root)))
+    }
+
+    /// Register `root` as watched by the replica. Returns the other live
+    /// monitor watching it already, whose lock is left alone.
+    pub fn acquire(&mut self, replica_id: &str, root: &Path) -> io::Result<Option<Holder>> {
+        let path = self.lock_path(root);
+        if !self.files.contains_key(&path) {
+            fs::create_dir_all(&self.dir)?;
+            let contents = format!("{}\n{}\n", process::id(), root.display());
+            match lock(&path, &contents)? {
+                Locked::Ours(file) => self.files.insert(path.clone(), file),
+                Locked::Theirs(holder) => return Ok(Some(holder)),
+            };
+        }
+        self.held.insert(replica_id.into(), path);
+        Ok(None)
+    }
+
+    /// Drop the replica's claim, removing the lock once no other replica of
+    /// this monitor holds it.
+    pub fn release(&mut self, replica_id: &str) {
+        if let Some(path) = self.held.remove(replica_id) {
+            if !self.held.values().any(|held| *held == path) {
+                let _ = fs::remove_file(&path);
+                self.files.remove(&path);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.held.clear();
+        for (path, _) in self.files.drain() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Drop for ReplicaLocks {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[test]
+fn test_pid_file() {
+    let tmp = crate::test_dir();
+    let path = tmp.path().join("monitor.pid");
+
+    let pid_file = PidFile::create(&path).unwrap();
+    #[cfg(unix)]
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        format!("{}\n", process::id())
+    );
+    // Held by a monitor still running.
+    let err = PidFile::create(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    drop(pid_file);
+    assert!(!path.exists());
+
+    // Left behind by a process that is gone.
+    fs::write(&path, "2147483646\n").unwrap();
+    drop(PidFile::create(&path).unwrap());
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_replica_locks() {
+    let tmp = crate::test_dir();
+    let dir = tmp.path();
+    let root = Path::new("/tmp/sample");
+    let mut locks = ReplicaLocks::new(dir.to_owned(), Duplicates::Share);
+
+    assert_eq!(locks.acquire("123", root).unwrap(), None);
+    assert_eq!(locks.acquire("456", root).unwrap(), None);
+    let path = locks.lock_path(root);
+    #[cfg(unix)]
+    assert!(fs::read_to_string(&path)
+        .unwrap()
+        .ends_with("\n/tmp/sample\n"));
+
+    // Another monitor finds the lock taken.
+    let mut other = ReplicaLocks::new(dir.to_owned(), Duplicates::Share);
+    let holder = other.acquire("789", root).unwrap().unwrap();
+    // Windows keeps others from reading a locked file.
+    let pid = cfg!(unix).then(process::id);
+    assert_eq!(holder, Holder(pid));
+    assert!(other.held.is_empty());
+
+    locks.release("123");
+    assert!(path.exists());
+    locks.release("456");
+    assert!(!path.exists());
+    // And takes it over once released.
+    assert_eq!(other.acquire("789", root).unwrap(), None);
+    drop(other);
+}
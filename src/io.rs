@@ -0,0 +1,763 @@
+//! Transports to unison and the actors serving a session over them on tokio:
+//! the protocol actor owns the transport, reading commands into the event
+//! channel and writing what the monitor actor sends back on its response
+//! channel. The monitor actor owns the watches and changes.
+
+#[cfg(unix)]
+use crate::config::Config;
+use crate::error::{MonitorError, Result};
+#[cfg(unix)]
+use crate::logging::cycle_log_level;
+use crate::logging::RotatingFile;
+use crate::metrics::{self, Share};
+use crate::monitor::{Event, Monitor, Tag, Watch};
+use crate::protocol::{Response, ResponseSink};
+use log::info;
+#[cfg(unix)]
+use log::warn;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+#[cfg(test)]
+use std::io::Cursor;
+use std::io::{self, Write as _};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{
+    stdin, stdout, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader,
+    BufWriter, Stdin, Stdout,
+};
+#[cfg(windows)]
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient, NamedPipeServer};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{
+    channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
+};
+#[cfg(all(test, unix))]
+use tokio::task::LocalSet;
+use tokio::task::{spawn_local, JoinHandle};
+use tokio::time::{interval_at, Instant};
+#[cfg(windows)]
+use windows_sys::Win32::Foundation::ERROR_PIPE_BUSY;
+
+/// The named pipe a monitor running as a Windows service listens at, unless
+/// told otherwise with `--listen`.
+pub const SERVICE_PIPE: &str = r"\\.\pipe\unison-fsmonitor";
+
+/// How often watched paths are checked for deletion or recreation.
+pub const PATH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many filesystem events may queue up for the monitor actor before
+/// further ones are dropped.
+pub const EVENT_CAPACITY: usize = 65_536;
+
+/// Events dropped on overflow, by the tag of the watcher they came from, or
+/// `None` for the shared one.
+pub type Shed = HashMap<Option<Tag>, usize>;
+
+/// The sending end of the channel into the monitor actor. Filesystem events
+/// go through a bounded queue, so a stalled monitor cannot pile them up
+/// without limit: past `EVENT_CAPACITY` they are dropped and counted, and
+/// the monitor hears of them as `Event::Overflow` to rescan what they were
+/// about. Everything else, from commands to ticks, is never dropped.
+#[derive(Clone)]
+pub struct EventSender {
+    control: UnboundedSender<Event>,
+    events: Sender<Event>,
+    shed: Arc<Mutex<Shed>>,
+}
+
+/// The monitor actor's end of an `EventSender`, taking anything but
+/// filesystem events first.
+pub struct EventReceiver {
+    control: UnboundedReceiver<Event>,
+    events: Receiver<Event>,
+    shed: Arc<Mutex<Shed>>,
+}
+
+/// A channel into the monitor actor queueing up to `capacity` filesystem
+/// events.
+pub fn event_channel(capacity: usize) -> (EventSender, EventReceiver) {
+    let (control_tx, control_rx) = unbounded_channel();
+    let (events_tx, events_rx) = channel(capacity);
+    let shed = Arc::new(Mutex::new(Shed::new()));
+    (
+        EventSender {
+            control: control_tx,
+            events: events_tx,
+            shed: shed.clone(),
+        },
+        EventReceiver {
+            control: control_rx,
+            events: events_rx,
+            shed,
+        },
+    )
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "monitor actor stopped")
+}
+
+impl EventSender {
+    /// Queue `event`, failing only once the monitor actor stopped.
+    pub fn send(&self, event: Event) -> io::Result<()> {
+        if !event.is_filesystem() {
+            return self.control.send(event).map_err(|_| stopped());
+        }
+        match self.events.try_send(event) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(event)) => {
+                let tag = match event {
+                    Event::ReplicaEvent(tag, _) => Some(tag),
+                    _ => None,
+                };
+                let mut shed = self.shed.lock().unwrap();
+                // One notice until the monitor took the counts.
+                let first = shed.is_empty();
+                *shed.entry(tag).or_default() += 1;
+                if first {
+                    self.control
+                        .send(Event::Overflow(Shed::new()))
+                        .map_err(|_| stopped())?;
+                }
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(stopped()),
+        }
+    }
+}
+
+impl EventReceiver {
+    pub async fn recv(&mut self) -> Option<Event> {
+        let event = tokio::select! {
+            biased;
+            Some(event) = self.control.recv() => event,
+            Some(event) = self.events.recv() => event,
+            else => return None,
+        };
+        Some(self.fill(event))
+    }
+
+    pub fn try_recv(&mut self) -> Option<Event> {
+        let event = match self.control.try_recv() {
+            Ok(event) => event,
+            Err(_) => self.events.try_recv().ok()?,
+        };
+        Some(self.fill(event))
+    }
+
+    #[cfg(test)]
+    pub fn blocking_recv(&mut self) -> Option<Event> {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(self.recv())
+    }
+
+    /// How many filesystem events are queued.
+    pub fn queued(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Hand an overflow notice the counts so far.
+    fn fill(&mut self, event: Event) -> Event {
+        match event {
+            Event::Overflow(_) => Event::Overflow(std::mem::take(&mut *self.shed.lock().unwrap())),
+            event => event,
+        }
+    }
+}
+
+/// Line based connection to unison: commands come in through `Input`,
+/// responses go out through `Output`.
+pub trait ProtocolTransport {
+    type Input: AsyncBufRead + Unpin + Send + 'static;
+    type Output: AsyncWrite + Unpin + Send + 'static;
+
+    fn split(self) -> (Self::Input, Self::Output);
+}
+
+/// Unison running the monitor as a child process.
+pub struct Stdio;
+
+impl ProtocolTransport for Stdio {
+    type Input = BufReader<Stdin>;
+    type Output = Stdout;
+
+    fn split(self) -> (Self::Input, Self::Output) {
+        (BufReader::new(stdin()), stdout())
+    }
+}
+
+/// Unison connected over TCP, through a monitor run with `--connect`.
+impl ProtocolTransport for TcpStream {
+    type Input = BufReader<OwnedReadHalf>;
+    type Output = OwnedWriteHalf;
+
+    fn split(self) -> (Self::Input, Self::Output) {
+        let (input, output) = self.into_split();
+        (BufReader::new(input), output)
+    }
+}
+
+/// Unison connected over a named pipe, through a monitor run with
+/// `--connect`.
+#[cfg(windows)]
+impl ProtocolTransport for NamedPipeServer {
+    type Input = BufReader<ReadHalf<NamedPipeServer>>;
+    type Output = WriteHalf<NamedPipeServer>;
+
+    fn split(self) -> (Self::Input, Self::Output) {
+        let (input, output) = tokio::io::split(self);
+        (BufReader::new(input), output)
+    }
+}
+
+/// Whether `addr` names a named pipe rather than a TCP address.
+pub fn is_pipe(addr: &str) -> bool {
+    addr.starts_with(r"\\.\pipe\")
+}
+
+/// Open the named pipe `name`, waiting while all its instances are busy.
+#[cfg(windows)]
+pub async fn connect_pipe(name: &str) -> io::Result<NamedPipeClient> {
+    loop {
+        match ClientOptions::new().open(name) {
+            Err(err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            opened => return opened,
+        }
+    }
+}
+
+/// Pass stdin on to the monitor at the other end of `stream`, and what it
+/// answers on to stdout, until it closes the connection.
+pub async fn relay(stream: impl AsyncRead + AsyncWrite + Send + 'static) -> io::Result<()> {
+    let (mut input, mut output) = tokio::io::split(stream);
+    tokio::spawn(async move {
+        let _ = tokio::io::copy(&mut stdin(), &mut output).await;
+        // Unison is done, the monitor ends the session once it sees that.
+        let _ = output.shutdown().await;
+    });
+    let mut answers = stdout();
+    tokio::io::copy(&mut input, &mut answers).await?;
+    answers.flush().await
+}
+
+/// Any reader and writer pair, e.g. in-memory transcripts.
+impl<R, W> ProtocolTransport for (R, W)
+where
+    R: AsyncBufRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    type Input = R;
+    type Output = W;
+
+    fn split(self) -> (R, W) {
+        self
+    }
+}
+
+/// The monitor actor's end of the response channel.
+pub struct Responses(UnboundedSender<Response>);
+
+impl ResponseSink for Responses {
+    fn send(&mut self, response: Response) -> io::Result<()> {
+        self.0
+            .send(response)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "protocol actor stopped"))
+    }
+
+    /// The protocol actor flushes once it runs out of responses.
+    fn flush_responses(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Every protocol line of the sessions, timestamped and appended to a file
+/// `--trace` names, to attach to bug reports.
+#[derive(Clone)]
+pub struct Trace {
+    file: Arc<Mutex<RotatingFile>>,
+    session: Option<Arc<str>>,
+}
+
+impl Trace {
+    pub fn new(file: RotatingFile) -> Self {
+        Self {
+            file: Arc::new(Mutex::new(file)),
+            session: None,
+        }
+    }
+
+    /// The same trace, with each line telling it came from `session`, for
+    /// sessions running side by side.
+    pub fn session(&self, session: impl std::fmt::Display) -> Self {
+        Self {
+            file: self.file.clone(),
+            session: Some(session.to_string().into()),
+        }
+    }
+
+    /// Record `line`, as it was read with `<<` or written with `>>`.
+    fn record(&self, direction: &str, line: &str) {
+        let now = humantime::format_rfc3339_millis(SystemTime::now());
+        let mut record = match &self.session {
+            Some(session) => format!("{} {} {} {}", now, session, direction, line),
+            None => format!("{} {} {}", now, direction, line),
+        };
+        // The last line may end without one.
+        if !record.ends_with('\n') {
+            record.push('\n');
+        }
+        // One write per record, so rotation never splits one. Failing to
+        // trace does not end the session.
+        let _ = self.file.lock().unwrap().write_all(record.as_bytes());
+    }
+}
+
+/// Start the protocol actor on `transport`, feeding commands to `tx` and
+/// recording both directions to `trace`. Its writer ends once the returned
+/// `Responses` is dropped, handing back the output.
+pub fn spawn_protocol<T: ProtocolTransport>(
+    transport: T,
+    tx: EventSender,
+    trace: Option<Trace>,
+) -> (Responses, JoinHandle<io::Result<T::Output>>) {
+    let (input, output) = transport.split();
+    let (responses_tx, responses_rx) = unbounded_channel();
+    spawn_reader(input, tx, trace.clone());
+    (
+        Responses(responses_tx),
+        spawn_writer(output, responses_rx, trace),
+    )
+}
+
+/// Forward input lines, then `Event::Eof` once unison closes it or
+/// `Event::InputError` once reading fails, even by panicking.
+pub fn spawn_reader<R: AsyncBufRead + Unpin + Send + 'static>(
+    mut input: R,
+    tx: EventSender,
+    trace: Option<Trace>,
+) {
+    let reader_tx = tx.clone();
+    let reader = tokio::spawn(async move {
+        let mut line = String::new();
+        loop {
+            let event = match input.read_line(&mut line).await {
+                Ok(0) => Event::Eof,
+                Ok(_) => {
+                    if let Some(trace) = &trace {
+                        trace.record("<<", &line);
+                    }
+                    Event::Input(std::mem::take(&mut line))
+                }
+                Err(err) => Event::InputError(err),
+            };
+            let last = !matches!(event, Event::Input(_));
+            if reader_tx.send(event).is_err() || last {
+                return;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        if let Err(err) = reader.await {
+            let _ = tx.send(Event::InputError(io::Error::other(err)));
+        }
+    });
+}
+
+/// Write responses as lines, flushing whenever none are left queued: a batch
+/// of RECURSIVE lines goes out with its DONE.
+pub fn spawn_writer<W: AsyncWrite + Unpin + Send + 'static>(
+    output: W,
+    mut rx: UnboundedReceiver<Response>,
+    trace: Option<Trace>,
+) -> JoinHandle<io::Result<W>> {
+    tokio::spawn(async move {
+        let mut output = BufWriter::new(output);
+        // One buffer for every line.
+        let mut line = String::new();
+        while let Some(response) = rx.recv().await {
+            line.clear();
+            let _ = writeln!(line, "{}", response);
+            if let Some(trace) = &trace {
+                trace.record(">>", &line);
+            }
+            output.write_all(line.as_bytes()).await?;
+            if rx.is_empty() {
+                output.flush().await?;
+            }
+        }
+        output.flush().await?;
+        Ok(output.into_inner())
+    })
+}
+
+/// Cycle the log level on every `SIGUSR2`, to get traces out of a running
+/// session.
+#[cfg(unix)]
+pub fn spawn_log_level_signal() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = signal(SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            cycle_log_level();
+        }
+    });
+    Ok(())
+}
+
+/// Have the monitor of the session log its statistics on every `SIGUSR1`.
+#[cfg(unix)]
+pub fn spawn_stats_signal(tx: EventSender) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            if tx.send(Event::Stats).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Read the config again with `reload` on every `SIGHUP`, handing it to the
+/// monitor as `Event::Reload`. A config that fails to load is logged and the
+/// monitor keeps the one it has.
+#[cfg(unix)]
+pub fn spawn_reload_signal(
+    tx: EventSender,
+    mut reload: impl FnMut() -> Result<Config> + Send + 'static,
+) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            match reload() {
+                Ok(config) => {
+                    info!("Config reloaded.");
+                    if tx.send(Event::Reload(Box::new(config))).is_err() {
+                        return;
+                    }
+                }
+                Err(err) => warn!("Cannot reload config, keeping the old one: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Send `Event::Tick` every `PATH_CHECK_INTERVAL`.
+pub fn spawn_ticker(tx: EventSender) {
+    tokio::spawn(async move {
+        let mut ticks = interval_at(Instant::now() + PATH_CHECK_INTERVAL, PATH_CHECK_INTERVAL);
+        loop {
+            ticks.tick().await;
+            if tx.send(Event::Tick).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Start the monitor actor on the current `LocalSet`. A panic in it ends the
+/// session with an error rather than taking the protocol actor down before
+/// it wrote everything out.
+pub fn spawn_monitor<WATCH, WRITE>(
+    mut monitor: Monitor<WATCH, WRITE>,
+    rx: EventReceiver,
+) -> JoinHandle<Result<()>>
+where
+    WATCH: Watch + 'static,
+    WRITE: ResponseSink + 'static,
+{
+    spawn_local(async move { run(&mut monitor, rx).await })
+}
+
+/// Wait for the monitor actor, then for the protocol actor to write out what
+/// it was sent.
+pub async fn join<W>(
+    monitor: JoinHandle<Result<()>>,
+    protocol: JoinHandle<io::Result<W>>,
+) -> Result<W> {
+    let result = monitor
+        .await
+        .map_err(|err| MonitorError::IoError(io::Error::other(err)));
+    let output = protocol.await.map_err(io::Error::other)??;
+    result??;
+    Ok(output)
+}
+
+/// Handle events until unison closes its input, failing if reading it did
+/// or on a fatal error. Commands are handled ahead of the filesystem events
+/// queued before them, so a flood of events cannot keep unison waiting for
+/// its answers; the events are worked off one by one in between.
+pub async fn run<WATCH: Watch, WRITE: ResponseSink>(
+    monitor: &mut Monitor<WATCH, WRITE>,
+    mut rx: EventReceiver,
+) -> Result<()> {
+    // What this session adds to the gauges, until it ends.
+    let mut replicas = Share::new(&metrics::REPLICAS);
+    let mut pending = Share::new(&metrics::PENDING_CHANGES);
+    let mut queued = Share::new(&metrics::QUEUED_EVENTS);
+    while let Some(event) = rx.recv().await {
+        match event {
+            Event::Eof => {
+                info!("input closed, exiting.");
+                return monitor.shutdown();
+            }
+            Event::InputError(err) => {
+                monitor.shutdown()?;
+                return Err(err.into());
+            }
+            event => {
+                if let Err(err) = monitor.handle_event(event) {
+                    if let Err(err) = monitor.handle_error(err) {
+                        monitor.shutdown()?;
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        replicas.set(monitor.replicas.len());
+        pending.set(
+            monitor
+                .replicas
+                .values()
+                .map(|replica| replica.pending_changes.len())
+                .sum(),
+        );
+        queued.set(rx.queued());
+    }
+    monitor.shutdown()
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_transcript() {
+    struct Watcher {}
+    impl Watch for Watcher {}
+
+    let input = "VERSION 1\nSTART 123 /tmp\nDONE\nFROBNICATE\nWAIT\nCHANGES 123\n";
+    let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+    let (tx, rx) = event_channel(EVENT_CAPACITY);
+    spawn_reader(input.as_bytes(), tx, None);
+
+    run(&mut monitor, rx).await.unwrap();
+
+    assert_eq!(
+        String::from_utf8(monitor.writer.into_inner()).unwrap(),
+        "VERSION 1\nOK\nERROR Missing%20argument%201%20to%20WAIT\nDONE\n"
+    );
+    assert!(monitor.replicas.is_empty());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_commands_preempt() {
+    struct Watcher {}
+    impl Watch for Watcher {}
+
+    let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+    let (tx, rx) = event_channel(EVENT_CAPACITY);
+    for line in ["START 123 /tmp\n", "DONE\n"] {
+        tx.send(Event::Input(line.into())).unwrap();
+    }
+    tx.send(Event::FSEvent(
+        notify::Event::new(notify::EventKind::Any).add_path("/tmp/filename".into()),
+    ))
+    .unwrap();
+    tx.send(Event::Input("CHANGES 123\n".into())).unwrap();
+    drop(tx);
+
+    run(&mut monitor, rx).await.unwrap();
+
+    // Answered before the event queued ahead of it was handled.
+    assert_eq!(
+        String::from_utf8(monitor.writer.into_inner()).unwrap(),
+        "OK\nDONE\n"
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_overflow() {
+    struct Watcher {}
+    impl Watch for Watcher {}
+
+    let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+    let (tx, rx) = event_channel(1);
+    for line in ["START 123 /tmp\n", "DONE\n"] {
+        tx.send(Event::Input(line.into())).unwrap();
+    }
+    for filename in ["/tmp/a", "/tmp/b", "/tmp/c"] {
+        tx.send(Event::FSEvent(
+            notify::Event::new(notify::EventKind::Any).add_path(filename.into()),
+        ))
+        .unwrap();
+    }
+    assert_eq!(tx.shed.lock().unwrap()[&None], 2);
+    tx.send(Event::Input("CHANGES 123\n".into())).unwrap();
+    drop(tx);
+
+    run(&mut monitor, rx).await.unwrap();
+
+    // The replica is rescanned as a whole for what was dropped.
+    assert_eq!(
+        String::from_utf8(monitor.writer.into_inner()).unwrap(),
+        "OK\nRECURSIVE \nDONE\n"
+    );
+}
+
+#[tokio::test]
+async fn test_tcp() {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    struct Watcher {}
+    impl Watch for Watcher {}
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut unison = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let (tx, rx) = event_channel(EVENT_CAPACITY);
+    let (responses, protocol) = spawn_protocol(stream, tx, None);
+    let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), responses);
+    unison.write_all(b"VERSION 1\n").await.unwrap();
+    unison.shutdown().await.unwrap();
+
+    run(&mut monitor, rx).await.unwrap();
+    drop(monitor);
+    drop(protocol.await.unwrap().unwrap());
+
+    let mut answers = String::new();
+    unison.read_to_string(&mut answers).await.unwrap();
+    assert_eq!(answers, "VERSION 1\n");
+}
+
+#[tokio::test]
+async fn test_input_error() {
+    struct Watcher {}
+    impl Watch for Watcher {}
+
+    let input = &b"VERSION 1\n\xff\nVERSION 1\n"[..];
+    let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+    let (tx, rx) = event_channel(EVENT_CAPACITY);
+    spawn_reader(input, tx, None);
+
+    let err = run(&mut monitor, rx).await.unwrap_err();
+
+    assert!(matches!(err, MonitorError::IoError(_)));
+    assert_eq!(monitor.writer.into_inner(), b"VERSION 1\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_actors() {
+    struct Watcher {}
+    impl Watch for Watcher {}
+
+    let input = "VERSION 1\nSTART 123 /tmp\nDONE\nWAIT 123\nCHANGES 123\n";
+    let (tx, rx) = event_channel(EVENT_CAPACITY);
+    let (responses, protocol) = spawn_protocol((input.as_bytes(), Vec::new()), tx, None);
+    let monitor = Monitor::new(|_, _| Ok(Watcher {}), responses);
+
+    let output = LocalSet::new()
+        .run_until(async { join(spawn_monitor(monitor, rx), protocol).await })
+        .await
+        .unwrap();
+
+    assert_eq!(String::from_utf8(output).unwrap(), "VERSION 1\nOK\nDONE\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_trace() {
+    use crate::logging::Rotation;
+
+    struct Watcher {}
+    impl Watch for Watcher {}
+
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    let path = base.join("trace");
+    let trace = Trace::new(RotatingFile::open(&path, Rotation::Never, 0).unwrap());
+
+    let input = "VERSION 1\nSTART 123 /tmp\nDONE\nCHANGES 123";
+    let (tx, rx) = event_channel(EVENT_CAPACITY);
+    let (responses, protocol) = spawn_protocol(
+        (input.as_bytes(), Vec::new()),
+        tx,
+        Some(trace.session("127.0.0.1:4000")),
+    );
+    let monitor = Monitor::new(|_, _| Ok(Watcher {}), responses);
+    LocalSet::new()
+        .run_until(async { join(spawn_monitor(monitor, rx), protocol).await })
+        .await
+        .unwrap();
+
+    let recorded = std::fs::read_to_string(&path).unwrap();
+    // Each line starts with a timestamp like 2024-01-02T03:04:05.678Z. The
+    // two directions interleave as the actors happen to run.
+    let lines: Vec<&str> = recorded
+        .lines()
+        .map(|line| line.split_once("Z ").unwrap().1)
+        .collect();
+    let (read, written): (Vec<&str>, Vec<&str>) =
+        lines.iter().partition(|line| line.contains(" << "));
+    assert_eq!(
+        read,
+        [
+            "127.0.0.1:4000 << VERSION 1",
+            "127.0.0.1:4000 << START 123 /tmp",
+            "127.0.0.1:4000 << DONE",
+            "127.0.0.1:4000 << CHANGES 123",
+        ]
+    );
+    assert_eq!(
+        written,
+        [
+            "127.0.0.1:4000 >> VERSION 1",
+            "127.0.0.1:4000 >> OK",
+            "127.0.0.1:4000 >> DONE",
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_monitor_panic() {
+    struct Watcher {}
+    impl Watch for Watcher {}
+
+    let input = "VERSION 1\nSTART 123 /tmp\n";
+    let (tx, rx) = event_channel(EVENT_CAPACITY);
+    let (responses, protocol) = spawn_protocol((input.as_bytes(), Vec::new()), tx, None);
+    let monitor = Monitor::new(|_, _| -> Result<Watcher> { panic!("boom") }, responses);
+
+    let local = LocalSet::new();
+    let monitor = local.spawn_local(async move { spawn_monitor(monitor, rx).await.unwrap_err() });
+    let err = local.run_until(monitor).await.unwrap();
+    assert!(err.is_panic());
+
+    // Whatever the monitor sent before still reaches unison.
+    assert_eq!(protocol.await.unwrap().unwrap(), b"VERSION 1\n");
+}
+
+#[test]
+fn test_is_pipe() {
+    assert!(is_pipe(SERVICE_PIPE));
+    assert!(!is_pipe("127.0.0.1:7789"));
+}
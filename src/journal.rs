@@ -0,0 +1,170 @@
+//! Changes unison has not taken yet, kept on disk per replica so that a
+//! monitor started after one crashed or was restarted still reports them.
+
+use crate::instance::hash;
+use crate::protocol::{decode, encode, Id};
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// `unison-fsmonitor` in the user's state directory.
+pub fn default_dir() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))?;
+    Some(dir.join("unison-fsmonitor"))
+}
+
+/// A journal file per replica in `dir`, holding a line with the time in
+/// seconds since the epoch and the percent-encoded path for each change
+/// unison has not acknowledged. Files without changes are removed.
+#[derive(Debug)]
+pub struct Journal {
+    dir: PathBuf,
+    /// What the file of each replica holds, with when each change was first
+    /// journaled.
+    entries: HashMap<Id, BTreeMap<PathBuf, SystemTime>>,
+}
+
+impl Journal {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn path(&self, replica_id: &str, root: &Path) -> PathBuf {
+        self.dir.join(format!(
+            "{:016x}-{:016x}.journal",
+            hash(root),
+            hash(Path::new(replica_id))
+        ))
+    }
+
+    /// The changes journaled for a replica starting on `root`, which are
+    /// kept until `record` says otherwise.
+    pub fn replay(&mut self, replica_id: &str, root: &Path) -> Vec<PathBuf> {
+        let path = self.path(replica_id, root);
+        let entries = match fs::read_to_string(&path) {
+            Ok(text) => parse(&text),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                warn!("Cannot read {}: {}", path.display(), err);
+                BTreeMap::new()
+            }
+        };
+        if let Some(oldest) = entries.values().min() {
+            let age = SystemTime::now()
+                .duration_since(*oldest)
+                .unwrap_or_default();
+            info!(
+                "replica {}: {} changes journaled, the oldest {}s ago",
+                replica_id,
+                entries.len(),
+                age.as_secs()
+            );
+        }
+        let changes = entries.keys().cloned().collect();
+        self.entries.insert(replica_id.into(), entries);
+        changes
+    }
+
+    /// Journal the outstanding `changes` of a replica, writing its file only
+    /// if they differ from what it holds.
+    pub fn record<'a>(
+        &mut self,
+        replica_id: &str,
+        root: &Path,
+        changes: impl IntoIterator<Item = &'a PathBuf>,
+    ) {
+        let now = SystemTime::now();
+        let old = self.entries.remove(replica_id).unwrap_or_default();
+        let entries: BTreeMap<PathBuf, SystemTime> = changes
+            .into_iter()
+            .map(|change| (change.clone(), old.get(change).copied().unwrap_or(now)))
+            .collect();
+        if !entries.keys().eq(old.keys()) {
+            let path = self.path(replica_id, root);
+            if let Err(err) = self.write(&path, &entries) {
+                warn!("Cannot write {}: {}", path.display(), err);
+            }
+        }
+        self.entries.insert(replica_id.into(), entries);
+    }
+
+    /// Stop tracking a replica, leaving its file as it is.
+    pub fn forget(&mut self, replica_id: &str) {
+        self.entries.remove(replica_id);
+    }
+
+    fn write(&self, path: &Path, entries: &BTreeMap<PathBuf, SystemTime>) -> io::Result<()> {
+        if entries.is_empty() {
+            return match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            };
+        }
+        fs::create_dir_all(&self.dir)?;
+        let mut text = String::new();
+        for (change, time) in entries {
+            let secs = time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            text.push_str(&format!("{} {}\n", secs, encode(change.as_os_str())));
+        }
+        // Written aside first, a crash meanwhile leaves the old file intact.
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// The entries of a journal file, skipping lines that do not parse.
+fn parse(text: &str) -> BTreeMap<PathBuf, SystemTime> {
+    text.lines()
+        .filter_map(|line| {
+            let (secs, change) = line.split_once(' ')?;
+            let time = UNIX_EPOCH + Duration::from_secs(secs.parse().ok()?);
+            Some((PathBuf::from(decode(change)), time))
+        })
+        .collect()
+}
+
+#[test]
+fn test_journal() {
+    let tmp = crate::test_dir();
+    let dir = tmp.path();
+    let root = Path::new("/tmp/sample");
+    let changes = [PathBuf::from("a b"), "c/d".into()];
+
+    let mut journal = Journal::new(dir.to_owned());
+    assert!(journal.replay("123", root).is_empty());
+    journal.record("123", root, &changes);
+    let path = journal.path("123", root);
+    let written = fs::metadata(&path).unwrap().modified().unwrap();
+    let text = fs::read_to_string(&path).unwrap();
+    assert!(text.contains(" a%20b\n"));
+    assert!(text.ends_with(" c/d\n"));
+    // Unchanged, the file is left alone.
+    std::thread::sleep(Duration::from_millis(10));
+    journal.record("123", root, &changes);
+    assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), written);
+
+    // Another monitor picks up where this one left off, by replica.
+    let mut journal = Journal::new(dir.to_owned());
+    assert!(journal.replay("456", root).is_empty());
+    assert_eq!(journal.replay("123", root), changes);
+    journal.forget("123");
+    assert_eq!(journal.replay("123", root), changes);
+
+    // Nothing outstanding, nothing left.
+    journal.record("123", root, []);
+    assert!(!path.exists());
+}
@@ -0,0 +1,49 @@
+//! unison-fsmonitor implementation. The binary wires these modules to stdio,
+//! they can as well be used to embed the monitor elsewhere.
+
+pub mod backend;
+pub mod bench;
+pub mod budget;
+pub mod cli;
+pub mod coarse;
+pub mod config;
+pub mod error;
+#[cfg(target_os = "linux")]
+pub mod fanotify;
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+pub mod fen;
+pub mod filter;
+#[cfg(target_os = "macos")]
+pub mod fsevents;
+pub mod gitignore;
+pub mod hardlinks;
+pub mod index;
+pub mod instance;
+pub mod io;
+pub mod journal;
+pub mod logging;
+pub mod metrics;
+pub mod monitor;
+pub mod netfs;
+pub mod paths;
+pub mod pipeline;
+pub mod profile;
+pub mod protocol;
+pub mod scan;
+#[cfg(windows)]
+pub mod service;
+pub mod snapshot;
+#[cfg(unix)]
+pub mod watchman;
+#[cfg(windows)]
+pub mod windows;
+
+/// A directory of its own for a test, removed along with what is in it
+/// when dropped.
+#[cfg(test)]
+pub(crate) fn test_dir() -> tempfile::TempDir {
+    tempfile::Builder::new()
+        .prefix("unison-fsmonitor-test-")
+        .tempdir()
+        .unwrap()
+}
@@ -0,0 +1,446 @@
+//! Logging to stderr or a rotated file through `env_logger`.
+
+use env_logger::{Target, WriteStyle};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{info, LevelFilter};
+use serde_json::Map;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Level raised to at runtime, by unison sending `DEBUG` or by `SIGUSR2`,
+/// `LevelFilter::Off` until then.
+pub(crate) static RAISED: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
+
+/// Level of the default logger, what `RAISED` adds to.
+static CONFIGURED: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
+
+fn load(level: &AtomicUsize) -> LevelFilter {
+    LevelFilter::iter()
+        .nth(level.load(Ordering::Relaxed))
+        .unwrap_or(LevelFilter::Trace)
+}
+
+/// The level logging was raised to at runtime.
+pub fn raised_level() -> LevelFilter {
+    load(&RAISED)
+}
+
+/// How each log record is written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// `env_logger` lines, to be read.
+    #[default]
+    Text,
+    /// One JSON object per line, with the replica, path and event kind a
+    /// record is about as fields of their own.
+    Json,
+}
+
+/// `env_logger` honouring `RUST_LOG` or the configured level, logging
+/// anything up to the raised level on top.
+pub struct Logger {
+    file: Option<SharedFile>,
+    format: LogFormat,
+    default: RwLock<env_logger::Logger>,
+    raised: env_logger::Logger,
+}
+
+/// The installed logger, to change its level later.
+static LOGGER: OnceLock<&'static Logger> = OnceLock::new();
+
+impl Logger {
+    /// Log to stderr, or to `log_file`, in `format`. `level` replaces the
+    /// default level of `RUST_LOG`.
+    pub fn init(
+        log_file: Option<RotatingFile>,
+        format: LogFormat,
+        level: Option<LevelFilter>,
+    ) -> io::Result<()> {
+        let file = log_file.map(|file| SharedFile(Arc::new(Mutex::new(file))));
+        let raised = builder(&file, format)
+            .filter_level(LevelFilter::Trace)
+            .build();
+        let default = configure(&file, format, level);
+        let logger = Box::leak(Box::new(Logger {
+            file,
+            format,
+            default: RwLock::new(default),
+            raised,
+        }));
+        log::set_logger(logger).map_err(io::Error::other)?;
+        let _ = LOGGER.set(logger);
+        Ok(())
+    }
+}
+
+fn builder(file: &Option<SharedFile>, format: LogFormat) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(file) = file {
+        builder
+            .target(Target::Pipe(Box::new(file.clone())))
+            .write_style(WriteStyle::Never);
+    }
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let timestamp = buf.timestamp_millis();
+            write_json(buf, timestamp, record)
+        });
+    }
+    builder
+}
+
+/// Write `record` as a line of JSON: its timestamp, level, target and
+/// message, then the key-values logged with it like `replica`, `path` and
+/// `kind`.
+fn write_json(
+    out: &mut impl Write,
+    timestamp: impl Display,
+    record: &log::Record,
+) -> io::Result<()> {
+    let mut object = Map::new();
+    object.insert("timestamp".into(), timestamp.to_string().into());
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    object.insert("message".into(), record.args().to_string().into());
+    // Fields only fail to visit by returning an error themselves.
+    let _ = record.key_values().visit(&mut Fields(&mut object));
+    serde_json::to_writer(&mut *out, &object)?;
+    writeln!(out)
+}
+
+/// Adds the key-values of a record to its JSON object.
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().to_owned(), value);
+        Ok(())
+    }
+}
+
+/// The default logger at `level`, or what `RUST_LOG` says without one.
+fn configure(
+    file: &Option<SharedFile>,
+    format: LogFormat,
+    level: Option<LevelFilter>,
+) -> env_logger::Logger {
+    let mut builder = builder(file, format);
+    if let Some(level) = level {
+        builder.filter_level(level);
+    }
+    let default = builder.build();
+    CONFIGURED.store(default.filter() as usize, Ordering::Relaxed);
+    log::set_max_level(default.filter().max(raised_level()));
+    default
+}
+
+/// Replace the configured level, e.g. after the config file changed. `None`
+/// goes back to `RUST_LOG`.
+pub fn set_level(level: Option<LevelFilter>) {
+    if let Some(logger) = LOGGER.get() {
+        let default = configure(&logger.file, logger.format, level);
+        let filter = default.filter();
+        *logger.default.write().unwrap() = default;
+        info!("Logging at {} level.", filter);
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= raised_level() || self.default.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        let default = self.default.read().unwrap();
+        if default.matches(record) {
+            default.log(record)
+        } else if record.level() <= raised_level() {
+            self.raised.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.default.read().unwrap().flush()
+    }
+}
+
+fn raise_to(level: LevelFilter) {
+    RAISED.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(load(&CONFIGURED).max(level));
+}
+
+/// Log protocol traces and raw fsevents for the rest of the session.
+pub fn enable_debug_logging() {
+    if raised_level() < LevelFilter::Debug {
+        raise_to(LevelFilter::Debug);
+        info!("Debug logging enabled.");
+    }
+}
+
+fn next_level(level: LevelFilter) -> LevelFilter {
+    match level {
+        LevelFilter::Trace => LevelFilter::Off,
+        LevelFilter::Debug => LevelFilter::Trace,
+        _ => LevelFilter::Debug,
+    }
+}
+
+/// Step up to debug, then trace, then back to the configured level.
+pub fn cycle_log_level() {
+    let level = next_level(raised_level());
+    if level == LevelFilter::Off {
+        // Still at trace, so this makes it out.
+        info!("Logging at the configured level again.");
+        raise_to(level);
+    } else {
+        raise_to(level);
+        info!("Logging at {} level.", level);
+    }
+}
+
+/// When the log file is moved aside for a fresh one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// Once it would grow beyond this many bytes.
+    Size(u64),
+    /// On the first record of a new day, in UTC.
+    Daily,
+    Never,
+}
+
+/// `daily`, `never` or a size in bytes with an optional `K`, `M` or `G`
+/// suffix.
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => return Ok(Rotation::Daily),
+            "never" => return Ok(Rotation::Never),
+            _ => {}
+        }
+        let (digits, unit) = match s.char_indices().last() {
+            Some((idx, 'K' | 'k')) => (&s[..idx], 1 << 10),
+            Some((idx, 'M' | 'm')) => (&s[..idx], 1 << 20),
+            Some((idx, 'G' | 'g')) => (&s[..idx], 1 << 30),
+            _ => (s, 1),
+        };
+        match digits.parse::<u64>() {
+            Ok(size) if size > 0 => Ok(Rotation::Size(size.saturating_mul(unit))),
+            _ => Err(format!("{} is neither daily, never nor a size like 10M", s)),
+        }
+    }
+}
+
+fn today() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() / (24 * 60 * 60)
+}
+
+/// A log file rotated to `FILE.1`, `FILE.2` and so on, keeping a bounded
+/// number of old ones.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    keep: usize,
+    file: File,
+    size: u64,
+    day: u64,
+}
+
+impl RotatingFile {
+    /// Append to `path`, rotating it as `rotation` says and keeping `keep`
+    /// rotated files.
+    pub fn open(path: &Path, rotation: Rotation, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            rotation,
+            keep,
+            file,
+            size,
+            day: today(),
+        })
+    }
+
+    fn rotated(&self, idx: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", idx));
+        path.into()
+    }
+
+    fn due(&self, len: usize) -> bool {
+        match self.rotation {
+            Rotation::Size(max) => self.size > 0 && self.size + len as u64 > max,
+            Rotation::Daily => self.day != today(),
+            Rotation::Never => false,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // Renaming onto an existing file fails on Windows.
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for idx in (1..self.keep).rev() {
+                let from = self.rotated(idx);
+                if from.exists() {
+                    fs::rename(from, self.rotated(idx + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.day = today();
+        Ok(())
+    }
+}
+
+/// Rotates between records, `env_logger` writes each with one call.
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.due(buf.len()) {
+            self.rotate()?;
+        }
+        let len = self.file.write(buf)?;
+        self.size += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// One log file for both the default and the debug logger.
+#[derive(Clone)]
+struct SharedFile(Arc<Mutex<RotatingFile>>);
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+#[test]
+fn test_parse_rotation() {
+    assert_eq!("daily".parse(), Ok(Rotation::Daily));
+    assert_eq!("never".parse(), Ok(Rotation::Never));
+    assert_eq!("4096".parse(), Ok(Rotation::Size(4096)));
+    assert_eq!("64K".parse(), Ok(Rotation::Size(64 << 10)));
+    assert_eq!("10M".parse(), Ok(Rotation::Size(10 << 20)));
+    assert_eq!("1g".parse(), Ok(Rotation::Size(1 << 30)));
+    assert!("0".parse::<Rotation>().is_err());
+    assert!("M".parse::<Rotation>().is_err());
+    assert!("weekly".parse::<Rotation>().is_err());
+}
+
+#[test]
+fn test_rotate_by_size() {
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    let path = base.join("fsmonitor.log");
+
+    let mut file = RotatingFile::open(&path, Rotation::Size(10), 2).unwrap();
+    for record in ["first\n", "second\n", "third\n", "fourth\n"] {
+        file.write_all(record.as_bytes()).unwrap();
+    }
+    // A record larger than the limit still goes into a file of its own.
+    file.write_all(b"a long fifth\n").unwrap();
+
+    let read = |path: &Path| fs::read_to_string(path).unwrap();
+    assert_eq!(read(&path), "a long fifth\n");
+    assert_eq!(read(&base.join("fsmonitor.log.1")), "fourth\n");
+    assert_eq!(read(&base.join("fsmonitor.log.2")), "third\n");
+    assert!(!base.join("fsmonitor.log.3").exists());
+}
+
+#[test]
+fn test_rotate_daily() {
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    let path = base.join("fsmonitor.log");
+
+    let mut file = RotatingFile::open(&path, Rotation::Daily, 1).unwrap();
+    file.write_all(b"yesterday\n").unwrap();
+    file.day -= 1;
+    file.write_all(b"today\n").unwrap();
+    file.write_all(b"still today\n").unwrap();
+
+    let read = |path: &Path| fs::read_to_string(path).unwrap();
+    assert_eq!(read(&path), "today\nstill today\n");
+    assert_eq!(read(&base.join("fsmonitor.log.1")), "yesterday\n");
+}
+
+#[test]
+fn test_write_json() {
+    let mut out = Vec::new();
+    let fields: [(&str, &dyn log::kv::ToValue); 4] = [
+        ("replica", &"123"),
+        ("path", &"docs/a \"b\".txt"),
+        ("kind", &"Modify(Data(Any))"),
+        ("count", &3u64),
+    ];
+    write_json(
+        &mut out,
+        "2024-01-02T03:04:05.678Z",
+        &log::Record::builder()
+            .args(format_args!("replica {}: {} changed", 123, "docs"))
+            .level(log::Level::Debug)
+            .target("unison_fsmonitor::monitor")
+            .key_values(&fields)
+            .build(),
+    )
+    .unwrap();
+    let line = String::from_utf8(out).unwrap();
+    assert!(line.ends_with("}\n"));
+    assert_eq!(line.lines().count(), 1);
+    let object: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(
+        object,
+        serde_json::json!({
+            "timestamp": "2024-01-02T03:04:05.678Z",
+            "level": "DEBUG",
+            "target": "unison_fsmonitor::monitor",
+            "message": "replica 123: docs changed",
+            "replica": "123",
+            "path": "docs/a \"b\".txt",
+            "kind": "Modify(Data(Any))",
+            "count": 3,
+        })
+    );
+}
+
+#[test]
+fn test_next_level() {
+    assert_eq!(next_level(LevelFilter::Off), LevelFilter::Debug);
+    assert_eq!(next_level(LevelFilter::Debug), LevelFilter::Trace);
+    assert_eq!(next_level(LevelFilter::Trace), LevelFilter::Off);
+}
@@ -1,524 +1,328 @@
-use failure::{bail, Fallible};
-use log::{debug, info};
-use notify::{RawEvent, RecommendedWatcher, RecursiveMode};
-use std::collections::{HashMap, HashSet};
-use std::io::{stdin, stdout, BufRead, Write};
-use std::path::{Path, PathBuf};
+use clap::Parser;
+use log::{info, warn};
+use std::future::Future;
+use std::io::stdout;
+use std::path::Path;
 use std::process::exit;
-use std::sync::mpsc::channel;
-use std::thread;
-
-fn encode(s: &str) -> impl AsRef<str> {
-    percent_encoding::utf8_percent_encode(s, percent_encoding::NON_ALPHANUMERIC).to_string()
-}
-
-#[test]
-fn test_encode() {
-    assert_eq!(encode("before%after").as_ref(), "before%25after");
+use std::sync::Arc;
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::ServerOptions;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(windows)]
+use tokio::sync::Notify;
+use tokio::task::{spawn_local, LocalSet};
+use unison_fsmonitor::backend::{self, Background};
+use unison_fsmonitor::bench;
+#[cfg(windows)]
+use unison_fsmonitor::cli::ServiceAction;
+use unison_fsmonitor::cli::{Command, Options};
+use unison_fsmonitor::config::{Config, Settings};
+use unison_fsmonitor::instance::{self, PidFile, ReplicaLocks};
+use unison_fsmonitor::io::{self, ProtocolTransport, Stdio, Trace};
+use unison_fsmonitor::journal::{self, Journal};
+use unison_fsmonitor::logging::{Logger, RotatingFile};
+use unison_fsmonitor::metrics;
+use unison_fsmonitor::monitor::Monitor;
+use unison_fsmonitor::scan::SafetyNet;
+#[cfg(windows)]
+use unison_fsmonitor::service;
+use unison_fsmonitor::snapshot::{self, Errors};
+
+async fn once(options: Options, config: Config) -> anyhow::Result<()> {
+    let (tx, rx) = io::event_channel(io::EVENT_CAPACITY);
+    let factory = move |replica_id: &str, settings: &Settings| {
+        backend::select(settings)?.watcher(replica_id, tx.clone())
+    };
+    let mut monitor = Monitor::new(factory, Errors::default());
+    monitor.config = config;
+    snapshot::run(
+        &mut monitor,
+        rx,
+        &options.once,
+        options.format,
+        &mut stdout().lock(),
+    )
+    .await?;
+    Ok(())
 }
 
-fn decode<'a>(s: &'a str) -> impl AsRef<str> + 'a {
-    percent_encoding::percent_decode(s.as_bytes()).decode_utf8_lossy()
+async fn run_bench(path: &Path, files: usize, config: Config) -> anyhow::Result<()> {
+    // Watches are set up right away, to time them.
+    let (tx, rx) = io::event_channel(io::EVENT_CAPACITY);
+    let factory = move |replica_id: &str, settings: &Settings| {
+        backend::select(settings)?.watcher(replica_id, tx.clone())
+    };
+    let mut monitor = Monitor::new(factory, Errors::default());
+    monitor.config = config;
+    bench::run(&mut monitor, rx, path, files, &mut stdout().lock()).await?;
+    Ok(())
 }
 
-fn parse_input(input: &str) -> Fallible<(String, Vec<String>)> {
-    let mut cmd = String::new();
-    let mut args = vec![];
-    for (idx, word) in input.split_whitespace().enumerate() {
-        if idx == 0 {
-            cmd = word.to_owned();
-        } else {
-            args.push(decode(word).as_ref().to_owned())
-        }
+async fn serve(options: Options, config: Config) -> anyhow::Result<()> {
+    let _pid_file = match &options.pid_file {
+        Some(path) => Some(PidFile::create(path)?),
+        None => None,
+    };
+    #[cfg(unix)]
+    io::spawn_log_level_signal()?;
+    let trace = open_trace(&options)?;
+    if let Some(addr) = &options.metrics_listen {
+        metrics::spawn_server(addr).await?;
+    }
+    match options.listen.clone() {
+        Some(addr) => listen(Arc::new(options), &addr, std::future::pending(), trace).await,
+        None => session(Stdio, Arc::new(options), config, trace).await,
     }
-    Ok((cmd, args))
 }
 
-#[derive(Debug)]
-enum Event {
-    Input(String),
-    FSEvent(RawEvent),
+/// The file `--trace` names, rotated like the log file.
+fn open_trace(options: &Options) -> std::io::Result<Option<Trace>> {
+    let trace = match &options.trace {
+        Some(path) => Some(Trace::new(RotatingFile::open(
+            path,
+            options.log_rotate,
+            options.log_keep,
+        )?)),
+        None => None,
+    };
+    Ok(trace)
 }
 
-trait Watch {
-    fn watch(&mut self, _path: &Path, _recursive_mode: RecursiveMode) -> Fallible<()> {
-        Ok(())
-    }
-
-    fn unwatch(&mut self, _path: &Path) -> Fallible<()> {
-        Ok(())
+/// Serve a session for each connection to `addr` until `stop`, each with
+/// the config as it is when the connection comes in. Their lines go to
+/// `trace` marked with the address of the peer.
+async fn listen(
+    options: Arc<Options>,
+    addr: &str,
+    stop: impl Future<Output = ()>,
+    trace: Option<Trace>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Listening on {}", listener.local_addr()?);
+    tokio::pin!(stop);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = &mut stop => return Ok(()),
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Cannot accept a connection: {}", err);
+                continue;
+            }
+        };
+        spawn_session(stream, peer, &options, trace.as_ref());
     }
 }
 
-impl Watch for RecommendedWatcher {
-    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Fallible<()> {
-        Ok(notify::Watcher::watch(self, path, recursive_mode)?)
-    }
-
-    fn unwatch(&mut self, path: &Path) -> Fallible<()> {
-        Ok(notify::Watcher::unwatch(self, path)?)
+/// Serve a session for each client of the named pipe `name` until `stop`,
+/// as `listen` does for TCP. Only `user`, a SID, may connect besides the
+/// local system.
+#[cfg(windows)]
+async fn listen_pipe(
+    options: Arc<Options>,
+    name: &str,
+    user: &str,
+    stop: impl Future<Output = ()>,
+    trace: Option<Trace>,
+) -> anyhow::Result<()> {
+    let mut security = service::PipeSecurity::new(user)?;
+    let mut create = |first| {
+        let mut pipe = ServerOptions::new();
+        pipe.first_pipe_instance(first);
+        // SAFETY: the attributes outlive the call.
+        unsafe { pipe.create_with_security_attributes_raw(name, security.attributes()) }
+    };
+    // Failing if another monitor listens there already.
+    let mut server = create(true)?;
+    info!("Listening on {}", name);
+    tokio::pin!(stop);
+    loop {
+        let connected = tokio::select! {
+            connected = server.connect() => connected,
+            () = &mut stop => return Ok(()),
+        };
+        // The next client connects to a fresh instance.
+        let client = std::mem::replace(&mut server, create(false)?);
+        if let Err(err) = connected {
+            warn!("Cannot accept a connection: {}", err);
+            continue;
+        }
+        let peer = match service::client_pid(&client) {
+            Some(pid) => format!("process {}", pid),
+            None => "a process".into(),
+        };
+        spawn_session(client, peer, &options, trace.as_ref());
     }
 }
 
-type Id = String;
-
-#[derive(Debug)]
-struct Replica {
-    pub root: PathBuf,
-    /// Currently being watched paths.
-    pub paths: HashSet<PathBuf>,
-    /// Paths of pending changes. Paths are relative as required by unison.
-    pub pending_changes: HashSet<PathBuf>,
+/// Serve a session with `peer` over `transport`, with the config as it is
+/// now, logging how it ends. Its lines go to `trace` marked with `peer`.
+fn spawn_session(
+    transport: impl ProtocolTransport + 'static,
+    peer: impl std::fmt::Display + 'static,
+    options: &Arc<Options>,
+    trace: Option<&Trace>,
+) {
+    info!("Session with {} started", peer);
+    let options = options.clone();
+    let trace = trace.map(|trace| trace.session(&peer));
+    spawn_local(async move {
+        let ended = match options.config() {
+            Ok(config) => session(transport, options, config, trace).await,
+            Err(err) => Err(err.into()),
+        };
+        match ended {
+            Ok(()) => info!("Session with {} ended", peer),
+            Err(err) => warn!("Session with {} failed: {:#}", peer, err),
+        }
+    });
 }
 
-impl Replica {
-    pub fn new(root: PathBuf) -> Replica {
-        Replica {
-            root,
-            paths: HashSet::new(),
-            pending_changes: HashSet::new(),
+/// Serve unison over `transport` until it closes it.
+async fn session(
+    transport: impl ProtocolTransport,
+    options: Arc<Options>,
+    config: Config,
+    trace: Option<Trace>,
+) -> anyhow::Result<()> {
+    // Input, fsevents, ticks and reloads all feed one channel, so each is
+    // handled as soon as it arrives without polling.
+    let (tx, rx) = io::event_channel(io::EVENT_CAPACITY);
+    let watcher_tx = tx.clone();
+    // Unison hears back on START while its watches are still being set up.
+    let factory = move |replica_id: &str, settings: &Settings| {
+        let mut watcher = backend::select(settings)?.watcher(replica_id, watcher_tx.clone())?;
+        if let Some(interval) = settings.safety_scan {
+            let net = SafetyNet::new(watcher, replica_id, watcher_tx.clone(), interval);
+            watcher = Box::new(net);
         }
+        Ok(Background::new(watcher, replica_id, watcher_tx.clone()))
+    };
+
+    let (responses, protocol) = io::spawn_protocol(transport, tx.clone(), trace);
+    let mut monitor = Monitor::new(factory, responses);
+    monitor.selective = options.selective;
+    monitor.config = config;
+    monitor.locks = Some(ReplicaLocks::new(
+        instance::default_dir(),
+        options.duplicates,
+    ));
+    monitor.journal = options
+        .state_dir
+        .clone()
+        .or_else(journal::default_dir)
+        .map(Journal::new);
+
+    // Sessions over TCP read the config as they start instead.
+    #[cfg(unix)]
+    if options.listen.is_none() {
+        io::spawn_reload_signal(tx.clone(), move || {
+            let config = options.config()?;
+            unison_fsmonitor::logging::set_level(options.log_level.or(config.log_level));
+            Ok(config)
+        })?;
     }
+    #[cfg(unix)]
+    io::spawn_stats_signal(tx.clone())?;
+    io::spawn_ticker(tx);
+    let monitor = io::spawn_monitor(monitor, rx);
 
-    /// Check if path is being watched in this replica.
-    pub fn is_watching(&self, path: &Path) -> bool {
-        self.paths.iter().any(|base| path.starts_with(base))
-    }
-}
+    io::join(monitor, protocol).await?;
 
-struct Monitor<WATCH: Watch, WRITE: Write> {
-    pub current_path: PathBuf,
-    pub replicas: HashMap<Id, Replica>,
-    pub link_map: HashMap<PathBuf, HashSet<PathBuf>>,
-    pub watcher: WATCH,
-    pub writer: WRITE,
+    Ok(())
 }
 
-impl<WATCH: Watch, WRITE: Write> Monitor<WATCH, WRITE> {
-    pub fn new(watcher: WATCH, writer: WRITE) -> Self {
-        Self {
-            current_path: PathBuf::new(),
-            replicas: HashMap::new(),
-            link_map: HashMap::new(),
-            watcher,
-            writer,
+#[cfg(windows)]
+fn run_service(action: &ServiceAction) -> anyhow::Result<()> {
+    match action {
+        ServiceAction::Install => {
+            let args: Vec<String> = std::env::args().skip(1).collect();
+            service::install(&args)?;
         }
+        ServiceAction::Uninstall => service::uninstall()?,
+        ServiceAction::Run { .. } => service::run(serve_service)?,
     }
+    Ok(())
+}
 
-    pub fn is_watching(&self, path: &Path) -> bool {
-        self.replicas
-            .values()
-            .any(|replica| replica.is_watching(path))
-    }
-
-    pub fn handle_event(&mut self, event: Event) -> Fallible<()> {
-        debug!("event: {:?}", event);
-
-        match event {
-            Event::Input(input) => {
-                let (cmd, args) = parse_input(&input)?;
-
-                match cmd.as_str() {
-                    "VERSION" => {
-                        let version = &args[0];
-                        if version != "1" {
-                            bail!("Unexpected version: {:?}", version);
-                        }
-
-                        self.send_cmd("VERSION", &["1"]);
-                    }
-                    "START" => {
-                        // Start or append watching dirs.
-                        // e.g.,
-                        // START 123 root
-                        // START 123 root subdir
-                        let replica_id = args[0].clone();
-                        let root = PathBuf::from(&args[1]);
-                        self.current_path = root.clone();
-
-                        if let Some(dir) = args.get(2) {
-                            self.current_path = self.current_path.join(dir);
-                        }
-
-                        let replica = self
-                            .replicas
-                            .entry(replica_id)
-                            .or_insert_with(|| Replica::new(root));
-
-                        if !replica.is_watching(&self.current_path) {
-                            self.watcher
-                                .watch(&self.current_path, RecursiveMode::Recursive)?;
-                            replica.paths.insert(self.current_path.clone());
-                        }
-
-                        debug!("replicas: {:?}", self.replicas);
-                        self.send_ack();
-                    }
-                    "DIR" => {
-                        // Add sub-dir to watch list.
-                        self.send_ack();
-                    }
-                    "LINK" => {
-                        // Follow a link.
-                        let path = self
-                            .current_path
-                            .join(args.get(0).cloned().unwrap_or_default());
-                        let realpath = path.canonicalize()?;
-
-                        self.watcher.watch(&realpath, RecursiveMode::Recursive)?;
-                        self.link_map.entry(realpath).or_default().insert(path);
-                        debug!("link_map: {:?}", self.link_map);
-                        self.send_ack();
-                    }
-                    "WAIT" => {
-                        // Start waiting replica.
-                        let replica_id = &args[0];
-                        if !self.replicas.contains_key(replica_id) {
-                            self.send_error(&format!("Unknown replica: {}", replica_id));
-                        }
-                    }
-                    "CHANGES" => {
-                        // Request pending changes.
-                        let replica_id = &args[0];
-                        let mut changed_paths = HashSet::new();
-                        if let Some(replica) = self.replicas.get_mut(replica_id) {
-                            changed_paths.extend(replica.pending_changes.drain());
-                        }
-                        for p in changed_paths {
-                            self.send_recursive(&p);
-                        }
-                        self.send_done();
-                    }
-                    "RESET" => {
-                        // Stop observing replica.
-                        let replica_id = &args[0];
-                        if let Some(replica) = self.replicas.remove(replica_id) {
-                            for path in &replica.paths {
-                                if !self.is_watching(&path) {
-                                    self.watcher.unwatch(&path)?;
-                                }
-                            }
-                        }
-                        debug!("replicas: {:?}", self.replicas);
-                    }
-                    "DEBUG" | "DONE" => {
-                        // TODO: update debug level.
-                    }
-                    _ => {
-                        self.send_error(&format!("Unrecognized cmd: {}", cmd));
-                    }
-                }
-            }
-            Event::FSEvent(fsevent) => {
-                let mut matched_replica_ids = HashSet::new();
-
-                if let Some(path) = fsevent.path {
-                    let mut paths = vec![path.clone()];
-                    // Get all possible symbolic links for this path.
-                    for (realpath, links) in &self.link_map {
-                        if let Ok(postfix) = path.strip_prefix(realpath) {
-                            for link in links {
-                                paths.push(link.join(postfix));
-                            }
-                        }
-                    }
-
-                    for (id, replica) in self.replicas.iter_mut() {
-                        for path in &paths {
-                            if let Ok(relative_path) = path.strip_prefix(&replica.root) {
-                                matched_replica_ids.insert(id.clone());
-                                // Unison requires relative path for changes.
-                                replica.pending_changes.insert(relative_path.into());
-                            }
-                        }
-                    }
-                }
-
-                if matched_replica_ids.is_empty() {
-                    info!("No replica found for event.")
-                }
-
-                for id in &matched_replica_ids {
-                    self.send_changes(id);
-                }
-            }
+/// What Windows runs as the service: a session for each connection to
+/// `--listen`, or to `io::SERVICE_PIPE`, until the service is stopped.
+#[cfg(windows)]
+fn serve_service(stop: Arc<Notify>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let options = Arc::new(Options::parse());
+    let user = match &options.command {
+        Some(Command::Service {
+            action: ServiceAction::Run { user },
+        }) => user.clone(),
+        _ => return Err("Not run as the service".into()),
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let trace = open_trace(&options)?;
+    let stopped = async move { stop.notified().await };
+    LocalSet::new().block_on(&runtime, async {
+        if let Some(addr) = &options.metrics_listen {
+            metrics::spawn_server(addr).await?;
         }
-
-        Ok(())
-    }
-
-    fn send_cmd(&mut self, cmd: &str, args: &[&str]) {
-        let mut output = cmd.to_owned();
-        for arg in args {
-            output += " ";
-            output += &encode(arg).as_ref();
+        match &options.listen {
+            Some(addr) => listen(options.clone(), addr, stopped, trace).await,
+            None => listen_pipe(options.clone(), io::SERVICE_PIPE, &user, stopped, trace).await,
         }
-
-        debug!(">> {}", output);
-        let _ = writeln!(self.writer, "{}", output);
-    }
-
-    fn send_ack(&mut self) {
-        self.send_cmd("OK", &[]);
-    }
-
-    fn send_changes(&mut self, replica: &str) {
-        self.send_cmd("CHANGES", &[replica]);
-    }
-
-    fn send_recursive(&mut self, path: &Path) {
-        self.send_cmd("RECURSIVE", &[&path.to_string_lossy()]);
-    }
-
-    fn send_done(&mut self) {
-        self.send_cmd("DONE", &[]);
-    }
-
-    fn send_error(&mut self, msg: &str) {
-        self.send_cmd("ERROR", &[msg]);
-        exit(1);
-    }
+    })?;
+    Ok(())
 }
 
-#[cfg(test)]
-mod test {
-    use crate::*;
-    use notify::Op;
-    use std::io::Cursor;
-
-    struct Watcher {}
-
-    impl Watch for Watcher {}
-
-    #[test]
-    fn test_version() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-
-        monitor
-            .handle_event(Event::Input("VERSION 1\n".into()))
-            .unwrap();
-
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec!["VERSION 1"]
-        );
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let options = Options::parse();
+    let config = options.config()?;
+    let log_file = match &options.log_file {
+        Some(path) => Some(RotatingFile::open(
+            path,
+            options.log_rotate,
+            options.log_keep,
+        )?),
+        None => None,
+    };
+    Logger::init(
+        log_file,
+        options.log_format,
+        options.log_level.or(config.log_level),
+    )?;
+
+    if let Some(Command::Bench { path, files }) = &options.command {
+        return run_bench(path, *files, config).await;
     }
-
-    #[test]
-    fn test_start() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-        let id = "123";
-        let root = PathBuf::from("/tmp/sample");
-
-        monitor
-            .handle_event(Event::Input(format!(
-                "START {} {}\n",
-                id,
-                root.to_string_lossy()
-            )))
-            .unwrap();
-
-        assert_eq!(monitor.replicas.len(), 1);
-        assert!(monitor.replicas.contains_key(id));
-        assert_eq!(monitor.replicas.get(id).unwrap().root, root);
-        assert!(monitor.replicas.get(id).unwrap().paths.contains(&root));
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec!["OK"]
-        );
-    }
-
-    #[test]
-    fn test_start_with_subdir() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-        let id = "123";
-        let root = PathBuf::from("/tmp/sample");
-        let subdir = PathBuf::from("subdir");
-
-        monitor
-            .handle_event(Event::Input(format!(
-                "START {} {} {}\n",
-                id,
-                root.to_string_lossy(),
-                subdir.to_string_lossy()
-            )))
-            .unwrap();
-
-        assert_eq!(monitor.replicas.len(), 1);
-        assert!(monitor.replicas.contains_key(id));
-        assert_eq!(monitor.replicas.get(id).unwrap().root, root);
-        assert!(monitor
-            .replicas
-            .get(id)
-            .unwrap()
-            .paths
-            .contains(&root.join(&subdir)));
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec!["OK"]
-        );
+    #[cfg(windows)]
+    if let Some(Command::Service { action }) = &options.command {
+        return run_service(action);
     }
-
-    #[test]
-    fn test_dir() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-
-        monitor.handle_event(Event::Input("DIR\n".into())).unwrap();
-
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec!["OK"]
-        );
+    if !options.once.is_empty() {
+        return once(options, config).await;
     }
-
-    #[test]
-    fn test_dir_with_dir() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-
-        monitor
-            .handle_event(Event::Input("DIR dir\n".into()))
-            .unwrap();
-
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec!["OK"]
-        );
-    }
-
-    #[test]
-    fn test_changes() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-        let id = "123";
-        let root = "/tmp/sample";
-        let filename = "filename";
-
-        monitor
-            .handle_event(Event::Input(format!("START {} {}\n", id, root)))
-            .unwrap();
-        monitor
-            .handle_event(Event::FSEvent(RawEvent {
-                path: Option::Some(PathBuf::from(root).join(filename)),
-                op: Result::Ok(Op::CREATE),
-                cookie: None,
-            }))
-            .unwrap();
-        monitor
-            .handle_event(Event::Input(format!("CHANGES {}\n", id)))
-            .unwrap();
-
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec![
-                "OK",
-                &format!("CHANGES {}", id),
-                &format!("RECURSIVE {}", filename),
-                "DONE"
-            ]
-        );
-    }
-
-    #[test]
-    fn test_changes_with_subdir() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-        let id = "123";
-        let root = "/tmp/sample";
-        let subdir = "subdir";
-        let filename = "filename";
-
-        monitor
-            .handle_event(Event::Input(format!("START {} {} {}\n", id, root, subdir)))
-            .unwrap();
-        monitor
-            .handle_event(Event::FSEvent(RawEvent {
-                path: Option::Some(PathBuf::from(root).join(subdir).join(filename)),
-                op: Result::Ok(Op::CREATE),
-                cookie: None,
-            }))
-            .unwrap();
-        monitor
-            .handle_event(Event::Input(format!("CHANGES {}\n", id)))
-            .unwrap();
-
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec![
-                "OK",
-                &format!("CHANGES {}", id),
-                &format!("RECURSIVE {}%2F{}", subdir, filename),
-                "DONE"
-            ]
-        );
-    }
-}
-
-fn main() -> Fallible<()> {
-    env_logger::init();
-
-    let (fsevent_tx, fsevent_rx) = channel();
-    let watcher: RecommendedWatcher = notify::Watcher::new_raw(fsevent_tx)?;
-
-    let stdout = stdout();
-    let stdout = stdout.lock();
-    let mut monitor = Monitor::new(watcher, stdout);
-
-    let (tx, rx) = channel();
-
-    let tx_clone = tx.clone();
-    thread::spawn(move || -> Fallible<()> {
-        let stdin = stdin();
-        let mut handle = stdin.lock();
-
-        loop {
-            let mut input = String::new();
-            handle.read_line(&mut input)?;
-            tx_clone.send(Event::Input(input))?;
+    if let (None, Some(addr)) = (&options.listen, &options.connect) {
+        #[cfg(windows)]
+        if io::is_pipe(addr) {
+            io::relay(io::connect_pipe(addr).await?).await?;
+            exit(0);
         }
-    });
-
-    thread::spawn(move || -> Fallible<()> {
-        for event in fsevent_rx {
-            tx.send(Event::FSEvent(event))?;
-        }
-        Ok(())
-    });
+        let stream = TcpStream::connect(addr).await?;
+        io::relay(stream).await?;
+        // Stdin is still read from and would keep the runtime from shutting
+        // down.
+        exit(0);
+    }
 
-    for event in rx {
-        monitor.handle_event(event)?;
+    // The monitor actor is not `Send`, it runs on this thread alongside the
+    // protocol actor's tasks.
+    if let Err(err) = LocalSet::new().run_until(serve(options, config)).await {
+        // Stdin may still be open and its blocking read would keep the
+        // runtime from shutting down.
+        eprintln!("Error: {:#}", err);
+        exit(1);
     }
 
     Ok(())
@@ -0,0 +1,255 @@
+//! Counters and gauges summed over all sessions, served over HTTP in the
+//! Prometheus text format at `--metrics-listen`, for alerting on dropped
+//! events, growing queues or failing watches.
+
+use log::{info, warn};
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Only ever goes up.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Goes up and down, as the sessions holding a `Share` of it set theirs.
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What one session adds to a gauge, taken out again once it is dropped.
+pub struct Share {
+    gauge: &'static Gauge,
+    value: i64,
+}
+
+impl Share {
+    pub fn new(gauge: &'static Gauge) -> Self {
+        Self { gauge, value: 0 }
+    }
+
+    pub fn set(&mut self, value: usize) {
+        let value = value as i64;
+        self.gauge
+            .0
+            .fetch_add(value - self.value, Ordering::Relaxed);
+        self.value = value;
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+pub static EVENTS: Counter = Counter::new();
+pub static EVENTS_DROPPED: Counter = Counter::new();
+pub static OVERFLOWS: Counter = Counter::new();
+pub static CHANGES_REPORTED: Counter = Counter::new();
+pub static WATCH_ERRORS: Counter = Counter::new();
+pub static REPLICAS: Gauge = Gauge::new();
+pub static PENDING_CHANGES: Gauge = Gauge::new();
+pub static QUEUED_EVENTS: Gauge = Gauge::new();
+
+const COUNTERS: [(&str, &str, &Counter); 5] = [
+    (
+        "unison_fsmonitor_events_total",
+        "Filesystem events handled.",
+        &EVENTS,
+    ),
+    (
+        "unison_fsmonitor_events_dropped_total",
+        "Filesystem events dropped as the monitor fell behind.",
+        &EVENTS_DROPPED,
+    ),
+    (
+        "unison_fsmonitor_overflows_total",
+        "Times events were lost, to a full queue or an overflowing watcher.",
+        &OVERFLOWS,
+    ),
+    (
+        "unison_fsmonitor_changes_reported_total",
+        "Changes reported to unison.",
+        &CHANGES_REPORTED,
+    ),
+    (
+        "unison_fsmonitor_watch_errors_total",
+        "Watches that could not be set up.",
+        &WATCH_ERRORS,
+    ),
+];
+
+const GAUGES: [(&str, &str, &Gauge); 3] = [
+    (
+        "unison_fsmonitor_replicas",
+        "Replicas being monitored.",
+        &REPLICAS,
+    ),
+    (
+        "unison_fsmonitor_pending_changes",
+        "Changes waiting for unison to ask for them.",
+        &PENDING_CHANGES,
+    ),
+    (
+        "unison_fsmonitor_queued_events",
+        "Filesystem events queued for the monitor.",
+        &QUEUED_EVENTS,
+    ),
+];
+
+/// All metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut text = String::new();
+    for (name, help, counter) in COUNTERS {
+        let _ = write!(
+            text,
+            "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+            name,
+            help,
+            name,
+            name,
+            counter.get()
+        );
+    }
+    for (name, help, gauge) in GAUGES {
+        let _ = write!(
+            text,
+            "# HELP {} {}\n# TYPE {} gauge\n{} {}\n",
+            name,
+            help,
+            name,
+            name,
+            gauge.get()
+        );
+    }
+    text
+}
+
+/// Serve the metrics at `addr` for as long as the process runs.
+pub async fn spawn_server(addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(err) = respond(stream).await {
+                            warn!("Cannot serve metrics: {}", err);
+                        }
+                    });
+                }
+                Err(err) => warn!("Cannot accept a connection for metrics: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Answer one request, `GET /metrics` with the metrics and anything else
+/// with an error, then close the connection.
+async fn respond(mut stream: TcpStream) -> io::Result<()> {
+    // Only the request line matters, which fits in what arrives first.
+    let mut request = [0; 1024];
+    let len = stream.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..len]);
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        (Some("GET"), _) => ("404 Not Found", "Not found, try /metrics\n".into()),
+        _ => ("405 Method Not Allowed", "Only GET is served\n".into()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[test]
+fn test_share() {
+    static GAUGE: Gauge = Gauge::new();
+
+    let mut first = Share::new(&GAUGE);
+    let mut second = Share::new(&GAUGE);
+    first.set(3);
+    second.set(4);
+    assert_eq!(GAUGE.get(), 7);
+    first.set(1);
+    assert_eq!(GAUGE.get(), 5);
+    drop(second);
+    assert_eq!(GAUGE.get(), 1);
+}
+
+#[tokio::test]
+async fn test_server() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    spawn_server(&addr).await.unwrap();
+
+    let get = |request: &'static str| {
+        let addr = addr.clone();
+        async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        }
+    };
+
+    let response = get("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(head.contains(&format!("Content-Length: {}", body.len())));
+    for (name, _, _) in COUNTERS {
+        assert!(body.contains(&format!("# TYPE {} counter\n{} ", name, name)));
+    }
+    for (name, _, _) in GAUGES {
+        assert!(body.contains(&format!("# TYPE {} gauge\n{} ", name, name)));
+    }
+    // Every sample is a name and a number.
+    for line in body.lines().filter(|line| !line.starts_with('#')) {
+        let (_, value) = line.split_once(' ').unwrap();
+        value.parse::<i64>().unwrap();
+    }
+
+    let response = get("GET / HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    let response = get("POST /metrics HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+}
@@ -0,0 +1,4627 @@
+//! Replica bookkeeping: the watch registry, pending changes per replica and
+//! the protocol commands driving them.
+
+use crate::coarse;
+use crate::config::{Config, Settings};
+use crate::error::{MonitorError, Result};
+use crate::filter::{self, Glob};
+use crate::gitignore::{self, GitIgnores};
+use crate::hardlinks::Hardlinks;
+use crate::index::ReplicaIndex;
+use crate::instance::{Duplicates, ReplicaLocks};
+use crate::journal::Journal;
+use crate::logging::enable_debug_logging;
+use crate::metrics;
+use crate::netfs;
+use crate::paths::{
+    expand_short_names, is_case_insensitive, is_mount_point, normalize_unicode, normalize_windows,
+    strip_prefix_ignore_case,
+};
+use crate::pipeline::{Change, Pipeline};
+use crate::protocol::{Id, Phase, Request, Response, ResponseSink, SUPPORTED_VERSIONS};
+use crate::scan;
+use log::{debug, info, warn};
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::RecursiveMode;
+use std::borrow::Cow;
+use std::cell::{Ref, RefCell};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How many changes may be pending for a replica before it is rescanned as a
+/// whole instead.
+pub const MAX_PENDING: usize = 100_000;
+
+/// How long the watches of a reset replica are kept for a replica starting
+/// on the same root, as unison starts its replicas again on reconnecting.
+pub const PARK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long the first half of a rename waits for the second to pair with.
+pub const RENAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Id a watcher tags its events with, shared rather than copied for each.
+pub type Tag = Arc<str>;
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Event {
+    Input(String),
+    /// From a watcher serving all replicas.
+    FSEvent(notify::Event),
+    /// From the watcher of the replicas sharing it, see `WatchRegistry`.
+    ReplicaEvent(Tag, notify::Event),
+    /// Unison closed its end of stdin.
+    Eof,
+    /// Reading from unison failed, nothing more will arrive.
+    InputError(std::io::Error),
+    /// Periodic check that watched paths still exist.
+    Tick,
+    /// The config file was read again.
+    Reload(Box<Config>),
+    /// A watch set up in the background is in place, or failed.
+    Watched(Tag, PathBuf, Result<()>),
+    /// Filesystem events were dropped as the monitor fell behind, by the tag
+    /// of the watcher they came from or `None` for the one serving all.
+    Overflow(HashMap<Option<Tag>, usize>),
+    /// Statistics were asked for with `SIGUSR1`.
+    Stats,
+    /// The hard links below a root were mapped in the background, for the
+    /// replicas there sharing the watcher with this tag.
+    Hardlinks(Tag, PathBuf, Hardlinks),
+}
+
+impl Event {
+    /// Whether the event comes from a watcher. Those queue behind everything
+    /// else and are dropped when too many pile up: unison gives up on
+    /// answers that take too long.
+    pub fn is_filesystem(&self) -> bool {
+        matches!(self, Event::FSEvent(_) | Event::ReplicaEvent(..))
+    }
+}
+
+pub trait Watch {
+    fn watch(&mut self, _path: &Path, _recursive_mode: RecursiveMode) -> Result<()> {
+        Ok(())
+    }
+
+    fn unwatch(&mut self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// Check that a replica path can be watched at all.
+    fn validate(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// Set up the watch of `path` again, e.g. after its directory was
+    /// deleted and recreated.
+    fn refresh(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        // The old watch died with the directory, failing to drop it is fine.
+        let _ = self.unwatch(path);
+        self.watch(path, recursive_mode)
+    }
+
+    /// Map the hard links below `root`, right away or, where walking the
+    /// tree would hold up unison, later as `Event::Hardlinks`.
+    fn hardlinks(&mut self, root: &Path) -> Option<Hardlinks> {
+        Some(Hardlinks::scan(root))
+    }
+}
+
+/// Fail unless `path` is an existing, readable directory.
+pub fn validate_dir(path: &Path) -> Result<()> {
+    if !fs::metadata(path)?.is_dir() {
+        return Err(MonitorError::WatchError("Not a directory".into()));
+    }
+    fs::read_dir(path)?;
+    Ok(())
+}
+
+impl<T: Watch + ?Sized> Watch for Box<T> {
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        (**self).watch(path, recursive_mode)
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        (**self).unwatch(path)
+    }
+
+    fn validate(&self, path: &Path) -> Result<()> {
+        (**self).validate(path)
+    }
+
+    fn refresh(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        (**self).refresh(path, recursive_mode)
+    }
+
+    fn hardlinks(&mut self, root: &Path) -> Option<Hardlinks> {
+        (**self).hardlinks(root)
+    }
+}
+
+/// Reference-counted watch registrations. Only the outermost registered paths
+/// hold a recursive watch, nested ones are served by it and get their own
+/// watch back once it goes away. Single directories (see `DIR`) are watched
+/// non-recursively unless a recursive registration already covers them.
+pub struct Watches<WATCH: Watch> {
+    pub watcher: WATCH,
+    pub counts: HashMap<PathBuf, usize>,
+    pub singles: HashMap<PathBuf, usize>,
+    /// Registrations of a reset replica whose watcher another one took over,
+    /// see `WatchRegistry::reattach`. They go to the first to register them
+    /// again, the rest is dropped with `release_leftover`.
+    pub leftover: HashSet<PathBuf>,
+    pub leftover_singles: HashSet<PathBuf>,
+}
+
+impl<WATCH: Watch> Watches<WATCH> {
+    pub fn new(watcher: WATCH) -> Self {
+        Self {
+            watcher,
+            counts: HashMap::new(),
+            singles: HashMap::new(),
+            leftover: HashSet::new(),
+            leftover_singles: HashSet::new(),
+        }
+    }
+
+    /// Check if a registered path other than `path` itself contains it.
+    fn is_covered(&self, path: &Path) -> bool {
+        self.counts
+            .keys()
+            .any(|base| base != path && path.starts_with(base))
+    }
+
+    /// Check if a recursive registration contains the single directory `path`.
+    fn is_covered_single(&self, path: &Path) -> bool {
+        self.counts.keys().any(|base| path.starts_with(base))
+    }
+
+    /// Registered paths under `path` that currently hold their own watch.
+    fn outermost_under(&self, path: &Path) -> Vec<PathBuf> {
+        self.counts
+            .keys()
+            .filter(|p| *p != path && p.starts_with(path) && !self.is_covered(p))
+            .cloned()
+            .collect()
+    }
+
+    /// Single directories under `path` that currently hold their own watch.
+    fn singles_under(&self, path: &Path) -> Vec<PathBuf> {
+        self.singles
+            .keys()
+            .filter(|p| p.starts_with(path) && !self.is_covered_single(p))
+            .cloned()
+            .collect()
+    }
+
+    pub fn add(&mut self, path: &Path) -> Result<()> {
+        if let Some(count) = self.counts.get_mut(path) {
+            if !self.leftover.remove(path) {
+                *count += 1;
+            }
+            return Ok(());
+        }
+
+        if !self.is_covered(path) {
+            // Backends share per-directory watches, so nested watches must go
+            // before the outer one is set up.
+            let nested = self.outermost_under(path);
+            let singles = self.singles_under(path);
+            for p in nested.iter().chain(&singles) {
+                self.unwatch(p);
+            }
+            if let Err(err) = self.watcher.watch(path, RecursiveMode::Recursive) {
+                for p in &nested {
+                    self.watch(p, RecursiveMode::Recursive);
+                }
+                for p in &singles {
+                    self.watch(p, RecursiveMode::NonRecursive);
+                }
+                return Err(err);
+            }
+        }
+        self.counts.insert(path.to_owned(), 1);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        if !Self::release(&mut self.counts, path) {
+            return;
+        }
+
+        if !self.is_covered(path) {
+            self.unwatch(path);
+            for p in self.outermost_under(path) {
+                self.watch(&p, RecursiveMode::Recursive);
+            }
+            for p in self.singles_under(path) {
+                self.watch(&p, RecursiveMode::NonRecursive);
+            }
+        }
+    }
+
+    pub fn add_single(&mut self, path: &Path) -> Result<()> {
+        if let Some(count) = self.singles.get_mut(path) {
+            if !self.leftover_singles.remove(path) {
+                *count += 1;
+            }
+            return Ok(());
+        }
+
+        if !self.is_covered_single(path) {
+            self.watcher.watch(path, RecursiveMode::NonRecursive)?;
+        }
+        self.singles.insert(path.to_owned(), 1);
+        Ok(())
+    }
+
+    pub fn remove_single(&mut self, path: &Path) {
+        if Self::release(&mut self.singles, path) && !self.is_covered_single(path) {
+            self.unwatch(path);
+        }
+    }
+
+    /// Drop the registrations taken over from a reset replica that were not
+    /// registered again.
+    pub fn release_leftover(&mut self) {
+        for path in std::mem::take(&mut self.leftover) {
+            self.remove(&path);
+        }
+        for path in std::mem::take(&mut self.leftover_singles) {
+            self.remove_single(&path);
+        }
+    }
+
+    /// Set up the watch for a registered path again, e.g. after its directory
+    /// was deleted and recreated.
+    pub fn refresh(&mut self, path: &Path) -> Result<()> {
+        let recursive_mode = if self.counts.contains_key(path) && !self.is_covered(path) {
+            RecursiveMode::Recursive
+        } else if self.singles.contains_key(path) && !self.is_covered_single(path) {
+            RecursiveMode::NonRecursive
+        } else {
+            return Ok(());
+        };
+        self.watcher.refresh(path, recursive_mode)
+    }
+
+    /// Drop one reference, returning whether it was the last one.
+    fn release(counts: &mut HashMap<PathBuf, usize>, path: &Path) -> bool {
+        match counts.get_mut(path) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => {
+                counts.remove(path);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Paths holding a watch of their own, with the mode they are watched in.
+    fn watched(&self) -> Vec<(PathBuf, RecursiveMode)> {
+        let recursive = self
+            .counts
+            .keys()
+            .filter(|p| !self.is_covered(p))
+            .map(|p| (p.clone(), RecursiveMode::Recursive));
+        let singles = self
+            .singles
+            .keys()
+            .filter(|p| !self.is_covered_single(p))
+            .map(|p| (p.clone(), RecursiveMode::NonRecursive));
+        recursive.chain(singles).collect()
+    }
+
+    /// Set up the watches of all registrations, e.g. on a new watcher.
+    fn watch_all(&mut self) {
+        for (path, recursive_mode) in self.watched() {
+            self.watch(&path, recursive_mode);
+        }
+    }
+
+    /// Drop every registration.
+    pub fn clear(&mut self) {
+        for (path, _) in self.watched() {
+            self.unwatch(&path);
+        }
+        self.counts.clear();
+        self.singles.clear();
+        self.leftover.clear();
+        self.leftover_singles.clear();
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) {
+        if let Err(err) = self.watcher.watch(path, recursive_mode) {
+            metrics::WATCH_ERRORS.inc();
+            warn!("Cannot watch {}: {}", path.display(), err);
+        }
+    }
+
+    /// Unwatch a path, a vanished path is not worth failing over.
+    fn unwatch(&mut self, path: &Path) {
+        if let Err(err) = self.watcher.unwatch(path) {
+            warn!("Cannot unwatch {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Builds a watcher, given the id to tag its events with and the settings of
+/// the replica it is first built for.
+pub type WatchFactory<WATCH> = Box<dyn FnMut(&str, &Settings) -> Result<WATCH>>;
+
+/// The part of a replica in a watcher it shares with others, which is what
+/// its `Watches` register on. Paths registered by several replicas take one
+/// watch, paths below another replica's recursive watch take none.
+pub struct Share<WATCH: Watch> {
+    /// Id the shared watcher tags its events with.
+    pub tag: Id,
+    group: Rc<RefCell<Watches<WATCH>>>,
+    /// How each path this replica holds a watch on is watched.
+    modes: HashMap<PathBuf, RecursiveMode>,
+}
+
+impl<WATCH: Watch> Share<WATCH> {
+    /// The registrations of all replicas sharing the watcher.
+    pub fn group(&self) -> Ref<'_, Watches<WATCH>> {
+        self.group.borrow()
+    }
+}
+
+impl<WATCH: Watch> Watch for Share<WATCH> {
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        let mut group = self.group.borrow_mut();
+        match recursive_mode {
+            RecursiveMode::Recursive => group.add(path)?,
+            RecursiveMode::NonRecursive => group.add_single(path)?,
+        }
+        self.modes.insert(path.to_owned(), recursive_mode);
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        let mut group = self.group.borrow_mut();
+        match self.modes.remove(path) {
+            Some(RecursiveMode::Recursive) => group.remove(path),
+            Some(RecursiveMode::NonRecursive) => group.remove_single(path),
+            None => {
+                return Err(MonitorError::WatchError(format!(
+                    "Not watching {}",
+                    path.display()
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self, path: &Path) -> Result<()> {
+        self.group.borrow().watcher.validate(path)
+    }
+
+    /// The other replicas may hold the same watch, it is set up again for
+    /// all of them.
+    fn refresh(&mut self, path: &Path, _recursive_mode: RecursiveMode) -> Result<()> {
+        self.group.borrow_mut().refresh(path)
+    }
+
+    fn hardlinks(&mut self, root: &Path) -> Option<Hardlinks> {
+        self.group.borrow_mut().watcher.hardlinks(root)
+    }
+}
+
+/// A watcher with the settings it was built with.
+struct Group<WATCH: Watch> {
+    settings: Settings,
+    watches: Rc<RefCell<Watches<WATCH>>>,
+}
+
+/// The watches of a reset replica, kept for one starting on the same root.
+struct Parked<WATCH: Watch> {
+    settings: Settings,
+    watches: Watches<Share<WATCH>>,
+    since: Instant,
+}
+
+/// One watcher for all replicas whose watchers would behave the same, see
+/// `same_watcher`. Overlapping roots, as of several profiles rooted in the
+/// same home directory, take the watches of one, and events go to every
+/// replica sharing the watcher. A failing watcher takes those replicas down
+/// together, replicas with other settings keep theirs.
+pub struct WatchRegistry<WATCH: Watch> {
+    factory: WatchFactory<WATCH>,
+    pub replicas: HashMap<Id, Watches<Share<WATCH>>>,
+    /// Watches of reset replicas by canonical root, for `PARK_TIMEOUT`.
+    parked: HashMap<PathBuf, Parked<WATCH>>,
+    /// Watchers by the id they tag their events with, which is that of the
+    /// replica they were built for unless another watcher had it already.
+    groups: HashMap<Id, Group<WATCH>>,
+    /// The replicas sharing each watcher, by its tag. Events hand them on
+    /// as they are, rather than looking them up anew each time.
+    members: HashMap<Id, Rc<HashSet<Id>>>,
+}
+
+/// Whether watchers built with either settings behave the same.
+fn same_watcher(a: &Settings, b: &Settings) -> bool {
+    a.backend == b.backend
+        && a.debounce == b.debounce
+        && a.poll_interval == b.poll_interval
+        && a.fsevents_latency == b.fsevents_latency
+        && a.storm_rate == b.storm_rate
+        && a.safety_scan == b.safety_scan
+        && a.coarse_timestamps == b.coarse_timestamps
+        && a.sample_contents == b.sample_contents
+}
+
+/// Poll a replica on a network filesystem, where the native backend misses
+/// changes made elsewhere, unless a backend was chosen for it. Returns the
+/// type of the filesystem when it does.
+fn poll_network_fs(settings: &mut Settings, root: &Path) -> Option<String> {
+    if settings.backend != "auto" {
+        return None;
+    }
+    let fstype = netfs::network_fs(root)?;
+    settings.backend = "poll".into();
+    Some(fstype)
+}
+
+/// Have a replica on FAT or exFAT polled by size and coarse mtimes, should
+/// it be polled. Returns the type of the filesystem when it is on one.
+fn coarse_timestamps(settings: &mut Settings, root: &Path) -> Option<String> {
+    let fstype = coarse::coarse_fs(root)?;
+    settings.coarse_timestamps = true;
+    Some(fstype)
+}
+
+impl<WATCH: Watch> WatchRegistry<WATCH> {
+    pub fn new(factory: impl FnMut(&str, &Settings) -> Result<WATCH> + 'static) -> Self {
+        Self {
+            factory: Box::new(factory),
+            replicas: HashMap::new(),
+            parked: HashMap::new(),
+            groups: HashMap::new(),
+            members: HashMap::new(),
+        }
+    }
+
+    /// Keep `watches` as those of `replica_id`, a member of their watcher.
+    fn insert(&mut self, replica_id: &str, watches: Watches<Share<WATCH>>) {
+        let members = self.members.entry(watches.watcher.tag.clone()).or_default();
+        Rc::make_mut(members).insert(replica_id.into());
+        self.replicas.insert(replica_id.into(), watches);
+    }
+
+    /// Take the watches of `replica_id` out, and it out of the members of
+    /// their watcher.
+    fn take(&mut self, replica_id: &str) -> Option<Watches<Share<WATCH>>> {
+        let watches = self.replicas.remove(replica_id)?;
+        let tag = &watches.watcher.tag;
+        if let Some(members) = self.members.get_mut(tag) {
+            Rc::make_mut(members).remove(replica_id);
+            if members.is_empty() {
+                self.members.remove(tag);
+            }
+        }
+        Some(watches)
+    }
+
+    /// Watches of a replica, joining it to a watcher on first use.
+    pub fn add(
+        &mut self,
+        replica_id: &str,
+        settings: &Settings,
+    ) -> Result<&mut Watches<Share<WATCH>>> {
+        if !self.replicas.contains_key(replica_id) {
+            let share = self.share(replica_id, settings)?;
+            self.insert(replica_id, Watches::new(share));
+        }
+        Ok(self.replicas.get_mut(replica_id).unwrap())
+    }
+
+    /// A share in the watcher for `settings`, building one for `replica_id`
+    /// unless there is one already.
+    fn share(&mut self, replica_id: &str, settings: &Settings) -> Result<Share<WATCH>> {
+        let found = self
+            .groups
+            .iter()
+            .find(|(_, group)| same_watcher(&group.settings, settings));
+        let (tag, group) = match found {
+            Some((tag, group)) => {
+                debug!("replica {}: sharing the watcher of {}", replica_id, tag);
+                (tag.clone(), group.watches.clone())
+            }
+            None => {
+                // Events of watchers tagged the same could not be told apart.
+                let mut tag = Id::from(replica_id);
+                for n in 1.. {
+                    if !self.groups.contains_key(&tag) {
+                        break;
+                    }
+                    tag = format!("{}-{}", replica_id, n).into();
+                }
+                let watcher = (self.factory)(&tag, settings)?;
+                let watches = Rc::new(RefCell::new(Watches::new(watcher)));
+                let group = Group {
+                    settings: settings.clone(),
+                    watches: watches.clone(),
+                };
+                self.groups.insert(tag.clone(), group);
+                (tag, watches)
+            }
+        };
+        Ok(Share {
+            tag,
+            group,
+            modes: HashMap::new(),
+        })
+    }
+
+    /// Drop the watchers no replica shares anymore.
+    fn prune(&mut self) {
+        self.groups.retain(|tag, group| {
+            let shared = Rc::strong_count(&group.watches) > 1;
+            if !shared {
+                debug!("Dropping the watcher {}", tag);
+            }
+            shared
+        });
+    }
+
+    pub fn get_mut(&mut self, replica_id: &str) -> Option<&mut Watches<Share<WATCH>>> {
+        self.replicas.get_mut(replica_id)
+    }
+
+    /// Map the hard links below `root` with the watcher of `replica_id`,
+    /// see `Watch::hardlinks`.
+    pub fn hardlinks(&mut self, replica_id: &str, root: &Path) -> Option<Hardlinks> {
+        self.replicas.get_mut(replica_id)?.watcher.hardlinks(root)
+    }
+
+    /// The replicas sharing the watcher that tags its events `tag`.
+    pub fn members(&self, tag: &str) -> Rc<HashSet<Id>> {
+        self.members.get(tag).cloned().unwrap_or_default()
+    }
+
+    /// Drop all watches of a replica, and its watcher if no other replica
+    /// shares it.
+    pub fn remove(&mut self, replica_id: &str) {
+        if let Some(mut watches) = self.take(replica_id) {
+            watches.clear();
+        }
+        self.prune();
+    }
+
+    /// Keep the watches of a reset replica, for a replica starting on
+    /// `realroot` to take over.
+    pub fn park(&mut self, replica_id: &str, realroot: &Path, settings: &Settings) {
+        let watches = match self.take(replica_id) {
+            Some(watches) => watches,
+            None => return,
+        };
+        let parked = Parked {
+            settings: settings.clone(),
+            watches,
+            since: Instant::now(),
+        };
+        if let Some(mut old) = self.parked.insert(realroot.to_owned(), parked) {
+            old.watches.clear();
+            self.prune();
+        }
+    }
+
+    /// Hand a starting replica the watches parked for `realroot`, if they
+    /// are on the watcher for its settings. Its registrations stay in place
+    /// until `Watches::release_leftover`.
+    pub fn reattach(&mut self, replica_id: &str, realroot: &Path, settings: &Settings) -> bool {
+        if self.replicas.contains_key(replica_id) {
+            return false;
+        }
+        let mut parked = match self.parked.remove(realroot) {
+            Some(parked) => parked,
+            None => return false,
+        };
+        if !same_watcher(&parked.settings, settings) {
+            parked.watches.clear();
+            self.prune();
+            return false;
+        }
+        let mut watches = parked.watches;
+        watches.leftover = watches.counts.keys().cloned().collect();
+        watches.leftover_singles = watches.singles.keys().cloned().collect();
+        self.insert(replica_id, watches);
+        true
+    }
+
+    /// Drop the parked watches no replica took over in time.
+    pub fn expire(&mut self, now: Instant) {
+        self.parked.retain(|root, parked| {
+            if now.duration_since(parked.since) < PARK_TIMEOUT {
+                return true;
+            }
+            debug!("Dropping the watches of {}", root.display());
+            parked.watches.clear();
+            false
+        });
+        self.prune();
+    }
+
+    /// Move the registrations of a replica over to the watcher for
+    /// `settings`, e.g. to poll once the native backend ran out of watches.
+    pub fn replace(&mut self, replica_id: &str, settings: &Settings) -> Result<()> {
+        let mut watches = Watches::new(self.share(replica_id, settings)?);
+        if let Some(mut old) = self.take(replica_id) {
+            watches.counts = old.counts.clone();
+            watches.singles = old.singles.clone();
+            watches.leftover = std::mem::take(&mut old.leftover);
+            watches.leftover_singles = std::mem::take(&mut old.leftover_singles);
+            // Set up first, a path the other watcher has too is not
+            // watched anew.
+            watches.watch_all();
+            old.clear();
+        }
+        self.insert(replica_id, watches);
+        self.prune();
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        for (_, mut watches) in self.replicas.drain() {
+            watches.clear();
+        }
+        for (_, mut parked) in self.parked.drain() {
+            parked.watches.clear();
+        }
+        self.groups.clear();
+        self.members.clear();
+    }
+}
+
+/// Add `path` to `changes` unless a parent of it is in there already,
+/// replacing its descendants, and return whether it was. Descendants sort
+/// right after the path, so neither takes a look at every change.
+fn insert_change(changes: &mut BTreeSet<PathBuf>, path: PathBuf) -> bool {
+    if path.ancestors().any(|ancestor| changes.contains(ancestor)) {
+        return false;
+    }
+    let descendants: Vec<PathBuf> = changes
+        .range::<Path, _>((Bound::Excluded(path.as_path()), Bound::Unbounded))
+        .take_while(|change| change.starts_with(&path))
+        .cloned()
+        .collect();
+    for descendant in &descendants {
+        changes.remove(descendant);
+    }
+    changes.insert(path)
+}
+
+#[derive(Debug)]
+pub struct Replica {
+    pub root: PathBuf,
+    /// Canonical form of `root`, which is what the watcher reports events in.
+    pub realroot: PathBuf,
+    /// Currently being watched paths.
+    pub paths: HashSet<PathBuf>,
+    /// Paths of pending changes. Paths are relative as required by unison.
+    pub pending_changes: BTreeSet<PathBuf>,
+    /// Changes sent in reply to `CHANGES` that unison has not acknowledged by
+    /// waiting again yet.
+    pub reported_changes: BTreeSet<PathBuf>,
+    /// Whether unison is waiting for this replica to change.
+    pub waiting: bool,
+    /// Directories announced with `DIR`.
+    pub dirs: HashSet<PathBuf>,
+    /// Watch `dirs` one by one instead of `paths` recursively, down to
+    /// `settings.max_depth` if set.
+    pub selective: bool,
+    /// Watched paths that disappeared, watched again once they are back.
+    pub lost: HashSet<PathBuf>,
+    /// Watched paths a filesystem was mounted at. Unmounted, they stay but
+    /// no longer hold the replica, which is suspended until they are
+    /// mounted again.
+    pub mounts: HashSet<PathBuf>,
+    /// Paths unison started before they existed, watched once they do.
+    pub awaited: HashSet<PathBuf>,
+    /// Match event paths without regard to case, for APFS, NTFS and the like.
+    pub case_insensitive: bool,
+    /// Links followed with `LINK`, by canonical target.
+    pub links: HashMap<PathBuf, HashSet<PathBuf>>,
+    /// Other paths bind mounts make the canonical root reachable under,
+    /// each with the path at or below the canonical root it stands for.
+    pub bind_mounts: Vec<(PathBuf, PathBuf)>,
+    /// Resolved from the config when the replica starts.
+    pub settings: Settings,
+    /// Globs announced with `IGNORE`, which add to `settings.ignore`.
+    pub ignore: Vec<Glob>,
+    /// Read once the replica starts if its settings ask for it.
+    pub gitignores: Option<GitIgnores>,
+    /// Files linked more than once, read from the canonical root when the
+    /// replica starts if its settings ask for it.
+    pub hardlinks: Option<Hardlinks>,
+    /// When paths were last reported, while `settings.hold_down` is set.
+    pub reported_at: HashMap<PathBuf, Instant>,
+    /// Changes held back until the hold-down of their path passed.
+    pub held: BTreeSet<PathBuf>,
+    /// Changes since the last safety scan, while `settings.safety_scan` is
+    /// set. The scan only reports what is not among them.
+    pub seen: BTreeSet<PathBuf>,
+    /// Changes recorded since the replica started, rescans of all of it
+    /// included, for the statistics.
+    pub changes: u64,
+    /// Events dropped for the replica as the monitor fell behind.
+    pub dropped: u64,
+}
+
+impl Replica {
+    pub fn new(root: PathBuf) -> Replica {
+        Replica {
+            realroot: root.clone(),
+            root,
+            paths: HashSet::new(),
+            pending_changes: BTreeSet::new(),
+            reported_changes: BTreeSet::new(),
+            waiting: false,
+            dirs: HashSet::new(),
+            selective: false,
+            lost: HashSet::new(),
+            mounts: HashSet::new(),
+            awaited: HashSet::new(),
+            case_insensitive: false,
+            links: HashMap::new(),
+            bind_mounts: vec![],
+            settings: Settings::default(),
+            ignore: vec![],
+            gitignores: None,
+            hardlinks: None,
+            reported_at: HashMap::new(),
+            held: BTreeSet::new(),
+            seen: BTreeSet::new(),
+            changes: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Record a change, skipping paths already covered by a pending parent.
+    pub fn add_change(&mut self, path: PathBuf) {
+        self.changes += 1;
+        if !insert_change(&mut self.pending_changes, path) {
+            return;
+        }
+        // Rather than grow without bound while unison is busy, rescan it all.
+        let limit = self.settings.max_pending;
+        if limit > 0 && self.pending_changes.len() > limit {
+            warn!(
+                "More than {} changes pending below {}, rescanning all of it",
+                limit,
+                self.root.display()
+            );
+            self.pending_changes.clear();
+            self.pending_changes.insert(PathBuf::new());
+        }
+    }
+
+    /// Move pending changes to the unacknowledged ones and return all of those.
+    pub fn take_changes(&mut self) -> BTreeSet<PathBuf> {
+        if self.settings.hold_down.is_some() {
+            let now = Instant::now();
+            for path in &self.pending_changes {
+                self.reported_at.insert(path.clone(), now);
+            }
+        }
+        for path in std::mem::take(&mut self.pending_changes) {
+            self.add_reported(path);
+        }
+        self.reported_changes.clone()
+    }
+
+    fn add_reported(&mut self, path: PathBuf) {
+        insert_change(&mut self.reported_changes, path);
+    }
+
+    /// Hold a change to `path` back if the path was reported within its
+    /// hold-down, returning whether it was.
+    pub fn hold(&mut self, path: &Path, now: Instant) -> bool {
+        let window = match self.settings.hold_down {
+            Some(window) => window,
+            None => return false,
+        };
+        match self.reported_at.get(path) {
+            Some(at) if now.duration_since(*at) < window => {
+                self.held.insert(path.to_owned());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Queue the held changes whose hold-down passed, returning whether
+    /// there were any.
+    pub fn release(&mut self, now: Instant) -> bool {
+        let window = self.settings.hold_down.unwrap_or_default();
+        self.reported_at
+            .retain(|_, at| now.duration_since(*at) < window);
+        let released: Vec<PathBuf> = self
+            .held
+            .iter()
+            .filter(|path| !self.reported_at.contains_key(*path))
+            .cloned()
+            .collect();
+        for path in &released {
+            self.held.remove(path);
+            self.add_change(path.clone());
+        }
+        !released.is_empty()
+    }
+
+    /// Unison processed the last reply once it waits again.
+    pub fn acknowledge(&mut self) {
+        self.reported_changes.clear();
+    }
+
+    /// Queue unacknowledged changes again, e.g. when unison restarts watching.
+    pub fn requeue(&mut self) {
+        for path in std::mem::take(&mut self.reported_changes) {
+            self.add_change(path);
+        }
+    }
+
+    /// Whether watched `path` is there, and mounted if it was a mount point.
+    pub fn is_present(&self, path: &Path) -> bool {
+        path.is_dir() && (!self.mounts.contains(path) || is_mount_point(path))
+    }
+
+    /// Whether `path` is below a mount point that was unmounted.
+    pub fn is_suspended(&self, path: &Path) -> bool {
+        self.mounts
+            .iter()
+            .any(|mount| self.lost.contains(mount) && path.starts_with(mount))
+    }
+
+    /// Suspend the replica while mount point `path` is unmounted.
+    fn unmounted(&mut self, id: &str, path: &Path) {
+        if self.lost.insert(path.to_owned()) {
+            warn!(
+                "{} was unmounted, suspending replica {} until it is mounted again",
+                path.display(),
+                id
+            );
+        }
+    }
+
+    /// Make unison rescan the whole replica.
+    pub fn mark_dirty(&mut self) {
+        self.add_change(PathBuf::new());
+    }
+
+    /// Map a path under the canonical root back into the namespace unison used.
+    pub fn translate<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        match self.strip(path, &self.realroot) {
+            Some(postfix) if self.realroot != self.root => Cow::Owned(self.root.join(postfix)),
+            _ => Cow::Borrowed(path),
+        }
+    }
+
+    /// Inverse of `translate`, where the watch registry keeps the path.
+    pub fn realpath(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.root) {
+            Ok(postfix) if self.realroot != self.root => self.realroot.join(postfix),
+            _ => path.to_owned(),
+        }
+    }
+
+    /// The paths events for the replica arrive under: the root as unison
+    /// gave it, the canonical root, the targets of followed links and bind
+    /// mounts of the tree elsewhere.
+    pub fn prefixes(&self) -> impl Iterator<Item = &Path> {
+        [self.root.as_path(), &self.realroot]
+            .into_iter()
+            .chain(self.links.keys().map(PathBuf::as_path))
+            .chain(self.bind_mounts.iter().map(|(alias, _)| alias.as_path()))
+    }
+
+    /// Look up the bind mounts of the canonical root, which share its
+    /// watches: events may carry their paths rather than the root's.
+    fn find_bind_mounts(&mut self, id: &str) {
+        self.bind_mounts = netfs::bind_mounts(&self.realroot);
+        for (alias, path) in &self.bind_mounts {
+            info!(
+                "replica {}: {} is also mounted at {}",
+                id,
+                path.display(),
+                alias.display()
+            );
+        }
+    }
+
+    /// Whether `path` lies no deeper below the root than watched directories
+    /// may.
+    pub fn within_depth(&self, path: &Path) -> bool {
+        match (self.settings.max_depth, self.strip(path, &self.root)) {
+            (Some(max_depth), Some(relative)) => relative.components().count() <= max_depth,
+            _ => true,
+        }
+    }
+
+    /// Check if path is being watched in this replica.
+    pub fn is_watching(&self, path: &Path) -> bool {
+        self.paths
+            .iter()
+            .any(|base| self.strip(path, base).is_some())
+    }
+
+    /// `path.strip_prefix(base)`, ignoring case if the filesystem does.
+    pub fn strip<'a>(&self, path: &'a Path, base: &Path) -> Option<&'a Path> {
+        if self.case_insensitive {
+            strip_prefix_ignore_case(path, base)
+        } else {
+            path.strip_prefix(base).ok()
+        }
+    }
+}
+
+/// Counts behind the statistics logged on `SIGUSR1`.
+#[derive(Debug)]
+struct Stats {
+    started: Instant,
+    /// Filesystem events handled.
+    events: u64,
+    /// Filesystem events dropped as the monitor fell behind.
+    dropped: u64,
+    /// When statistics were last logged, and `events` then.
+    last: (Instant, u64),
+}
+
+impl Stats {
+    fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            events: 0,
+            dropped: 0,
+            last: (now, 0),
+        }
+    }
+}
+
+/// Events per second in `events` over `elapsed`.
+fn rate(events: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    events as f64 / elapsed.as_secs_f64()
+}
+
+pub struct Monitor<WATCH: Watch, WRITE: ResponseSink> {
+    /// Negotiated protocol version.
+    pub version: u32,
+    pub phase: Phase,
+    /// Replica of the `START` exchange in progress.
+    pub current_replica: Id,
+    pub current_path: PathBuf,
+    pub replicas: HashMap<Id, Replica>,
+    /// Where events for each replica arrive, kept along with `replicas`.
+    pub index: ReplicaIndex,
+    pub watches: WatchRegistry<WATCH>,
+    /// Settings replicas start with.
+    pub config: Config,
+    /// Stages changes pass before they are recorded.
+    pub pipeline: Pipeline,
+    /// Normalize replica and event paths to NFC before matching and reporting.
+    pub normalize_unicode: bool,
+    /// Drop `\\?\` prefixes, expand short names and use forward slashes, as
+    /// unison does on Windows.
+    pub normalize_windows: bool,
+    /// Watch only the directories unison announces, for huge replicas.
+    pub selective: bool,
+    /// Tells other monitors which replica roots this one watches.
+    pub locks: Option<ReplicaLocks>,
+    /// Keeps the changes unison has not taken yet across restarts.
+    pub journal: Option<Journal>,
+    /// Where renames arriving in halves started, by the watcher's cookie.
+    renames: HashMap<usize, (PathBuf, Instant)>,
+    stats: Stats,
+    pub writer: WRITE,
+}
+
+impl<WATCH: Watch, WRITE: ResponseSink> Monitor<WATCH, WRITE> {
+    pub fn new(
+        factory: impl FnMut(&str, &Settings) -> Result<WATCH> + 'static,
+        writer: WRITE,
+    ) -> Self {
+        Self {
+            version: 1,
+            phase: Phase::AwaitingVersion,
+            current_replica: Id::from(""),
+            current_path: PathBuf::new(),
+            replicas: HashMap::new(),
+            index: ReplicaIndex::default(),
+            watches: WatchRegistry::new(factory),
+            config: Config::default(),
+            pipeline: Pipeline::default(),
+            normalize_unicode: cfg!(target_os = "macos"),
+            normalize_windows: cfg!(windows),
+            selective: false,
+            locks: None,
+            journal: None,
+            renames: HashMap::new(),
+            stats: Stats::new(Instant::now()),
+            writer,
+        }
+    }
+
+    fn normalize(&self, path: &Path) -> PathBuf {
+        let mut path = path.to_owned();
+        if self.normalize_windows {
+            path = normalize_windows(&expand_short_names(&path));
+        }
+        if self.normalize_unicode {
+            path = normalize_unicode(&path);
+        }
+        path
+    }
+
+    pub fn handle_event(&mut self, event: Event) -> Result<()> {
+        match &event {
+            Event::Input(input) => debug!(kind = "request"; "<< {}", input.trim_end()),
+            _ => debug!("event: {:?}", event),
+        }
+
+        match event {
+            Event::Input(input) => {
+                let request = input.parse::<Request>()?;
+                if self.phase == Phase::AwaitingVersion
+                    && !matches!(request, Request::Version(_) | Request::Blank)
+                {
+                    debug!("No VERSION, assuming {}", self.version);
+                }
+                self.phase = self.phase.next(&request);
+
+                if let Request::Version(versions) = request {
+                    self.negotiate_version(&versions)?;
+                } else {
+                    match self.version {
+                        1 => self.handle_command_v1(request)?,
+                        version => {
+                            return Err(MonitorError::UnsupportedFeature(format!(
+                                "No dispatcher for version {}",
+                                version
+                            )))
+                        }
+                    }
+                }
+            }
+            Event::FSEvent(fsevent) => {
+                self.stats.events += 1;
+                metrics::EVENTS.inc();
+                self.handle_fsevent(None, fsevent)
+            }
+            Event::ReplicaEvent(tag, fsevent) => {
+                self.stats.events += 1;
+                metrics::EVENTS.inc();
+                let members = self.watches.members(&tag);
+                self.handle_fsevent(Some(&*members), fsevent)
+            }
+            Event::Eof => {
+                self.shutdown()?;
+            }
+            Event::InputError(err) => {
+                self.shutdown()?;
+                return Err(err.into());
+            }
+            Event::Tick => {
+                self.check_paths();
+                self.watch_awaited();
+                self.release_held();
+                self.watches.expire(Instant::now());
+                self.renames
+                    .retain(|_, (_, at)| at.elapsed() < RENAME_TIMEOUT);
+                self.journal_changes();
+            }
+            Event::Reload(config) => {
+                self.reload(*config);
+            }
+            Event::Watched(tag, path, result) => {
+                // Each replica that registered the path hears of it.
+                let members = self.watches.members(&tag);
+                for id in members.iter() {
+                    let registered = self.watches.replicas.get(id).is_some_and(|watches| {
+                        watches.counts.contains_key(&path) || watches.singles.contains_key(&path)
+                    });
+                    if !registered {
+                        continue;
+                    }
+                    let result = match &result {
+                        Ok(()) => Ok(()),
+                        Err(MonitorError::WatchLimit(msg)) => {
+                            Err(MonitorError::WatchLimit(msg.clone()))
+                        }
+                        Err(err) => Err(MonitorError::WatchError(err.to_string())),
+                    };
+                    self.handle_watched(id, &path, result);
+                }
+            }
+            Event::Overflow(shed) => self.handle_overflow(shed),
+            Event::Stats => {
+                let (summary, replicas) = self.stats(Instant::now());
+                info!("{}", summary);
+                for (id, line) in replicas {
+                    info!(replica = &*id; "{}", line);
+                }
+            }
+            Event::Hardlinks(tag, root, hardlinks) => {
+                // Until now changes went without their aliases.
+                let members = self.watches.members(&tag);
+                for id in members.iter() {
+                    if let Some(replica) = self.replicas.get_mut(id) {
+                        if replica.settings.hardlinks
+                            && replica.hardlinks.is_none()
+                            && replica.realroot == root
+                        {
+                            replica.hardlinks = Some(hardlinks.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record the changes an fsevent means for the replicas, or only for
+    /// `targets` if the event came from the watcher they share.
+    fn handle_fsevent(&mut self, targets: Option<&HashSet<Id>>, mut fsevent: notify::Event) {
+        self.pair_rename(&mut fsevent);
+        // Everything below a moved directory moved along, nothing of it is
+        // held back.
+        let moved_dir = fsevent.kind == EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+            && fsevent.paths.last().is_some_and(|to| to.is_dir());
+        let mut matched_replica_ids = HashSet::new();
+        // Events were dropped, e.g. the inotify queue overflowed.
+        let rescan = fsevent.need_rescan();
+        if rescan {
+            metrics::OVERFLOWS.inc();
+            warn!("Rescan requested for {:?}", fsevent.paths);
+        }
+        let rename = matches!(fsevent.kind, EventKind::Modify(ModifyKind::Name(_)));
+        // What a safety scan found, which the watcher may have reported.
+        let scan = fsevent.info() == Some(scan::SCAN_INFO);
+        let mut missed: HashMap<Id, usize> = HashMap::new();
+        let now = Instant::now();
+
+        for path in &fsevent.paths {
+            let path = self.normalize(path);
+            let ids = self.index.lookup(&path);
+            for id in ids.filter(|id| targets.is_none_or(|targets| targets.contains(*id))) {
+                let replica = match self.replicas.get_mut(id) {
+                    Some(replica) => replica,
+                    None => continue,
+                };
+                // The other paths the change shows under, if any.
+                let mut aliases = Vec::new();
+                // Writes through one hard link change the others too.
+                if let Some(hardlinks) = &mut replica.hardlinks {
+                    hardlinks.update(&path);
+                    aliases.extend(hardlinks.aliases(&path).cloned());
+                }
+                // Get all possible symbolic links for this path.
+                for (realpath, links) in &replica.links {
+                    if let Ok(postfix) = path.strip_prefix(realpath) {
+                        for link in links {
+                            aliases.push(link.join(postfix));
+                        }
+                    }
+                }
+                // Watches are shared with bind mounts of the same
+                // directories, which events may come through.
+                for (alias, realpath) in &replica.bind_mounts {
+                    if let Ok(postfix) = path.strip_prefix(alias) {
+                        aliases.push(realpath.join(postfix));
+                    }
+                }
+
+                for path in std::iter::once(&path).chain(&aliases) {
+                    let path = replica.translate(path);
+                    // Only paths Unison asked for belong to the replica.
+                    if !replica.is_watching(&path) {
+                        continue;
+                    }
+                    // The watched directory itself went away.
+                    if replica.paths.contains(&*path) && !replica.is_present(&path) {
+                        if replica.mounts.contains(&*path) {
+                            replica.unmounted(id, &path);
+                        } else {
+                            info!("{} disappeared", path.display());
+                            replica.lost.insert(path.to_path_buf());
+                            replica.mark_dirty();
+                            matched_replica_ids.insert(id.clone());
+                        }
+                    }
+                    // What changes under an unmounted mount point is not
+                    // the replica's.
+                    if replica.is_suspended(&path) {
+                        continue;
+                    }
+                    let relative_path = match replica.strip(&path, &replica.root) {
+                        Some(relative_path) => relative_path,
+                        None => continue,
+                    };
+                    // What a `.gitignore` stopped ignoring may have changed
+                    // meanwhile, rescan its directory.
+                    if relative_path.ends_with(gitignore::FILE_NAME) {
+                        let dir = relative_path.parent().unwrap_or(Path::new(""));
+                        if let Some(gitignores) = &mut replica.gitignores {
+                            if gitignores.reload(&replica.root, dir) {
+                                debug!("{} reloaded", path.display());
+                                replica.add_change(dir.to_owned());
+                                matched_replica_ids.insert(id.clone());
+                            }
+                        }
+                    }
+                    // Unison requires relative path for changes.
+                    // A rename carries both ends, or each arrives on its own.
+                    // Report the parents so both listings are rescanned.
+                    // Never widen to the root, that rescans everything.
+                    let parent = relative_path.parent().filter(|parent| {
+                        rename
+                            && *parent != Path::new("")
+                            && replica.is_watching(&replica.root.join(parent))
+                    });
+                    let changes = std::iter::once(relative_path).chain(parent);
+                    for path in changes.map(Path::to_owned) {
+                        let change = Change {
+                            replica: id.clone(),
+                            path,
+                            kind: fsevent.kind,
+                        };
+                        if let Some(change) = self.pipeline.process(change, replica) {
+                            if scan {
+                                let seen = &replica.seen;
+                                if change.path.ancestors().any(|path| seen.contains(path)) {
+                                    continue;
+                                }
+                                *missed.entry(id.clone()).or_default() += 1;
+                            } else if replica.settings.safety_scan.is_some() {
+                                insert_change(&mut replica.seen, change.path.clone());
+                            }
+                            // Reported not long ago, wait for the hold-down.
+                            if !moved_dir && replica.hold(&change.path, now) {
+                                debug!(
+                                    replica = &**id, path:% = change.path.display();
+                                    "Holding back {}", change.path.display()
+                                );
+                                continue;
+                            }
+                            debug!(
+                                replica = &**id,
+                                path:% = change.path.display(),
+                                kind:? = change.kind;
+                                "replica {}: {} changed", id, change.path.display()
+                            );
+                            matched_replica_ids.insert(id.clone());
+                            replica.add_change(change.path);
+                        }
+                    }
+                }
+                if rescan && replica.strip(&replica.realroot, &path).is_some() {
+                    matched_replica_ids.insert(id.clone());
+                    replica.mark_dirty();
+                }
+            }
+        }
+        if rescan && fsevent.paths.is_empty() {
+            for (id, replica) in self.replicas.iter_mut() {
+                if targets.is_none_or(|targets| targets.contains(id)) {
+                    matched_replica_ids.insert(id.clone());
+                    replica.mark_dirty();
+                }
+            }
+        }
+
+        if let (true, Some(targets)) = (scan, targets) {
+            for target in targets {
+                if let Some(replica) = self.replicas.get_mut(target) {
+                    replica.seen.clear();
+                }
+                if let Some(missed) = missed.get(target) {
+                    warn!(
+                        "replica {}: safety scan found {} changes the watcher missed",
+                        target, missed
+                    );
+                }
+            }
+        }
+
+        if matched_replica_ids.is_empty() {
+            debug!("No replica changed by event.")
+        }
+
+        self.notify_changes(&matched_replica_ids);
+    }
+
+    /// Have unison rescan the replicas whose events were dropped. Those of
+    /// the watcher without a tag may have been for any replica.
+    fn handle_overflow(&mut self, shed: HashMap<Option<Tag>, usize>) {
+        let mut dirty = HashSet::new();
+        if !shed.is_empty() {
+            metrics::OVERFLOWS.inc();
+        }
+        for (tag, count) in shed {
+            self.stats.dropped += count as u64;
+            metrics::EVENTS_DROPPED.add(count as u64);
+            let targets = tag.map(|tag| self.watches.members(&tag));
+            warn!(
+                "Monitor fell behind, dropped {} events for {}",
+                count,
+                match &targets {
+                    Some(targets) => targets.iter().cloned().collect::<Vec<_>>().join(", "),
+                    None => "all replicas".into(),
+                }
+            );
+            for (id, replica) in self.replicas.iter_mut() {
+                if targets.as_ref().is_none_or(|targets| targets.contains(id)) {
+                    replica.dropped += count as u64;
+                    replica.mark_dirty();
+                    dirty.insert(id.clone());
+                }
+            }
+        }
+        self.notify_changes(&dirty);
+    }
+
+    /// How the monitor has been doing up to `now`: events handled and
+    /// dropped in all, then a line with the watches, changes and dropped
+    /// events of each replica.
+    fn stats(&mut self, now: Instant) -> (String, Vec<(Id, String)>) {
+        let (last, events) = self.stats.last;
+        self.stats.last = (now, self.stats.events);
+        let uptime = Duration::from_secs(now.duration_since(self.stats.started).as_secs());
+        let summary = format!(
+            "Up {}, {} replicas, {} events ({:.1}/s, {:.1}/s since the last statistics), {} dropped",
+            humantime::format_duration(uptime),
+            self.replicas.len(),
+            self.stats.events,
+            rate(self.stats.events, now.duration_since(self.stats.started)),
+            rate(self.stats.events - events, now.duration_since(last)),
+            self.stats.dropped,
+        );
+        let mut lines = vec![];
+        let mut ids: Vec<&Id> = self.replicas.keys().collect();
+        ids.sort();
+        for id in ids {
+            let replica = &self.replicas[id];
+            let watches = self
+                .watches
+                .replicas
+                .get(id)
+                .map_or(0, |watches| watches.counts.len() + watches.singles.len());
+            let line = format!(
+                "replica {} at {}: {} watches on {}, {} pending, {} reported, {} held, {} changes, {} dropped",
+                id,
+                replica.root.display(),
+                watches,
+                replica.settings.backend,
+                replica.pending_changes.len(),
+                replica.reported_changes.len(),
+                replica.held.len(),
+                replica.changes,
+                replica.dropped,
+            );
+            lines.push((id.clone(), line));
+        }
+        (summary, lines)
+    }
+
+    /// Join the halves of a rename the watcher reported apart, the second
+    /// one becomes the whole rename from the first one's path. The first
+    /// half was reported on its own already, but not as the move of a
+    /// directory.
+    fn pair_rename(&mut self, fsevent: &mut notify::Event) {
+        let cookie = match fsevent.tracker() {
+            Some(cookie) => cookie,
+            None => return,
+        };
+        match (fsevent.kind, fsevent.paths.as_slice()) {
+            (EventKind::Modify(ModifyKind::Name(RenameMode::From)), [from]) => {
+                self.renames.insert(cookie, (from.clone(), Instant::now()));
+            }
+            (EventKind::Modify(ModifyKind::Name(RenameMode::To)), [_]) => {
+                if let Some((from, _)) = self.renames.remove(&cookie) {
+                    fsevent.kind = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+                    fsevent.paths.insert(0, from);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Report the changes held back whose hold-down passed.
+    fn release_held(&mut self) {
+        let now = Instant::now();
+        let released: HashSet<Id> = self
+            .replicas
+            .iter_mut()
+            .filter_map(|(id, replica)| replica.release(now).then(|| id.clone()))
+            .collect();
+        self.notify_changes(&released);
+    }
+
+    /// Have unison rescan what a recursive watch set up in the background
+    /// covers, it may have missed changes meanwhile. A failed one takes its
+    /// replica down like at `START`.
+    fn handle_watched(&mut self, replica_id: &str, path: &Path, result: Result<()>) {
+        // Counted even when polling makes up for it.
+        if result.is_err() {
+            metrics::WATCH_ERRORS.inc();
+        }
+        let err = match result {
+            Ok(()) => {
+                let recursive = self
+                    .watches
+                    .get_mut(replica_id)
+                    .is_some_and(|watches| watches.counts.contains_key(path));
+                if let Some(replica) = self.replicas.get_mut(replica_id) {
+                    if recursive {
+                        match replica.strip(path, &replica.realroot) {
+                            Some(postfix) => replica.add_change(postfix.to_owned()),
+                            None => replica.mark_dirty(),
+                        }
+                        self.notify_changes(&[Id::from(replica_id)].into());
+                    }
+                }
+                return;
+            }
+            Err(MonitorError::WatchLimit(msg)) => match self.poll_instead(replica_id, &msg) {
+                Ok(true) => return,
+                Ok(false) => MonitorError::WatchLimit(msg),
+                Err(err) => err,
+            },
+            Err(err) => err,
+        };
+        if let Some(replica) = self.replicas.get(replica_id) {
+            let msg = format!(
+                "Cannot watch {}: {}",
+                replica.translate(path).display(),
+                err
+            );
+            self.send_replica_error(replica_id, &msg);
+        }
+    }
+
+    /// Notify once per wait, unison asks for the changes afterwards.
+    fn notify_changes(&mut self, replica_ids: &HashSet<Id>) {
+        for id in replica_ids {
+            if let Some(replica) = self.replicas.get_mut(id) {
+                if replica.waiting {
+                    replica.waiting = false;
+                    self.send_changes(id);
+                }
+            }
+        }
+    }
+
+    /// Notice watched paths that were deleted or unmounted without an event,
+    /// and watch the ones that came back. Either way the whole replica is
+    /// rescanned, but not while unmounted, which would look as if all of it
+    /// was deleted.
+    fn check_paths(&mut self) {
+        let mut matched_replica_ids = HashSet::new();
+        for (id, replica) in self.replicas.iter_mut() {
+            let paths: Vec<PathBuf> = replica.paths.iter().cloned().collect();
+            for path in paths {
+                let exists = replica.is_present(&path);
+                if !exists && replica.mounts.contains(&path) {
+                    replica.unmounted(id, &path);
+                    continue;
+                } else if !exists && replica.lost.insert(path.clone()) {
+                    info!("{} disappeared", path.display());
+                } else if exists && replica.lost.contains(&path) {
+                    let refreshed = match self.watches.get_mut(id) {
+                        Some(watches) => watches.refresh(&replica.realpath(&path)),
+                        None => Ok(()),
+                    };
+                    if let Err(err) = refreshed {
+                        warn!("Cannot watch {} again: {}", path.display(), err);
+                        continue;
+                    }
+                    if replica.mounts.contains(&path) {
+                        info!("{} is mounted again", path.display());
+                    } else {
+                        info!("{} is back", path.display());
+                    }
+                    replica.lost.remove(&path);
+                } else {
+                    continue;
+                }
+                replica.mark_dirty();
+                matched_replica_ids.insert(id.clone());
+            }
+        }
+        self.notify_changes(&matched_replica_ids);
+    }
+
+    /// Watch the paths unison started before they existed once they do, and
+    /// have their replicas rescanned.
+    fn watch_awaited(&mut self) {
+        let appeared: Vec<(Id, PathBuf)> = self
+            .replicas
+            .iter()
+            .flat_map(|(id, replica)| {
+                replica
+                    .awaited
+                    .iter()
+                    .filter(|path| path.is_dir())
+                    .map(move |path| (id.clone(), path.clone()))
+            })
+            .collect();
+        let mut matched_replica_ids = HashSet::new();
+        for (id, path) in appeared {
+            let replica = &self.replicas[&id];
+            // What depends on the root could only be told now.
+            if path == replica.root {
+                let root = replica.root.clone();
+                let realroot = root
+                    .canonicalize()
+                    .map(|realroot| self.normalize(&realroot))
+                    .unwrap_or_else(|_| root.clone());
+                let replica = self.replicas.get_mut(&id).unwrap();
+                replica.case_insensitive = is_case_insensitive(&root);
+                if replica.settings.gitignore {
+                    replica.gitignores = Some(GitIgnores::load(&root));
+                }
+                if replica.settings.hardlinks {
+                    replica.hardlinks = self.watches.hardlinks(&id, &realroot);
+                }
+                replica.realroot = realroot;
+                replica.find_bind_mounts(&id);
+                self.index
+                    .update(&id, replica.prefixes(), replica.case_insensitive);
+                if let Some(fstype) = coarse_timestamps(&mut replica.settings, &root) {
+                    info!(
+                        "replica {}: {} is on {}, which keeps mtimes to two seconds",
+                        id,
+                        root.display(),
+                        fstype
+                    );
+                    if let Err(err) = self.watches.replace(&id, &replica.settings) {
+                        warn!("Cannot watch {} yet: {}", path.display(), err);
+                        continue;
+                    }
+                }
+            }
+            let watched = if self.replicas[&id].selective {
+                self.add_dir(&id, &path)
+            } else {
+                let realpath = self.replicas[&id].realpath(&path);
+                self.watch_or_poll(&id, |watches| watches.add(&realpath))
+            };
+            if let Err(err) = watched {
+                warn!("Cannot watch {} yet: {}", path.display(), err);
+                continue;
+            }
+            info!("{} appeared, watching it", path.display());
+            let replica = self.replicas.get_mut(&id).unwrap();
+            replica.awaited.remove(&path);
+            if is_mount_point(&path) {
+                replica.mounts.insert(path.clone());
+            }
+            replica.paths.insert(path);
+            replica.mark_dirty();
+            matched_replica_ids.insert(id);
+        }
+        self.notify_changes(&matched_replica_ids);
+    }
+
+    /// Switch to a new config. Ignores apply right away, what is no longer
+    /// ignored is rescanned as it may have changed meanwhile. Watchers
+    /// are kept, so other settings only apply once a replica restarts.
+    fn reload(&mut self, config: Config) {
+        let mut matched_replica_ids = HashSet::new();
+        for (id, replica) in self.replicas.iter_mut() {
+            let mut settings = config.settings(&replica.root);
+            poll_network_fs(&mut settings, &replica.root);
+            settings.ignore.extend(replica.ignore.iter().cloned());
+            // Ignores dropped and exceptions added may uncover changes.
+            let (old, new) = (&replica.settings.ignore, &settings.ignore);
+            let dropped = old.iter().filter(|glob| !new.contains(glob));
+            let added = new.iter().filter(|glob| !old.contains(glob));
+            let unignored: Vec<PathBuf> = dropped
+                .filter(|glob| !glob.is_negated())
+                .chain(added.filter(|glob| glob.is_negated()))
+                .map(|glob| glob.base())
+                .collect();
+            for path in unignored {
+                replica.add_change(path);
+                matched_replica_ids.insert(id.clone());
+            }
+            if settings.ignore != replica.settings.ignore {
+                info!("replica {}: ignoring {:?}", id, settings.ignore);
+            }
+            if settings.debounce != replica.settings.debounce
+                || settings.backend != replica.settings.backend
+                || settings.poll_interval != replica.settings.poll_interval
+                || settings.fsevents_latency != replica.settings.fsevents_latency
+                || settings.storm_rate != replica.settings.storm_rate
+                || settings.safety_scan != replica.settings.safety_scan
+                || settings.sample_contents != replica.settings.sample_contents
+                || settings.max_depth != replica.settings.max_depth
+            {
+                info!("replica {}: other settings apply once it restarts", id);
+            }
+            replica.settings.ignore = settings.ignore;
+            replica.settings.ignore_metadata = settings.ignore_metadata;
+            replica.settings.xattrs = settings.xattrs;
+            replica.settings.ignore_unison_files = settings.ignore_unison_files;
+            replica.settings.ignore_snapshots = settings.ignore_snapshots;
+            replica.settings.hold_down = settings.hold_down;
+            replica.settings.wait_for_path = settings.wait_for_path;
+            replica.settings.collapse_after = settings.collapse_after;
+            replica.settings.max_pending = settings.max_pending;
+            // Regexes and profile rules cannot tell what they stopped ignoring.
+            if settings.ignore_regex != replica.settings.ignore_regex {
+                info!("replica {}: ignoring {:?}", id, settings.ignore_regex);
+                let unignored = replica
+                    .settings
+                    .ignore_regex
+                    .iter()
+                    .any(|regex| !settings.ignore_regex.contains(regex));
+                if unignored {
+                    replica.mark_dirty();
+                    matched_replica_ids.insert(id.clone());
+                }
+                replica.settings.ignore_regex = settings.ignore_regex;
+            }
+            if settings.profile != replica.settings.profile {
+                replica.settings.profile = settings.profile;
+                replica.mark_dirty();
+                matched_replica_ids.insert(id.clone());
+            }
+            if settings.gitignore != replica.settings.gitignore {
+                replica.settings.gitignore = settings.gitignore;
+                replica.gitignores = settings.gitignore.then(|| GitIgnores::load(&replica.root));
+                if !settings.gitignore {
+                    replica.mark_dirty();
+                    matched_replica_ids.insert(id.clone());
+                }
+            }
+            if settings.hardlinks != replica.settings.hardlinks {
+                replica.settings.hardlinks = settings.hardlinks;
+                replica.hardlinks = None;
+                if settings.hardlinks {
+                    replica.hardlinks = self.watches.hardlinks(id, &replica.realroot);
+                }
+            }
+        }
+        self.config = config;
+        self.notify_changes(&matched_replica_ids);
+    }
+
+    /// Answer `VERSION` with the highest version both sides support.
+    fn negotiate_version(&mut self, versions: &[u32]) -> Result<()> {
+        let version = versions
+            .iter()
+            .copied()
+            .filter(|version| SUPPORTED_VERSIONS.contains(version))
+            .max();
+        match version {
+            Some(version) => {
+                self.version = version;
+                self.send(Response::Version(version));
+                Ok(())
+            }
+            None => Err(MonitorError::UnsupportedFeature(format!(
+                "Unexpected version: {:?}, supported: {:?}",
+                versions, SUPPORTED_VERSIONS
+            ))),
+        }
+    }
+
+    fn handle_command_v1(&mut self, request: Request) -> Result<()> {
+        match request {
+            Request::Start {
+                replica: replica_id,
+                root,
+                path,
+            } => {
+                // Start or append watching dirs.
+                // e.g.,
+                // START 123 root
+                // START 123 root subdir
+                let root = self.normalize(&root);
+                self.current_replica = replica_id.clone();
+                self.current_path = root.clone();
+
+                if let Some(dir) = path {
+                    self.current_path = self.normalize(&self.current_path.join(dir));
+                }
+
+                // e.g., /tmp is reported as /private/tmp on macOS.
+                let realroot = root
+                    .canonicalize()
+                    .map(|realroot| self.normalize(&realroot))
+                    .unwrap_or_else(|_| root.clone());
+                let settings = match self.replicas.get(&replica_id) {
+                    Some(replica) => replica.settings.clone(),
+                    None => {
+                        let mut settings = self.config.settings(&root);
+                        if let Some(fstype) = coarse_timestamps(&mut settings, &root) {
+                            info!(
+                                "replica {}: {} is on {}, which keeps mtimes to two seconds",
+                                replica_id,
+                                root.display(),
+                                fstype
+                            );
+                        }
+                        if let Some(fstype) = poll_network_fs(&mut settings, &root) {
+                            info!(
+                                "replica {}: {} is on {}, polling it",
+                                replica_id,
+                                root.display(),
+                                fstype
+                            );
+                        } else if let Some(how) =
+                            netfs::container_mount(&root).filter(|_| settings.backend != "poll")
+                        {
+                            warn!(
+                                "replica {}: {} is {}. Changes made outside the container, \
+                                 on the host or in other containers, may never be seen. Set \
+                                 backend = \"poll\" for it if they are missed",
+                                replica_id,
+                                root.display(),
+                                how
+                            );
+                        }
+                        settings
+                    }
+                };
+                if !self.replicas.contains_key(&replica_id)
+                    && self.watches.reattach(&replica_id, &realroot, &settings)
+                {
+                    info!(
+                        "replica {}: reusing the watches of {}",
+                        replica_id,
+                        root.display()
+                    );
+                }
+                // Unison would hang on a failed watch, answer with an error.
+                // A path that does not exist yet is waited for if asked to.
+                let awaited = settings.wait_for_path && !self.current_path.exists();
+                let validated = self
+                    .watches
+                    .add(&replica_id, &settings)
+                    .and_then(|watches| {
+                        if awaited {
+                            return Ok(());
+                        }
+                        watches.watcher.validate(&self.current_path)
+                    });
+                if let Err(err) = validated {
+                    let msg = format!("Cannot watch {}: {}", self.current_path.display(), err);
+                    self.send_replica_error(&replica_id, &msg);
+                    return Ok(());
+                }
+
+                let new = !self.replicas.contains_key(&replica_id);
+                if new {
+                    if let Err(err) = self.lock_replica(&replica_id, &realroot) {
+                        self.send_replica_error(&replica_id, &err.to_string());
+                        return Ok(());
+                    }
+                }
+
+                // Limiting the depth takes watching directories one by one.
+                let selective = self.selective || settings.max_depth.is_some();
+                let hardlinks = if new && settings.hardlinks {
+                    self.watches.hardlinks(&replica_id, &realroot)
+                } else {
+                    None
+                };
+                let replica = self
+                    .replicas
+                    .entry(replica_id.clone())
+                    .or_insert_with(|| Replica {
+                        selective,
+                        case_insensitive: is_case_insensitive(&root),
+                        gitignores: settings.gitignore.then(|| GitIgnores::load(&root)),
+                        hardlinks,
+                        settings,
+                        ..Replica::new(root)
+                    });
+                replica.realroot = realroot;
+                if new && !awaited {
+                    replica.find_bind_mounts(&replica_id);
+                }
+                replica.requeue();
+                // Changed while no monitor was around to tell unison.
+                if let (true, Some(journal)) = (new, &mut self.journal) {
+                    for path in journal.replay(&replica_id, &replica.root) {
+                        replica.add_change(path);
+                    }
+                }
+                self.index
+                    .update(&replica_id, replica.prefixes(), replica.case_insensitive);
+
+                if awaited {
+                    info!(
+                        "replica {}: {} does not exist, watching it once it does",
+                        replica_id,
+                        self.current_path.display()
+                    );
+                    replica.awaited.insert(self.current_path.clone());
+                } else if replica.selective {
+                    let path = self.current_path.clone();
+                    if let Err(err) = self.add_dir(&replica_id, &path) {
+                        let msg = format!("Cannot watch {}: {}", path.display(), err);
+                        self.send_replica_error(&replica_id, &msg);
+                        return Ok(());
+                    }
+                } else if !replica.is_watching(&self.current_path) {
+                    // Registered by canonical path, which is what events carry.
+                    let realpath = replica.realpath(&self.current_path);
+                    let watched = self.watch_or_poll(&replica_id, |watches| watches.add(&realpath));
+                    if let Err(err) = watched {
+                        let msg = format!("Cannot watch {}: {}", self.current_path.display(), err);
+                        self.send_replica_error(&replica_id, &msg);
+                        return Ok(());
+                    }
+
+                    // Narrower paths are superseded by the new one.
+                    if let (Some(replica), Some(watches)) = (
+                        self.replicas.get_mut(&replica_id),
+                        self.watches.get_mut(&replica_id),
+                    ) {
+                        let covered: Vec<PathBuf> = replica
+                            .paths
+                            .iter()
+                            .filter(|path| path.starts_with(&self.current_path))
+                            .cloned()
+                            .collect();
+                        for path in &covered {
+                            replica.paths.remove(path);
+                            replica.mounts.remove(path);
+                            watches.remove(&replica.realpath(path));
+                        }
+                        replica.paths.insert(self.current_path.clone());
+                        if is_mount_point(&self.current_path) {
+                            replica.mounts.insert(self.current_path.clone());
+                        }
+                    }
+                }
+
+                debug!("replicas: {:?}", self.replicas);
+                self.send_ack();
+            }
+            Request::Dir(dir) => {
+                // Add sub-dir to watch list.
+                let path = self.normalize(&self.current_path.join(dir));
+                if let Err(err) = self.add_dir(&self.current_replica.clone(), &path) {
+                    let msg = format!("Cannot watch {}: {}", path.display(), err);
+                    self.send_replica_error(&self.current_replica.clone(), &msg);
+                    return Ok(());
+                }
+                self.send_ack();
+            }
+            Request::Link(link) => {
+                // Follow a link.
+                let path = self.normalize(&self.current_path.join(link));
+                let realpath = match path.canonicalize() {
+                    Ok(realpath) => self.normalize(&realpath),
+                    Err(err) => {
+                        let msg = format!("Cannot follow link {}: {}", path.display(), err);
+                        self.send_replica_error(&self.current_replica.clone(), &msg);
+                        return Ok(());
+                    }
+                };
+
+                let replica_id = self.current_replica.clone();
+                let replica = match self.replicas.get_mut(&replica_id) {
+                    Some(replica) => replica,
+                    None => {
+                        self.send_ack();
+                        return Ok(());
+                    }
+                };
+                // The target is registered once, however many links lead to it.
+                if !replica.links.contains_key(&realpath) {
+                    let added = self
+                        .watches
+                        .add(&replica_id, &replica.settings)
+                        .and_then(|watches| watches.add(&realpath));
+                    if let Err(err) = added {
+                        let msg = format!("Cannot watch {}: {}", realpath.display(), err);
+                        self.send_replica_error(&replica_id, &msg);
+                        return Ok(());
+                    }
+                }
+                replica.links.entry(realpath).or_default().insert(path);
+                debug!("links: {:?}", replica.links);
+                self.index
+                    .update(&replica_id, replica.prefixes(), replica.case_insensitive);
+                self.send_ack();
+            }
+            Request::Ignore(pattern) => {
+                // Ignore more for the replica being started.
+                let replica_id = self.current_replica.clone();
+                let glob: Glob = match pattern.parse() {
+                    Ok(glob) => glob,
+                    Err(err) => {
+                        // The replica itself is fine, keep watching it.
+                        warn!("replica {}: cannot ignore {}", replica_id, err);
+                        self.send(Response::Error(format!("Cannot ignore {}", err)));
+                        return Ok(());
+                    }
+                };
+                if let Some(replica) = self.replicas.get_mut(&replica_id) {
+                    if !replica.ignore.contains(&glob) {
+                        info!("replica {}: ignoring {}", replica_id, glob);
+                        replica.ignore.push(glob.clone());
+                        replica.settings.ignore.push(glob);
+                    }
+                }
+                self.send_ack();
+            }
+            Request::Wait(replica_id) => {
+                // Start waiting replica.
+                match self.replicas.get_mut(&replica_id) {
+                    Some(replica) => {
+                        replica.acknowledge();
+                        if replica.pending_changes.is_empty() {
+                            replica.waiting = true;
+                        } else {
+                            // Changes arrived before unison started waiting.
+                            replica.waiting = false;
+                            self.send_changes(&replica_id);
+                        }
+                    }
+                    None => {
+                        let msg = format!("Unknown replica: {}", replica_id);
+                        self.send_replica_error(&replica_id, &msg);
+                    }
+                }
+            }
+            Request::Changes(replica_id) => {
+                // Request pending changes.
+                let changed_paths = self
+                    .replicas
+                    .get_mut(&replica_id)
+                    .map(|replica| replica.take_changes())
+                    .unwrap_or_default();
+                for p in changed_paths {
+                    self.send_recursive(&p);
+                }
+                self.send_done();
+            }
+            Request::Reset(replica_id) => {
+                // Stop observing replica, keeping its watches for a while.
+                if let Some(replica) = self.replicas.get(&replica_id) {
+                    self.watches
+                        .park(&replica_id, &replica.realroot, &replica.settings);
+                }
+                self.journal_changes();
+                self.remove_replica(&replica_id);
+                debug!("replicas: {:?}", self.replicas);
+            }
+            Request::Debug => {
+                enable_debug_logging();
+            }
+            Request::Done => {
+                if let Some(watches) = self.watches.get_mut(&self.current_replica) {
+                    watches.release_leftover();
+                }
+            }
+            Request::Blank => {}
+            // Answered before dispatching.
+            Request::Version(_) => {}
+            Request::Unknown(cmd) => {
+                // Newer unison releases may add commands, keep the session
+                // alive. Only the START exchange expects an answer per line.
+                warn!("Unrecognized cmd: {}", cmd);
+                if self.phase == Phase::StartingReplica {
+                    self.send(Response::Error(format!("Unrecognized cmd: {}", cmd)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record a directory announced for a replica, watching it on its own in
+    /// selective mode.
+    fn add_dir(&mut self, replica_id: &str, path: &Path) -> Result<()> {
+        let replica = match self.replicas.get_mut(replica_id) {
+            Some(replica) => replica,
+            None => return Ok(()),
+        };
+        if replica.dirs.contains(path) {
+            return Ok(());
+        }
+        // Watching every snapshot of a tree would take a watch per directory
+        // and snapshot.
+        let in_snapshot = replica
+            .strip(path, &replica.root)
+            .is_some_and(filter::in_snapshot);
+        if replica.settings.ignore_snapshots && in_snapshot {
+            debug!("Skipping snapshot directory {}", path.display());
+            return Ok(());
+        }
+        let watched = replica.selective && replica.within_depth(path);
+        if watched {
+            let realpath = replica.realpath(path);
+            self.watch_or_poll(replica_id, |watches| watches.add_single(&realpath))?;
+        }
+        if let Some(replica) = self.replicas.get_mut(replica_id) {
+            if watched {
+                replica.paths.insert(path.to_owned());
+                if is_mount_point(path) {
+                    replica.mounts.insert(path.to_owned());
+                }
+            }
+            replica.dirs.insert(path.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Set up a watch of a replica, moving the replica over to polling if
+    /// the native backend ran out of watches and it may fall back.
+    fn watch_or_poll(
+        &mut self,
+        replica_id: &str,
+        watch: impl Fn(&mut Watches<Share<WATCH>>) -> Result<()>,
+    ) -> Result<()> {
+        let replica = match self.replicas.get(replica_id) {
+            Some(replica) => replica,
+            None => return Ok(()),
+        };
+        let watched = watch(self.watches.add(replica_id, &replica.settings)?);
+        if let Err(MonitorError::WatchLimit(msg)) = &watched {
+            if self.poll_instead(replica_id, msg)? {
+                let settings = &self.replicas[replica_id].settings;
+                return watch(self.watches.add(replica_id, settings)?);
+            }
+        }
+        watched
+    }
+
+    /// Move a replica over to polling, with the watches it has, if the
+    /// native backend ran out of watches and it may fall back.
+    fn poll_instead(&mut self, replica_id: &str, msg: &str) -> Result<bool> {
+        let replica = match self.replicas.get_mut(replica_id) {
+            Some(replica)
+                if replica.settings.poll_fallback && replica.settings.backend != "poll" =>
+            {
+                replica
+            }
+            _ => return Ok(false),
+        };
+        warn!(
+            replica = replica_id;
+            "replica {}: {}, polling it instead", replica_id, msg
+        );
+        replica.settings.backend = "poll".into();
+        // Leaving the native watcher frees the watches no other replica
+        // holds.
+        self.watches.replace(replica_id, &replica.settings)?;
+        Ok(true)
+    }
+
+    /// Register the root of a starting replica with other monitors, failing
+    /// if one watches it already and duplicates are refused.
+    fn lock_replica(&mut self, replica_id: &str, root: &Path) -> Result<()> {
+        let locks = match &mut self.locks {
+            Some(locks) => locks,
+            None => return Ok(()),
+        };
+        match locks.acquire(replica_id, root) {
+            Ok(None) => Ok(()),
+            Ok(Some(holder)) if locks.duplicates == Duplicates::Refuse => {
+                Err(MonitorError::WatchError(format!(
+                    "{} is watched by another monitor, {}",
+                    root.display(),
+                    holder
+                )))
+            }
+            Ok(Some(holder)) => {
+                warn!(
+                    "{} is watched by another monitor too, {}",
+                    root.display(),
+                    holder
+                );
+                Ok(())
+            }
+            Err(err) => {
+                warn!("Cannot register {}: {}", root.display(), err);
+                Ok(())
+            }
+        }
+    }
+
+    /// Write the changes of each replica that unison has not acknowledged
+    /// to the journal.
+    fn journal_changes(&mut self) {
+        let journal = match &mut self.journal {
+            Some(journal) => journal,
+            None => return,
+        };
+        for (id, replica) in &self.replicas {
+            let changes = replica
+                .pending_changes
+                .iter()
+                .chain(&replica.reported_changes)
+                .chain(&replica.held);
+            journal.record(id, &replica.root, changes);
+        }
+    }
+
+    /// Forget a replica, dropping its watcher.
+    fn remove_replica(&mut self, replica_id: &str) {
+        self.replicas.remove(replica_id);
+        self.index.remove(replica_id);
+        self.watches.remove(replica_id);
+        if let Some(locks) = &mut self.locks {
+            locks.release(replica_id);
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.forget(replica_id);
+        }
+    }
+
+    /// Stop watching everything and flush pending output.
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.journal_changes();
+        self.replicas.clear();
+        self.index.clear();
+        self.watches.clear();
+        if let Some(locks) = &mut self.locks {
+            locks.clear();
+        }
+        self.writer.flush_responses()?;
+        Ok(())
+    }
+
+    fn send(&mut self, response: Response) {
+        match &response {
+            Response::Changes(replica) => {
+                debug!(kind = "response", replica = &**replica; ">> {}", response)
+            }
+            Response::Recursive(_) => {
+                metrics::CHANGES_REPORTED.inc();
+                debug!(kind = "response"; ">> {}", response)
+            }
+            _ => debug!(kind = "response"; ">> {}", response),
+        }
+        let _ = self.writer.send(response);
+    }
+
+    fn send_ack(&mut self) {
+        self.send(Response::Ok);
+    }
+
+    fn send_changes(&mut self, replica: &Id) {
+        self.send(Response::Changes(replica.clone()));
+    }
+
+    /// The protocol has no non-recursive variant. Events carry the exact path
+    /// that changed, so a file is rescanned on its own and only directory
+    /// events make unison walk a subtree.
+    fn send_recursive(&mut self, path: &Path) {
+        self.send(Response::Recursive(path.to_owned()));
+    }
+
+    fn send_done(&mut self) {
+        self.send(Response::Done);
+    }
+
+    /// Report an error from handling an event to unison. Fatal errors are
+    /// passed on, the session cannot continue after them.
+    pub fn handle_error(&mut self, err: MonitorError) -> Result<()> {
+        warn!("{}", err);
+        if matches!(
+            err,
+            MonitorError::WatchError(_) | MonitorError::WatchLimit(_)
+        ) {
+            metrics::WATCH_ERRORS.inc();
+        }
+        self.send(Response::Error(err.to_string()));
+        if err.is_fatal() {
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Report a failure confined to one replica, which stops being monitored
+    /// while the others keep going.
+    fn send_replica_error(&mut self, replica_id: &str, msg: &str) {
+        warn!(replica = replica_id; "replica {}: {}", replica_id, msg);
+        self.send(Response::Error(msg.to_owned()));
+        self.remove_replica(replica_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::logging::raised_level;
+    use crate::protocol::encode;
+    use log::LevelFilter;
+    use notify::event::{
+        AccessKind, AccessMode, CreateKind, DataChange, Flag, RemoveKind, RenameMode,
+    };
+    use std::io::{BufRead, BufWriter, Cursor};
+    use std::time::Duration;
+
+    struct Watcher {}
+
+    impl Watch for Watcher {}
+
+    /// Keeps track of the paths holding an OS watch.
+    #[derive(Default)]
+    struct RecordingWatcher {
+        paths: HashSet<PathBuf>,
+        /// Subset of `paths` watched non-recursively.
+        singles: HashSet<PathBuf>,
+    }
+
+    impl Watch for RecordingWatcher {
+        fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+            self.paths.insert(path.to_owned());
+            if recursive_mode == RecursiveMode::NonRecursive {
+                self.singles.insert(path.to_owned());
+            }
+            Ok(())
+        }
+
+        fn unwatch(&mut self, path: &Path) -> Result<()> {
+            if !self.paths.remove(path) {
+                return Err(MonitorError::WatchError(format!(
+                    "Not watching {}",
+                    path.display()
+                )));
+            }
+            self.singles.remove(path);
+            Ok(())
+        }
+    }
+
+    /// Validates paths against the real filesystem.
+    struct CheckingWatcher {}
+
+    impl Watch for CheckingWatcher {
+        fn validate(&self, path: &Path) -> Result<()> {
+            validate_dir(path)
+        }
+    }
+
+    /// Fails to watch one particular path.
+    struct BrokenWatcher {
+        path: PathBuf,
+    }
+
+    impl Watch for BrokenWatcher {
+        fn watch(&mut self, path: &Path, _recursive_mode: RecursiveMode) -> Result<()> {
+            if path == self.path {
+                return Err(MonitorError::WatchError("No such file or directory".into()));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_version() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("VERSION 1\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["VERSION 1"]
+        );
+    }
+
+    #[test]
+    fn test_start() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let id = "123";
+        let root = PathBuf::from("/tmp/sample");
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START {} {}\n",
+                id,
+                root.to_string_lossy()
+            )))
+            .unwrap();
+
+        assert_eq!(monitor.replicas.len(), 1);
+        assert!(monitor.replicas.contains_key(id));
+        assert_eq!(monitor.replicas.get(id).unwrap().root, root);
+        assert!(monitor.replicas.get(id).unwrap().paths.contains(&root));
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK"]
+        );
+    }
+
+    #[test]
+    fn test_start_with_subdir() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let id = "123";
+        let root = PathBuf::from("/tmp/sample");
+        let subdir = PathBuf::from("subdir");
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START {} {} {}\n",
+                id,
+                root.to_string_lossy(),
+                subdir.to_string_lossy()
+            )))
+            .unwrap();
+
+        assert_eq!(monitor.replicas.len(), 1);
+        assert!(monitor.replicas.contains_key(id));
+        assert_eq!(monitor.replicas.get(id).unwrap().root, root);
+        assert!(monitor
+            .replicas
+            .get(id)
+            .unwrap()
+            .paths
+            .contains(&root.join(&subdir)));
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK"]
+        );
+    }
+
+    #[test]
+    fn test_dir() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor.handle_event(Event::Input("DIR\n".into())).unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK"]
+        );
+    }
+
+    #[test]
+    fn test_dir_with_dir() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("DIR dir\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK"]
+        );
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let id = "123";
+        let root = "/tmp/sample";
+        let filename = "filename";
+
+        monitor
+            .handle_event(Event::Input(format!("START {} {}\n", id, root)))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("WAIT {}\n", id)))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(PathBuf::from(root).join(filename)),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("CHANGES {}\n", id)))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec![
+                "OK",
+                &format!("CHANGES {}", id),
+                &format!("RECURSIVE {}", filename),
+                "DONE"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_changes_with_subdir() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let id = "123";
+        let root = "/tmp/sample";
+        let subdir = "subdir";
+        let filename = "filename";
+
+        monitor
+            .handle_event(Event::Input(format!("START {} {} {}\n", id, root, subdir)))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("WAIT {}\n", id)))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(PathBuf::from(root).join(subdir).join(filename)),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("CHANGES {}\n", id)))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec![
+                "OK",
+                &format!("CHANGES {}", id),
+                &format!("RECURSIVE {}/{}", subdir, filename),
+                "DONE"
+            ]
+        );
+    }
+
+    /// Create `root/link -> target` under a fresh temporary directory.
+    #[cfg(unix)]
+    fn link_fixture() -> (tempfile::TempDir, PathBuf) {
+        let tmp = crate::test_dir();
+        let base = tmp.path();
+        std::fs::create_dir_all(base.join("root")).unwrap();
+        std::fs::create_dir_all(base.join("target")).unwrap();
+        let base = base.canonicalize().unwrap();
+        std::os::unix::fs::symlink(base.join("target"), base.join("root").join("link")).unwrap();
+        (tmp, base)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_link() {
+        let (_tmp, base) = link_fixture();
+
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let id = "123";
+        let root = base.join("root");
+        let filename = "filename";
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START {} {}\n",
+                id,
+                root.to_string_lossy()
+            )))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("LINK link\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("WAIT {}\n", id)))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(base.join("target").join(filename)),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("CHANGES {}\n", id)))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec![
+                "OK",
+                "OK",
+                &format!("CHANGES {}", id),
+                &format!("RECURSIVE link/{}", filename),
+                "DONE"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_eof() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        monitor.handle_event(Event::Eof).unwrap();
+
+        assert!(monitor.replicas.is_empty());
+        assert!(monitor.watches.replicas.is_empty());
+    }
+
+    #[test]
+    fn test_start_supersedes_subdir() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let id = "123";
+        let root = PathBuf::from("/tmp/sample");
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START {} {} subdir\n",
+                id,
+                root.to_string_lossy()
+            )))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!(
+                "START {} {}\n",
+                id,
+                root.to_string_lossy()
+            )))
+            .unwrap();
+
+        let paths = &monitor.replicas.get(id).unwrap().paths;
+        assert_eq!(paths.len(), 1);
+        assert!(paths.contains(&root));
+    }
+
+    #[test]
+    fn test_changes_outside_subdir() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let root = "/tmp/sample";
+
+        monitor
+            .handle_event(Event::Input(format!("START 123 {} subdir\n", root)))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("START 456 {}\n", root)))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 456\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(PathBuf::from(root).join("other").join("filename")),
+            ))
+            .unwrap();
+
+        assert!(monitor
+            .replicas
+            .get("123")
+            .unwrap()
+            .pending_changes
+            .is_empty());
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK", "OK", "CHANGES 456"]
+        );
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let root = "/tmp/sample";
+
+        monitor
+            .handle_event(Event::Input(format!("START 123 {}\n", root)))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("START 456 {}\n", root)))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(PathBuf::from(root).join("filename")),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("RESET 123\n".into()))
+            .unwrap();
+
+        assert!(!monitor.replicas.contains_key("123"));
+        assert_eq!(
+            monitor.replicas.get("456").unwrap().pending_changes.len(),
+            1
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reset_link() {
+        let (_tmp, base) = link_fixture();
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START 123 {}\n",
+                base.join("root").to_string_lossy()
+            )))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("LINK link\n".into()))
+            .unwrap();
+        assert_eq!(monitor.replicas["123"].links.len(), 1);
+        monitor
+            .handle_event(Event::Input("RESET 123\n".into()))
+            .unwrap();
+
+        assert!(monitor.watches.replicas.is_empty());
+    }
+
+    #[test]
+    fn test_safety_scan() {
+        let mut monitor = Monitor::new(|_, _| Ok(RecordingWatcher::default()), Cursor::new(vec![]));
+        monitor.config.safety_scan = Some(600);
+        for line in ["START 123 /tmp/sample\n", "DONE\n"] {
+            monitor.handle_event(Event::Input(line.into())).unwrap();
+        }
+        let event = |paths: &[&str]| {
+            let mut event = notify::Event::new(EventKind::Any).set_info(scan::SCAN_INFO);
+            event.paths = paths.iter().map(PathBuf::from).collect();
+            Event::ReplicaEvent("123".into(), event)
+        };
+        monitor
+            .handle_event(Event::ReplicaEvent(
+                "123".into(),
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path("/tmp/sample/a/b".into()),
+            ))
+            .unwrap();
+        let replica = monitor.replicas.get_mut("123").unwrap();
+        replica.take_changes();
+        replica.acknowledge();
+
+        // Reported by the watcher already.
+        monitor
+            .handle_event(event(&["/tmp/sample/a/b/c", "/tmp/sample/d"]))
+            .unwrap();
+        let replica = monitor.replicas.get_mut("123").unwrap();
+        assert_eq!(replica.take_changes(), [PathBuf::from("d")].into());
+        assert!(replica.seen.is_empty());
+
+        // Not since the last scan.
+        monitor.handle_event(event(&["/tmp/sample/a/b"])).unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            [PathBuf::from("a/b")].into()
+        );
+    }
+
+    #[test]
+    fn test_journal_replay() {
+        let tmp = crate::test_dir();
+        let dir = tmp.path();
+        let start = |monitor: &mut Monitor<RecordingWatcher, Cursor<Vec<u8>>>| {
+            monitor.journal = Some(Journal::new(dir.to_owned()));
+            for line in ["START 123 /tmp/sample\n", "DONE\n"] {
+                monitor.handle_event(Event::Input(line.into())).unwrap();
+            }
+        };
+
+        let mut monitor = Monitor::new(|_, _| Ok(RecordingWatcher::default()), Cursor::new(vec![]));
+        start(&mut monitor);
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path("/tmp/sample/filename".into()),
+            ))
+            .unwrap();
+        // Unison never asked for it before the monitor went away.
+        monitor.shutdown().unwrap();
+
+        let mut monitor = Monitor::new(|_, _| Ok(RecordingWatcher::default()), Cursor::new(vec![]));
+        start(&mut monitor);
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            [PathBuf::from("filename")].into()
+        );
+
+        // Taken and acknowledged, the journal is cleared.
+        for line in ["CHANGES 123\n", "WAIT 123\n"] {
+            monitor.handle_event(Event::Input(line.into())).unwrap();
+        }
+        monitor.journal_changes();
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_reset_reuses_watches() {
+        let built = std::rc::Rc::new(std::cell::Cell::new(0));
+        let count = built.clone();
+        let mut monitor = Monitor::new(
+            move |_, _| {
+                count.set(count.get() + 1);
+                Ok(RecordingWatcher::default())
+            },
+            Cursor::new(vec![]),
+        );
+        let root = PathBuf::from("/tmp/sample");
+
+        for line in [
+            "START 123 /tmp/sample\n",
+            "DONE\n",
+            "RESET 123\n",
+            "START 456 /tmp/sample\n",
+            "DONE\n",
+            "WAIT 456\n",
+        ] {
+            monitor.handle_event(Event::Input(line.into())).unwrap();
+        }
+        assert_eq!(built.get(), 1);
+        let watches = monitor.watches.get_mut("456").unwrap();
+        assert_eq!(watches.counts[&root], 1);
+        assert!(watches.watcher.group().watcher.paths.contains(&root));
+
+        // The watcher still tags its events with the old id.
+        monitor
+            .handle_event(Event::ReplicaEvent(
+                "123".into(),
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(root.join("filename")),
+            ))
+            .unwrap();
+        assert_eq!(
+            monitor.replicas["456"].pending_changes,
+            [PathBuf::from("filename")].into()
+        );
+
+        // A replica with the old id shares the watcher too.
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/other\n".into()))
+            .unwrap();
+        assert_eq!(built.get(), 1);
+        assert_eq!(
+            *monitor.watches.members("123"),
+            ["123".into(), "456".into()].into()
+        );
+
+        // Once the grace period passed, a replica starts from scratch.
+        monitor
+            .handle_event(Event::Input("RESET 456\n".into()))
+            .unwrap();
+        monitor.watches.expire(Instant::now() + PARK_TIMEOUT);
+        let paths = |monitor: &Monitor<RecordingWatcher, Cursor<Vec<u8>>>| {
+            monitor.watches.replicas["123"]
+                .watcher
+                .group()
+                .watcher
+                .paths
+                .clone()
+        };
+        assert_eq!(paths(&monitor), [PathBuf::from("/tmp/other")].into());
+        monitor
+            .handle_event(Event::Input("START 456 /tmp/sample\n".into()))
+            .unwrap();
+        assert_eq!(paths(&monitor), [PathBuf::from("/tmp/other"), root].into());
+    }
+
+    #[test]
+    fn test_wait() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let root = PathBuf::from("/tmp/sample");
+        let event = |filename| {
+            Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(root.join(filename)),
+            )
+        };
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        // Not waited for yet, no notification.
+        monitor.handle_event(event("a")).unwrap();
+        // Pending changes are announced as soon as unison waits.
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+        monitor.handle_event(event("b")).unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+        // A new wait cycle notifies once for any number of events.
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+        monitor.handle_event(event("c")).unwrap();
+        monitor.handle_event(event("d")).unwrap();
+
+        monitor.writer.set_position(0);
+        let lines = monitor
+            .writer
+            .lines()
+            .collect::<std::io::Result<Vec<String>>>()
+            .unwrap();
+        assert_eq!(lines[..3], ["OK", "CHANGES 123", "CHANGES 123"]);
+        assert_eq!(lines[5..], ["DONE", "CHANGES 123"]);
+    }
+
+    #[test]
+    fn test_changes_notified_once() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let root = "/tmp/sample";
+
+        for id in &["123", "456"] {
+            monitor
+                .handle_event(Event::Input(format!("START {} {}\n", id, root)))
+                .unwrap();
+            monitor
+                .handle_event(Event::Input(format!("WAIT {}\n", id)))
+                .unwrap();
+        }
+        for _ in 0..3 {
+            monitor
+                .handle_event(Event::FSEvent(
+                    notify::Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                        .add_path(PathBuf::from(root).join("filename")),
+                ))
+                .unwrap();
+        }
+
+        monitor.writer.set_position(0);
+        let mut lines = monitor
+            .writer
+            .lines()
+            .collect::<std::io::Result<Vec<String>>>()
+            .unwrap();
+        lines.sort();
+        assert_eq!(lines, vec!["CHANGES 123", "CHANGES 456", "OK", "OK"]);
+    }
+
+    #[test]
+    fn test_changes_keeps_other_replicas() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let root = "/tmp/sample";
+
+        monitor
+            .handle_event(Event::Input(format!("START 123 {}\n", root)))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("START 456 {}\n", root)))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(PathBuf::from(root).join("filename")),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+        monitor.handle_event(Event::Input("DIR\n".into())).unwrap();
+
+        assert!(monitor
+            .replicas
+            .get("123")
+            .unwrap()
+            .pending_changes
+            .is_empty());
+        assert!(monitor
+            .replicas
+            .get("456")
+            .unwrap()
+            .pending_changes
+            .contains(Path::new("filename")));
+    }
+
+    #[test]
+    fn test_changes_collapsed() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let root = PathBuf::from("/tmp/sample");
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        for path in &["a/b", "a", "a/c", "a", "b"] {
+            monitor
+                .handle_event(Event::FSEvent(
+                    notify::Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                        .add_path(root.join(path)),
+                ))
+                .unwrap();
+        }
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK", "RECURSIVE a", "RECURSIVE b", "DONE"]
+        );
+    }
+
+    #[test]
+    fn test_version_negotiation() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("VERSION 1 2\n".into()))
+            .unwrap();
+        assert_eq!(monitor.version, 1);
+        assert!(monitor
+            .handle_event(Event::Input("VERSION 2\n".into()))
+            .is_err());
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["VERSION 1"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_changes_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/caf%E9\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(PathBuf::from(OsStr::from_bytes(b"/tmp/caf\xe9/\xff"))),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK", "RECURSIVE %FF", "DONE"]
+        );
+    }
+
+    #[test]
+    fn test_changes_normalize_unicode() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        monitor.normalize_unicode = true;
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/caf%C3%A9\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(PathBuf::from("/tmp/cafe\u{301}/a\u{308}")),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK", "RECURSIVE %C3%A4", "DONE"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_changes_canonical_root() {
+        let (_tmp, base) = link_fixture();
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START 123 {}\n",
+                base.join("root").join("link").to_string_lossy()
+            )))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(base.join("target").join("filename")),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK", "RECURSIVE filename", "DONE"]
+        );
+    }
+
+    #[test]
+    fn test_changes_bind_mount() {
+        let tmp = crate::test_dir();
+        let base = tmp.path();
+        let root = base.canonicalize().unwrap();
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START 123 {}\n",
+                root.to_string_lossy()
+            )))
+            .unwrap();
+        // The subdirectory is mounted elsewhere too, and the watch reports
+        // it there.
+        let replica = monitor.replicas.get_mut("123").unwrap();
+        replica.bind_mounts = vec![("/srv/app".into(), root.join("app"))];
+        monitor
+            .index
+            .update(&"123".into(), replica.prefixes(), false);
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path("/srv/app/filename".into()),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK", "RECURSIVE app/filename", "DONE"]
+        );
+    }
+
+    #[test]
+    fn test_rescan() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                    .add_path(PathBuf::from("/tmp/sample/filename")),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Other).set_flag(Flag::Rescan),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK", "CHANGES 123", "RECURSIVE ", "DONE"]
+        );
+    }
+
+    #[test]
+    fn test_replica_error() {
+        let watcher = |_: &str, _: &Settings| {
+            Ok(BrokenWatcher {
+                path: PathBuf::from("/tmp/bad"),
+            })
+        };
+        let mut monitor = Monitor::new(watcher, Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/good\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("START 456 /tmp/bad\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 456\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+
+        assert!(monitor.replicas.contains_key("123"));
+        assert!(!monitor.replicas.contains_key("456"));
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec![
+                "OK",
+                "ERROR Cannot%20watch%20/tmp/bad:%20No%20such%20file%20or%20directory",
+                "ERROR Unknown%20replica:%20456",
+            ]
+        );
+    }
+
+    /// Runs out of watches unless polling.
+    struct ExhaustedWatcher {
+        polling: bool,
+    }
+
+    impl Watch for ExhaustedWatcher {
+        fn watch(&mut self, _path: &Path, _recursive_mode: RecursiveMode) -> Result<()> {
+            if !self.polling {
+                return Err(MonitorError::WatchLimit("Out of file watches".into()));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_watched() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        for line in [
+            "START 123 /tmp/sample\n",
+            "DONE\n",
+            "START 456 /tmp/other\n",
+            "DIR subdir\n",
+            "DONE\n",
+            "WAIT 123\n",
+        ] {
+            monitor.handle_event(Event::Input(line.into())).unwrap();
+        }
+
+        monitor
+            .handle_event(Event::Watched(
+                "123".into(),
+                PathBuf::from("/tmp/sample"),
+                Ok(()),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+        // Nothing to rescan for a single directory. The watcher is shared,
+        // only the replica registering the path hears of it.
+        monitor
+            .handle_event(Event::Watched(
+                "123".into(),
+                PathBuf::from("/tmp/other/subdir"),
+                Ok(()),
+            ))
+            .unwrap();
+        assert!(monitor.replicas["456"].pending_changes.is_empty());
+        monitor
+            .handle_event(Event::Watched(
+                "123".into(),
+                PathBuf::from("/tmp/other"),
+                Err(MonitorError::WatchError("Permission denied".into())),
+            ))
+            .unwrap();
+        assert!(!monitor.replicas.contains_key("456"));
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec![
+                "OK",
+                "OK",
+                "OK",
+                "CHANGES 123",
+                "RECURSIVE ",
+                "DONE",
+                "ERROR Cannot%20watch%20/tmp/other:%20Permission%20denied",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_poll_fallback() {
+        let watcher = |_: &str, settings: &Settings| {
+            Ok(ExhaustedWatcher {
+                polling: settings.backend == "poll",
+            })
+        };
+        let mut monitor = Monitor::new(watcher, Cursor::new(vec![]));
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        assert!(!monitor.replicas.contains_key("123"));
+        let output = String::from_utf8(monitor.writer.get_ref().clone()).unwrap();
+        assert!(
+            output.starts_with("ERROR Cannot%20watch%20/tmp/sample:%20Out%20of%20file%20watches")
+        );
+        assert!(output.contains("fs.inotify.max_user_watches"));
+
+        let mut monitor = Monitor::new(watcher, Cursor::new(vec![]));
+        monitor.config = "poll-fallback = true".parse().unwrap();
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        assert_eq!(monitor.writer.get_ref(), b"OK\n");
+        assert_eq!(monitor.replicas["123"].settings.backend, "poll");
+        assert!(
+            monitor
+                .watches
+                .get_mut("123")
+                .unwrap()
+                .watcher
+                .group()
+                .watcher
+                .polling
+        );
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), BufWriter::new(Cursor::new(vec![])));
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        assert_eq!(monitor.writer.get_ref().get_ref(), b"OK\n");
+
+        monitor.send_recursive(Path::new("filename"));
+        assert_eq!(monitor.writer.get_ref().get_ref(), b"OK\n");
+        monitor.send_done();
+        assert_eq!(
+            monitor.writer.get_ref().get_ref(),
+            b"OK\nRECURSIVE filename\nDONE\n"
+        );
+    }
+
+    #[test]
+    fn test_nested_replicas() {
+        let mut monitor = Monitor::new(|_, _| Ok(RecordingWatcher::default()), Cursor::new(vec![]));
+        let outer = PathBuf::from("/tmp/projects");
+        let inner = outer.join("app");
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/projects/app\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("START 456 /tmp/projects\n".into()))
+            .unwrap();
+        // The outer root takes the one watch for both.
+        assert_eq!(
+            monitor.watches.replicas["123"]
+                .watcher
+                .group()
+                .watcher
+                .paths,
+            vec![outer.clone()].into_iter().collect()
+        );
+        assert!(monitor.watches.replicas["456"].counts.contains_key(&outer));
+
+        monitor
+            .handle_event(Event::ReplicaEvent(
+                "123".into(),
+                notify::Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                    .add_path(inner.join("filename")),
+            ))
+            .unwrap();
+        assert!(monitor.replicas["123"]
+            .pending_changes
+            .contains(Path::new("filename")));
+        assert!(monitor.replicas["456"]
+            .pending_changes
+            .contains(Path::new("app/filename")));
+
+        monitor
+            .handle_event(Event::Input("RESET 456\n".into()))
+            .unwrap();
+        monitor.watches.expire(Instant::now() + PARK_TIMEOUT);
+        // The inner root is watched on its own again.
+        assert!(!monitor.watches.replicas.contains_key("456"));
+        assert_eq!(
+            monitor.watches.replicas["123"]
+                .watcher
+                .group()
+                .watcher
+                .paths,
+            vec![inner.clone()].into_iter().collect()
+        );
+
+        monitor
+            .handle_event(Event::Input("RESET 123\n".into()))
+            .unwrap();
+        assert!(monitor.watches.replicas.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_shared_replica_path() {
+        let (_tmp, base) = link_fixture();
+        let target = base.join("target");
+        let mut monitor = Monitor::new(|_, _| Ok(RecordingWatcher::default()), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START 123 {}\n",
+                base.join("root").join("link").to_string_lossy()
+            )))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!(
+                "START 456 {}\n",
+                target.to_string_lossy()
+            )))
+            .unwrap();
+        assert!(monitor.watches.replicas["123"].counts.contains_key(&target));
+        assert!(monitor.watches.replicas["456"].counts.contains_key(&target));
+
+        monitor
+            .handle_event(Event::Input("RESET 123\n".into()))
+            .unwrap();
+        assert!(monitor.watches.replicas["456"]
+            .watcher
+            .group()
+            .watcher
+            .paths
+            .contains(&target));
+
+        monitor
+            .handle_event(Event::Input("RESET 456\n".into()))
+            .unwrap();
+        assert!(monitor.watches.replicas.is_empty());
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("DEBUG\n".into()))
+            .unwrap();
+
+        assert!(raised_level() >= LevelFilter::Debug);
+        assert!(log::max_level() >= LevelFilter::Debug);
+        assert!(monitor.writer.get_ref().is_empty());
+    }
+
+    #[test]
+    fn test_dir_recorded() {
+        let mut monitor = Monitor::new(|_, _| Ok(RecordingWatcher::default()), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("DIR subdir\n".into()))
+            .unwrap();
+
+        assert!(monitor.replicas["123"]
+            .dirs
+            .contains(Path::new("/tmp/sample/subdir")));
+        assert!(monitor.watches.replicas["123"]
+            .watcher
+            .group()
+            .watcher
+            .singles
+            .is_empty());
+    }
+
+    #[test]
+    fn test_selective() {
+        let mut monitor = Monitor::new(|_, _| Ok(RecordingWatcher::default()), Cursor::new(vec![]));
+        monitor.selective = true;
+        let root = PathBuf::from("/tmp/sample");
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("DIR a\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("DIR a%2Fb\n".into()))
+            .unwrap();
+        assert_eq!(
+            monitor.watches.replicas["123"]
+                .watcher
+                .group()
+                .watcher
+                .singles,
+            vec![root.clone(), root.join("a"), root.join("a/b")]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            monitor.watches.replicas["123"]
+                .watcher
+                .group()
+                .watcher
+                .paths
+                .len(),
+            3
+        );
+
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                    .add_path(root.join("a/b/filename")),
+            ))
+            .unwrap();
+        assert!(monitor.replicas["123"]
+            .pending_changes
+            .contains(Path::new("a/b/filename")));
+
+        monitor
+            .handle_event(Event::Input("RESET 123\n".into()))
+            .unwrap();
+        assert!(monitor.watches.replicas.is_empty());
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let mut monitor = Monitor::new(|_, _| Ok(RecordingWatcher::default()), Cursor::new(vec![]));
+        monitor.config = "max-depth = 1".parse().unwrap();
+        let root = PathBuf::from("/tmp/sample");
+
+        for line in [
+            "START 123 /tmp/sample\n",
+            "DIR a\n",
+            "DIR a%2Fb\n",
+            "DONE\n",
+        ] {
+            monitor.handle_event(Event::Input(line.into())).unwrap();
+        }
+        assert_eq!(
+            monitor.watches.replicas["123"]
+                .watcher
+                .group()
+                .watcher
+                .singles,
+            vec![root.clone(), root.join("a")].into_iter().collect()
+        );
+        assert_eq!(
+            monitor.watches.replicas["123"]
+                .watcher
+                .group()
+                .watcher
+                .paths
+                .len(),
+            2
+        );
+
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                    .add_path(root.join("a/b/filename")),
+            ))
+            .unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            [PathBuf::from("a")].into()
+        );
+    }
+
+    #[test]
+    fn test_snapshot_dirs() {
+        let mut monitor = Monitor::new(|_, _| Ok(RecordingWatcher::default()), Cursor::new(vec![]));
+        monitor.selective = true;
+        let root = PathBuf::from("/tmp/sample");
+
+        for line in [
+            "START 123 /tmp/sample\n",
+            "DIR a\n",
+            "DIR .zfs\n",
+            "DIR .zfs%2Fsnapshot\n",
+            "DIR a%2F.snapshot\n",
+            "DONE\n",
+        ] {
+            monitor.handle_event(Event::Input(line.into())).unwrap();
+        }
+        assert_eq!(
+            monitor.watches.replicas["123"]
+                .watcher
+                .group()
+                .watcher
+                .singles,
+            vec![root.clone(), root.join("a")].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_insert_change() {
+        let mut changes = BTreeSet::new();
+        for path in ["a/b", "a/c/d", "a b", "a-b", "ab", "b"] {
+            assert!(insert_change(&mut changes, path.into()));
+        }
+        assert!(!insert_change(&mut changes, "a/b/c".into()));
+
+        // Descendants go, neighbours sorting close by stay.
+        assert!(insert_change(&mut changes, "a".into()));
+        let paths: Vec<&str> = changes.iter().map(|p| p.to_str().unwrap()).collect();
+        assert_eq!(paths, ["a", "a b", "a-b", "ab", "b"]);
+
+        assert!(insert_change(&mut changes, "".into()));
+        assert_eq!(changes, [PathBuf::new()].into());
+        assert!(!insert_change(&mut changes, "c".into()));
+    }
+
+    #[test]
+    fn test_max_pending() {
+        let mut replica = Replica::new("/tmp/sample".into());
+        replica.settings.max_pending = 3;
+        for path in ["a", "b", "c", "c/d"] {
+            replica.add_change(path.into());
+        }
+        assert_eq!(replica.pending_changes.len(), 3);
+
+        replica.add_change("e".into());
+        assert_eq!(replica.pending_changes, [PathBuf::new()].into());
+        replica.add_change("f".into());
+        assert_eq!(replica.pending_changes, [PathBuf::new()].into());
+    }
+
+    #[test]
+    fn test_hold_down() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        monitor.config = "hold-down = 60".parse().unwrap();
+        let event = || {
+            Event::FSEvent(
+                notify::Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                    .add_path(PathBuf::from("/tmp/sample/app.log")),
+            )
+        };
+
+        for line in ["START 123 /tmp/sample\n", "DONE\n", "WAIT 123\n"] {
+            monitor.handle_event(Event::Input(line.into())).unwrap();
+        }
+        monitor.handle_event(event()).unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+        // Written again right after it was reported.
+        monitor.handle_event(event()).unwrap();
+
+        let replica = monitor.replicas.get_mut("123").unwrap();
+        assert!(replica.pending_changes.is_empty());
+        assert_eq!(replica.held, [PathBuf::from("app.log")].into());
+        assert!(!replica.release(Instant::now() + Duration::from_secs(30)));
+        assert!(replica.release(Instant::now() + Duration::from_secs(60)));
+        assert_eq!(replica.pending_changes, [PathBuf::from("app.log")].into());
+        assert!(replica.held.is_empty() && replica.reported_at.is_empty());
+        assert_eq!(
+            String::from_utf8(monitor.writer.into_inner()).unwrap(),
+            "OK\nCHANGES 123\nRECURSIVE app.log\nDONE\n"
+        );
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("FROBNICATE x\n".into()))
+            .unwrap();
+        monitor.handle_event(Event::Input("DONE\n".into())).unwrap();
+        monitor
+            .handle_event(Event::Input("FROBNICATE y\n".into()))
+            .unwrap();
+        monitor.handle_event(Event::Input("\n".into())).unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK", "ERROR Unrecognized%20cmd:%20FROBNICATE", "DONE"]
+        );
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let root = PathBuf::from("/tmp/sample");
+        let rename = |path: PathBuf| {
+            Event::FSEvent(
+                notify::Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Any)))
+                    .add_path(path),
+            )
+        };
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample subdir\n".into()))
+            .unwrap();
+        // Moved out of the replica, then into another directory of it.
+        monitor
+            .handle_event(rename(root.join("subdir/a/from")))
+            .unwrap();
+        monitor
+            .handle_event(rename(PathBuf::from("/tmp/other")))
+            .unwrap();
+        monitor
+            .handle_event(rename(PathBuf::from("/tmp/other/from")))
+            .unwrap();
+        monitor
+            .handle_event(rename(root.join("subdir/to")))
+            .unwrap();
+
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            vec![PathBuf::from("subdir")].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_root_recreated() {
+        let tmp = crate::test_dir();
+        let base = tmp.path();
+        let base = base.canonicalize().unwrap();
+        let mut monitor = Monitor::new(|_, _| Ok(RecordingWatcher::default()), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START 123 {}\n",
+                base.to_string_lossy()
+            )))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+        std::fs::remove_dir(&base).unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Remove(RemoveKind::Any)).add_path(base.clone()),
+            ))
+            .unwrap();
+        assert!(monitor.replicas["123"].lost.contains(&base));
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+
+        // Recreated, noticed on the next check.
+        monitor
+            .watches
+            .get_mut("123")
+            .unwrap()
+            .watcher
+            .group
+            .borrow_mut()
+            .watcher
+            .paths
+            .clear();
+        monitor.handle_event(Event::Tick).unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+        std::fs::create_dir(&base).unwrap();
+        monitor.handle_event(Event::Tick).unwrap();
+        std::fs::remove_dir(&base).unwrap();
+
+        assert!(monitor.replicas["123"].lost.is_empty());
+        assert!(monitor.watches.replicas["123"]
+            .watcher
+            .group()
+            .watcher
+            .paths
+            .contains(&base));
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK", "CHANGES 123", "RECURSIVE ", "DONE", "CHANGES 123"]
+        );
+    }
+
+    #[test]
+    fn test_root_unmounted() {
+        let tmp = crate::test_dir();
+        let base = tmp.path();
+        let base = base.canonicalize().unwrap();
+        let mut monitor = Monitor::new(|_, _| Ok(RecordingWatcher::default()), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START 123 {}\n",
+                base.to_string_lossy()
+            )))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+        // Taken for a mount point, it is not one while it is on the same
+        // device as its parent.
+        monitor
+            .replicas
+            .get_mut("123")
+            .unwrap()
+            .mounts
+            .insert(base.clone());
+        monitor.handle_event(Event::Tick).unwrap();
+        assert!(monitor.replicas["123"].is_suspended(&base.join("file")));
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any)).add_path(base.join("file")),
+            ))
+            .unwrap();
+        assert!(monitor.replicas["123"].pending_changes.is_empty());
+
+        // Mounted again.
+        monitor.replicas.get_mut("123").unwrap().mounts.clear();
+        monitor.handle_event(Event::Tick).unwrap();
+        std::fs::remove_dir(&base).unwrap();
+
+        assert!(monitor.replicas["123"].lost.is_empty());
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            [PathBuf::new()].into_iter().collect()
+        );
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK", "CHANGES 123"]
+        );
+    }
+
+    #[test]
+    fn test_wait_for_path() {
+        let tmp = crate::test_dir();
+        let base = tmp.path().join("replica");
+        let mut monitor = Monitor::new(|_, _| Ok(RecordingWatcher::default()), Cursor::new(vec![]));
+        monitor.config = "wait-for-path = true".parse().unwrap();
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START 123 {}\n",
+                base.to_string_lossy()
+            )))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+        monitor.handle_event(Event::Tick).unwrap();
+        assert!(monitor.replicas["123"].awaited.contains(&base));
+
+        std::fs::create_dir_all(&base).unwrap();
+        monitor.handle_event(Event::Tick).unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+        let realroot = base.canonicalize().unwrap();
+        std::fs::remove_dir(&base).unwrap();
+
+        assert!(monitor.replicas["123"].awaited.is_empty());
+        assert!(monitor.watches.replicas["123"]
+            .watcher
+            .group()
+            .watcher
+            .paths
+            .contains(&realroot));
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK", "CHANGES 123", "RECURSIVE ", "DONE"]
+        );
+    }
+
+    #[test]
+    fn test_start_invalid_path() {
+        let tmp = crate::test_dir();
+        let base = tmp.path();
+        std::fs::write(base.join("file"), b"").unwrap();
+        let mut monitor = Monitor::new(|_, _| Ok(CheckingWatcher {}), Cursor::new(vec![]));
+
+        for (id, path) in &[
+            ("1", base.join("missing")),
+            ("2", base.join("file")),
+            ("3", base.to_owned()),
+        ] {
+            monitor
+                .handle_event(Event::Input(format!(
+                    "START {} {}\n",
+                    id,
+                    encode(path.as_os_str())
+                )))
+                .unwrap();
+        }
+
+        assert_eq!(
+            monitor.replicas.keys().map(|id| &**id).collect::<Vec<_>>(),
+            ["3"]
+        );
+        monitor.writer.set_position(0);
+        let lines = monitor
+            .writer
+            .lines()
+            .collect::<std::io::Result<Vec<String>>>()
+            .unwrap();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("ERROR Cannot%20watch"));
+        assert!(lines[1].ends_with("Not%20a%20directory"));
+        assert_eq!(lines[2], "OK");
+    }
+
+    #[test]
+    fn test_changes_normalize_windows() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        monitor.normalize_windows = true;
+
+        monitor
+            .handle_event(Event::Input("START 123 c:%5CUsers%5Cme\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(PathBuf::from(r"\\?\C:\Users\me\dir\file.txt")),
+            ))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["OK", "RECURSIVE dir/file.txt", "DONE"]
+        );
+    }
+
+    #[test]
+    fn test_changes_case_insensitive() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("START 123 /Users/Me/Sync\n".into()))
+            .unwrap();
+        // As if START had found the root on such a filesystem.
+        let replica = monitor.replicas.get_mut("123").unwrap();
+        replica.case_insensitive = true;
+        monitor
+            .index
+            .update(&"123".into(), replica.prefixes(), true);
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(PathBuf::from("/users/me/SYNC/File.txt")),
+            ))
+            .unwrap();
+
+        assert!(monitor.replicas["123"]
+            .pending_changes
+            .contains(Path::new("File.txt")));
+    }
+
+    #[test]
+    fn test_changes_unacknowledged() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let root = PathBuf::from("/tmp/sample");
+        let event = |filename| {
+            Event::FSEvent(
+                notify::Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                    .add_path(root.join(filename)),
+            )
+        };
+        let input = |line: &str| Event::Input(line.to_owned());
+
+        monitor
+            .handle_event(input("START 123 /tmp/sample\n"))
+            .unwrap();
+        monitor.handle_event(event("a")).unwrap();
+        monitor.handle_event(input("CHANGES 123\n")).unwrap();
+        // Queried again before waiting, "a" is sent again.
+        monitor.handle_event(event("b")).unwrap();
+        monitor.handle_event(input("CHANGES 123\n")).unwrap();
+        // Acknowledged by waiting.
+        monitor.handle_event(input("WAIT 123\n")).unwrap();
+        monitor.handle_event(input("CHANGES 123\n")).unwrap();
+        // Restarting the replica queues unacknowledged changes again.
+        monitor.handle_event(event("c")).unwrap();
+        monitor.handle_event(input("CHANGES 123\n")).unwrap();
+        monitor
+            .handle_event(input("START 123 /tmp/sample\n"))
+            .unwrap();
+        assert!(monitor.replicas["123"]
+            .pending_changes
+            .contains(Path::new("c")));
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec![
+                "OK",
+                "RECURSIVE a",
+                "DONE",
+                "RECURSIVE a",
+                "RECURSIVE b",
+                "DONE",
+                "DONE",
+                "CHANGES 123",
+                "RECURSIVE c",
+                "DONE",
+                "OK"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_argument() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        let err = monitor
+            .handle_event(Event::Input("WAIT\n".into()))
+            .unwrap_err();
+        assert!(matches!(err, MonitorError::ProtocolError(_)));
+        monitor.handle_error(err).unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<std::io::Result<Vec<String>>>()
+                .unwrap(),
+            vec!["ERROR Missing%20argument%201%20to%20WAIT", "DONE"]
+        );
+    }
+
+    #[test]
+    fn test_rename_both() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let root = PathBuf::from("/tmp/sample");
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                    .add_path(root.join("a/from"))
+                    .add_path(root.join("b/to")),
+            ))
+            .unwrap();
+
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            vec![PathBuf::from("a"), PathBuf::from("b")]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_directory_moved() {
+        let tmp = crate::test_dir();
+        let base = tmp.path();
+        std::fs::create_dir_all(base.join("b/to/c")).unwrap();
+        let base = base.canonicalize().unwrap();
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        monitor.config.hold_down = Some(60);
+        let rename = |mode, path: PathBuf| {
+            Event::FSEvent(
+                notify::Event::new(EventKind::Modify(ModifyKind::Name(mode)))
+                    .add_path(path)
+                    .set_tracker(7),
+            )
+        };
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START 123 {}\n",
+                base.to_string_lossy()
+            )))
+            .unwrap();
+        monitor
+            .handle_event(rename(RenameMode::From, base.join("a/from")))
+            .unwrap();
+        let replica = monitor.replicas.get_mut("123").unwrap();
+        assert_eq!(replica.take_changes(), [PathBuf::from("a")].into());
+        replica.acknowledge();
+
+        // Both ends are reported, held back or not.
+        monitor
+            .handle_event(rename(RenameMode::To, base.join("b/to")))
+            .unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            [PathBuf::from("a"), PathBuf::from("b")].into()
+        );
+        assert!(monitor.renames.is_empty());
+    }
+
+    #[test]
+    fn test_access_ignored() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Access(AccessKind::Open(AccessMode::Any)))
+                    .add_path(PathBuf::from("/tmp/sample/filename")),
+            ))
+            .unwrap();
+
+        assert!(monitor.replicas["123"].pending_changes.is_empty());
+    }
+
+    #[test]
+    fn test_replica_settings() {
+        let backends = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let seen = backends.clone();
+        let mut monitor = Monitor::new(
+            move |_, settings: &Settings| {
+                seen.borrow_mut().push(settings.backend.clone());
+                Ok(Watcher {})
+            },
+            Cursor::new(vec![]),
+        );
+        monitor.config =
+            "[[replica]]\npath = \"/tmp/sample\"\nbackend = \"poll\"\nignore = [\"build\"]"
+                .parse()
+                .unwrap();
+        let event = |path: &str| {
+            Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(PathBuf::from(path)),
+            )
+        };
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("START 456 /tmp/other\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(event("/tmp/sample/build/out"))
+            .unwrap();
+        monitor.handle_event(event("/tmp/other/build/out")).unwrap();
+
+        assert_eq!(*backends.borrow(), ["poll", "auto"]);
+        assert!(monitor.replicas["123"].pending_changes.is_empty());
+        assert_eq!(
+            monitor.replicas["456"].pending_changes,
+            [PathBuf::from("build/out")].into()
+        );
+    }
+
+    #[test]
+    fn test_ignore_command() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let event = |path: &str| {
+            Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(PathBuf::from(path)),
+            )
+        };
+
+        for line in [
+            "START 123 /tmp/sample\n",
+            "IGNORE build\n",
+            "IGNORE !build/config.json\n",
+            "IGNORE [abc\n",
+            "DONE\n",
+            "START 456 /tmp/other\n",
+            "DONE\n",
+        ] {
+            monitor.handle_event(Event::Input(line.into())).unwrap();
+        }
+        for path in [
+            "/tmp/sample/build/out",
+            "/tmp/sample/build/config.json",
+            "/tmp/other/build/out",
+        ] {
+            monitor.handle_event(event(path)).unwrap();
+        }
+        // Announced ignores survive reloads of the config.
+        monitor.handle_event(Event::Reload(Box::default())).unwrap();
+
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            [PathBuf::from("build/config.json")].into()
+        );
+        assert_eq!(
+            monitor.replicas["123"].settings.ignore.last(),
+            Some(&"!build/config.json".parse().unwrap())
+        );
+        assert_eq!(
+            monitor.replicas["456"].pending_changes,
+            [PathBuf::from("build/out")].into()
+        );
+        assert_eq!(
+            String::from_utf8(monitor.writer.into_inner()).unwrap(),
+            "OK\nOK\nOK\nERROR Cannot%20ignore%20[abc:%20unclosed%20[\nOK\n"
+        );
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        monitor.config = "ignore = [\"build\", \".git\"]".parse().unwrap();
+        let event = |path: &str| {
+            Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(PathBuf::from(path)),
+            )
+        };
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(event("/tmp/sample/build/out"))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Reload(Box::new(
+                "ignore = [\".git\", \"!.git/config\", \"dist\"]\ndebounce-ms = 500"
+                    .parse()
+                    .unwrap(),
+            )))
+            .unwrap();
+        monitor.handle_event(event("/tmp/sample/dist/out")).unwrap();
+
+        // What happened in the build directory while it was ignored is
+        // rescanned, and so is what became an exception.
+        let replica = &monitor.replicas["123"];
+        assert_eq!(
+            replica.pending_changes,
+            [PathBuf::from("build"), ".git/config".into()].into()
+        );
+        assert_eq!(
+            replica.settings.ignore,
+            [
+                ".git".parse().unwrap(),
+                "!.git/config".parse().unwrap(),
+                "dist".parse().unwrap()
+            ]
+        );
+        assert_eq!(replica.settings.debounce, crate::backend::DEBOUNCE_TIMEOUT);
+        assert_eq!(monitor.config.debounce_ms, Some(500));
+        assert_eq!(
+            String::from_utf8(monitor.writer.into_inner()).unwrap(),
+            "OK\nCHANGES 123\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_duplicate_refused() {
+        let tmp = crate::test_dir();
+        let dir = tmp.path();
+        let mut other = ReplicaLocks::new(dir.to_owned(), Duplicates::Share);
+        other.acquire("123", Path::new("/tmp/sample")).unwrap();
+
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        monitor.locks = Some(ReplicaLocks::new(dir.to_owned(), Duplicates::Refuse));
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("START 456 /tmp/other\n".into()))
+            .unwrap();
+
+        assert!(!monitor.replicas.contains_key("123"));
+        assert!(monitor.replicas.contains_key("456"));
+        assert_eq!(
+            String::from_utf8(monitor.writer.into_inner()).unwrap(),
+            format!(
+                "ERROR /tmp/sample%20is%20watched%20by%20another%20monitor,%20pid%20{}\nOK\n",
+                std::process::id()
+            )
+        );
+        drop(monitor.locks);
+        drop(other);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hardlinks() {
+        let tmp = crate::test_dir();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        let root = root.canonicalize().unwrap();
+        fs::write(root.join("a/file"), b"").unwrap();
+        fs::hard_link(root.join("a/file"), root.join("link")).unwrap();
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        monitor.config = "hardlinks = true".parse().unwrap();
+
+        monitor
+            .handle_event(Event::Input(format!("START 123 {}\n", root.display())))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Modify(ModifyKind::Any)).add_path(root.join("link")),
+            ))
+            .unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            [PathBuf::from("a/file"), PathBuf::from("link")].into()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hardlinks_deferred() {
+        /// Maps hard links later, as `Background` does.
+        struct DeferringWatcher {}
+
+        impl Watch for DeferringWatcher {
+            fn hardlinks(&mut self, _root: &Path) -> Option<Hardlinks> {
+                None
+            }
+        }
+
+        let tmp = crate::test_dir();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        let root = root.canonicalize().unwrap();
+        fs::write(root.join("a/file"), b"").unwrap();
+        fs::hard_link(root.join("a/file"), root.join("link")).unwrap();
+        let mut monitor = Monitor::new(|_, _| Ok(DeferringWatcher {}), Cursor::new(vec![]));
+        monitor.config = "hardlinks = true".parse().unwrap();
+        let modified = || {
+            Event::FSEvent(
+                notify::Event::new(EventKind::Modify(ModifyKind::Any)).add_path(root.join("link")),
+            )
+        };
+
+        monitor
+            .handle_event(Event::Input(format!("START 123 {}\n", root.display())))
+            .unwrap();
+        // Without the map yet, the change goes without its aliases.
+        monitor.handle_event(modified()).unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            [PathBuf::from("link")].into()
+        );
+
+        monitor
+            .handle_event(Event::Hardlinks(
+                "123".into(),
+                root.clone(),
+                Hardlinks::scan(&root),
+            ))
+            .unwrap();
+        monitor.handle_event(modified()).unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            [PathBuf::from("a/file"), PathBuf::from("link")].into()
+        );
+    }
+
+    #[test]
+    fn test_gitignore() {
+        let tmp = crate::test_dir();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("web")).unwrap();
+        fs::write(root.join("web/.gitignore"), "dist\n").unwrap();
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        monitor.config = "gitignore = true".parse().unwrap();
+        let event = |path: &str| {
+            Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any)).add_path(root.join(path)),
+            )
+        };
+
+        monitor
+            .handle_event(Event::Input(format!("START 123 {}\n", root.display())))
+            .unwrap();
+        monitor.handle_event(event("web/dist/app.js")).unwrap();
+        assert!(monitor.replicas["123"].pending_changes.is_empty());
+
+        fs::write(root.join("web/.gitignore"), "build\n").unwrap();
+        monitor.handle_event(event("web/.gitignore")).unwrap();
+        monitor.handle_event(event("web/build/app.js")).unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            [PathBuf::from("web")].into()
+        );
+    }
+
+    #[test]
+    fn test_replica_event() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        monitor.config = "[[replica]]\npath = \"/tmp/sample/sub\"\nbackend = \"poll\""
+            .parse()
+            .unwrap();
+        let event = || {
+            notify::Event::new(EventKind::Create(CreateKind::Any))
+                .add_path(PathBuf::from("/tmp/sample/sub/filename"))
+        };
+
+        for id in ["123", "456"] {
+            monitor
+                .handle_event(Event::Input(format!("START {} /tmp/sample\n", id)))
+                .unwrap();
+        }
+        monitor
+            .handle_event(Event::Input("START 789 /tmp/sample/sub\n".into()))
+            .unwrap();
+        // The first two share a watcher and hear of its events, the third
+        // polls with a watcher of its own.
+        assert_eq!(
+            *monitor.watches.members("123"),
+            ["123".into(), "456".into()].into()
+        );
+        monitor
+            .handle_event(Event::ReplicaEvent("123".into(), event()))
+            .unwrap();
+
+        for id in ["123", "456"] {
+            assert_eq!(
+                monitor.replicas[id].pending_changes,
+                vec![PathBuf::from("sub/filename")].into_iter().collect()
+            );
+        }
+        assert!(monitor.replicas["789"].pending_changes.is_empty());
+    }
+
+    #[test]
+    fn test_watch_registry_reattach() {
+        let mut registry = WatchRegistry::new(|_, _| Ok(RecordingWatcher::default()));
+        let root = PathBuf::from("/tmp/sample");
+        let settings = Settings::default();
+
+        let watches = registry.add("123", &settings).unwrap();
+        watches.add(&root).unwrap();
+        watches.add_single(&PathBuf::from("/tmp/other")).unwrap();
+        registry.park("123", &root, &settings);
+        assert!(registry.replicas.is_empty());
+
+        let poll = Settings {
+            backend: "poll".into(),
+            ..Settings::default()
+        };
+        assert!(!registry.reattach("456", &root, &poll));
+        registry.add("456", &settings).unwrap();
+        registry.park("456", &root, &settings);
+        assert!(!registry.reattach("789", Path::new("/tmp/elsewhere"), &settings));
+        assert!(registry.reattach("789", &root, &settings));
+
+        // Registering again takes over, the rest is dropped.
+        let watches = registry.get_mut("789").unwrap();
+        watches.add(&root.join("subdir")).unwrap();
+        watches.release_leftover();
+        assert_eq!(
+            watches.counts.keys().collect::<Vec<_>>(),
+            [&root.join("subdir")]
+        );
+        assert_eq!(
+            watches.watcher.group().watcher.paths,
+            [root.join("subdir")].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_watch_registry_replace() {
+        let mut registry = WatchRegistry::new(|_, _| Ok(RecordingWatcher::default()));
+        let root = PathBuf::from("/tmp/sample");
+
+        let watches = registry.add("123", &Settings::default()).unwrap();
+        watches.add(&root).unwrap();
+        watches.add(&root.join("a")).unwrap();
+        watches.add_single(&PathBuf::from("/tmp/other")).unwrap();
+        let poll = Settings {
+            backend: "poll".into(),
+            ..Settings::default()
+        };
+        registry.replace("123", &poll).unwrap();
+
+        // The old watcher went with its last replica.
+        assert_eq!(
+            registry.groups.keys().map(|id| &**id).collect::<Vec<_>>(),
+            ["123-1"]
+        );
+        let watches = &registry.replicas["123"];
+        assert_eq!(watches.counts.len(), 2);
+        assert_eq!(watches.watcher.group().watcher.paths.len(), 2);
+        assert!(watches.watcher.group().watcher.paths.contains(&root));
+        assert_eq!(
+            watches.watcher.group().watcher.singles,
+            vec![PathBuf::from("/tmp/other")].into_iter().collect()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_eof_unwatch_fails() {
+        /// Fails every unwatch, like paths another replica still watches or
+        /// link targets never watched on their own.
+        struct FailingWatcher {}
+
+        impl Watch for FailingWatcher {
+            fn unwatch(&mut self, path: &Path) -> Result<()> {
+                Err(MonitorError::WatchError(format!(
+                    "Not watching {}",
+                    path.display()
+                )))
+            }
+        }
+
+        let (_tmp, base) = link_fixture();
+        let root = base.join("root");
+        let mut monitor = Monitor::new(|_, _| Ok(FailingWatcher {}), Cursor::new(vec![]));
+        for id in ["123", "456"] {
+            monitor
+                .handle_event(Event::Input(format!(
+                    "START {} {}\n",
+                    id,
+                    root.to_string_lossy()
+                )))
+                .unwrap();
+            monitor
+                .handle_event(Event::Input("LINK link\n".into()))
+                .unwrap();
+        }
+
+        // Logged and carried on from, so unison's EOF still ends cleanly.
+        monitor.handle_event(Event::Eof).unwrap();
+
+        assert!(monitor.replicas.is_empty());
+        assert!(monitor.watches.replicas.is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Cursor::new(vec![]));
+        let root = PathBuf::from("/tmp/sample");
+        let started = monitor.stats.started;
+
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        for filename in ["a", "b"] {
+            monitor
+                .handle_event(Event::FSEvent(
+                    notify::Event::new(EventKind::Create(CreateKind::Any))
+                        .add_path(root.join(filename)),
+                ))
+                .unwrap();
+        }
+        monitor
+            .handle_event(Event::Overflow(HashMap::from([(None, 3)])))
+            .unwrap();
+
+        let (summary, replicas) = monitor.stats(started + Duration::from_secs(2));
+        assert_eq!(
+            summary,
+            "Up 2s, 1 replicas, 2 events (1.0/s, 1.0/s since the last statistics), 3 dropped"
+        );
+        assert_eq!(
+            replicas,
+            [(
+                "123".into(),
+                "replica 123 at /tmp/sample: 1 watches on auto, 1 pending, 0 reported, 0 held, \
+                 3 changes, 3 dropped"
+                    .to_owned()
+            )]
+        );
+
+        // The rate since then counts from here.
+        monitor
+            .handle_event(Event::FSEvent(
+                notify::Event::new(EventKind::Create(CreateKind::Any)).add_path(root.join("c")),
+            ))
+            .unwrap();
+        let (summary, _) = monitor.stats(started + Duration::from_secs(4));
+        assert_eq!(
+            summary,
+            "Up 4s, 1 replicas, 3 events (0.8/s, 0.5/s since the last statistics), 3 dropped"
+        );
+    }
+}
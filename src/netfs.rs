@@ -0,0 +1,393 @@
+//! Network filesystems and directories shared into virtual machines, where
+//! changes made on other machines never reach the native backend. Replicas
+//! on them are polled unless told otherwise. Containers only share a kernel
+//! with their host on Linux, and bind mounts into them are pointed out.
+//! Directories bind-mounted elsewhere as well are watched once, by inode,
+//! and events may carry either path.
+
+use std::path::{Path, PathBuf};
+
+/// Filesystem types mounted from elsewhere, as Linux and the BSDs name them.
+const NETWORK_TYPES: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "afpfs",
+    "afs",
+    "9p",
+    "webdav",
+    "davfs",
+    "sshfs",
+    "fuse.sshfs",
+    "fuse.rclone",
+    // Host directories in Docker Desktop, Lima, VirtualBox, Parallels and
+    // VMware guests.
+    "virtiofs",
+    "fuse.grpcfuse",
+    "fakeowner",
+    "osxfs",
+    "vboxsf",
+    "prl_fs",
+    "vmhgfs",
+    "fuse.vmhgfs-fuse",
+    // Windows drives in WSL 1, see `wsl_drive` for WSL 2.
+    "drvfs",
+];
+
+/// An entry of `/proc/self/mountinfo`.
+#[cfg(any(target_os = "linux", test))]
+#[derive(Debug, PartialEq)]
+struct Mount<'a> {
+    /// The `major:minor` of the device, the same for every mount of a
+    /// filesystem.
+    dev: &'a str,
+    /// The directory of the mounted filesystem that is mounted, `/` unless
+    /// it is a bind mount of a directory below.
+    root: String,
+    dir: String,
+    fstype: &'a str,
+    source: String,
+    /// Options of the filesystem, rather than of the mount.
+    options: &'a str,
+}
+
+/// The entries of the text of `/proc/self/mountinfo`.
+#[cfg(any(target_os = "linux", test))]
+fn mounts(mountinfo: &str) -> impl Iterator<Item = Mount<'_>> {
+    mountinfo.lines().filter_map(|line| {
+        let mut fields = line.split(' ');
+        let dev = fields.nth(2)?;
+        let (root, dir) = (fields.next()?, fields.next()?);
+        // Optional fields up to a lone dash, then the type.
+        let mut fields = fields.skip_while(|field| *field != "-").skip(1);
+        let (fstype, source) = (fields.next()?, fields.next()?);
+        Some(Mount {
+            dev,
+            root: unescape(root),
+            dir: unescape(dir),
+            fstype,
+            source: unescape(source),
+            options: fields.next().unwrap_or_default(),
+        })
+    })
+}
+
+/// The mount holding `path`, the one with the longest mount point, from the
+/// text of `/proc/self/mountinfo`.
+#[cfg(any(target_os = "linux", test))]
+fn mount_of<'a>(mountinfo: &'a str, path: &Path) -> Option<Mount<'a>> {
+    mounts(mountinfo)
+        .filter(|mount| path.starts_with(&mount.dir))
+        .max_by_key(|mount| mount.dir.len())
+}
+
+/// Mount points with spaces and the like in them are written in octal.
+#[cfg(any(target_os = "linux", test))]
+fn unescape(field: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = field.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let octal = tail
+            .get(..3)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match (byte, octal) {
+            (b'\\', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[3..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Whether this runs in the Windows Subsystem for Linux.
+#[cfg(target_os = "linux")]
+fn wsl() -> bool {
+    std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .is_ok_and(|release| release.to_lowercase().contains("microsoft"))
+}
+
+/// Whether a mount is a Windows drive, which WSL 2 mounts over 9p or
+/// virtiofs, as `C:\` or `drvfs`.
+#[cfg(any(target_os = "linux", test))]
+fn wsl_drive(mount: &Mount) -> bool {
+    let drive = mount.source.len() == 3 && mount.source.ends_with(":\\");
+    matches!(mount.fstype, "9p" | "virtiofs")
+        && (drive || mount.source == "drvfs" || mount.options.contains("aname=drvfs"))
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn fs_type(path: &Path) -> Option<String> {
+    let path = path.canonicalize().ok()?;
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    let mount = mount_of(&mountinfo, &path)?;
+    if wsl_drive(&mount) && wsl() {
+        return Some("drvfs".into());
+    }
+    Some(mount.fstype.to_owned())
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+pub(crate) fn fs_type(path: &Path) -> Option<String> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statfs is plain data, filled in by the call.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// NetBSD has no `statfs`, its `statvfs` names the filesystem instead.
+#[cfg(target_os = "netbsd")]
+pub(crate) fn fs_type(path: &Path) -> Option<String> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statvfs is plain data, filled in by the call.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Mapped drives and UNC paths are all remote, whatever filesystem serves
+/// them.
+#[cfg(windows)]
+pub(crate) fn fs_type(path: &Path) -> Option<String> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{GetDriveTypeW, GetVolumePathNameW};
+    use windows_sys::Win32::System::WindowsProgramming::DRIVE_REMOTE;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut volume = vec![0u16; wide.len().max(4)];
+    if unsafe { GetVolumePathNameW(wide.as_ptr(), volume.as_mut_ptr(), volume.len() as u32) } == 0 {
+        return None;
+    }
+    if unsafe { GetDriveTypeW(volume.as_ptr()) } == DRIVE_REMOTE {
+        return Some("a network share".into());
+    }
+    None
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    windows
+)))]
+pub(crate) fn fs_type(_path: &Path) -> Option<String> {
+    None
+}
+
+/// The type of the network filesystem `path` is on, if it is on one.
+pub fn network_fs(path: &Path) -> Option<String> {
+    let fstype = fs_type(path)?;
+    (cfg!(windows) || NETWORK_TYPES.contains(&fstype.as_str())).then_some(fstype)
+}
+
+/// Whether this runs in a Docker, Podman, LXC or Kubernetes container.
+#[cfg(target_os = "linux")]
+fn in_container() -> bool {
+    Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists()
+        || std::fs::read_to_string("/proc/1/cgroup").is_ok_and(|cgroup| {
+            ["docker", "kubepods", "containerd", "libpod", "lxc"]
+                .iter()
+                .any(|name| cgroup.contains(name))
+        })
+}
+
+/// How a mount in a container may hide changes made outside of it.
+#[cfg(any(target_os = "linux", test))]
+fn container_note(mount: &Mount) -> Option<&'static str> {
+    if mount.fstype == "overlay" {
+        Some("on the overlay filesystem of this container")
+    } else if mount.root != "/" {
+        Some("bind-mounted into this container")
+    } else {
+        None
+    }
+}
+
+/// How `path` is mounted into the container this runs in, if it is and
+/// changes made outside of it may not reach the native backend.
+#[cfg(target_os = "linux")]
+pub fn container_mount(path: &Path) -> Option<&'static str> {
+    if !in_container() {
+        return None;
+    }
+    let path = path.canonicalize().ok()?;
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    container_note(&mount_of(&mountinfo, &path)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn container_mount(_path: &Path) -> Option<&'static str> {
+    None
+}
+
+/// `base` with `rest` below it, without a trailing slash for no `rest`.
+#[cfg(any(target_os = "linux", test))]
+fn join(base: &Path, rest: &Path) -> PathBuf {
+    if rest.as_os_str().is_empty() {
+        base.to_owned()
+    } else {
+        base.join(rest)
+    }
+}
+
+/// Where else the directory at canonical `path` is mounted, from the text
+/// of `/proc/self/mountinfo`: each other path with the one at or below
+/// `path` it shows. Other mounts of the same filesystem show the directory
+/// if they mount it or a directory above it, or part of it if they mount a
+/// directory below.
+#[cfg(any(target_os = "linux", test))]
+fn aliases_of(mountinfo: &str, path: &Path) -> Vec<(PathBuf, PathBuf)> {
+    let mounts: Vec<Mount> = mounts(mountinfo).collect();
+    let own = match mounts
+        .iter()
+        .filter(|mount| path.starts_with(&mount.dir))
+        .max_by_key(|mount| mount.dir.len())
+    {
+        Some(own) => own,
+        None => return vec![],
+    };
+    // Where `path` is within its filesystem.
+    let inner = join(
+        Path::new(&own.root),
+        path.strip_prefix(&own.dir).unwrap_or(Path::new("")),
+    );
+    let mut aliases = vec![];
+    for mount in &mounts {
+        if mount.dev != own.dev || std::ptr::eq(mount, own) {
+            continue;
+        }
+        let (root, dir) = (Path::new(&mount.root), Path::new(&mount.dir));
+        let alias = if let Ok(rest) = inner.strip_prefix(root) {
+            (join(dir, rest), path.to_owned())
+        } else if let Ok(rest) = root.strip_prefix(&inner) {
+            (dir.to_owned(), join(path, rest))
+        } else {
+            continue;
+        };
+        // A bind mount within the tree shows what events carry anyway.
+        if !alias.0.starts_with(path) && !aliases.contains(&alias) {
+            aliases.push(alias);
+        }
+    }
+    aliases
+}
+
+/// Other paths bind mounts make the directory at `path` reachable under,
+/// each with the path at or below `path` it stands for.
+#[cfg(target_os = "linux")]
+pub fn bind_mounts(path: &Path) -> Vec<(PathBuf, PathBuf)> {
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => return vec![],
+    };
+    match std::fs::read_to_string("/proc/self/mountinfo") {
+        Ok(mountinfo) => aliases_of(&mountinfo, &path),
+        Err(_) => vec![],
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_mounts(_path: &Path) -> Vec<(PathBuf, PathBuf)> {
+    vec![]
+}
+
+#[test]
+fn test_mount_of() {
+    let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 0:35 / /mnt/nfs rw,relatime shared:20 - nfs4 server:/export rw
+41 22 0:36 / /mnt/my\\040share rw - cifs //nas/my\\040share rw
+42 40 0:37 / /mnt/nfs/nested rw,nosuid shared:21 master:3 - fuse.sshfs me@host:/home/me rw
+43 22 8:1 /home/me/src /src rw,relatime - ext4 /dev/sda1 rw
+";
+    let fstype = |path: &str| mount_of(mountinfo, Path::new(path)).unwrap().fstype;
+    assert_eq!(fstype("/home/me"), "ext4");
+    assert_eq!(fstype("/mnt/nfs/data"), "nfs4");
+    assert_eq!(fstype("/mnt/nfs/nested/src"), "fuse.sshfs");
+    assert_eq!(fstype("/mnt/my share/docs"), "cifs");
+    // Whole components only.
+    assert_eq!(fstype("/mnt/nfs2"), "ext4");
+
+    let wsl = "\
+61 1 8:32 / / rw,relatime - ext4 /dev/sdc rw
+93 61 0:56 / /mnt/c rw,noatime - 9p C:\\134 rw,dirsync,aname=drvfs;path=C:\\;uid=1000
+94 61 0:57 / /mnt/d rw,noatime - 9p drvfs rw,dirsync,aname=drvfs;path=D:\\
+95 61 0:58 / /mnt/share rw - 9p share rw,trans=virtio
+";
+    let drive = |path: &str| wsl_drive(&mount_of(wsl, Path::new(path)).unwrap());
+    assert!(drive("/mnt/c/Users"));
+    assert!(drive("/mnt/d"));
+    assert!(!drive("/home/me"));
+    assert!(!drive("/mnt/share"));
+
+    let note = |path: &str| container_note(&mount_of(mountinfo, Path::new(path)).unwrap());
+    assert_eq!(note("/src/lib"), Some("bind-mounted into this container"));
+    assert_eq!(note("/mnt/nfs/data"), None);
+    let overlay = "1 0 0:30 / / rw - overlay overlay rw,lowerdir=/l,upperdir=/u";
+    let mount = mount_of(overlay, Path::new("/app")).unwrap();
+    assert_eq!(
+        container_note(&mount),
+        Some("on the overlay filesystem of this container")
+    );
+}
+
+#[test]
+fn test_aliases_of() {
+    let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+30 22 8:2 / /data rw,relatime shared:2 - ext4 /dev/sda2 rw
+31 22 8:2 /src /home/me/src rw,relatime shared:2 - ext4 /dev/sda2 rw
+32 22 8:2 /src/app /srv/app rw,relatime shared:2 - ext4 /dev/sda2 rw
+33 22 8:3 /src /mnt/other rw,relatime - ext4 /dev/sda3 rw
+";
+    let aliases = |path: &str| aliases_of(mountinfo, Path::new(path));
+    let pair = |alias: &str, path: &str| (PathBuf::from(alias), PathBuf::from(path));
+    // Through the mount of the whole filesystem, a bind mount of the
+    // directory and one of a directory below.
+    assert_eq!(
+        aliases("/data/src"),
+        [
+            pair("/home/me/src", "/data/src"),
+            pair("/srv/app", "/data/src/app")
+        ]
+    );
+    assert_eq!(
+        aliases("/home/me/src/app/lib"),
+        [
+            pair("/data/src/app/lib", "/home/me/src/app/lib"),
+            pair("/srv/app/lib", "/home/me/src/app/lib")
+        ]
+    );
+    // Nothing else of /data is mounted elsewhere, nor is anything of sda1.
+    assert_eq!(aliases("/data/docs"), []);
+    assert_eq!(aliases("/home/me"), []);
+}
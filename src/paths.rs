@@ -0,0 +1,272 @@
+//! Path normalization applied to replica and event paths before they are
+//! matched against each other and reported to unison.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+/// Compose decomposed Unicode (as returned by HFS+/APFS) into NFC. Non UTF-8
+/// paths are returned untouched.
+pub fn normalize_unicode(path: &Path) -> PathBuf {
+    match path.to_str() {
+        Some(s) if !is_nfc(s) => PathBuf::from(s.nfc().collect::<String>()),
+        _ => path.to_owned(),
+    }
+}
+
+#[test]
+fn test_normalize_unicode() {
+    assert_eq!(
+        normalize_unicode(Path::new("/tmp/cafe\u{301}/a\u{308}")),
+        Path::new("/tmp/caf\u{e9}/\u{e4}")
+    );
+    assert_eq!(
+        normalize_unicode(Path::new("/tmp/caf\u{e9}")),
+        Path::new("/tmp/caf\u{e9}")
+    );
+}
+
+/// Bring a Windows path into the form unison uses: extended-length `\\?\`
+/// prefixes stripped, an upper case drive letter and forward slashes. Done on
+/// the string so it behaves the same on every host.
+pub fn normalize_windows(path: &Path) -> PathBuf {
+    let s = match path.to_str() {
+        Some(s) => s,
+        None => return path.to_owned(),
+    };
+
+    let s = if let Some(unc) = s.strip_prefix(r"\\?\UNC\") {
+        format!(r"\\{}", unc)
+    } else if let Some(local) = s.strip_prefix(r"\\?\") {
+        local.to_owned()
+    } else {
+        s.to_owned()
+    };
+
+    let mut s = s.replace('\\', "/");
+    let bytes = s.as_bytes();
+    if bytes.len() >= 2 && bytes[1] == b':' && bytes[0].is_ascii_lowercase() {
+        s[..1].make_ascii_uppercase();
+    }
+    PathBuf::from(s)
+}
+
+#[test]
+fn test_normalize_windows() {
+    assert_eq!(
+        normalize_windows(Path::new(r"\\?\C:\Users\me\file.txt")),
+        Path::new("C:/Users/me/file.txt")
+    );
+    assert_eq!(
+        normalize_windows(Path::new(r"c:\Users")),
+        Path::new("C:/Users")
+    );
+    assert_eq!(
+        normalize_windows(Path::new(r"\\?\UNC\server\share\dir")),
+        Path::new("//server/share/dir")
+    );
+    assert_eq!(
+        normalize_windows(Path::new("C:/already/fine")),
+        Path::new("C:/already/fine")
+    );
+}
+
+/// Expand the short 8.3 names Windows may report, like `PROGRA~1`, to the
+/// long ones unison uses. A removed entry cannot be looked up anymore, its
+/// directory is expanded then. Elsewhere paths are returned untouched.
+#[cfg(windows)]
+pub fn expand_short_names(path: &Path) -> PathBuf {
+    use std::os::windows::ffi::OsStrExt;
+    // Only short names have a tilde, spare the rest the lookup.
+    if !path.as_os_str().encode_wide().any(|c| c == u16::from(b'~')) {
+        return path.to_owned();
+    }
+    if let Some(long) = long_path(path) {
+        return long;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => long_path(parent).map_or(path.to_owned(), |p| p.join(name)),
+        _ => path.to_owned(),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn expand_short_names(path: &Path) -> PathBuf {
+    path.to_owned()
+}
+
+#[cfg(windows)]
+fn long_path(path: &Path) -> Option<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use windows_sys::Win32::Storage::FileSystem::GetLongPathNameW;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut buf = vec![0u16; 260];
+    loop {
+        let len =
+            unsafe { GetLongPathNameW(wide.as_ptr(), buf.as_mut_ptr(), buf.len() as u32) } as usize;
+        if len == 0 {
+            return None;
+        }
+        // Too small, `len` is the size it takes.
+        if len < buf.len() {
+            buf.truncate(len);
+            return Some(OsString::from_wide(&buf).into());
+        }
+        buf.resize(len, 0);
+    }
+}
+
+#[cfg(windows)]
+#[test]
+fn test_expand_short_names() {
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use windows_sys::Win32::Storage::FileSystem::GetShortPathNameW;
+
+    let tmp = crate::test_dir();
+    let long = tmp.path().join("long name");
+    fs::create_dir_all(&long).unwrap();
+    let long = long_path(&long).unwrap();
+    let wide: Vec<u16> = long.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut buf = vec![0u16; 260];
+    let len = unsafe { GetShortPathNameW(wide.as_ptr(), buf.as_mut_ptr(), buf.len() as u32) };
+    buf.truncate(len as usize);
+    let short = PathBuf::from(std::ffi::OsString::from_wide(&buf));
+    // Volumes may have short names turned off.
+    if short == long {
+        return;
+    }
+    assert_eq!(expand_short_names(&short), long);
+    // Gone, the directory is expanded still.
+    assert_eq!(expand_short_names(&short.join("gone")), long.join("gone"));
+}
+
+/// Probe whether the filesystem holding `path` ignores case, by looking `path`
+/// up again with the case of its last cased component swapped.
+pub fn is_case_insensitive(path: &Path) -> bool {
+    let components: Vec<Component> = path.components().collect();
+    for (idx, component) in components.iter().enumerate().rev() {
+        let name = match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        };
+        let name = match name {
+            Some(name) => name,
+            None => continue,
+        };
+        let swapped: String = name
+            .chars()
+            .map(|c| {
+                if c.is_lowercase() {
+                    c.to_uppercase().to_string()
+                } else {
+                    c.to_lowercase().to_string()
+                }
+            })
+            .collect();
+        if swapped == name {
+            continue;
+        }
+
+        let mut probe: PathBuf = components[..idx].iter().collect();
+        probe.push(swapped);
+        probe.extend(&components[idx + 1..]);
+        return is_same_file(path, &probe);
+    }
+    false
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        #[cfg(unix)]
+        (Ok(a), Ok(b)) => {
+            use std::os::unix::fs::MetadataExt;
+            a.dev() == b.dev() && a.ino() == b.ino()
+        }
+        #[cfg(not(unix))]
+        (Ok(_), Ok(_)) => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn test_is_case_insensitive() {
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    fs::create_dir_all(base.join("Probe")).unwrap();
+    let insensitive = is_case_insensitive(&base.join("Probe"));
+    let expected = base.join("PROBE").exists();
+
+    assert_eq!(insensitive, expected);
+    assert!(!is_case_insensitive(Path::new(
+        "/nonexistent/unison-fsmonitor"
+    )));
+}
+
+/// Whether a filesystem is mounted at `path`, on another device than its
+/// parent. Never on Windows, where volumes come and go with their drive
+/// letters.
+pub fn is_mount_point(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (fs::metadata(path), path.parent().map(fs::metadata)) {
+            (Ok(meta), Some(Ok(parent))) => meta.dev() != parent.dev(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+#[test]
+fn test_is_mount_point() {
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    fs::create_dir_all(base.join("dir")).unwrap();
+    assert!(!is_mount_point(&base.join("dir")));
+    assert!(!is_mount_point(&base.join("gone")));
+    if cfg!(target_os = "linux") {
+        assert!(is_mount_point(Path::new("/proc")));
+    }
+}
+
+/// `path.strip_prefix(base)` comparing components without regard to case.
+pub fn strip_prefix_ignore_case<'a>(path: &'a Path, base: &Path) -> Option<&'a Path> {
+    let mut components = path.components();
+    for expected in base.components() {
+        let component = components.next()?;
+        let (a, b) = (component.as_os_str(), expected.as_os_str());
+        let equal = match (a.to_str(), b.to_str()) {
+            (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+            _ => a == b,
+        };
+        if !equal {
+            return None;
+        }
+    }
+    Some(components.as_path())
+}
+
+#[test]
+fn test_strip_prefix_ignore_case() {
+    assert_eq!(
+        strip_prefix_ignore_case(Path::new("/Users/Me/Docs/a.txt"), Path::new("/users/me")),
+        Some(Path::new("Docs/a.txt"))
+    );
+    assert_eq!(
+        strip_prefix_ignore_case(
+            Path::new("/tmp/\u{c9}t\u{e9}/x"),
+            Path::new("/tmp/\u{e9}t\u{e9}")
+        ),
+        Some(Path::new("x"))
+    );
+    assert_eq!(
+        strip_prefix_ignore_case(Path::new("/tmp/other"), Path::new("/tmp/sample")),
+        None
+    );
+}
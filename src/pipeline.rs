@@ -0,0 +1,555 @@
+//! Stages a change passes on its way from an fsevent to the pending changes
+//! of a replica. Each stage may alter the change or drop it.
+
+use crate::filter;
+use crate::monitor::Replica;
+use crate::profile;
+use crate::protocol::Id;
+use notify::event::{MetadataKind, ModifyKind};
+use notify::EventKind;
+use serde::Deserialize;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+/// A change to a replica, as derived from an fsevent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub replica: Id,
+    /// Relative to the replica root, empty for the root itself.
+    pub path: PathBuf,
+    pub kind: EventKind,
+}
+
+pub trait Stage {
+    /// Pass `change` on to the next stage, or drop it by returning `None`.
+    fn process(&mut self, change: Change, replica: &Replica) -> Option<Change>;
+}
+
+/// How many pending changes directly in a directory it takes for the
+/// directory to be reported as a whole instead.
+pub const COLLAPSE_AFTER: usize = 100;
+
+/// Stages run in order, up to the first one dropping the change.
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self { stages: vec![] }
+    }
+
+    pub fn push(&mut self, stage: impl Stage + 'static) {
+        self.stages.push(Box::new(stage));
+    }
+
+    pub fn process(&mut self, change: Change, replica: &Replica) -> Option<Change> {
+        self.stages
+            .iter_mut()
+            .try_fold(change, |change, stage| stage.process(change, replica))
+    }
+}
+
+impl Default for Pipeline {
+    /// Drops what unison has no use for or would hear about anyway.
+    fn default() -> Self {
+        let mut pipeline = Self::new();
+        pipeline.push(IgnoreAccess);
+        pipeline.push(IgnoreMetadata);
+        pipeline.push(IgnoreUnisonFiles::new(profile::unison_dir()));
+        pipeline.push(IgnoreSnapshots);
+        pipeline.push(IgnorePaths);
+        pipeline.push(LimitDepth);
+        pipeline.push(Coalesce);
+        pipeline.push(CollapseSiblings);
+        pipeline
+    }
+}
+
+/// Drops opening and closing files, which changes nothing on them.
+pub struct IgnoreAccess;
+
+impl Stage for IgnoreAccess {
+    fn process(&mut self, change: Change, _replica: &Replica) -> Option<Change> {
+        match change.kind {
+            EventKind::Access(_) => None,
+            _ => Some(change),
+        }
+    }
+}
+
+/// What to make of changes to extended attributes alone, like the
+/// quarantine flags and Finder info macOS keeps updating.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Xattrs {
+    /// Like any other attribute, dropped with `ignore-metadata`.
+    #[default]
+    Auto,
+    /// Always, for syncs of metadata. Backends that cannot tell them from
+    /// other attributes, like inotify, report those too.
+    Report,
+    /// Never, where the backend tells them apart.
+    Ignore,
+}
+
+/// Drops changes to permissions, ownership, access times and other attributes
+/// if the replica's settings ask for it, for syncs that leave them alone.
+/// A newer mtime is kept, polling reports writes as nothing else. Extended
+/// attributes go by `settings.xattrs`.
+pub struct IgnoreMetadata;
+
+impl Stage for IgnoreMetadata {
+    fn process(&mut self, change: Change, replica: &Replica) -> Option<Change> {
+        let settings = &replica.settings;
+        let kind = match change.kind {
+            EventKind::Modify(ModifyKind::Metadata(kind)) => kind,
+            _ => return Some(change),
+        };
+        let keep = match (settings.xattrs, kind) {
+            (Xattrs::Ignore, MetadataKind::Extended) => false,
+            (Xattrs::Report, MetadataKind::Extended | MetadataKind::Any) => true,
+            _ => !settings.ignore_metadata || kind == MetadataKind::WriteTime,
+        };
+        keep.then_some(change)
+    }
+}
+
+/// Drops the files unison writes itself while syncing, which would wake it
+/// again: its temporary files next to the ones it updates, and its archives
+/// should the unison directory lie within a replica.
+pub struct IgnoreUnisonFiles {
+    /// Where unison keeps its archives.
+    dir: Option<PathBuf>,
+}
+
+/// The temporary and merge files of unison, `.unison.NAME.HASH.unison.tmp`
+/// and the like.
+const TEMP_PREFIX: &str = ".unison.";
+
+/// Archives, fingerprint caches, locks and their temporary copies, named by
+/// a kind and a hash of the roots.
+const ARCHIVE_KINDS: [&str; 5] = ["ar", "fp", "lk", "sc", "tm"];
+
+fn is_archive(name: &str) -> bool {
+    match (name.get(..2), name.get(2..)) {
+        (Some(kind), Some(hash)) => {
+            ARCHIVE_KINDS.contains(&kind)
+                && !hash.is_empty()
+                && hash.chars().all(|c| c.is_ascii_hexdigit())
+        }
+        _ => false,
+    }
+}
+
+impl IgnoreUnisonFiles {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+
+    fn is_unison_file(&self, path: &Path) -> bool {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return false,
+        };
+        name.starts_with(TEMP_PREFIX) || (is_archive(&name) && self.dir.as_deref() == path.parent())
+    }
+}
+
+impl Stage for IgnoreUnisonFiles {
+    fn process(&mut self, change: Change, replica: &Replica) -> Option<Change> {
+        if replica.settings.ignore_unison_files
+            && self.is_unison_file(&replica.root.join(&change.path))
+        {
+            None
+        } else {
+            Some(change)
+        }
+    }
+}
+
+/// Drops changes within snapshot directories unless the replica's settings
+/// include them, nothing changes there but snapshots coming and going.
+pub struct IgnoreSnapshots;
+
+impl Stage for IgnoreSnapshots {
+    fn process(&mut self, change: Change, replica: &Replica) -> Option<Change> {
+        if replica.settings.ignore_snapshots && filter::in_snapshot(&change.path) {
+            None
+        } else {
+            Some(change)
+        }
+    }
+}
+
+/// Drops changes to pending paths and below pending directories, unison
+/// rescans those as a whole.
+pub struct Coalesce;
+
+impl Stage for Coalesce {
+    fn process(&mut self, change: Change, replica: &Replica) -> Option<Change> {
+        // A lookup per ancestor rather than a look at every pending change,
+        // of which there may be `MAX_PENDING`.
+        if change
+            .path
+            .ancestors()
+            .any(|ancestor| replica.pending_changes.contains(ancestor))
+        {
+            None
+        } else {
+            Some(change)
+        }
+    }
+}
+
+/// Widens a change to its directory once more than the replica's settings
+/// allow are pending in there, as after a checkout or an install. One
+/// rescan of the directory beats a line per file for unison and monitor
+/// alike. The root is left alone, that would rescan everything.
+pub struct CollapseSiblings;
+
+impl Stage for CollapseSiblings {
+    fn process(&mut self, mut change: Change, replica: &Replica) -> Option<Change> {
+        let limit = replica.settings.collapse_after;
+        let parent = match change.path.parent() {
+            Some(parent) if limit > 0 && parent != Path::new("") => parent,
+            _ => return Some(change),
+        };
+        // Everything below a directory sorts right after it.
+        let siblings = replica
+            .pending_changes
+            .range::<Path, _>((Bound::Excluded(parent), Bound::Unbounded))
+            .take_while(|pending| pending.starts_with(parent))
+            .filter(|pending| pending.parent() == Some(parent))
+            .take(limit)
+            .count();
+        if siblings >= limit {
+            change.path = parent.to_owned();
+        }
+        Some(change)
+    }
+}
+
+/// Drops changes at or below the paths the replica's settings ignore, and
+/// those its unison profile or `.gitignore` files skip.
+pub struct IgnorePaths;
+
+impl Stage for IgnorePaths {
+    fn process(&mut self, change: Change, replica: &Replica) -> Option<Change> {
+        let settings = &replica.settings;
+        let ignored = filter::is_ignored(&settings.ignore, &change.path)
+            || settings
+                .ignore_regex
+                .iter()
+                .any(|regex| regex.is_match(&change.path));
+        let skipped = settings
+            .profile
+            .as_ref()
+            .is_some_and(|profile| profile.is_ignored(&change.path));
+        let untracked = replica
+            .gitignores
+            .as_ref()
+            .is_some_and(|gitignores| gitignores.is_ignored(&replica.root, &change.path));
+        if ignored || skipped || untracked {
+            None
+        } else {
+            Some(change)
+        }
+    }
+}
+
+/// Cuts changes below the depth the replica's settings watch down to that
+/// depth, unison rescans the directory there recursively.
+pub struct LimitDepth;
+
+impl Stage for LimitDepth {
+    fn process(&mut self, mut change: Change, replica: &Replica) -> Option<Change> {
+        if let Some(max_depth) = replica.settings.max_depth {
+            if change.path.components().count() > max_depth {
+                change.path = change.path.components().take(max_depth).collect();
+            }
+        }
+        Some(change)
+    }
+}
+
+/// Keeps the changes `predicate` accepts.
+pub struct Filter<F>(pub F);
+
+impl<F: FnMut(&Change, &Replica) -> bool> Stage for Filter<F> {
+    fn process(&mut self, change: Change, replica: &Replica) -> Option<Change> {
+        if (self.0)(&change, replica) {
+            Some(change)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::profile::{Ignores, Rule};
+    use notify::event::{AccessKind, CreateKind};
+    use std::sync::Arc;
+
+    fn change(path: &str, kind: EventKind) -> Change {
+        Change {
+            replica: "123".into(),
+            path: path.into(),
+            kind,
+        }
+    }
+
+    fn replica(pending: &[&str]) -> Replica {
+        let mut replica = Replica::new("/tmp/sample".into());
+        for path in pending {
+            replica.add_change(path.into());
+        }
+        replica
+    }
+
+    #[test]
+    fn test_ignore_access() {
+        let replica = replica(&[]);
+        let create = change("a", EventKind::Create(CreateKind::File));
+        assert_eq!(IgnoreAccess.process(create.clone(), &replica), Some(create));
+        assert_eq!(
+            IgnoreAccess.process(change("a", EventKind::Access(AccessKind::Any)), &replica),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ignore_metadata() {
+        let mut replica = replica(&[]);
+        let chmod = change(
+            "a",
+            EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)),
+        );
+        let touch = change(
+            "a",
+            EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime)),
+        );
+        let write = change("a", EventKind::Modify(ModifyKind::Any));
+        assert_eq!(
+            IgnoreMetadata.process(chmod.clone(), &replica),
+            Some(chmod.clone())
+        );
+
+        replica.settings.ignore_metadata = true;
+        assert_eq!(IgnoreMetadata.process(chmod, &replica), None);
+        for kind in [
+            MetadataKind::Any,
+            MetadataKind::Ownership,
+            MetadataKind::AccessTime,
+        ] {
+            let change = change("a", EventKind::Modify(ModifyKind::Metadata(kind)));
+            assert_eq!(IgnoreMetadata.process(change, &replica), None);
+        }
+        assert_eq!(IgnoreMetadata.process(touch.clone(), &replica), Some(touch));
+        assert_eq!(IgnoreMetadata.process(write.clone(), &replica), Some(write));
+    }
+
+    #[test]
+    fn test_xattrs() {
+        let mut replica = replica(&[]);
+        let metadata = |kind| change("a", EventKind::Modify(ModifyKind::Metadata(kind)));
+        let xattr = metadata(MetadataKind::Extended);
+        let attrib = metadata(MetadataKind::Any);
+        let chmod = metadata(MetadataKind::Permissions);
+
+        replica.settings.xattrs = Xattrs::Ignore;
+        assert_eq!(IgnoreMetadata.process(xattr.clone(), &replica), None);
+        assert_eq!(
+            IgnoreMetadata.process(attrib.clone(), &replica),
+            Some(attrib.clone())
+        );
+
+        // Kept where the rest of the metadata is dropped.
+        replica.settings.xattrs = Xattrs::Report;
+        replica.settings.ignore_metadata = true;
+        assert_eq!(
+            IgnoreMetadata.process(xattr.clone(), &replica),
+            Some(xattr.clone())
+        );
+        assert_eq!(
+            IgnoreMetadata.process(attrib.clone(), &replica),
+            Some(attrib)
+        );
+        assert_eq!(IgnoreMetadata.process(chmod, &replica), None);
+
+        replica.settings.xattrs = Xattrs::Auto;
+        assert_eq!(IgnoreMetadata.process(xattr, &replica), None);
+    }
+
+    #[test]
+    fn test_ignore_unison_files() {
+        let mut replica = replica(&[]);
+        let mut stage = IgnoreUnisonFiles::new(Some("/tmp/sample/.unison".into()));
+        let kind = EventKind::Create(CreateKind::File);
+        let archive = ".unison/ar0123456789abcdef0123456789abcdef";
+        for path in [
+            "a/.unison.b.7a2f9c.unison.tmp",
+            ".unison.merge1-b",
+            archive,
+            ".unison/lk0123456789abcdef0123456789abcdef",
+        ] {
+            assert_eq!(
+                stage.process(change(path, kind), &replica),
+                None,
+                "{}",
+                path
+            );
+        }
+        for path in [
+            "a/b.unison",
+            ".unison/default.prf",
+            "a/ar0123456789abcdef",
+            ".unison/archive",
+            ".unison",
+        ] {
+            assert!(
+                stage.process(change(path, kind), &replica).is_some(),
+                "{}",
+                path
+            );
+        }
+
+        replica.settings.ignore_unison_files = false;
+        assert!(stage.process(change(archive, kind), &replica).is_some());
+    }
+
+    #[test]
+    fn test_ignore_snapshots() {
+        let mut replica = replica(&[]);
+        let kind = EventKind::Create(CreateKind::Folder);
+        let snapshot = change(".zfs/snapshot/daily-1", kind);
+        assert_eq!(IgnoreSnapshots.process(snapshot.clone(), &replica), None);
+        assert!(IgnoreSnapshots
+            .process(change("a", kind), &replica)
+            .is_some());
+
+        replica.settings.ignore_snapshots = false;
+        assert_eq!(
+            IgnoreSnapshots.process(snapshot.clone(), &replica),
+            Some(snapshot)
+        );
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let root = replica(&[""]);
+        let replica = replica(&["a/b"]);
+        let kind = EventKind::Modify(ModifyKind::Any);
+        assert_eq!(Coalesce.process(change("a/b", kind), &replica), None);
+        assert_eq!(Coalesce.process(change("a/b/c", kind), &replica), None);
+        assert!(Coalesce.process(change("a/bc", kind), &replica).is_some());
+        assert!(Coalesce.process(change("a", kind), &replica).is_some());
+
+        // The whole replica pending covers every path.
+        assert_eq!(Coalesce.process(change("a", kind), &root), None);
+    }
+
+    #[test]
+    fn test_collapse_siblings() {
+        let mut replica = replica(&["a/b/1", "a/b/2", "a/b/c/3", "a/bc", "x"]);
+        let kind = EventKind::Create(CreateKind::File);
+        replica.settings.collapse_after = 2;
+        assert_eq!(
+            CollapseSiblings.process(change("a/b/4", kind), &replica),
+            Some(change("a/b", kind))
+        );
+        assert_eq!(
+            CollapseSiblings.process(change("a/b/c/5", kind), &replica),
+            Some(change("a/b/c/5", kind))
+        );
+        assert_eq!(
+            CollapseSiblings.process(change("a/d", kind), &replica),
+            Some(change("a/d", kind))
+        );
+        // Top level changes never widen to the root.
+        assert_eq!(
+            CollapseSiblings.process(change("y", kind), &replica),
+            Some(change("y", kind))
+        );
+
+        replica.settings.collapse_after = 0;
+        assert_eq!(
+            CollapseSiblings.process(change("a/b/4", kind), &replica),
+            Some(change("a/b/4", kind))
+        );
+    }
+
+    #[test]
+    fn test_ignore_paths() {
+        let mut replica = replica(&[]);
+        replica.settings.ignore = ["target", "!target/keep", "a/.git", "**/*.swp"]
+            .iter()
+            .map(|pattern| pattern.parse().unwrap())
+            .collect();
+        replica.settings.ignore_regex = vec![r"logs/\d+".parse().unwrap()];
+        replica.settings.profile = Some(Arc::new(Ignores {
+            ignore: vec![Rule::parse("Name *.bak").unwrap()],
+            ignorenot: vec![],
+        }));
+        let mut stage = IgnorePaths;
+        let kind = EventKind::Modify(ModifyKind::Any);
+        assert_eq!(stage.process(change("target", kind), &replica), None);
+        assert_eq!(stage.process(change("target/debug", kind), &replica), None);
+        assert_eq!(stage.process(change("a/.git/HEAD", kind), &replica), None);
+        assert_eq!(stage.process(change("a/b/.c.swp", kind), &replica), None);
+        assert_eq!(stage.process(change("a/b.bak", kind), &replica), None);
+        assert_eq!(stage.process(change("logs/42/app", kind), &replica), None);
+        assert!(stage
+            .process(change("logs/latest", kind), &replica)
+            .is_some());
+        assert!(stage.process(change("targets", kind), &replica).is_some());
+        assert!(stage
+            .process(change("target/keep", kind), &replica)
+            .is_some());
+        assert!(stage.process(change("a", kind), &replica).is_some());
+        assert!(stage.process(change("", kind), &replica).is_some());
+    }
+
+    #[test]
+    fn test_limit_depth() {
+        let mut replica = replica(&[]);
+        let kind = EventKind::Create(CreateKind::File);
+        let deep = change("2024/05/img.jpg", kind);
+        assert_eq!(
+            LimitDepth.process(deep.clone(), &replica),
+            Some(deep.clone())
+        );
+
+        replica.settings.max_depth = Some(2);
+        assert_eq!(
+            LimitDepth.process(deep, &replica),
+            Some(change("2024/05", kind))
+        );
+        let shallow = change("2024", kind);
+        assert_eq!(LimitDepth.process(shallow.clone(), &replica), Some(shallow));
+        replica.settings.max_depth = Some(0);
+        assert_eq!(
+            LimitDepth.process(change("2024", kind), &replica),
+            Some(change("", kind))
+        );
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let mut pipeline = Pipeline::default();
+        pipeline.push(Filter(|change: &Change, _: &Replica| {
+            change.path.extension().is_none_or(|ext| ext != "tmp")
+        }));
+        let replica = replica(&["a"]);
+        let kind = EventKind::Create(CreateKind::File);
+
+        assert_eq!(pipeline.process(change("a/b", kind), &replica), None);
+        assert_eq!(pipeline.process(change("b.tmp", kind), &replica), None);
+        assert_eq!(
+            pipeline.process(change("b", kind), &replica),
+            Some(change("b", kind))
+        );
+    }
+}
@@ -0,0 +1,348 @@
+//! The `ignore` and `ignorenot` preferences of a unison profile, so the
+//! monitor stays quiet about paths unison would skip anyway:
+//!
+//! ```text
+//! ignore = Name *.tmp
+//! ignore = Path build
+//! ignorenot = Path build/config.json
+//! ignore = Regex .*/cache/[0-9]+
+//! ignore = BelowPath .git
+//! include common
+//! ```
+
+use crate::error::{MonitorError, Result};
+use regex::Regex;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How deep `include` may nest, against profiles including each other.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// What part of a path a rule looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// The last component, at any depth.
+    Name,
+    /// The whole path.
+    Path,
+    /// The path or any of its parents.
+    BelowPath,
+    /// The whole path, with a regular expression.
+    Regex,
+}
+
+/// One `ignore` or `ignorenot` value.
+#[derive(Clone)]
+pub struct Rule {
+    kind: Kind,
+    pattern: String,
+    regex: Regex,
+    /// A name pattern starting with a wildcard, which skips dotfiles.
+    dotless: bool,
+}
+
+/// Translate a unison glob into the body of a regex: `*` and `?` stay within
+/// a component, `[...]` is a character class and `{a,b}` matches either.
+fn translate(pattern: &str) -> std::result::Result<String, String> {
+    let mut regex = String::new();
+    let mut chars = pattern.chars();
+    let mut alternatives = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        closed = true;
+                        break;
+                    }
+                    match c {
+                        '-' => regex.push('-'),
+                        c => regex.push_str(&regex::escape(&c.to_string())),
+                    }
+                }
+                if !closed {
+                    return Err(format!("{}: unclosed [", pattern));
+                }
+                regex.push(']');
+            }
+            '{' => {
+                alternatives += 1;
+                regex.push_str("(?:");
+            }
+            ',' if alternatives > 0 => regex.push('|'),
+            '}' if alternatives > 0 => {
+                alternatives -= 1;
+                regex.push(')');
+            }
+            '\\' => match chars.next() {
+                Some(c) => regex.push_str(&regex::escape(&c.to_string())),
+                None => return Err(format!("{}: trailing \\", pattern)),
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    if alternatives > 0 {
+        return Err(format!("{}: unclosed {{", pattern));
+    }
+    Ok(regex)
+}
+
+impl Rule {
+    /// Parse a value like `Name *.tmp`.
+    pub fn parse(value: &str) -> std::result::Result<Rule, String> {
+        let (kind, pattern) = value
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("{}: expected a kind and a pattern", value))?;
+        let pattern = pattern.trim();
+        let kind = match kind {
+            "Name" => Kind::Name,
+            "Path" => Kind::Path,
+            "BelowPath" => Kind::BelowPath,
+            "Regex" => Kind::Regex,
+            _ => return Err(format!("{}: unknown kind {}", value, kind)),
+        };
+        let body = match kind {
+            Kind::Regex => pattern.to_owned(),
+            _ => translate(pattern)?,
+        };
+        let regex =
+            Regex::new(&format!("^(?:{})$", body)).map_err(|err| format!("{}: {}", value, err))?;
+        Ok(Rule {
+            kind,
+            pattern: pattern.to_owned(),
+            regex,
+            dotless: kind == Kind::Name && pattern.starts_with(['*', '?']),
+        })
+    }
+
+    /// Whether the rule matches `path` itself, relative to the replica root
+    /// with `/` between components.
+    fn is_match(&self, path: &str) -> bool {
+        match self.kind {
+            Kind::Name => {
+                let name = path.rsplit('/').next().unwrap_or(path);
+                !(self.dotless && name.starts_with('.')) && self.regex.is_match(name)
+            }
+            Kind::Path | Kind::Regex => self.regex.is_match(path),
+            Kind::BelowPath => ancestors(path).any(|path| self.regex.is_match(path)),
+        }
+    }
+}
+
+impl PartialEq for Rule {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind && self.pattern == other.pattern
+    }
+}
+
+impl Eq for Rule {}
+
+impl fmt::Debug for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} {}", self.kind, self.pattern)
+    }
+}
+
+/// `a`, `a/b` and `a/b/c` for `a/b/c`.
+fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/')
+        .map(move |(idx, _)| &path[..idx])
+        .chain(std::iter::once(path))
+}
+
+/// The ignore preferences of a profile and the profiles it includes.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Ignores {
+    pub ignore: Vec<Rule>,
+    pub ignorenot: Vec<Rule>,
+}
+
+/// The directory unison keeps its profiles in: `UNISON`, or `~/.unison`.
+pub fn unison_dir() -> Option<PathBuf> {
+    env::var_os("UNISON").map(PathBuf::from).or_else(|| {
+        env::var_os("HOME")
+            .or_else(|| env::var_os("USERPROFILE"))
+            .map(|home| Path::new(&home).join(".unison"))
+    })
+}
+
+/// The file of a profile given by name, like unison takes it, or by path.
+pub fn locate(profile: &str) -> Option<PathBuf> {
+    let path = Path::new(profile);
+    if path.components().count() > 1 || path.extension().is_some_and(|ext| ext == "prf") {
+        return Some(path.to_owned());
+    }
+    Some(unison_dir()?.join(format!("{}.prf", profile)))
+}
+
+impl Ignores {
+    pub fn load(path: &Path) -> Result<Ignores> {
+        let mut ignores = Ignores::default();
+        ignores.read(path, 0)?;
+        Ok(ignores)
+    }
+
+    fn read(&mut self, path: &Path, depth: usize) -> Result<()> {
+        let error = |msg: String| MonitorError::ConfigError(format!("{}: {}", path.display(), msg));
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(error("includes nest too deep".into()));
+        }
+        let text = fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some((directive, name)) = line.split_once(char::is_whitespace) {
+                let optional = directive.ends_with('?');
+                if matches!(directive.trim_end_matches('?'), "include" | "source") {
+                    // Included files live next to the profile.
+                    let included = path.with_file_name(name.trim());
+                    if optional && !included.exists() {
+                        continue;
+                    }
+                    self.read(&included, depth + 1)?;
+                    continue;
+                }
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value),
+                None => continue,
+            };
+            match key {
+                "ignore" => self.ignore.push(Rule::parse(value).map_err(error)?),
+                "ignorenot" => self.ignorenot.push(Rule::parse(value).map_err(error)?),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether unison skips `path`, relative to the replica root. It does if
+    /// it or a parent matches an `ignore` and no `ignorenot`, as unison does
+    /// not descend into what it ignores.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let path = path.to_string_lossy().replace('\\', "/");
+        if path.is_empty() {
+            return false;
+        }
+        let ignored = ancestors(&path).any(|path| {
+            self.ignore.iter().any(|rule| rule.is_match(path))
+                && !self.ignorenot.iter().any(|rule| rule.is_match(path))
+        });
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ignores(ignore: &[&str], ignorenot: &[&str]) -> Ignores {
+        let rules = |values: &[&str]| {
+            values
+                .iter()
+                .map(|value| Rule::parse(value).unwrap())
+                .collect()
+        };
+        Ignores {
+            ignore: rules(ignore),
+            ignorenot: rules(ignorenot),
+        }
+    }
+
+    #[test]
+    fn test_kinds() {
+        let ignores = ignores(
+            &[
+                "Name *.tmp",
+                "Path build",
+                "BelowPath a/b",
+                "Regex logs/[0-9]{4}",
+                "Name {#*#,.#*}",
+            ],
+            &[],
+        );
+        let ignored = |path: &str| ignores.is_ignored(Path::new(path));
+        assert!(ignored("x.tmp"));
+        assert!(ignored("src/x.tmp"));
+        assert!(ignored("src/x.tmp/y"));
+        assert!(!ignored("src/.tmp"));
+        assert!(ignored("build"));
+        assert!(ignored("build/out"));
+        assert!(!ignored("src/build"));
+        assert!(ignored("a/b/c"));
+        assert!(ignored("logs/2024/today"));
+        assert!(!ignored("logs/24"));
+        assert!(ignored("src/#notes#"));
+        assert!(ignored("src/.#notes"));
+        assert!(!ignored("src/notes"));
+        assert!(!ignored(""));
+    }
+
+    #[test]
+    fn test_ignorenot() {
+        let ignores = ignores(&["Name *.o", "Path build"], &["Name keep.o", "Path build"]);
+        let ignored = |path: &str| ignores.is_ignored(Path::new(path));
+        assert!(ignored("a.o"));
+        assert!(!ignored("keep.o"));
+        // Not ignored itself, but below what is.
+        assert!(!ignored("build"));
+        assert!(ignored("build/x.o"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Rule::parse("*.tmp").is_err());
+        assert!(Rule::parse("Glob *.tmp").is_err());
+        assert!(Rule::parse("Name [abc").is_err());
+        assert!(Rule::parse("Name {a,b").is_err());
+        assert!(Rule::parse("Regex (").is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let tmp = crate::test_dir();
+        let dir = tmp.path();
+        fs::write(
+            dir.join("sync.prf"),
+            "# Home\nroot = /home/me\nignore = Name *.tmp\ninclude common\ninclude? missing\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("common"),
+            "ignore = Path .git\nignorenot = Name keep.tmp\n",
+        )
+        .unwrap();
+
+        let ignores = Ignores::load(&dir.join("sync.prf")).unwrap();
+        assert_eq!(
+            ignores,
+            self::ignores(&["Name *.tmp", "Path .git"], &["Name keep.tmp"])
+        );
+
+        fs::write(dir.join("common"), "ignore = Frob x\n").unwrap();
+        assert!(matches!(
+            Ignores::load(&dir.join("sync.prf")),
+            Err(MonitorError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_locate() {
+        assert_eq!(
+            locate("/tmp/sync.prf"),
+            Some(PathBuf::from("/tmp/sync.prf"))
+        );
+        assert_eq!(locate("other.prf"), Some(PathBuf::from("other.prf")));
+        if let Some(dir) = unison_dir() {
+            assert_eq!(locate("sync"), Some(dir.join("sync.prf")));
+        }
+    }
+}
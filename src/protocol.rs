@@ -0,0 +1,497 @@
+//! Line codec of the unison fsmonitor protocol: commands are a word followed
+//! by percent-encoded arguments, parsed into `Request` and written from
+//! `Response`.
+
+use crate::error::{MonitorError, Result};
+use percent_encoding::{AsciiSet, CONTROLS};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Protocol versions this monitor can speak.
+pub const SUPPORTED_VERSIONS: &[u32] = &[1];
+
+/// Bytes that would break a protocol word: controls, spaces and `%` itself.
+/// Non-ASCII bytes are always escaped by `percent_encoding`.
+pub const ENCODE_SET: &AsciiSet = &CONTROLS.add(b' ').add(b'%');
+
+/// Percent-encode the raw bytes of `s` so non UTF-8 names survive.
+pub fn encode(s: &OsStr) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        percent_encoding::percent_encode(s.as_bytes(), ENCODE_SET).to_string()
+    }
+    #[cfg(not(unix))]
+    {
+        percent_encoding::utf8_percent_encode(&s.to_string_lossy(), ENCODE_SET).to_string()
+    }
+}
+
+#[test]
+fn test_encode() {
+    let encode = |s| encode(OsStr::new(s));
+    assert_eq!(encode("before%after"), "before%25after");
+    assert_eq!(encode("my file.txt"), "my%20file.txt");
+    assert_eq!(encode("dir/line\nbreak"), "dir/line%0Abreak");
+}
+
+#[test]
+fn test_encode_roundtrip() {
+    for name in &[
+        "my file.txt",
+        "100%",
+        "%25",
+        "tab\there",
+        "new\nline\r\n",
+        "caf\u{e9}/\u{65e5}\u{672c}",
+        "\u{1}\u{7f} end",
+        "  ",
+    ] {
+        let encoded = encode(OsStr::new(name));
+        assert!(!encoded.contains(char::is_whitespace));
+        assert_eq!(decode(&encoded), OsStr::new(name));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_encode_non_utf8() {
+    use std::os::unix::ffi::OsStrExt;
+    let name = OsStr::from_bytes(b"caf\xe9 \xff.txt");
+    let encoded = encode(name);
+    assert_eq!(encoded, "caf%E9%20%FF.txt");
+    assert_eq!(decode(&encoded), name);
+}
+
+pub fn decode(s: &str) -> OsString {
+    let bytes: Vec<u8> = percent_encoding::percent_decode(s.as_bytes()).collect();
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        OsString::from_vec(bytes)
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8_lossy(&bytes).into_owned().into()
+    }
+}
+
+pub type Id = Arc<str>;
+
+/// A command sent by unison.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Protocol versions unison speaks.
+    Version(Vec<u32>),
+    /// Watch `root`, or `path` below it, for a replica.
+    Start {
+        replica: Id,
+        root: PathBuf,
+        path: Option<PathBuf>,
+    },
+    /// Directory below the current START path, empty for the path itself.
+    Dir(PathBuf),
+    /// Symbolic link below the current START path, to be followed.
+    Link(PathBuf),
+    /// Glob for the replica of the current START to ignore on top of its
+    /// settings. An extension of this monitor, unison never sends it.
+    Ignore(String),
+    Wait(Id),
+    Changes(Id),
+    Reset(Id),
+    Debug,
+    Done,
+    /// An empty line.
+    Blank,
+    /// A command unknown to this monitor, its arguments are dropped.
+    Unknown(String),
+}
+
+/// A line sent back to unison.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Version(u32),
+    Ok,
+    Changes(Id),
+    /// Path relative to the replica root to rescan, empty for all of it.
+    Recursive(PathBuf),
+    Done,
+    Error(String),
+}
+
+/// Phase of a session, advanced by every request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Nothing received yet. Unison opens with `VERSION`, a session opening
+    /// with anything else speaks version 1.
+    AwaitingVersion,
+    /// Between replica setups: waiting, reporting changes and resetting.
+    Idle,
+    /// From `START` to `DONE`, `DIR` and `LINK` describe the replica and
+    /// every line gets an answer.
+    StartingReplica,
+}
+
+impl Phase {
+    /// Phase after `request`. Blank lines change nothing, stray `DIR` and
+    /// `LINK` lines keep referring to the last `START`.
+    pub fn next(self, request: &Request) -> Phase {
+        match (self, request) {
+            (_, Request::Blank) => self,
+            (_, Request::Start { .. }) => Phase::StartingReplica,
+            (Phase::StartingReplica, Request::Done) => Phase::Idle,
+            (Phase::StartingReplica, _) => Phase::StartingReplica,
+            (_, _) => Phase::Idle,
+        }
+    }
+}
+
+/// Split a line into its command and decoded arguments.
+fn split(line: &str) -> (&str, Vec<OsString>) {
+    let mut words = line.split_whitespace();
+    let cmd = words.next().unwrap_or("");
+    (cmd, words.map(decode).collect())
+}
+
+/// Required argument `idx` of `cmd`.
+fn arg(cmd: &str, args: &mut [OsString], idx: usize) -> Result<OsString> {
+    match args.get_mut(idx) {
+        Some(arg) => Ok(std::mem::take(arg)),
+        None => Err(MonitorError::ProtocolError(format!(
+            "Missing argument {} to {}",
+            idx + 1,
+            cmd
+        ))),
+    }
+}
+
+fn lossy(arg: OsString) -> String {
+    arg.to_string_lossy().into_owned()
+}
+
+impl FromStr for Request {
+    type Err = MonitorError;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let (cmd, mut args) = split(line);
+        let optional = |args: &mut Vec<OsString>, idx: usize| {
+            args.get_mut(idx)
+                .map(|arg| PathBuf::from(std::mem::take(arg)))
+        };
+        Ok(match cmd {
+            "VERSION" => Request::Version(
+                args.iter()
+                    .map(|arg| {
+                        arg.to_str()
+                            .and_then(|version| version.parse().ok())
+                            .ok_or_else(|| {
+                                MonitorError::ProtocolError(format!("Bad version: {:?}", arg))
+                            })
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            "START" => Request::Start {
+                replica: lossy(arg(cmd, &mut args, 0)?).into(),
+                root: arg(cmd, &mut args, 1)?.into(),
+                path: optional(&mut args, 2),
+            },
+            "DIR" => Request::Dir(optional(&mut args, 0).unwrap_or_default()),
+            "LINK" => Request::Link(optional(&mut args, 0).unwrap_or_default()),
+            "IGNORE" => Request::Ignore(lossy(arg(cmd, &mut args, 0)?)),
+            "WAIT" => Request::Wait(lossy(arg(cmd, &mut args, 0)?).into()),
+            "CHANGES" => Request::Changes(lossy(arg(cmd, &mut args, 0)?).into()),
+            "RESET" => Request::Reset(lossy(arg(cmd, &mut args, 0)?).into()),
+            "DEBUG" => Request::Debug,
+            "DONE" => Request::Done,
+            "" => Request::Blank,
+            cmd => Request::Unknown(cmd.to_owned()),
+        })
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Request::Version(versions) => {
+                write!(f, "VERSION")?;
+                for version in versions {
+                    write!(f, " {}", version)?;
+                }
+                Ok(())
+            }
+            Request::Start {
+                replica,
+                root,
+                path,
+            } => {
+                write!(
+                    f,
+                    "START {} {}",
+                    encode(OsStr::new(&**replica)),
+                    encode(root.as_os_str())
+                )?;
+                if let Some(path) = path {
+                    write!(f, " {}", encode(path.as_os_str()))?;
+                }
+                Ok(())
+            }
+            Request::Dir(path) => write!(f, "DIR {}", encode(path.as_os_str())),
+            Request::Link(path) => write!(f, "LINK {}", encode(path.as_os_str())),
+            Request::Ignore(pattern) => write!(f, "IGNORE {}", encode(OsStr::new(pattern))),
+            Request::Wait(id) => write!(f, "WAIT {}", encode(OsStr::new(&**id))),
+            Request::Changes(id) => write!(f, "CHANGES {}", encode(OsStr::new(&**id))),
+            Request::Reset(id) => write!(f, "RESET {}", encode(OsStr::new(&**id))),
+            Request::Debug => write!(f, "DEBUG"),
+            Request::Done => write!(f, "DONE"),
+            Request::Blank => Ok(()),
+            Request::Unknown(cmd) => write!(f, "{}", cmd),
+        }
+    }
+}
+
+impl FromStr for Response {
+    type Err = MonitorError;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let (cmd, mut args) = split(line);
+        Ok(match cmd {
+            "VERSION" => {
+                let version = arg(cmd, &mut args, 0)?;
+                Response::Version(
+                    version
+                        .to_str()
+                        .and_then(|version| version.parse().ok())
+                        .ok_or_else(|| {
+                            MonitorError::ProtocolError(format!("Bad version: {:?}", version))
+                        })?,
+                )
+            }
+            "OK" => Response::Ok,
+            "CHANGES" => Response::Changes(lossy(arg(cmd, &mut args, 0)?).into()),
+            // The whole replica is sent as an empty word.
+            "RECURSIVE" => Response::Recursive(args.pop().unwrap_or_default().into()),
+            "DONE" => Response::Done,
+            "ERROR" => Response::Error(lossy(args.pop().unwrap_or_default())),
+            cmd => {
+                return Err(MonitorError::ProtocolError(format!(
+                    "Unrecognized response: {}",
+                    cmd
+                )))
+            }
+        })
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Response::Version(version) => write!(f, "VERSION {}", version),
+            Response::Ok => write!(f, "OK"),
+            Response::Changes(id) => write!(f, "CHANGES {}", encode(OsStr::new(&**id))),
+            Response::Recursive(path) => write!(f, "RECURSIVE {}", encode(path.as_os_str())),
+            Response::Done => write!(f, "DONE"),
+            Response::Error(msg) => write!(f, "ERROR {}", encode(OsStr::new(msg))),
+        }
+    }
+}
+
+/// Where responses to unison go.
+pub trait ResponseSink {
+    fn send(&mut self, response: Response) -> io::Result<()>;
+
+    /// Push out anything still buffered.
+    fn flush_responses(&mut self) -> io::Result<()>;
+}
+
+/// Lines written straight to unison.
+impl<W: Write> ResponseSink for W {
+    fn send(&mut self, response: Response) -> io::Result<()> {
+        writeln!(self, "{}", response)?;
+        // Every response but RECURSIVE is complete on its own, and RECURSIVE
+        // lines are always followed by DONE.
+        if !matches!(response, Response::Recursive(_)) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush_responses(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+#[test]
+fn test_parse_request() {
+    let parse = |line: &str| line.parse::<Request>().unwrap();
+    assert_eq!(parse("VERSION 1 2\n"), Request::Version(vec![1, 2]));
+    assert_eq!(
+        parse("START 123 /tmp/my%20root\n"),
+        Request::Start {
+            replica: "123".into(),
+            root: "/tmp/my root".into(),
+            path: None,
+        }
+    );
+    assert_eq!(
+        parse("START 123 /tmp/root sub/dir\n"),
+        Request::Start {
+            replica: "123".into(),
+            root: "/tmp/root".into(),
+            path: Some("sub/dir".into()),
+        }
+    );
+    assert_eq!(parse("DIR a%25b\n"), Request::Dir("a%b".into()));
+    assert_eq!(parse("DIR\n"), Request::Dir(PathBuf::new()));
+    assert_eq!(parse("LINK link\n"), Request::Link("link".into()));
+    assert_eq!(
+        parse("IGNORE **/*.o%20files\n"),
+        Request::Ignore("**/*.o files".into())
+    );
+    assert_eq!(parse("WAIT 123\n"), Request::Wait("123".into()));
+    assert_eq!(parse("CHANGES 123\n"), Request::Changes("123".into()));
+    assert_eq!(parse("RESET 123\n"), Request::Reset("123".into()));
+    assert_eq!(parse("DEBUG\n"), Request::Debug);
+    assert_eq!(parse("DONE\n"), Request::Done);
+    assert_eq!(parse("\n"), Request::Blank);
+    assert_eq!(
+        parse("FROBNICATE x\n"),
+        Request::Unknown("FROBNICATE".into())
+    );
+}
+
+#[test]
+fn test_parse_request_invalid() {
+    for line in &[
+        "VERSION one",
+        "START 123",
+        "START",
+        "WAIT",
+        "CHANGES",
+        "RESET",
+        "IGNORE",
+    ] {
+        assert!(matches!(
+            line.parse::<Request>(),
+            Err(MonitorError::ProtocolError(_))
+        ));
+    }
+}
+
+#[test]
+fn test_request_roundtrip() {
+    for request in [
+        Request::Version(vec![1, 2]),
+        Request::Start {
+            replica: "12 3".into(),
+            root: "/tmp/my root".into(),
+            path: Some("100%".into()),
+        },
+        Request::Start {
+            replica: "123".into(),
+            root: "/tmp/root".into(),
+            path: None,
+        },
+        Request::Dir("new\nline".into()),
+        Request::Link("link".into()),
+        Request::Ignore("!build/config 1.json".into()),
+        Request::Wait("123".into()),
+        Request::Changes("123".into()),
+        Request::Reset("123".into()),
+        Request::Debug,
+        Request::Done,
+        Request::Blank,
+        Request::Unknown("FROBNICATE".into()),
+    ] {
+        assert_eq!(request.to_string().parse::<Request>().unwrap(), request);
+    }
+}
+
+#[test]
+fn test_response_roundtrip() {
+    for (response, line) in [
+        (Response::Version(1), "VERSION 1"),
+        (Response::Ok, "OK"),
+        (Response::Changes("123".into()), "CHANGES 123"),
+        (Response::Recursive("my file".into()), "RECURSIVE my%20file"),
+        (Response::Recursive(PathBuf::new()), "RECURSIVE "),
+        (Response::Done, "DONE"),
+        (
+            Response::Error("Not a directory".into()),
+            "ERROR Not%20a%20directory",
+        ),
+    ] {
+        assert_eq!(response.to_string(), line);
+        assert_eq!(line.parse::<Response>().unwrap(), response);
+    }
+}
+
+/// Phases along `transcript`, one per line.
+#[cfg(test)]
+fn phases(transcript: &str) -> Vec<Phase> {
+    let mut phase = Phase::AwaitingVersion;
+    transcript
+        .lines()
+        .map(|line| {
+            phase = phase.next(&line.parse().unwrap());
+            phase
+        })
+        .collect()
+}
+
+#[test]
+fn test_phases() {
+    use Phase::*;
+    // Recorded from unison 2.53 syncing a replica with a followed link.
+    let transcript = "VERSION 1
+DEBUG
+START 6dc1b5ef5e1a8bc0 /home/me/sync
+DIR
+LINK link
+DIR sub%20dir
+DONE
+WAIT 6dc1b5ef5e1a8bc0
+CHANGES 6dc1b5ef5e1a8bc0
+WAIT 6dc1b5ef5e1a8bc0
+START 6dc1b5ef5e1a8bc0 /home/me/sync sub%20dir
+DONE
+RESET 6dc1b5ef5e1a8bc0
+";
+    assert_eq!(
+        phases(transcript),
+        vec![
+            Idle,
+            Idle,
+            StartingReplica,
+            StartingReplica,
+            StartingReplica,
+            StartingReplica,
+            Idle,
+            Idle,
+            Idle,
+            Idle,
+            StartingReplica,
+            Idle,
+            Idle,
+        ]
+    );
+}
+
+#[test]
+fn test_phases_without_version() {
+    use Phase::*;
+    assert_eq!(phases("\n"), vec![AwaitingVersion]);
+    assert_eq!(
+        phases("START 123 /tmp/sample\nFROBNICATE\n\nDONE\nDONE\n"),
+        vec![
+            StartingReplica,
+            StartingReplica,
+            StartingReplica,
+            Idle,
+            Idle
+        ]
+    );
+}
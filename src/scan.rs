@@ -0,0 +1,170 @@
+//! A safety net under the watchers: every so often each watched tree is
+//! scanned for changes its events missed, as when the kernel's queue
+//! overflowed or a filesystem was remounted underneath.
+
+use crate::error::Result;
+use crate::io::EventSender;
+use crate::monitor::{Event, Tag, Watch};
+use log::debug;
+use notify::event::EventKind;
+use notify::RecursiveMode;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// What events from a safety scan carry as their info, so the monitor can
+/// tell them from the watcher's.
+pub const SCAN_INFO: &str = "safety-scan";
+
+/// Modification time and size of every entry below a tree. Directories
+/// count by their presence alone, what changes within them shows up on its
+/// own.
+type Snapshot = HashMap<PathBuf, Option<(SystemTime, u64)>>;
+
+fn snapshot(root: &Path, recursive: bool) -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    let mut dirs = vec![root.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            // Links are not followed, like the watchers do.
+            let metadata = match fs::symlink_metadata(entry.path()) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                if recursive {
+                    dirs.push(entry.path());
+                }
+                snapshot.insert(entry.path(), None);
+            } else {
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                snapshot.insert(entry.path(), Some((modified, metadata.len())));
+            }
+        }
+    }
+    snapshot
+}
+
+/// Paths created, removed or modified between two snapshots.
+fn diff(old: &Snapshot, new: &Snapshot) -> Vec<PathBuf> {
+    let mut changed: Vec<PathBuf> = new
+        .iter()
+        .filter(|(path, entry)| old.get(*path) != Some(entry))
+        .chain(old.iter().filter(|(path, _)| !new.contains_key(*path)))
+        .map(|(path, _)| path.clone())
+        .collect();
+    changed.sort();
+    changed
+}
+
+/// Wraps the watcher of a replica to scan what it watches every
+/// `interval`. Each scan ends with one event tagged like the watcher's
+/// carrying what changed since the one before, or nothing.
+pub struct SafetyNet<W> {
+    inner: W,
+    tag: Tag,
+    tx: EventSender,
+    interval: Duration,
+    /// Dropped to stop the scanner of each watched path.
+    scanners: HashMap<PathBuf, mpsc::Sender<()>>,
+}
+
+impl<W: Watch> SafetyNet<W> {
+    pub fn new(inner: W, replica_id: &str, tx: EventSender, interval: Duration) -> Self {
+        Self {
+            inner,
+            tag: replica_id.into(),
+            tx,
+            interval,
+            scanners: HashMap::new(),
+        }
+    }
+
+    fn spawn(&self, path: &Path, recursive: bool) -> mpsc::Sender<()> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let (path, tag, tx, interval) = (
+            path.to_owned(),
+            self.tag.clone(),
+            self.tx.clone(),
+            self.interval,
+        );
+        thread::spawn(move || {
+            let mut last = snapshot(&path, recursive);
+            // Ends once the sender is dropped.
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let started = Instant::now();
+                let current = snapshot(&path, recursive);
+                let changed = diff(&last, &current);
+                debug!(
+                    "Safety scan of {} took {:?}, {} changed",
+                    path.display(),
+                    started.elapsed(),
+                    changed.len()
+                );
+                let mut event = notify::Event::new(EventKind::Any).set_info(SCAN_INFO);
+                event.paths = changed;
+                if tx.send(Event::ReplicaEvent(tag.clone(), event)).is_err() {
+                    return;
+                }
+                last = current;
+            }
+        });
+        stop
+    }
+}
+
+impl<W: Watch> Watch for SafetyNet<W> {
+    fn validate(&self, path: &Path) -> Result<()> {
+        self.inner.validate(path)
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        self.inner.watch(path, recursive_mode)?;
+        if !self.scanners.contains_key(path) {
+            let recursive = recursive_mode == RecursiveMode::Recursive;
+            let scanner = self.spawn(path, recursive);
+            self.scanners.insert(path.to_owned(), scanner);
+        }
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        self.scanners.remove(path);
+        self.inner.unwatch(path)
+    }
+}
+
+#[test]
+fn test_diff() {
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    fs::create_dir_all(base.join("a/b")).unwrap();
+    fs::write(base.join("a/b/kept"), b"").unwrap();
+    fs::write(base.join("a/removed"), b"").unwrap();
+    fs::write(base.join("grown"), b"").unwrap();
+    let old = snapshot(base, true);
+    assert_eq!(old.len(), 5);
+    assert_eq!(snapshot(base, false).len(), 2);
+
+    fs::remove_file(base.join("a/removed")).unwrap();
+    fs::write(base.join("a/b/created"), b"").unwrap();
+    fs::write(base.join("grown"), b"more").unwrap();
+    let new = snapshot(base, true);
+
+    assert_eq!(
+        diff(&old, &new),
+        [
+            base.join("a/b/created"),
+            base.join("a/removed"),
+            base.join("grown")
+        ]
+    );
+    assert!(diff(&new, &new).is_empty());
+}
@@ -0,0 +1,375 @@
+//! Running as a Windows service: `service install` registers the monitor
+//! with the service control manager, which starts it as `service run` and
+//! calls back into `service_main` on a thread of its own. That serves until
+//! the service is stopped.
+
+use crate::error::Result;
+use log::{error, info};
+use std::ffi::{c_void, OsStr};
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::AsRawHandle;
+use std::ptr::{null, null_mut};
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Notify;
+use windows_sys::core::PWSTR;
+use windows_sys::Win32::Foundation::{
+    CloseHandle, BOOL, ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR,
+};
+use windows_sys::Win32::Security::Authorization::{
+    ConvertSidToStringSidW, ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+};
+use windows_sys::Win32::Security::{
+    GetTokenInformation, TokenUser, PSECURITY_DESCRIPTOR, SC_HANDLE, SECURITY_ATTRIBUTES,
+    TOKEN_QUERY, TOKEN_USER,
+};
+use windows_sys::Win32::Storage::FileSystem::DELETE;
+use windows_sys::Win32::System::Memory::LocalFree;
+use windows_sys::Win32::System::Pipes::GetNamedPipeClientProcessId;
+use windows_sys::Win32::System::Services::{
+    ChangeServiceConfig2W, CloseServiceHandle, ControlService, CreateServiceW, DeleteService,
+    OpenSCManagerW, OpenServiceW, RegisterServiceCtrlHandlerExW, SetServiceStatus,
+    StartServiceCtrlDispatcherW, StartServiceW, SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE,
+    SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_ALL_ACCESS, SERVICE_AUTO_START,
+    SERVICE_CONFIG_DESCRIPTION, SERVICE_CONTROL_INTERROGATE, SERVICE_CONTROL_SHUTDOWN,
+    SERVICE_CONTROL_STOP, SERVICE_DESCRIPTIONW, SERVICE_ERROR_NORMAL, SERVICE_QUERY_STATUS,
+    SERVICE_RUNNING, SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE, SERVICE_STOP, SERVICE_STOPPED,
+    SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+};
+use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+/// Name the service is registered and shown under.
+pub const SERVICE_NAME: &str = "unison-fsmonitor";
+
+const DESCRIPTION: &str = "Reports file changes to the unison runs connecting to it.";
+
+/// What the service does: serve until the `Notify` is notified, then
+/// return.
+pub type Serve =
+    fn(Arc<Notify>) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+/// Handed from `run` to `service_main`, which Windows calls without any.
+static SERVE: OnceLock<Serve> = OnceLock::new();
+static STOP: OnceLock<Arc<Notify>> = OnceLock::new();
+static STATUS_HANDLE: AtomicIsize = AtomicIsize::new(0);
+
+fn wide(text: impl AsRef<OsStr>) -> Vec<u16> {
+    text.as_ref().encode_wide().chain(Some(0)).collect()
+}
+
+fn check(ok: BOOL) -> io::Result<()> {
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A handle of the service control manager or of a service.
+struct Handle(SC_HANDLE);
+
+impl Handle {
+    fn new(handle: SC_HANDLE) -> io::Result<Self> {
+        if handle == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(handle))
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe { CloseServiceHandle(self.0) };
+    }
+}
+
+/// The SID of the account this process runs as, in its string form.
+pub fn current_user() -> io::Result<String> {
+    let mut token = 0;
+    check(unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) })?;
+    let mut len = 0;
+    // Sized by the first call, which fails for the lack of a buffer.
+    unsafe { GetTokenInformation(token, TokenUser, null_mut(), 0, &mut len) };
+    // In words, for the alignment of the pointers in it.
+    let mut buffer = vec![0u64; (len as usize).div_ceil(8)];
+    let queried = check(unsafe {
+        GetTokenInformation(token, TokenUser, buffer.as_mut_ptr().cast(), len, &mut len)
+    });
+    unsafe { CloseHandle(token) };
+    queried?;
+    // SAFETY: filled in as a `TOKEN_USER` by the call.
+    let user = unsafe { &*buffer.as_ptr().cast::<TOKEN_USER>() };
+    let mut sid: PWSTR = null_mut();
+    check(unsafe { ConvertSidToStringSidW(user.User.Sid, &mut sid) })?;
+    // SAFETY: a NUL terminated string, freed once copied.
+    let text = unsafe {
+        let len = (0..).take_while(|&i| *sid.add(i) != 0).count();
+        let text = String::from_utf16_lossy(std::slice::from_raw_parts(sid, len));
+        LocalFree(sid as isize);
+        text
+    };
+    Ok(text)
+}
+
+/// Who may open a named pipe: only the local system and the account of a
+/// SID, and only on this machine.
+pub struct PipeSecurity {
+    descriptor: PSECURITY_DESCRIPTOR,
+    attributes: SECURITY_ATTRIBUTES,
+}
+
+impl PipeSecurity {
+    pub fn new(user: &str) -> io::Result<Self> {
+        let sddl = wide(format!("D:P(A;;GA;;;SY)(A;;GA;;;{})", user));
+        let mut descriptor = null_mut();
+        check(unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                sddl.as_ptr(),
+                SDDL_REVISION_1,
+                &mut descriptor,
+                null_mut(),
+            )
+        })?;
+        Ok(Self {
+            descriptor,
+            attributes: SECURITY_ATTRIBUTES {
+                nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+                lpSecurityDescriptor: descriptor,
+                bInheritHandle: 0,
+            },
+        })
+    }
+
+    /// The `SECURITY_ATTRIBUTES` to create pipe instances with.
+    pub fn attributes(&mut self) -> *mut c_void {
+        (&mut self.attributes as *mut SECURITY_ATTRIBUTES).cast()
+    }
+}
+
+impl Drop for PipeSecurity {
+    fn drop(&mut self) {
+        unsafe { LocalFree(self.descriptor as isize) };
+    }
+}
+
+/// The process at the other end of a named pipe, if Windows tells.
+pub fn client_pid(pipe: &impl AsRawHandle) -> Option<u32> {
+    let mut pid = 0;
+    let ok = unsafe { GetNamedPipeClientProcessId(pipe.as_raw_handle() as isize, &mut pid) };
+    (ok != 0).then_some(pid)
+}
+
+/// `arg` as `CommandLineToArgvW` reads it back: quoted if it has spaces or
+/// quotes, where backslashes only need escaping before a quote.
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_owned();
+    }
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        if c == '\\' {
+            backslashes += 1;
+            continue;
+        }
+        let escapes = if c == '"' {
+            backslashes * 2 + 1
+        } else {
+            backslashes
+        };
+        quoted.extend(std::iter::repeat_n('\\', escapes));
+        quoted.push(c);
+        backslashes = 0;
+    }
+    quoted.extend(std::iter::repeat_n('\\', backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+/// The command line the service starts with: that of `service install`,
+/// ending in `run` instead, for `user` to connect to.
+fn command_line(exe: &str, args: &[String], user: &str) -> String {
+    let args = match args.split_last() {
+        Some((last, args)) if last == "install" => args,
+        _ => args,
+    };
+    let mut line = quote(exe);
+    for arg in args
+        .iter()
+        .map(String::as_str)
+        .chain(["run", "--user", user])
+    {
+        line.push(' ');
+        line.push_str(&quote(arg));
+    }
+    line
+}
+
+/// Register the service to start with Windows, with the options in `args`,
+/// those of this process, and start it. Only the account installing it may
+/// connect to its pipe.
+pub fn install(args: &[String]) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let line = command_line(&exe.to_string_lossy(), args, &current_user()?);
+    let manager =
+        Handle::new(unsafe { OpenSCManagerW(null(), null(), SC_MANAGER_CREATE_SERVICE) })?;
+    let (name, path) = (wide(SERVICE_NAME), wide(&line));
+    let service = Handle::new(unsafe {
+        CreateServiceW(
+            manager.0,
+            name.as_ptr(),
+            name.as_ptr(),
+            SERVICE_ALL_ACCESS,
+            SERVICE_WIN32_OWN_PROCESS,
+            SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL,
+            path.as_ptr(),
+            null(),
+            null_mut(),
+            null(),
+            null(),
+            null(),
+        )
+    })?;
+    let mut description = wide(DESCRIPTION);
+    let info = SERVICE_DESCRIPTIONW {
+        lpDescription: description.as_mut_ptr(),
+    };
+    // Nothing but the services console shows it.
+    unsafe {
+        ChangeServiceConfig2W(
+            service.0,
+            SERVICE_CONFIG_DESCRIPTION,
+            (&info as *const SERVICE_DESCRIPTIONW).cast(),
+        )
+    };
+    check(unsafe { StartServiceW(service.0, 0, null()) })?;
+    info!("Installed and started service {}: {}", SERVICE_NAME, line);
+    Ok(())
+}
+
+/// Stop the service if it runs, and remove it.
+pub fn uninstall() -> Result<()> {
+    let manager = Handle::new(unsafe { OpenSCManagerW(null(), null(), SC_MANAGER_CONNECT) })?;
+    let name = wide(SERVICE_NAME);
+    let access = SERVICE_STOP | SERVICE_QUERY_STATUS | DELETE;
+    let service = Handle::new(unsafe { OpenServiceW(manager.0, name.as_ptr(), access) })?;
+    // SAFETY: plain data, filled in by the call.
+    let mut status: SERVICE_STATUS = unsafe { std::mem::zeroed() };
+    // Failing if it is not running.
+    unsafe { ControlService(service.0, SERVICE_CONTROL_STOP, &mut status) };
+    check(unsafe { DeleteService(service.0) })?;
+    info!("Removed service {}", SERVICE_NAME);
+    Ok(())
+}
+
+/// Hand this thread to the service control manager, which runs `serve` on
+/// another and returns once the service stopped. Fails unless Windows
+/// started this process as the service.
+pub fn run(serve: Serve) -> Result<()> {
+    let _ = SERVE.set(serve);
+    let mut name = wide(SERVICE_NAME);
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: name.as_mut_ptr(),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: null_mut(),
+            lpServiceProc: None,
+        },
+    ];
+    check(unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) })?;
+    Ok(())
+}
+
+/// Tell the service control manager how the service is doing, with a
+/// service specific `exit_code` once it stopped.
+fn set_status(state: SERVICE_STATUS_CURRENT_STATE, exit_code: u32) {
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: if state == SERVICE_RUNNING {
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+        } else {
+            0
+        },
+        dwWin32ExitCode: if exit_code == 0 {
+            NO_ERROR
+        } else {
+            ERROR_SERVICE_SPECIFIC_ERROR
+        },
+        dwServiceSpecificExitCode: exit_code,
+        dwCheckPoint: 0,
+        dwWaitHint: if state == SERVICE_STOP_PENDING {
+            5000
+        } else {
+            0
+        },
+    };
+    unsafe { SetServiceStatus(STATUS_HANDLE.load(Ordering::Relaxed), &status) };
+}
+
+unsafe extern "system" fn handle_control(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    _context: *mut c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_status(SERVICE_STOP_PENDING, 0);
+            if let Some(stop) = STOP.get() {
+                stop.notify_one();
+            }
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut PWSTR) {
+    let name = wide(SERVICE_NAME);
+    let handle = RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(handle_control), null());
+    if handle == 0 {
+        error!(
+            "Cannot register the service control handler: {}",
+            io::Error::last_os_error()
+        );
+        return;
+    }
+    STATUS_HANDLE.store(handle, Ordering::Relaxed);
+    let stop = STOP.get_or_init(|| Arc::new(Notify::new())).clone();
+    set_status(SERVICE_RUNNING, 0);
+    let exit_code = match SERVE.get().map(|serve| serve(stop)) {
+        Some(Ok(())) => 0,
+        Some(Err(err)) => {
+            error!("Service failed: {}", err);
+            1
+        }
+        None => 1,
+    };
+    set_status(SERVICE_STOPPED, exit_code);
+}
+
+#[test]
+fn test_command_line() {
+    assert_eq!(quote(r"C:\bin\fsmonitor.exe"), r"C:\bin\fsmonitor.exe");
+    assert_eq!(quote(r"C:\Program Files\x"), r#""C:\Program Files\x""#);
+    assert_eq!(quote(r#"say "hi""#), r#""say \"hi\"""#);
+    assert_eq!(quote(r"C:\my dir\"), r#""C:\my dir\\""#);
+    assert_eq!(quote(""), r#""""#);
+
+    let args: Vec<String> = ["--listen", "127.0.0.1:8000", "service", "install"]
+        .map(String::from)
+        .into();
+    assert_eq!(
+        command_line(
+            r"C:\Program Files\fsmonitor.exe",
+            &args,
+            "S-1-5-21-1-2-3-1001"
+        ),
+        r#""C:\Program Files\fsmonitor.exe" --listen 127.0.0.1:8000 service run --user S-1-5-21-1-2-3-1001"#
+    );
+}
@@ -0,0 +1,158 @@
+//! `--once`: watch some roots for a single window, print what changed in it
+//! and exit, for scripts and cron jobs rather than `-repeat watch`.
+
+use crate::error::{MonitorError, Result};
+use crate::io::EventReceiver;
+use crate::monitor::{Event, Monitor, Watch};
+use crate::protocol::{encode, Response, ResponseSink};
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+
+/// How changes are printed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// `CHANGES` with the root for each root that changed, then a `RECURSIVE`
+    /// line per change and `DONE`, as unison would hear them.
+    #[default]
+    Protocol,
+    /// One absolute path per line.
+    Plain,
+}
+
+/// Collects the errors the monitor would send unison.
+#[derive(Debug, Default)]
+pub struct Errors(pub Vec<String>);
+
+impl ResponseSink for Errors {
+    fn send(&mut self, response: Response) -> io::Result<()> {
+        if let Response::Error(msg) = response {
+            self.0.push(msg);
+        }
+        Ok(())
+    }
+
+    fn flush_responses(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Watch each of `roots`, handle events until the window of the slowest one
+/// passed, then write the changes to `out`.
+pub async fn run<WATCH: Watch>(
+    monitor: &mut Monitor<WATCH, Errors>,
+    mut rx: EventReceiver,
+    roots: &[PathBuf],
+    format: Format,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut window = Duration::ZERO;
+    for root in roots {
+        // The root doubles as the replica id.
+        let id = root.to_string_lossy();
+        monitor.handle_event(Event::Input(format!(
+            "START {} {}\n",
+            encode(OsStr::new(id.as_ref())),
+            encode(root.as_os_str())
+        )))?;
+        match monitor.replicas.get(id.as_ref()) {
+            Some(replica) => {
+                let settings = &replica.settings;
+                // The debouncer holds events back for another timeout, and
+                // polling needs a scan to notice anything.
+                let mut root_window = settings.debounce * 2;
+                if settings.backend == "poll" {
+                    root_window += settings.poll_interval;
+                }
+                window = window.max(root_window);
+            }
+            None => {
+                let errors = std::mem::take(&mut monitor.writer.0);
+                monitor.shutdown()?;
+                return Err(MonitorError::WatchError(errors.join(", ")));
+            }
+        }
+    }
+
+    let deadline = Instant::now() + window;
+    while let Ok(Some(event)) = timeout_at(deadline, rx.recv()).await {
+        if let Err(err) = monitor.handle_event(event) {
+            monitor.handle_error(err)?;
+        }
+    }
+
+    for root in roots {
+        let id = root.to_string_lossy();
+        let changes = match monitor.replicas.get_mut(id.as_ref()) {
+            Some(replica) => replica.take_changes(),
+            None => continue,
+        };
+        match format {
+            Format::Protocol if !changes.is_empty() => {
+                writeln!(out, "{}", Response::Changes(id.into()))?;
+                for path in changes {
+                    writeln!(out, "{}", Response::Recursive(path))?;
+                }
+                writeln!(out, "{}", Response::Done)?;
+            }
+            Format::Protocol => {}
+            Format::Plain => {
+                for path in changes {
+                    writeln!(out, "{}", root.join(path).display())?;
+                }
+            }
+        }
+    }
+    out.flush()?;
+    monitor.shutdown()
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::io::{event_channel, EVENT_CAPACITY};
+    use notify::event::{CreateKind, EventKind};
+
+    struct Watcher {}
+
+    impl Watch for Watcher {}
+
+    async fn snapshot(format: Format) -> String {
+        let mut monitor = Monitor::new(|_, _| Ok(Watcher {}), Errors::default());
+        let (tx, rx) = event_channel(EVENT_CAPACITY);
+        tx.send(Event::FSEvent(
+            notify::Event::new(EventKind::Create(CreateKind::Any))
+                .add_path("/tmp/sample/a b".into())
+                .add_path("/tmp/other/c".into()),
+        ))
+        .unwrap();
+        let roots = [PathBuf::from("/tmp/sample"), "/tmp/other".into()];
+        let mut out = vec![];
+
+        run(&mut monitor, rx, &roots, format, &mut out)
+            .await
+            .unwrap();
+
+        assert!(monitor.replicas.is_empty());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_protocol() {
+        assert_eq!(
+            snapshot(Format::Protocol).await,
+            "CHANGES /tmp/sample\nRECURSIVE a%20b\nDONE\n\
+             CHANGES /tmp/other\nRECURSIVE c\nDONE\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_plain() {
+        assert_eq!(
+            snapshot(Format::Plain).await,
+            "/tmp/sample/a b\n/tmp/other/c\n"
+        );
+    }
+}
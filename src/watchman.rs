@@ -0,0 +1,373 @@
+//! Watchman, for trees a Watchman server watches already, as large
+//! monorepos tend to have. Each watched path is a subscription on one
+//! connection to the server, whose file notifications become events.
+//! Watchman crawls, recrawls and ignores what `.watchmanconfig` says as it
+//! would for any other client.
+
+use log::warn;
+use notify::event::{CreateKind, EventKind, Flag, ModifyKind, RemoveKind};
+use notify::{Config, EventHandler, RecursiveMode, WatcherKind};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// Fields of the files in a notification.
+const FIELDS: [&str; 4] = ["name", "exists", "new", "type"];
+
+fn generic(msg: &str) -> notify::Error {
+    notify::Error::generic(&format!("Watchman: {}", msg))
+}
+
+/// Where the server listens: `$WATCHMAN_SOCK` as for Watchman's own
+/// clients, or what the watchman command says, which starts the server if
+/// need be.
+fn sockname() -> notify::Result<PathBuf> {
+    if let Some(sock) = std::env::var_os("WATCHMAN_SOCK") {
+        return Ok(sock.into());
+    }
+    let output = Command::new("watchman")
+        .args(["--output-encoding=json", "--no-pretty", "get-sockname"])
+        .output()
+        .map_err(|err| generic(&format!("cannot run watchman: {}", err)))?;
+    let response: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|_| generic(String::from_utf8_lossy(&output.stderr).trim()))?;
+    match response["sockname"].as_str() {
+        Some(sock) => Ok(sock.into()),
+        None => Err(response_error(&response)),
+    }
+}
+
+fn response_error(response: &Value) -> notify::Error {
+    generic(response["error"].as_str().unwrap_or("unexpected response"))
+}
+
+/// The events of a subscription's notification, for the files below `dir`.
+fn events(dir: &Path, pdu: &Value) -> Vec<notify::Event> {
+    // Watchman recrawled, after its own queue overflowed, or the tree is
+    // gone: anything may have changed.
+    if pdu["is_fresh_instance"] == true || pdu["canceled"] == true {
+        return vec![notify::Event::new(EventKind::Other)
+            .set_flag(Flag::Rescan)
+            .add_path(dir.to_owned())];
+    }
+    let files = pdu["files"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    files
+        .iter()
+        .filter_map(|file| {
+            let name = file["name"].as_str()?;
+            let kind = if file["exists"] == false {
+                EventKind::Remove(RemoveKind::Any)
+            } else if file["new"] == true && file["type"] == "d" {
+                EventKind::Create(CreateKind::Folder)
+            } else if file["new"] == true {
+                EventKind::Create(CreateKind::File)
+            } else {
+                EventKind::Modify(ModifyKind::Any)
+            };
+            Some(notify::Event::new(kind).add_path(dir.join(name)))
+        })
+        .collect()
+}
+
+/// The directory a path is subscribed through, and which of its files the
+/// subscription is for. Watchman watches directories only, a file is
+/// watched through its directory.
+fn query(path: &Path, recursive_mode: RecursiveMode) -> (PathBuf, Option<Value>) {
+    let name = path.file_name().and_then(|name| name.to_str());
+    match (path.parent(), name) {
+        (Some(dir), Some(name)) if !path.is_dir() => {
+            (dir.to_owned(), Some(json!(["name", name, "wholename"])))
+        }
+        _ if recursive_mode == RecursiveMode::NonRecursive => (
+            path.to_owned(),
+            Some(json!(["dirname", "", ["depth", "eq", 0]])),
+        ),
+        _ => (path.to_owned(), None),
+    }
+}
+
+/// Subscriptions by name, with the directory the names of their files are
+/// relative to.
+type Subscriptions = Arc<Mutex<HashMap<String, PathBuf>>>;
+
+/// Pass on notifications and hand back responses until the connection
+/// closes.
+fn read_pdus(
+    stream: UnixStream,
+    responses: mpsc::Sender<Value>,
+    subscriptions: Subscriptions,
+    stopped: Arc<AtomicBool>,
+    mut handler: impl EventHandler,
+) {
+    for line in BufReader::new(stream).lines() {
+        let pdu = line.and_then(|line| Ok(serde_json::from_str::<Value>(&line)?));
+        let pdu = match pdu {
+            Ok(pdu) => pdu,
+            Err(_) => break,
+        };
+        if pdu["unilateral"] != true {
+            if responses.send(pdu).is_err() {
+                return;
+            }
+            continue;
+        }
+        let dir = pdu["subscription"]
+            .as_str()
+            .and_then(|name| subscriptions.lock().unwrap().get(name).cloned());
+        if let Some(dir) = dir {
+            for event in events(&dir, &pdu) {
+                handler.handle_event(Ok(event));
+            }
+        }
+    }
+    if stopped.load(Ordering::Relaxed) {
+        return;
+    }
+    // Changes from now on are lost, have all watched trees rescanned.
+    let mut err = generic("the server closed the connection");
+    for dir in subscriptions.lock().unwrap().values() {
+        err = err.add_path(dir.clone());
+    }
+    handler.handle_event(Err(err));
+}
+
+/// A watched path.
+struct Watched {
+    /// The root Watchman watches it under.
+    root: String,
+    subscription: String,
+}
+
+/// Watches paths through subscriptions on one connection to a Watchman
+/// server.
+pub struct WatchmanWatcher {
+    stream: UnixStream,
+    responses: mpsc::Receiver<Value>,
+    subscriptions: Subscriptions,
+    stopped: Arc<AtomicBool>,
+    watched: HashMap<PathBuf, Watched>,
+    /// For names of subscriptions.
+    next: usize,
+}
+
+impl WatchmanWatcher {
+    /// Connect to the server listening on `sock`.
+    pub fn connect<F: EventHandler>(sock: &Path, event_handler: F) -> notify::Result<Self> {
+        let stream = UnixStream::connect(sock)
+            .map_err(|err| generic(&format!("cannot connect to {}: {}", sock.display(), err)))?;
+        let reader = stream.try_clone().map_err(notify::Error::io)?;
+        let (responses_tx, responses) = mpsc::channel();
+        let subscriptions = Subscriptions::default();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (shared, flag) = (subscriptions.clone(), stopped.clone());
+        thread::Builder::new()
+            .name("watchman".into())
+            .spawn(move || read_pdus(reader, responses_tx, shared, flag, event_handler))
+            .map_err(notify::Error::io)?;
+        Ok(Self {
+            stream,
+            responses,
+            subscriptions,
+            stopped,
+            watched: HashMap::new(),
+            next: 0,
+        })
+    }
+
+    /// Send a command and wait for its response.
+    fn command(&mut self, command: Value) -> notify::Result<Value> {
+        let mut line = command.to_string();
+        line.push('\n');
+        self.stream
+            .write_all(line.as_bytes())
+            .map_err(notify::Error::io)?;
+        let response = self
+            .responses
+            .recv()
+            .map_err(|_| generic("the server closed the connection"))?;
+        if response.get("error").is_some() {
+            return Err(response_error(&response));
+        }
+        if let Some(warning) = response["warning"].as_str() {
+            warn!("Watchman: {}", warning);
+        }
+        Ok(response)
+    }
+}
+
+impl notify::Watcher for WatchmanWatcher {
+    fn new<F: EventHandler>(event_handler: F, _config: Config) -> notify::Result<Self> {
+        Self::connect(&sockname()?, event_handler)
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        if !path.exists() {
+            return Err(notify::Error::path_not_found().add_path(path.to_owned()));
+        }
+        let _ = self.unwatch(path);
+        let (dir, expression) = query(path, recursive_mode);
+        let utf8 = dir.to_str().ok_or_else(|| {
+            generic("cannot watch a path that is not UTF-8").add_path(dir.clone())
+        })?;
+        let project = self.command(json!(["watch-project", utf8]))?;
+        let root = match project["watch"].as_str() {
+            Some(root) => root.to_owned(),
+            None => return Err(response_error(&project)),
+        };
+        // Changes from now on only, not all files there are.
+        let clock = self.command(json!(["clock", root]))?["clock"].clone();
+        let mut query = json!({
+            "since": clock,
+            "fields": FIELDS,
+            "empty_on_fresh_instance": true,
+        });
+        if let Some(relative) = project["relative_path"].as_str() {
+            query["relative_root"] = relative.into();
+        }
+        if let Some(expression) = expression {
+            query["expression"] = expression;
+        }
+        let subscription = format!("unison-fsmonitor-{}", self.next);
+        self.next += 1;
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .insert(subscription.clone(), dir);
+        let subscribed = self.command(json!(["subscribe", root, subscription, query]));
+        if let Err(err) = subscribed {
+            self.subscriptions.lock().unwrap().remove(&subscription);
+            return Err(err.add_path(path.to_owned()));
+        }
+        self.watched
+            .insert(path.to_owned(), Watched { root, subscription });
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        let watched = match self.watched.remove(path) {
+            Some(watched) => watched,
+            None => return Err(notify::Error::watch_not_found().add_path(path.to_owned())),
+        };
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .remove(&watched.subscription);
+        self.command(json!(["unsubscribe", watched.root, watched.subscription]))?;
+        Ok(())
+    }
+
+    /// None of notify's kinds, which only tell per-directory and polling
+    /// backends from the rest here.
+    fn kind() -> WatcherKind {
+        WatcherKind::NullWatcher
+    }
+}
+
+impl Drop for WatchmanWatcher {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // Subscriptions end with the connection.
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+#[test]
+fn test_events() {
+    let dir = Path::new("/repo/sub");
+    let pdu = json!({
+        "subscription": "unison-fsmonitor-0",
+        "unilateral": true,
+        "files": [
+            {"name": "a/new", "exists": true, "new": true, "type": "f"},
+            {"name": "a/dir", "exists": true, "new": true, "type": "d"},
+            {"name": "changed", "exists": true, "new": false, "type": "f"},
+            {"name": "gone", "exists": false, "new": false, "type": "f"},
+        ],
+    });
+    let changes: Vec<_> = events(dir, &pdu)
+        .into_iter()
+        .map(|event| (event.kind, event.paths))
+        .collect();
+    assert_eq!(
+        changes,
+        [
+            (EventKind::Create(CreateKind::File), vec![dir.join("a/new")]),
+            (
+                EventKind::Create(CreateKind::Folder),
+                vec![dir.join("a/dir")]
+            ),
+            (
+                EventKind::Modify(ModifyKind::Any),
+                vec![dir.join("changed")]
+            ),
+            (EventKind::Remove(RemoveKind::Any), vec![dir.join("gone")]),
+        ]
+    );
+    let fresh = events(dir, &json!({"is_fresh_instance": true, "files": []}));
+    assert_eq!(fresh[0].flag(), Some(Flag::Rescan));
+    assert_eq!(fresh[0].paths, [dir]);
+}
+
+#[test]
+fn test_watchman() {
+    use notify::Watcher;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::time::Duration;
+
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    fs::create_dir_all(base.join("repo/sub")).unwrap();
+    let sock = base.join("sock");
+    let listener = UnixListener::bind(&sock).unwrap();
+    // Answers as Watchman would, then notifies once.
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let writer = stream.try_clone().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let mut respond = |response: Value| {
+            let command: Value = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+            writeln!(&writer, "{}", response).unwrap();
+            command
+        };
+        let project = respond(json!({"watch": "/repo", "relative_path": "sub"}));
+        assert_eq!(project[0], "watch-project");
+        respond(json!({"clock": "c:1:2"}));
+        let subscribe = respond(json!({"subscribe": "unison-fsmonitor-0", "clock": "c:1:2"}));
+        assert_eq!(subscribe[1], "/repo");
+        assert_eq!(subscribe[3]["relative_root"], "sub");
+        assert_eq!(subscribe[3]["since"], "c:1:2");
+        let pdu = json!({
+            "subscription": "unison-fsmonitor-0",
+            "unilateral": true,
+            "files": [{"name": "file", "exists": true, "new": true, "type": "f"}],
+        });
+        writeln!(&writer, "{}", pdu).unwrap();
+        let unsubscribe = respond(json!({"unsubscribe": "unison-fsmonitor-0"}));
+        assert_eq!(unsubscribe[0], "unsubscribe");
+    });
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = WatchmanWatcher::connect(&sock, tx).unwrap();
+    let watched = base.join("repo/sub");
+    watcher.watch(&watched, RecursiveMode::Recursive).unwrap();
+    let event = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!(event.kind, EventKind::Create(CreateKind::File));
+    assert_eq!(event.paths, [watched.join("file")]);
+
+    watcher.unwatch(&watched).unwrap();
+    assert!(watcher.unwatch(&watched).is_err());
+    server.join().unwrap();
+    // The server went away.
+    assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap().is_err());
+}
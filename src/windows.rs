@@ -0,0 +1,287 @@
+//! ReadDirectoryChangesW on Windows, one blocking read per watched
+//! directory on a thread of its own. When more changes arrive than the
+//! buffer holds, Windows drops them all and says so with an empty read;
+//! notify's watcher passes that on as nothing at all. Here it asks for a
+//! rescan of the watched tree.
+
+use notify::event::{CreateKind, EventKind, Flag, ModifyKind, RemoveKind, RenameMode};
+use notify::{Config, EventHandler, RecursiveMode, WatcherKind};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use windows_sys::Win32::Foundation::{
+    CloseHandle, ERROR_OPERATION_ABORTED, HANDLE, INVALID_HANDLE_VALUE,
+};
+use windows_sys::Win32::Storage::FileSystem::{
+    CreateFileW, ReadDirectoryChangesW, FILE_ACTION_ADDED, FILE_ACTION_MODIFIED,
+    FILE_ACTION_REMOVED, FILE_ACTION_RENAMED_NEW_NAME, FILE_ACTION_RENAMED_OLD_NAME,
+    FILE_FLAG_BACKUP_SEMANTICS, FILE_LIST_DIRECTORY, FILE_NOTIFY_CHANGE_ATTRIBUTES,
+    FILE_NOTIFY_CHANGE_CREATION, FILE_NOTIFY_CHANGE_DIR_NAME, FILE_NOTIFY_CHANGE_FILE_NAME,
+    FILE_NOTIFY_CHANGE_LAST_WRITE, FILE_NOTIFY_CHANGE_SECURITY, FILE_NOTIFY_CHANGE_SIZE,
+    FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE, OPEN_EXISTING,
+};
+use windows_sys::Win32::System::IO::CancelIoEx;
+
+/// Bytes read at once, the most network shares take.
+const BUF_SIZE: usize = 64 * 1024;
+
+/// What each directory is watched for.
+const FILTER: u32 = FILE_NOTIFY_CHANGE_FILE_NAME
+    | FILE_NOTIFY_CHANGE_DIR_NAME
+    | FILE_NOTIFY_CHANGE_ATTRIBUTES
+    | FILE_NOTIFY_CHANGE_SIZE
+    | FILE_NOTIFY_CHANGE_LAST_WRITE
+    | FILE_NOTIFY_CHANGE_CREATION
+    | FILE_NOTIFY_CHANGE_SECURITY;
+
+/// Size of `FILE_NOTIFY_INFORMATION` up to the name.
+const HEADER_LEN: usize = 12;
+
+/// The action and the name relative to the watched directory of each
+/// change in what one read returned.
+fn parse(buf: &[u8]) -> Vec<(u32, PathBuf)> {
+    let u32_at = |at: usize| u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap());
+    let mut changes = vec![];
+    let mut at = 0;
+    while at + HEADER_LEN <= buf.len() {
+        let next = u32_at(at) as usize;
+        let action = u32_at(at + 4);
+        let len = u32_at(at + 8) as usize;
+        let name = match buf.get(at + HEADER_LEN..at + HEADER_LEN + len) {
+            Some(name) => name,
+            None => break,
+        };
+        let name: Vec<u16> = name
+            .chunks_exact(2)
+            .map(|pair| u16::from_ne_bytes([pair[0], pair[1]]))
+            .collect();
+        changes.push((action, OsString::from_wide(&name).into()));
+        if next == 0 {
+            break;
+        }
+        at += next;
+    }
+    changes
+}
+
+fn kind(action: u32) -> EventKind {
+    match action {
+        FILE_ACTION_ADDED => EventKind::Create(CreateKind::Any),
+        FILE_ACTION_REMOVED => EventKind::Remove(RemoveKind::Any),
+        FILE_ACTION_MODIFIED => EventKind::Modify(ModifyKind::Any),
+        FILE_ACTION_RENAMED_OLD_NAME => EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+        FILE_ACTION_RENAMED_NEW_NAME => EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+        _ => EventKind::Other,
+    }
+}
+
+/// A directory handle, moved to the thread reading it.
+#[derive(Clone, Copy)]
+struct Handle(HANDLE);
+
+// SAFETY: handles may be used and closed from any thread.
+unsafe impl Send for Handle {}
+
+/// A watched directory.
+struct Reader {
+    handle: Handle,
+    stopped: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Reader {
+    fn stop(self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // A read started after the cancel would block, cancel until the
+        // thread is gone.
+        while !self.thread.is_finished() {
+            unsafe { CancelIoEx(self.handle.0, std::ptr::null()) };
+            thread::sleep(Duration::from_millis(10));
+        }
+        let _ = self.thread.join();
+        unsafe { CloseHandle(self.handle.0) };
+    }
+}
+
+/// Read the changes below `root` until stopped.
+fn read_changes(
+    root: &Path,
+    handle: Handle,
+    recursive: bool,
+    stopped: &AtomicBool,
+    handler: &Mutex<dyn EventHandler>,
+) {
+    // Names in the buffer are aligned to four bytes.
+    let mut buf = vec![0u32; BUF_SIZE / 4];
+    while !stopped.load(Ordering::Relaxed) {
+        let mut read = 0;
+        let ok = unsafe {
+            ReadDirectoryChangesW(
+                handle.0,
+                buf.as_mut_ptr().cast(),
+                BUF_SIZE as u32,
+                recursive.into(),
+                FILTER,
+                &mut read,
+                std::ptr::null_mut(),
+                None,
+            )
+        };
+        let mut handler = handler.lock().unwrap();
+        if ok == 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(ERROR_OPERATION_ABORTED as i32) {
+                handler.handle_event(Err(notify::Error::io(err).add_path(root.to_owned())));
+            }
+            return;
+        }
+        if read == 0 {
+            let event = notify::Event::new(EventKind::Other)
+                .set_flag(Flag::Rescan)
+                .add_path(root.to_owned());
+            handler.handle_event(Ok(event));
+            continue;
+        }
+        // SAFETY: `read` bytes of `buf` were filled in.
+        let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr().cast(), read as usize) };
+        for (action, name) in parse(bytes) {
+            let event = notify::Event::new(kind(action)).add_path(root.join(name));
+            handler.handle_event(Ok(event));
+        }
+    }
+}
+
+/// Watches each directory with a ReadDirectoryChangesW loop of its own.
+pub struct WindowsWatcher {
+    handler: Arc<Mutex<dyn EventHandler>>,
+    readers: HashMap<PathBuf, Reader>,
+}
+
+impl notify::Watcher for WindowsWatcher {
+    fn new<F: EventHandler>(event_handler: F, _config: Config) -> notify::Result<Self> {
+        Ok(Self {
+            handler: Arc::new(Mutex::new(event_handler)),
+            readers: HashMap::new(),
+        })
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        if !path.is_dir() {
+            return Err(notify::Error::path_not_found().add_path(path.to_owned()));
+        }
+        let _ = self.unwatch(path);
+        let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        let handle = unsafe {
+            CreateFileW(
+                wide.as_ptr(),
+                FILE_LIST_DIRECTORY,
+                FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+                std::ptr::null(),
+                OPEN_EXISTING,
+                FILE_FLAG_BACKUP_SEMANTICS,
+                0,
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            let err = io::Error::last_os_error();
+            return Err(notify::Error::io(err).add_path(path.to_owned()));
+        }
+        let handle = Handle(handle);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (root, flag, handler) = (path.to_owned(), stopped.clone(), self.handler.clone());
+        let recursive = recursive_mode == RecursiveMode::Recursive;
+        let thread = thread::spawn(move || {
+            read_changes(&root, handle, recursive, &flag, &*handler);
+        });
+        self.readers.insert(
+            path.to_owned(),
+            Reader {
+                handle,
+                stopped,
+                thread,
+            },
+        );
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        match self.readers.remove(path) {
+            Some(reader) => {
+                reader.stop();
+                Ok(())
+            }
+            None => Err(notify::Error::watch_not_found().add_path(path.to_owned())),
+        }
+    }
+
+    fn kind() -> WatcherKind {
+        WatcherKind::ReadDirectoryChangesWatcher
+    }
+}
+
+impl Drop for WindowsWatcher {
+    fn drop(&mut self) {
+        for (_, reader) in self.readers.drain() {
+            reader.stop();
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    let entry = |next: u32, action: u32, name: &str| {
+        let name: Vec<u8> = name.encode_utf16().flat_map(u16::to_ne_bytes).collect();
+        let mut entry = vec![];
+        entry.extend(next.to_ne_bytes());
+        entry.extend(action.to_ne_bytes());
+        entry.extend((name.len() as u32).to_ne_bytes());
+        entry.extend(name);
+        entry.resize(entry.len().next_multiple_of(4), 0);
+        entry
+    };
+    let first = entry(0, FILE_ACTION_RENAMED_OLD_NAME, r"dir\old");
+    let mut buf = entry(first.len() as u32, FILE_ACTION_RENAMED_OLD_NAME, r"dir\old");
+    buf.extend(entry(0, FILE_ACTION_RENAMED_NEW_NAME, r"dir\new"));
+    let changes = parse(&buf);
+    assert_eq!(
+        changes,
+        [
+            (FILE_ACTION_RENAMED_OLD_NAME, PathBuf::from(r"dir\old")),
+            (FILE_ACTION_RENAMED_NEW_NAME, PathBuf::from(r"dir\new")),
+        ]
+    );
+    assert_eq!(
+        kind(changes[1].0),
+        EventKind::Modify(ModifyKind::Name(RenameMode::To))
+    );
+    // Cut short, what is left is dropped.
+    assert_eq!(parse(&buf[..buf.len() - 4]).len(), 1);
+}
+
+#[test]
+fn test_windows() {
+    use notify::Watcher;
+    use std::fs;
+    use std::sync::mpsc;
+
+    let tmp = crate::test_dir();
+    let base = tmp.path();
+    fs::create_dir_all(base.join("dir")).unwrap();
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = WindowsWatcher::new(tx, Config::default()).unwrap();
+    watcher.watch(base, RecursiveMode::Recursive).unwrap();
+
+    fs::write(base.join(r"dir\file"), b"").unwrap();
+    let event = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!(event.kind, EventKind::Create(CreateKind::Any));
+    assert_eq!(event.paths, [base.join(r"dir\file")]);
+
+    watcher.unwatch(base).unwrap();
+    assert!(watcher.unwatch(base).is_err());
+}